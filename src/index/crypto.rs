@@ -0,0 +1,1053 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The key derivation and the wrapping of the store key.
+//!
+//! This module only deals with bytes, reading and writing the key file as
+//! well as generating random salts and IVs is left to the
+//! `EncryptedMmapDirectory`. Only `core` and `alloc` are used here, so the
+//! module can be reused in `no_std` contexts.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::fmt;
+
+use aes_ctr::stream_cipher::generic_array::GenericArray;
+use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher};
+use aes_ctr::Aes256Ctr;
+use crypto_mac::Mac;
+use crypto_mac::MacResult;
+use hkdf::Hkdf;
+use hmac::Hmac;
+use pbkdf2::pbkdf2;
+use sha2::Sha256;
+use sha2::Sha512;
+
+use zeroize::Zeroizing;
+
+/// KeyBuffer type that makes sure that the buffer is zeroed out before being
+/// dropped.
+pub(crate) type KeyBuffer = Zeroizing<Vec<u8>>;
+
+/// Key derivation result for our subsequent key derivations. The salt will be
+/// read from our key file and we will re-derive our encryption and MAC keys.
+pub(crate) type KeyDerivationResult = (KeyBuffer, KeyBuffer);
+
+// The constants here are chosen to be similar to the constants for the Matrix
+// key export format[1].
+// [1] https://matrix.org/docs/spec/client_server/r0.5.0#key-exports
+//
+// 16 byte random salt.
+pub(crate) const SALT_SIZE: usize = 16;
+// 16 byte random IV for the AES-CTR mode, see `Cipher::iv_size()`.
+pub(crate) const IV_SIZE: usize = 16;
+// 32 byte or 256 bit encryption keys. The store key and the key that wraps it
+// both have this size, a key file has no room for a store key of another size.
+pub(crate) const KEY_SIZE: usize = 32;
+// 32 byte message authentication code since HMAC-SHA256 is used.
+pub(crate) const MAC_LENGTH: usize = 32;
+// 1 byte for the store version.
+pub(crate) const VERSION: u8 = 1;
+// The version of key files whose store key was wrapped by a custom
+// `KeyWrapper`.
+pub(crate) const WRAPPED_VERSION: u8 = 2;
+// The version of passphrase protected key files that carry a canary, an
+// encrypted known plaintext that confirms the unwrapped store key.
+pub(crate) const CANARY_VERSION: u8 = 3;
+// The version of passphrase protected key files that record the length of
+// their header, so fields added to the header later on aren't ignored.
+pub(crate) const HEADER_LENGTH_VERSION: u8 = 4;
+// The version of passphrase protected key files that record the size of their
+// salt in the header, so a salt of a different size is refused before it's
+// read.
+pub(crate) const SALT_SIZE_VERSION: u8 = 5;
+// The plaintext of the canary, it's encrypted using a key that is expanded
+// from the store key.
+const CANARY_PLAINTEXT: [u8; CANARY_SIZE] = *b"seshat store key canary\0\0\0\0\0\0\0\0\0";
+// The size of the canary.
+const CANARY_SIZE: usize = 32;
+// The size of the part of a key file that follows the IV, the salt, the PBKDF
+// count, the MAC, and the encrypted key.
+const KEY_FILE_FIXED_SIZE: usize = SALT_SIZE + 4 + MAC_LENGTH + KEY_SIZE;
+// The number of bytes of a key file that need to be read to parse it, a single
+// byte more than a valid key file has, so a longer IV is noticed.
+pub(crate) const KEY_FILE_MAX_SIZE: usize =
+    1 + 1 + 1 + IV_SIZE + KEY_FILE_FIXED_SIZE + CANARY_SIZE + 1;
+// The size of a key slot, the IV, the salt, the PBKDF count, the two
+// encrypted keys, and the MAC.
+const KEY_SLOT_SIZE: usize = IV_SIZE + SALT_SIZE + 4 + 2 * KEY_SIZE + MAC_LENGTH;
+// Prefixes the MAC input of a key slot, the MAC key of the key file
+// authenticates the key file as well.
+const KEY_SLOT_CONTEXT: &[u8] = b"seshat key slot";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The errors of the key derivation and the wrapping of the store key.
+pub(crate) enum CryptoError {
+    /// The key file has an unknown version.
+    InvalidVersion,
+    /// The key file was written by a custom key wrapper.
+    WrappedKey,
+    /// The key file is too short.
+    Truncated,
+    /// The header of the key file has fields that were added by a newer
+    /// version.
+    UnknownHeaderFields,
+    /// A key slot doesn't have the expected size.
+    InvalidKeySlot,
+    /// The IV doesn't have the size the cipher expects.
+    InvalidIvLength {
+        length: usize,
+        cipher: Cipher,
+        expected: usize,
+    },
+    /// The store key doesn't have the expected size.
+    InvalidKeySize,
+    /// The salt doesn't have the expected size.
+    InvalidSaltSize,
+    /// The MAC of the store key didn't match.
+    InvalidMac,
+    /// The store key failed to decrypt its canary.
+    InvalidCanary,
+    /// A cipher or MAC couldn't be created using the given key.
+    InvalidKey,
+    /// The key couldn't be expanded.
+    KeyExpansion,
+    /// The end of the keystream was reached.
+    KeystreamEnd,
+    /// A primitive produced an unexpected result for a known answer test.
+    SelfTest(&'static str),
+    /// The files are sealed using a different cipher, or nonces of a
+    /// different size, than the key file declares.
+    CipherMismatch {
+        key_file: Cipher,
+        provider: Cipher,
+        nonce_size: usize,
+    },
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::InvalidVersion => f.write_str("invalid index store version"),
+            CryptoError::WrappedKey => {
+                f.write_str("the store key is wrapped by a custom key wrapper")
+            }
+            CryptoError::Truncated => f.write_str("truncated key file"),
+            CryptoError::UnknownHeaderFields => {
+                f.write_str("the key file header has fields this version doesn't understand")
+            }
+            CryptoError::InvalidIvLength {
+                length,
+                cipher,
+                expected,
+            } => write!(
+                f,
+                "invalid IV length {} for {:?}, expected {}",
+                length, cipher, expected
+            ),
+            CryptoError::InvalidKeySlot => f.write_str("invalid key slot size"),
+            CryptoError::InvalidKeySize => f.write_str("invalid store key size"),
+            CryptoError::InvalidSaltSize => f.write_str("invalid salt size"),
+            CryptoError::InvalidMac => f.write_str("invalid MAC of the store key"),
+            CryptoError::InvalidCanary => {
+                f.write_str("the store key doesn't decrypt the canary of the key file")
+            }
+            CryptoError::InvalidKey => f.write_str("invalid key length"),
+            CryptoError::KeyExpansion => f.write_str("unable to expand store key"),
+            CryptoError::KeystreamEnd => {
+                f.write_str("Decryption error, reached end of the keystream.")
+            }
+            CryptoError::SelfTest(primitive) => write!(f, "the {} self test failed", primitive),
+            CryptoError::CipherMismatch {
+                key_file,
+                provider,
+                nonce_size,
+            } => write!(
+                f,
+                "the key file declares {:?} but the files are sealed using {:?} with {} byte nonces",
+                key_file, provider, nonce_size
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The ciphers that encrypt the store key and, using the default provider,
+/// the Tantivy files.
+///
+/// The cipher of a key file is determined by the version of the key file.
+pub enum Cipher {
+    /// AES-256 in CTR mode, used by version 1, 3, 4, and 5 key files.
+    Aes256Ctr,
+}
+
+impl Cipher {
+    /// Get the cipher that a key file of the given version uses.
+    pub(crate) fn for_version(version: u8) -> Option<Self> {
+        match version {
+            VERSION | CANARY_VERSION | HEADER_LENGTH_VERSION | SALT_SIZE_VERSION => {
+                Some(Cipher::Aes256Ctr)
+            }
+            _ => None,
+        }
+    }
+
+    /// The size of the IV, in bytes, the cipher expects.
+    pub(crate) fn iv_size(self) -> usize {
+        match self {
+            Cipher::Aes256Ctr => IV_SIZE,
+        }
+    }
+
+    /// Check that the given IV has the size the cipher expects.
+    pub(crate) fn check_iv(self, iv: &[u8]) -> Result<(), CryptoError> {
+        if iv.len() == self.iv_size() {
+            Ok(())
+        } else {
+            Err(CryptoError::InvalidIvLength {
+                length: iv.len(),
+                cipher: self,
+                expected: self.iv_size(),
+            })
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Describes how the store key in a key file is protected.
+pub enum KeyProtection {
+    /// The store key is encrypted using the given cipher and a key that is
+    /// derived from a passphrase.
+    Passphrase(Cipher),
+    /// The store key was wrapped by the custom key wrapper with the given
+    /// name.
+    KeyWrapper(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The unencrypted header at the start of a key file.
+pub struct KeyFileHeader {
+    /// The version of the key file.
+    pub version: u8,
+    /// How the store key is protected.
+    pub protection: KeyProtection,
+}
+
+impl KeyFileHeader {
+    /// Parse the header at the start of the given key file data.
+    ///
+    /// Only the header is needed, that is the version and, for wrapped store
+    /// keys, the length and the name of the key wrapper. A header with fields
+    /// that were added by a newer version is refused.
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, CryptoError> {
+        let (version, data) = data.split_first().ok_or(CryptoError::Truncated)?;
+
+        let protection = if *version == WRAPPED_VERSION {
+            let (length, data) = data.split_first().ok_or(CryptoError::Truncated)?;
+            let name = data.get(..*length as usize).ok_or(CryptoError::Truncated)?;
+
+            KeyProtection::KeyWrapper(String::from_utf8_lossy(name).into_owned())
+        } else {
+            let cipher = Cipher::for_version(*version).ok_or(CryptoError::InvalidVersion)?;
+            check_header_length(*version, cipher, data)?;
+
+            KeyProtection::Passphrase(cipher)
+        };
+
+        Ok(KeyFileHeader {
+            version: *version,
+            protection,
+        })
+    }
+}
+
+/// The keys that protect the store key in the key file.
+pub(crate) struct WrappingKey {
+    pub(crate) key: KeyBuffer,
+    pub(crate) mac_key: KeyBuffer,
+    pub(crate) salt: Vec<u8>,
+    pub(crate) pbkdf_count: u32,
+}
+
+// Only the key derivation count is shown, the keys and the salt might
+// otherwise end up in logs.
+impl fmt::Debug for WrappingKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WrappingKey")
+            .field("pbkdf_count", &self.pbkdf_count)
+            .finish()
+    }
+}
+
+impl WrappingKey {
+    /// Derive the wrapping keys from the given passphrase and salt.
+    pub(crate) fn derive(passphrase: &str, salt: &[u8], pbkdf_count: u32) -> Self {
+        let (key, mac_key) = rederive_key(passphrase, salt, pbkdf_count);
+
+        WrappingKey {
+            key,
+            mac_key,
+            salt: salt.to_vec(),
+            pbkdf_count,
+        }
+    }
+
+    /// Encrypt the given store key using the given IV, the result is the
+    /// content of a key file.
+    pub(crate) fn encrypt(&self, store_key: &[u8], iv: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        Cipher::Aes256Ctr.check_iv(iv)?;
+
+        if store_key.len() != KEY_SIZE {
+            return Err(CryptoError::InvalidKeySize);
+        }
+
+        // The salt isn't prefixed by its size, a salt of a different size
+        // would shift the fields that follow it.
+        if self.salt.len() != SALT_SIZE {
+            return Err(CryptoError::InvalidSaltSize);
+        }
+
+        let mut encryptor =
+            Aes256Ctr::new_var(&self.key, iv).map_err(|_| CryptoError::InvalidKey)?;
+
+        let mut encrypted_key = [0u8; KEY_SIZE];
+        encrypted_key.copy_from_slice(&store_key);
+
+        let mut key_file =
+            Vec::with_capacity(1 + 1 + 1 + IV_SIZE + KEY_FILE_FIXED_SIZE + CANARY_SIZE);
+
+        // Write down our public salt and iv first, those will be needed to
+        // decrypt the key again.
+        key_file.push(SALT_SIZE_VERSION);
+        key_file.push(header_length(SALT_SIZE_VERSION, Cipher::Aes256Ctr));
+        key_file.push(SALT_SIZE as u8);
+        key_file.extend_from_slice(iv);
+        key_file.extend_from_slice(&self.salt);
+        key_file.extend_from_slice(&self.pbkdf_count.to_be_bytes());
+
+        // Encrypt our key.
+        encryptor
+            .try_apply_keystream(&mut encrypted_key)
+            .map_err(|_| CryptoError::KeystreamEnd)?;
+
+        // Calculate a MAC for our encrypted key and store it in the file before
+        // the key.
+        let mac = calculate_hmac(
+            SALT_SIZE_VERSION,
+            iv,
+            &self.salt,
+            &encrypted_key,
+            &self.mac_key,
+        )?;
+        let mac = mac.result();
+        key_file.extend_from_slice(&mac.code());
+
+        // Write down the encrypted key.
+        key_file.extend_from_slice(&encrypted_key);
+
+        // Finally the canary, decrypting it proves that the store key was
+        // unwrapped correctly.
+        let mut canary = CANARY_PLAINTEXT;
+        apply_canary_keystream(store_key, iv, &mut canary)?;
+        key_file.extend_from_slice(&canary);
+
+        Ok(key_file)
+    }
+
+    /// Decrypt the store key of the given key file, optionally skipping the
+    /// check of the MAC and the canary.
+    ///
+    /// The MAC only authenticates the encrypted store key, the canary, if
+    /// the key file has one, confirms that the decrypted store key is the
+    /// one that encrypted the canary.
+    pub(crate) fn decrypt(
+        &self,
+        key_file: &KeyFile,
+        verify_mac: bool,
+    ) -> Result<KeyBuffer, CryptoError> {
+        // First check our MAC of the encrypted key.
+        let expected_mac = MacResult::new(GenericArray::clone_from_slice(&key_file.mac));
+        let mac = calculate_hmac(
+            key_file.version,
+            &key_file.iv,
+            &key_file.salt,
+            &key_file.encrypted_key,
+            &self.mac_key,
+        )?;
+
+        if verify_mac && mac.result() != expected_mac {
+            return Err(CryptoError::InvalidMac);
+        }
+
+        let mut decryptor =
+            Aes256Ctr::new_var(&self.key, &key_file.iv).map_err(|_| CryptoError::InvalidKey)?;
+
+        // Parsing already makes sure of this, but a store key of the wrong
+        // size would only be noticed once the first file fails to decrypt.
+        if key_file.encrypted_key.len() != KEY_SIZE {
+            return Err(CryptoError::InvalidKeySize);
+        }
+
+        let mut out = Zeroizing::new(key_file.encrypted_key.clone());
+        decryptor
+            .try_apply_keystream(&mut out)
+            .map_err(|_| CryptoError::KeystreamEnd)?;
+
+        if let (true, Some(canary)) = (verify_mac, key_file.canary) {
+            let mut plaintext = canary;
+            apply_canary_keystream(&out, &key_file.iv, &mut plaintext)?;
+
+            if plaintext != CANARY_PLAINTEXT {
+                return Err(CryptoError::InvalidCanary);
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Encrypt the given keys, e.g. the ones that protect the key file, into
+    /// a key slot using the given IV, so the passphrase of this key unlocks
+    /// them as well. The result is the content of the slot.
+    pub(crate) fn seal_slot(&self, keys: &WrappingKey, iv: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        Cipher::Aes256Ctr.check_iv(iv)?;
+
+        if self.salt.len() != SALT_SIZE {
+            return Err(CryptoError::InvalidSaltSize);
+        }
+
+        if keys.key.len() != KEY_SIZE || keys.mac_key.len() != KEY_SIZE {
+            return Err(CryptoError::InvalidKeySize);
+        }
+
+        let mut encryptor =
+            Aes256Ctr::new_var(&self.key, iv).map_err(|_| CryptoError::InvalidKey)?;
+
+        let mut encrypted_keys = Zeroizing::new(Vec::with_capacity(2 * KEY_SIZE));
+        encrypted_keys.extend_from_slice(&keys.key);
+        encrypted_keys.extend_from_slice(&keys.mac_key);
+        encryptor
+            .try_apply_keystream(&mut encrypted_keys)
+            .map_err(|_| CryptoError::KeystreamEnd)?;
+
+        let mac = calculate_slot_hmac(
+            iv,
+            &self.salt,
+            self.pbkdf_count,
+            &encrypted_keys,
+            &keys.mac_key,
+        )?;
+
+        let mut slot = Vec::with_capacity(KEY_SLOT_SIZE);
+        slot.extend_from_slice(iv);
+        slot.extend_from_slice(&self.salt);
+        slot.extend_from_slice(&self.pbkdf_count.to_be_bytes());
+        slot.extend_from_slice(&encrypted_keys);
+        slot.extend_from_slice(&mac.result().code());
+
+        Ok(slot)
+    }
+
+    /// Decrypt the keys of the given key slot, they protect a key file that
+    /// uses the given salt and key derivation count.
+    ///
+    /// Returns `InvalidMac` if the slot wasn't sealed using this key or if
+    /// it was modified.
+    pub(crate) fn open_slot(
+        &self,
+        slot: &KeySlot,
+        salt: &[u8],
+        pbkdf_count: u32,
+    ) -> Result<WrappingKey, CryptoError> {
+        let mut decryptor =
+            Aes256Ctr::new_var(&self.key, &slot.iv).map_err(|_| CryptoError::InvalidKey)?;
+
+        let mut keys = Zeroizing::new(slot.encrypted_keys.clone());
+        decryptor
+            .try_apply_keystream(&mut keys)
+            .map_err(|_| CryptoError::KeystreamEnd)?;
+
+        let (key, mac_key) = keys.split_at(KEY_SIZE);
+        let keys = WrappingKey {
+            key: Zeroizing::new(key.to_vec()),
+            mac_key: Zeroizing::new(mac_key.to_vec()),
+            salt: salt.to_vec(),
+            pbkdf_count,
+        };
+
+        slot.verify(&keys)?;
+
+        Ok(keys)
+    }
+}
+
+/// The parsed content of a passphrase protected key file.
+///
+/// A key file holds a single wrapped store key, additional passphrases are
+/// stored in key slots, see `KeySlot`.
+pub(crate) struct KeyFile {
+    pub(crate) version: u8,
+    pub(crate) iv: Vec<u8>,
+    pub(crate) salt: Vec<u8>,
+    pub(crate) pbkdf_count: u32,
+    pub(crate) mac: [u8; MAC_LENGTH],
+    pub(crate) encrypted_key: Vec<u8>,
+    pub(crate) canary: Option<[u8; CANARY_SIZE]>,
+}
+
+impl KeyFile {
+    /// Parse a passphrase protected key file.
+    ///
+    /// There's no need to pass more than `KEY_FILE_MAX_SIZE` bytes, a longer
+    /// key file is invalid.
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, CryptoError> {
+        // The version determines the cipher and with it the size of the IV.
+        let (version, data) = data.split_first().ok_or(CryptoError::Truncated)?;
+
+        if *version == WRAPPED_VERSION {
+            return Err(CryptoError::WrappedKey);
+        }
+
+        let cipher = Cipher::for_version(*version).ok_or(CryptoError::InvalidVersion)?;
+        let data = check_header_length(*version, cipher, data)?;
+
+        // Key files older than the canary simply end with the encrypted key.
+        let (data, canary) = if *version >= CANARY_VERSION {
+            if data.len() < CANARY_SIZE {
+                return Err(CryptoError::Truncated);
+            }
+
+            let (data, canary) = data.split_at(data.len() - CANARY_SIZE);
+            let mut canary_bytes = [0u8; CANARY_SIZE];
+            canary_bytes.copy_from_slice(canary);
+
+            (data, Some(canary_bytes))
+        } else {
+            (data, None)
+        };
+
+        // Our key will be AES encrypted in CTR mode meaning the ciphertext
+        // will have the same size as the plaintext, the rest of the key file
+        // has a fixed size.
+        if data.len() < KEY_FILE_FIXED_SIZE {
+            return Err(CryptoError::Truncated);
+        }
+
+        // Everything that isn't part of the fixed size fields is our IV.
+        let (iv, rest) = data.split_at(data.len() - KEY_FILE_FIXED_SIZE);
+        cipher.check_iv(iv)?;
+
+        let (salt, rest) = rest.split_at(SALT_SIZE);
+        let (pbkdf_count, rest) = rest.split_at(4);
+        let (mac, encrypted_key) = rest.split_at(MAC_LENGTH);
+
+        let mut pbkdf_count_bytes = [0u8; 4];
+        pbkdf_count_bytes.copy_from_slice(pbkdf_count);
+        let mut mac_bytes = [0u8; MAC_LENGTH];
+        mac_bytes.copy_from_slice(mac);
+
+        Ok(KeyFile {
+            version: *version,
+            iv: iv.to_vec(),
+            salt: salt.to_vec(),
+            pbkdf_count: u32::from_be_bytes(pbkdf_count_bytes),
+            mac: mac_bytes,
+            encrypted_key: encrypted_key.to_vec(),
+            canary,
+        })
+    }
+}
+
+/// A key slot, the keys that protect the store key in the key file, encrypted
+/// using keys that are derived from a different passphrase.
+///
+/// Every slot is authenticated on its own using the MAC key it contains, so
+/// whoever can unlock the key file can check every slot without knowing
+/// their passphrases, and a corrupted slot doesn't affect the others.
+///
+/// ```text
+///     encrypted_keys = AES256-CTR(slot_key, iv, key || mac_key)
+///     mac = HMAC-SHA256(mac_key, context || iv || salt || pbkdf_count || encrypted_keys)
+///     slot = (iv || salt || pbkdf_count || encrypted_keys || mac)
+/// ```
+pub(crate) struct KeySlot {
+    pub(crate) iv: Vec<u8>,
+    pub(crate) salt: Vec<u8>,
+    pub(crate) pbkdf_count: u32,
+    pub(crate) encrypted_keys: Vec<u8>,
+    pub(crate) mac: [u8; MAC_LENGTH],
+}
+
+impl KeySlot {
+    /// Parse a key slot.
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, CryptoError> {
+        if data.len() != KEY_SLOT_SIZE {
+            return Err(CryptoError::InvalidKeySlot);
+        }
+
+        let (iv, rest) = data.split_at(IV_SIZE);
+        let (salt, rest) = rest.split_at(SALT_SIZE);
+        let (pbkdf_count, rest) = rest.split_at(4);
+        let (encrypted_keys, mac) = rest.split_at(2 * KEY_SIZE);
+
+        let mut pbkdf_count_bytes = [0u8; 4];
+        pbkdf_count_bytes.copy_from_slice(pbkdf_count);
+        let mut mac_bytes = [0u8; MAC_LENGTH];
+        mac_bytes.copy_from_slice(mac);
+
+        Ok(KeySlot {
+            iv: iv.to_vec(),
+            salt: salt.to_vec(),
+            pbkdf_count: u32::from_be_bytes(pbkdf_count_bytes),
+            encrypted_keys: encrypted_keys.to_vec(),
+            mac: mac_bytes,
+        })
+    }
+
+    /// Check that the slot contains the given keys, e.g. the ones that
+    /// protect the key file, and that it wasn't modified.
+    pub(crate) fn verify(&self, keys: &WrappingKey) -> Result<(), CryptoError> {
+        let expected_mac = MacResult::new(GenericArray::clone_from_slice(&self.mac));
+        let mac = calculate_slot_hmac(
+            &self.iv,
+            &self.salt,
+            self.pbkdf_count,
+            &self.encrypted_keys,
+            &keys.mac_key,
+        )?;
+
+        if mac.result() != expected_mac {
+            return Err(CryptoError::InvalidMac);
+        }
+
+        Ok(())
+    }
+}
+
+/// The length of the header of a key file of the given version that we
+/// understand, the size of the salt if the version records it, the IV, the
+/// salt, and the PBKDF count.
+fn header_length(version: u8, cipher: Cipher) -> u8 {
+    let salt_size_length = usize::from(version >= SALT_SIZE_VERSION);
+
+    (salt_size_length + cipher.iv_size() + SALT_SIZE + 4) as u8
+}
+
+/// Check the header length that key files of newer versions record after the
+/// version, returns the data that follows it.
+///
+/// A newer version might add fields to the header that change how the store
+/// key is protected, a longer header than the one we understand is refused
+/// instead of being parsed partially.
+///
+/// Newer key files record the size of their salt as the first field of the
+/// header, a salt of any size but `SALT_SIZE` is refused before anything else
+/// is read. Older key files don't record it, a header that implies a
+/// different salt size is refused like any other header of the wrong length.
+fn check_header_length(version: u8, cipher: Cipher, data: &[u8]) -> Result<&[u8], CryptoError> {
+    if version < HEADER_LENGTH_VERSION {
+        return Ok(data);
+    }
+
+    let (length, mut data) = data.split_first().ok_or(CryptoError::Truncated)?;
+
+    if version >= SALT_SIZE_VERSION {
+        let (salt_size, rest) = data.split_first().ok_or(CryptoError::Truncated)?;
+
+        if *salt_size as usize != SALT_SIZE {
+            return Err(CryptoError::InvalidSaltSize);
+        }
+
+        data = rest;
+    }
+
+    let expected = header_length(version, cipher);
+
+    match length.cmp(&expected) {
+        Ordering::Greater => Err(CryptoError::UnknownHeaderFields),
+        Ordering::Less => Err(CryptoError::Truncated),
+        Ordering::Equal => Ok(data),
+    }
+}
+
+/// Check the primitives that protect the store key and the files of a store
+/// against known answers.
+///
+/// The AES-256-CTR vector is the first two blocks of F.5.5 of NIST SP
+/// 800-38A, the HMAC-SHA256 vector is test case 2 of RFC 4231. PBKDF2 and
+/// HKDF are checked using SHA-512, as we use them, with the inputs of the
+/// common PBKDF2 vectors and of test case 1 of RFC 5869.
+pub(crate) fn self_test() -> Result<(), CryptoError> {
+    const AES_KEY: [u8; KEY_SIZE] = [
+        0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0, 0x85, 0x7d, 0x77,
+        0x81, 0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7, 0x2d, 0x98, 0x10, 0xa3, 0x09, 0x14,
+        0xdf, 0xf4,
+    ];
+    const AES_IV: [u8; IV_SIZE] = [
+        0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe,
+        0xff,
+    ];
+    const AES_PLAINTEXT: [u8; 32] = [
+        0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17,
+        0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf,
+        0x8e, 0x51,
+    ];
+    const AES_CIPHERTEXT: [u8; 32] = [
+        0x60, 0x1e, 0xc3, 0x13, 0x77, 0x57, 0x89, 0xa5, 0xb7, 0xa7, 0xf5, 0x04, 0xbb, 0xf3, 0xd2,
+        0x28, 0xf4, 0x43, 0xe3, 0xca, 0x4d, 0x62, 0xb5, 0x9a, 0xca, 0x84, 0xe9, 0x90, 0xca, 0xca,
+        0xf5, 0xc5,
+    ];
+    const HMAC_SHA256: [u8; MAC_LENGTH] = [
+        0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75,
+        0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec,
+        0x38, 0x43,
+    ];
+    const PBKDF2_SHA512: [u8; KEY_SIZE * 2] = [
+        0x86, 0x7f, 0x70, 0xcf, 0x1a, 0xde, 0x02, 0xcf, 0xf3, 0x75, 0x25, 0x99, 0xa3, 0xa5, 0x3d,
+        0xc4, 0xaf, 0x34, 0xc7, 0xa6, 0x69, 0x81, 0x5a, 0xe5, 0xd5, 0x13, 0x55, 0x4e, 0x1c, 0x8c,
+        0xf2, 0x52, 0xc0, 0x2d, 0x47, 0x0a, 0x28, 0x5a, 0x05, 0x01, 0xba, 0xd9, 0x99, 0xbf, 0xe9,
+        0x43, 0xc0, 0x8f, 0x05, 0x02, 0x35, 0xd7, 0xd6, 0x8b, 0x1d, 0xa5, 0x5e, 0x63, 0xf7, 0x3b,
+        0x60, 0xa5, 0x7f, 0xce,
+    ];
+    const HKDF_SHA512: [u8; KEY_SIZE * 2] = [
+        0xf5, 0xfa, 0x02, 0xb1, 0x82, 0x98, 0xa7, 0x2a, 0x8c, 0x23, 0x89, 0x8a, 0x87, 0x03, 0x47,
+        0x2c, 0x6e, 0xb1, 0x79, 0xdc, 0x20, 0x4c, 0x03, 0x42, 0x5c, 0x97, 0x0e, 0x3b, 0x16, 0x4b,
+        0xf9, 0x0f, 0xff, 0x22, 0xd0, 0x48, 0x36, 0xd0, 0xe2, 0x34, 0x3b, 0xac, 0xc4, 0xe7, 0xcb,
+        0x60, 0x45, 0xfa, 0xaa, 0x69, 0x8e, 0x0e, 0x3b, 0x3e, 0xb9, 0x13, 0x31, 0x30, 0x6d, 0xef,
+        0x1d, 0xb8, 0x31, 0x9e,
+    ];
+
+    let mut data = AES_PLAINTEXT;
+    let mut cipher = Aes256Ctr::new_var(&AES_KEY, &AES_IV).map_err(|_| CryptoError::InvalidKey)?;
+    cipher
+        .try_apply_keystream(&mut data)
+        .map_err(|_| CryptoError::KeystreamEnd)?;
+
+    if data != AES_CIPHERTEXT {
+        return Err(CryptoError::SelfTest("AES-256-CTR"));
+    }
+
+    let mut hmac = Hmac::<Sha256>::new_varkey(b"Jefe").map_err(|_| CryptoError::InvalidKey)?;
+    hmac.input(b"what do ya want for nothing?");
+
+    if hmac.result().code().as_slice() != &HMAC_SHA256[..] {
+        return Err(CryptoError::SelfTest("HMAC-SHA256"));
+    }
+
+    let (key, mac_key) = rederive_key("password", b"salt", 1);
+
+    if key[..] != PBKDF2_SHA512[..KEY_SIZE] || mac_key[..] != PBKDF2_SHA512[KEY_SIZE..] {
+        return Err(CryptoError::SelfTest("PBKDF2-HMAC-SHA512"));
+    }
+
+    let (key, mac_key) = expand_key(&[0x0b; 22], &[])?;
+
+    if key[..] != HKDF_SHA512[..KEY_SIZE] || mac_key[..] != HKDF_SHA512[KEY_SIZE..] {
+        return Err(CryptoError::SelfTest("HKDF-SHA512"));
+    }
+
+    Ok(())
+}
+
+/// Encrypt or decrypt the canary of a key file in place.
+///
+/// The canary is encrypted using AES-CTR, the IV of the key file and a key
+/// that is expanded from the store key, never using the store key itself.
+fn apply_canary_keystream(
+    store_key: &[u8],
+    iv: &[u8],
+    canary: &mut [u8; CANARY_SIZE],
+) -> Result<(), CryptoError> {
+    let (key, _) = expand_key(store_key, b"canary")?;
+    let mut cipher = Aes256Ctr::new_var(&key, iv).map_err(|_| CryptoError::InvalidKey)?;
+
+    cipher
+        .try_apply_keystream(canary)
+        .map_err(|_| CryptoError::KeystreamEnd)
+}
+
+/// Calculate a HMAC for the given inputs.
+pub(crate) fn calculate_hmac(
+    version: u8,
+    iv: &[u8],
+    salt: &[u8],
+    encrypted_data: &[u8],
+    hmac_key: &[u8],
+) -> Result<Hmac<Sha256>, CryptoError> {
+    let mut hmac = Hmac::<Sha256>::new_varkey(hmac_key).map_err(|_| CryptoError::InvalidKey)?;
+    hmac.input(&[version]);
+    hmac.input(&iv);
+    hmac.input(&salt);
+    hmac.input(&encrypted_data);
+    Ok(hmac)
+}
+
+/// Calculate the HMAC of a key slot.
+fn calculate_slot_hmac(
+    iv: &[u8],
+    salt: &[u8],
+    pbkdf_count: u32,
+    encrypted_keys: &[u8],
+    hmac_key: &[u8],
+) -> Result<Hmac<Sha256>, CryptoError> {
+    let mut hmac = Hmac::<Sha256>::new_varkey(hmac_key).map_err(|_| CryptoError::InvalidKey)?;
+    hmac.input(KEY_SLOT_CONTEXT);
+    hmac.input(iv);
+    hmac.input(salt);
+    hmac.input(&pbkdf_count.to_be_bytes());
+    hmac.input(encrypted_keys);
+    Ok(hmac)
+}
+
+/// Derive two keys from the given passphrase and the given salt using PBKDF2.
+pub(crate) fn rederive_key(passphrase: &str, salt: &[u8], pbkdf_count: u32) -> KeyDerivationResult {
+    let mut pbkdf_result = Zeroizing::new([0u8; KEY_SIZE * 2]);
+
+    pbkdf2::<Hmac<Sha512>>(
+        &passphrase.as_bytes(),
+        &salt,
+        pbkdf_count as usize,
+        &mut *pbkdf_result,
+    );
+    let (key, hmac_key) = pbkdf_result.split_at(KEY_SIZE);
+    (
+        Zeroizing::new(Vec::from(key)),
+        Zeroizing::new(Vec::from(hmac_key)),
+    )
+}
+
+/// Expand the given key into an encryption key and HMAC key.
+///
+/// The store key is expanded using an empty info string, keys for other
+/// purposes need to use a distinct info string.
+pub(crate) fn expand_key(key: &[u8], info: &[u8]) -> Result<KeyDerivationResult, CryptoError> {
+    let mut hkdf_result = Zeroizing::new([0u8; KEY_SIZE * 2]);
+
+    let hkdf = Hkdf::<Sha512>::new(None, &key);
+    hkdf.expand(info, &mut *hkdf_result)
+        .map_err(|_| CryptoError::KeyExpansion)?;
+    let (key, hmac_key) = hkdf_result.split_at(KEY_SIZE);
+    Ok((
+        Zeroizing::new(Vec::from(key)),
+        Zeroizing::new(Vec::from(hmac_key)),
+    ))
+}
+
+#[test]
+fn wrap_and_unwrap_without_a_directory() {
+    let salt = [1u8; SALT_SIZE];
+    let iv = [2u8; IV_SIZE];
+    let store_key = [3u8; KEY_SIZE];
+
+    let wrapping_key = WrappingKey::derive("wordpass", &salt, 10);
+    let key_file = wrapping_key
+        .encrypt(&store_key, &iv)
+        .expect("Can't wrap the store key");
+    assert_eq!(key_file.len(), KEY_FILE_MAX_SIZE - 1);
+
+    let parsed = KeyFile::parse(&key_file).expect("Can't parse the key file");
+    assert_eq!(parsed.version, SALT_SIZE_VERSION);
+    assert_eq!(parsed.iv, iv);
+    assert_eq!(parsed.salt, salt);
+    assert_eq!(parsed.pbkdf_count, 10);
+
+    let rederived = WrappingKey::derive("wordpass", &parsed.salt, parsed.pbkdf_count);
+    let unwrapped = rederived
+        .decrypt(&parsed, true)
+        .expect("Can't unwrap the store key");
+    assert_eq!(&unwrapped[..], &store_key[..]);
+
+    let wrong = WrappingKey::derive("password", &parsed.salt, parsed.pbkdf_count);
+    assert_eq!(
+        wrong.decrypt(&parsed, true).unwrap_err(),
+        CryptoError::InvalidMac
+    );
+
+    // Skipping the MAC check doesn't let a key of the wrong size through.
+    let mut short = KeyFile::parse(&key_file).unwrap();
+    short.encrypted_key.pop();
+    assert_eq!(
+        rederived.decrypt(&short, false).unwrap_err(),
+        CryptoError::InvalidKeySize
+    );
+
+    assert_eq!(
+        KeyFile::parse(&key_file[..10]).err(),
+        Some(CryptoError::Truncated)
+    );
+
+    let (encryption_key, mac_key) = expand_key(&store_key, b"").unwrap();
+    assert_eq!(encryption_key.len(), KEY_SIZE);
+    assert_ne!(encryption_key, mac_key);
+}
+
+#[test]
+fn seal_and_open_key_slots() {
+    let keys = WrappingKey::derive("wordpass", &[1u8; SALT_SIZE], 10);
+    let slot_key = WrappingKey::derive("password", &[2u8; SALT_SIZE], 20);
+
+    let slot = slot_key
+        .seal_slot(&keys, &[3u8; IV_SIZE])
+        .expect("Can't seal the key slot");
+    assert_eq!(slot.len(), KEY_SLOT_SIZE);
+
+    let parsed = KeySlot::parse(&slot).expect("Can't parse the key slot");
+    assert_eq!(parsed.pbkdf_count, 20);
+    assert_eq!(parsed.verify(&keys), Ok(()));
+
+    let rederived = WrappingKey::derive("password", &parsed.salt, parsed.pbkdf_count);
+    let opened = rederived
+        .open_slot(&parsed, &keys.salt, keys.pbkdf_count)
+        .expect("Can't open the key slot");
+    assert_eq!(opened.key, keys.key);
+    assert_eq!(opened.mac_key, keys.mac_key);
+    assert_eq!(opened.salt, keys.salt);
+    assert_eq!(opened.pbkdf_count, keys.pbkdf_count);
+
+    let wrong = WrappingKey::derive("wordpass", &parsed.salt, parsed.pbkdf_count);
+    assert_eq!(
+        wrong.open_slot(&parsed, &keys.salt, 10).err(),
+        Some(CryptoError::InvalidMac)
+    );
+
+    // Every field of the slot is authenticated.
+    for i in [0, IV_SIZE, IV_SIZE + SALT_SIZE, KEY_SLOT_SIZE - 1].iter() {
+        let mut corrupted = slot.clone();
+        corrupted[*i] ^= 1;
+        let corrupted = KeySlot::parse(&corrupted).unwrap();
+        assert_eq!(corrupted.verify(&keys), Err(CryptoError::InvalidMac));
+    }
+
+    assert_eq!(
+        KeySlot::parse(&slot[..slot.len() - 1]).err(),
+        Some(CryptoError::InvalidKeySlot)
+    );
+}
+
+#[test]
+fn parse_key_file_header() {
+    let header = KeyFileHeader::parse(&[VERSION]).unwrap();
+    assert_eq!(header.version, VERSION);
+    assert_eq!(
+        header.protection,
+        KeyProtection::Passphrase(Cipher::Aes256Ctr)
+    );
+
+    let header = KeyFileHeader::parse(&[WRAPPED_VERSION, 3, b't', b'p', b'm', 0xff]).unwrap();
+    assert_eq!(header.protection, KeyProtection::KeyWrapper("tpm".into()));
+
+    assert_eq!(KeyFileHeader::parse(&[]), Err(CryptoError::Truncated));
+    assert_eq!(
+        KeyFileHeader::parse(&[WRAPPED_VERSION, 3, b't']),
+        Err(CryptoError::Truncated)
+    );
+    assert_eq!(KeyFileHeader::parse(&[7]), Err(CryptoError::InvalidVersion));
+}
+
+#[test]
+fn refuse_unknown_header_fields() {
+    let wrapping_key = WrappingKey::derive("wordpass", &[1u8; SALT_SIZE], 10);
+    let key_file = wrapping_key
+        .encrypt(&[3u8; KEY_SIZE], &[2u8; IV_SIZE])
+        .unwrap();
+    assert_eq!(key_file[1] as usize, 1 + IV_SIZE + SALT_SIZE + 4);
+
+    // A newer version appends a field to the header, the rest of the key
+    // file stays the same.
+    let mut extended = Vec::new();
+    extended.push(key_file[0]);
+    extended.push(key_file[1] + 2);
+    extended.extend_from_slice(&key_file[2..2 + key_file[1] as usize]);
+    extended.extend_from_slice(&[0xff, 0xff]);
+    extended.extend_from_slice(&key_file[2 + key_file[1] as usize..]);
+
+    assert_eq!(
+        KeyFile::parse(&extended).err(),
+        Some(CryptoError::UnknownHeaderFields)
+    );
+    assert_eq!(
+        KeyFileHeader::parse(&extended),
+        Err(CryptoError::UnknownHeaderFields)
+    );
+
+    let mut shortened = key_file.clone();
+    shortened[1] -= 1;
+    assert_eq!(
+        KeyFile::parse(&shortened).err(),
+        Some(CryptoError::Truncated)
+    );
+    assert_eq!(
+        KeyFileHeader::parse(&[HEADER_LENGTH_VERSION]),
+        Err(CryptoError::Truncated)
+    );
+    assert_eq!(
+        KeyFileHeader::parse(&[SALT_SIZE_VERSION, key_file[1]]),
+        Err(CryptoError::Truncated)
+    );
+}
+
+#[test]
+fn reject_implausible_salt_sizes() {
+    let wrapping_key = WrappingKey::derive("wordpass", &[1u8; SALT_SIZE], 10);
+    let key_file = wrapping_key
+        .encrypt(&[3u8; KEY_SIZE], &[2u8; IV_SIZE])
+        .unwrap();
+
+    // A key file that records a larger or a smaller salt, with a header
+    // length that matches it.
+    for salt_size in [0, SALT_SIZE / 2, 2 * SALT_SIZE, 255 - IV_SIZE - 5].iter() {
+        let mut resized = vec![
+            SALT_SIZE_VERSION,
+            (1 + IV_SIZE + salt_size + 4) as u8,
+            *salt_size as u8,
+        ];
+        resized.extend_from_slice(&key_file[3..3 + IV_SIZE]);
+        resized.extend_from_slice(&vec![1u8; *salt_size]);
+        resized.extend_from_slice(&key_file[3 + IV_SIZE + SALT_SIZE..]);
+
+        assert_eq!(
+            KeyFile::parse(&resized).err(),
+            Some(CryptoError::InvalidSaltSize)
+        );
+        assert_eq!(
+            KeyFileHeader::parse(&resized).err(),
+            Some(CryptoError::InvalidSaltSize)
+        );
+    }
+
+    // Older key files don't record the size of the salt, a header that
+    // implies a different one has the wrong length.
+    let mut legacy = vec![HEADER_LENGTH_VERSION, key_file[1] - 1];
+    legacy.extend_from_slice(&key_file[3..]);
+    assert!(KeyFile::parse(&legacy).is_ok());
+
+    legacy[1] += 1;
+    assert_eq!(
+        KeyFile::parse(&legacy).err(),
+        Some(CryptoError::UnknownHeaderFields)
+    );
+    assert_eq!(
+        KeyFileHeader::parse(&legacy),
+        Err(CryptoError::UnknownHeaderFields)
+    );
+
+    // A salt of the wrong size is never written down.
+    for salt_size in [0, SALT_SIZE - 1, SALT_SIZE + 1].iter() {
+        let wrapping_key = WrappingKey::derive("wordpass", &vec![1u8; *salt_size], 10);
+        assert_eq!(
+            wrapping_key.encrypt(&[3u8; KEY_SIZE], &[2u8; IV_SIZE]),
+            Err(CryptoError::InvalidSaltSize)
+        );
+    }
+}
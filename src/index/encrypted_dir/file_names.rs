@@ -0,0 +1,392 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hidden file names and the mapping of Tantivy paths to paths on disk.
+
+use super::*;
+
+/// Maps the names of the Tantivy files to opaque names on disk and back, see
+/// `EncryptedMmapDirectory::with_hidden_file_names()`.
+///
+/// Names are encrypted deterministically using AES-CTR, the IV is a
+/// HMAC-SHA256 of the name. The same name always maps to the same name on
+/// disk, and revealing a name checks that the IV matches the decrypted name.
+/// The keys are expanded from a random secret that is stored in the names
+/// file of the store.
+///
+/// ```text
+///     iv = HMAC-SHA256(mac_key, name)[..16]
+///     disk_name = hex(iv || AES256-CTR(encryption_key, iv, name))
+/// ```
+pub(super) struct FileNames {
+    pub(super) secret: KeyBuffer,
+    encryption_key: KeyBuffer,
+    mac_key: KeyBuffer,
+}
+
+// The secret and the keys are left out, the file names are part of the
+// debug output of the directory.
+impl std::fmt::Debug for FileNames {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("FileNames")
+    }
+}
+
+impl FileNames {
+    fn new(secret: &[u8]) -> std::io::Result<Self> {
+        if secret.len() != KEY_SIZE {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "invalid file name secret",
+            ));
+        }
+
+        let (encryption_key, mac_key) = expand_key(secret, b"file-names")?;
+
+        Ok(FileNames {
+            secret: Zeroizing::new(secret.to_vec()),
+            encryption_key,
+            mac_key,
+        })
+    }
+
+    /// Calculate the IV of the given name.
+    fn iv(&self, name: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut mac =
+            Hmac::<Sha256>::new_varkey(&self.mac_key).map_err(|_| CryptoError::InvalidKey)?;
+        mac.input(name);
+
+        Ok(mac.result().code()[..IV_SIZE].to_vec())
+    }
+
+    /// Encrypt or decrypt the given name in place.
+    fn apply_keystream(&self, iv: &[u8], name: &mut [u8]) -> std::io::Result<()> {
+        let mut cipher =
+            Aes256Ctr::new_var(&self.encryption_key, iv).map_err(|_| CryptoError::InvalidKey)?;
+        cipher
+            .try_apply_keystream(name)
+            .map_err(|_| CryptoError::KeystreamEnd)?;
+
+        Ok(())
+    }
+
+    /// Get the name the file at the given path is stored under.
+    fn hide(&self, path: &Path) -> std::io::Result<PathBuf> {
+        // Tantivy only uses UTF-8 file names.
+        let name = path.to_string_lossy();
+        let iv = self.iv(name.as_bytes())?;
+
+        let mut encrypted = name.as_bytes().to_vec();
+        self.apply_keystream(&iv, &mut encrypted)?;
+
+        let hidden: String = iv
+            .iter()
+            .chain(encrypted.iter())
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        Ok(PathBuf::from(hidden))
+    }
+
+    /// Get the original path of a file that is stored under the given name,
+    /// `None` if the name wasn't produced by `hide()`.
+    pub(super) fn reveal(&self, path: &Path) -> Option<PathBuf> {
+        let hidden = path.to_str()?;
+
+        if hidden.len() % 2 != 0 || hidden.len() < 2 * IV_SIZE {
+            return None;
+        }
+
+        let bytes = (0..hidden.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hidden.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+
+        let (iv, encrypted) = bytes.split_at(IV_SIZE);
+        let mut name = encrypted.to_vec();
+        self.apply_keystream(iv, &mut name).ok()?;
+
+        if self.iv(&name).ok()? != iv {
+            return None;
+        }
+
+        String::from_utf8(name).ok().map(PathBuf::from)
+    }
+}
+
+impl EncryptedMmapDirectory {
+    /// Refuse to read or write files that resolve to a path outside of the
+    /// store directory.
+    ///
+    /// The mmap directory follows symlinks, a symlink that was planted in the
+    /// store directory would otherwise redirect reads and writes of the store
+    /// to an arbitrary file. Every path is resolved before it's used, paths
+    /// that leave the store directory, including dangling symlinks, are
+    /// refused with a `PermissionDenied` error. Symlinks that stay inside of
+    /// the store directory are still followed.
+    ///
+    /// This has no effect on stores that use a custom directory, their files
+    /// don't live on the file system as far as we know.
+    #[allow(dead_code)]
+    pub fn with_symlink_guard(mut self) -> Self {
+        self.refuse_symlink_escapes = true;
+        self
+    }
+
+    /// Hide the names of the Tantivy files.
+    ///
+    /// Tantivy names its files after the segments they belong to, the names
+    /// reveal how the index is structured. With hidden file names every file
+    /// is stored under an opaque name that is derived from its real name
+    /// using a random secret of the store, Tantivy keeps on using the real
+    /// names.
+    ///
+    /// The secret is stored, encrypted, next to the key file. Once a store
+    /// hides its file names, they're hidden whenever the store is opened,
+    /// this only needs to be called when the store is created. Returns an
+    /// error if the store already contains an index, the existing files
+    /// wouldn't be found anymore.
+    ///
+    /// Watch callbacks are only triggered if the inner directory notices
+    /// changes of the file `meta.json` is stored under, readers of a store
+    /// with hidden file names should be reloaded manually.
+    #[allow(dead_code)]
+    pub fn with_hidden_file_names(self) -> Result<Self, OpenDirectoryError> {
+        if self.state.file_names.read().unwrap().is_some() {
+            return Ok(self);
+        }
+
+        if self.inner_dir.exists(Path::new(META_FILE)) {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                "the file names of an existing index can't be hidden",
+            )
+            .into());
+        }
+
+        let secret = Zeroizing::new(EncryptedMmapDirectory::generate_key()?.to_vec());
+        self.write_file_names(&**self.state.provider()?, &secret)?;
+        *self.state.file_names.write().unwrap() = Some(Arc::new(FileNames::new(&secret)?));
+
+        Ok(self)
+    }
+
+    /// Get the name the file at the given path is stored under, see
+    /// `with_hidden_file_names()`.
+    ///
+    /// Paths that escape the store directory are refused here if the symlink
+    /// guard is enabled, see `with_symlink_guard()`.
+    pub(super) fn disk_path(&self, path: &Path) -> std::io::Result<PathBuf> {
+        let disk_path = match &*self.state.file_names.read().unwrap() {
+            Some(names) => names.hide(path)?,
+            None => path.to_owned(),
+        };
+
+        if self.refuse_symlink_escapes && !self.custom_directory {
+            self.check_contained(&disk_path)?;
+        }
+
+        Ok(disk_path)
+    }
+
+    /// Return an error if the given path resolves to a file outside of the
+    /// store directory.
+    fn check_contained(&self, path: &Path) -> std::io::Result<()> {
+        let root = self.path.canonicalize()?;
+        let path = self.path.join(path);
+
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => Some(resolved),
+            // A file that doesn't exist yet gets created in its parent
+            // directory, unless it's a dangling symlink.
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                if std::fs::symlink_metadata(&path).is_ok() {
+                    None
+                } else {
+                    match (path.parent(), path.file_name()) {
+                        (Some(parent), Some(name)) => Some(parent.canonicalize()?.join(name)),
+                        _ => None,
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        };
+
+        match resolved {
+            Some(resolved) if resolved.starts_with(&root) && resolved != root => Ok(()),
+            _ => Err(IoError::new(
+                ErrorKind::PermissionDenied,
+                "the file resolves to a path outside of the store directory",
+            )),
+        }
+    }
+
+    /// Load the secret that hides the file names of the store, if the store
+    /// hides them.
+    pub(super) fn load_file_names(&self) -> std::io::Result<()> {
+        let sealed = match std::fs::read(self.path.join(self.config.names_file())) {
+            Ok(s) => s,
+            Err(e) => {
+                if e.kind() == ErrorKind::NotFound {
+                    return Ok(());
+                }
+                return Err(e);
+            }
+        };
+
+        let secret = Zeroizing::new(self.decrypt(&sealed, Some(WriteMode::Atomic))?);
+        *self.state.file_names.write().unwrap() = Some(Arc::new(FileNames::new(&secret)?));
+
+        Ok(())
+    }
+
+    /// Encrypt and persist the secret that hides the file names.
+    pub(super) fn write_file_names(
+        &self,
+        provider: &dyn AeadProvider,
+        secret: &[u8],
+    ) -> std::io::Result<()> {
+        let sealed = StoreState::seal_file(provider, secret, WriteMode::Atomic)?;
+
+        self.config
+            .write_atomically(&self.path.join(self.config.names_file()), &sealed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn hide_file_names() {
+        use tantivy::collector::Count;
+        use tantivy::query::TermQuery;
+        use tantivy::schema::{IndexRecordOption, Schema, STRING};
+        use tantivy::Term;
+
+        let tmpdir = tempdir().unwrap();
+        let mut schema = Schema::builder();
+        let field = schema.add_text_field("body", STRING);
+        let schema = schema.build();
+
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store")
+            .with_hidden_file_names()
+            .expect("Can't hide the file names");
+        let index = tantivy::Index::create(dir.clone(), schema.clone()).unwrap();
+        let mut writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        writer.add_document(tantivy::doc!(field => "test"));
+        writer.commit().unwrap();
+        drop(writer);
+        drop(index);
+
+        // Only our own files and the lock files keep their names.
+        let config = StoreConfig::new();
+        for entry in std::fs::read_dir(tmpdir.path()).unwrap() {
+            let name = PathBuf::from(entry.unwrap().file_name());
+
+            if config.is_seshat_metadata(&name) || dir.is_unencrypted_file(&name) {
+                continue;
+            }
+
+            let name = name.to_str().unwrap();
+            assert!(
+                name.chars().all(|c| c.is_ascii_hexdigit()),
+                "The file name {} isn't hidden",
+                name
+            );
+        }
+
+        assert!(dir.files().unwrap().contains(&PathBuf::from(META_FILE)));
+        assert!(dir.exists(Path::new(META_FILE)));
+        assert!(dir.with_hidden_file_names().is_ok());
+
+        // The names stay hidden after the store key is rotated.
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        dir.rotate_store_key().unwrap();
+        drop(dir);
+        assert!(!tmpdir.path().join(META_FILE).exists());
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        let index = tantivy::Index::open(dir).expect("Can't reopen the index");
+        let searcher = index.reader().unwrap().searcher();
+        let query = TermQuery::new(
+            Term::from_field_text(field, "test"),
+            IndexRecordOption::Basic,
+        );
+        assert_eq!(searcher.search(&query, &Count).unwrap(), 1);
+
+        // The names of an existing index can't be hidden anymore.
+        let plain = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(plain.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+        tantivy::Index::create(dir.clone(), schema).unwrap();
+        assert!(dir.with_hidden_file_names().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuse_symlinks_that_escape_the_store() {
+        let tmpdir = tempdir().unwrap();
+        let outside = tempdir().unwrap();
+        let target = outside.path().join("target");
+        std::fs::write(&target, b"outside").unwrap();
+
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new(META_FILE);
+        dir.atomic_write(path, b"metadata").unwrap();
+
+        std::os::unix::fs::symlink(&target, tmpdir.path().join("escape")).unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("missing"),
+            tmpdir.path().join("dangling"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(tmpdir.path().join(META_FILE), tmpdir.path().join("inside"))
+            .unwrap();
+
+        // Without the guard the symlink is followed.
+        assert!(dir.exists(Path::new("escape")));
+        drop(dir);
+
+        let mut dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .unwrap()
+            .with_symlink_guard();
+
+        let refused = |e: &IoError| e.kind() == ErrorKind::PermissionDenied;
+
+        match dir.atomic_write(Path::new("escape"), b"content") {
+            Err(e) => assert!(refused(&e)),
+            Ok(_) => panic!("Wrote through a symlink that escapes the store"),
+        }
+        match dir.atomic_write(Path::new("dangling"), b"content") {
+            Err(e) => assert!(refused(&e)),
+            Ok(_) => panic!("Wrote through a dangling symlink"),
+        }
+        assert!(dir.atomic_read(Path::new("escape")).is_err());
+        assert!(dir.open_read(Path::new("escape")).is_err());
+        assert!(dir.open_write(Path::new("escape")).is_err());
+        assert!(!outside.path().join("missing").exists());
+        assert_eq!(std::fs::read(&target).unwrap(), b"outside");
+
+        // Paths inside of the store keep working.
+        assert_eq!(dir.atomic_read(path).unwrap(), b"metadata");
+        assert_eq!(dir.atomic_read(Path::new("inside")).unwrap(), b"metadata");
+        dir.atomic_write(Path::new("new.json"), b"content").unwrap();
+        assert_eq!(dir.atomic_read(Path::new("new.json")).unwrap(), b"content");
+    }
+}
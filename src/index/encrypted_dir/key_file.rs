@@ -0,0 +1,1272 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Creating, reading and rewrapping the key file that holds the store key.
+
+use super::*;
+
+/// Wraps the store key so it can be persisted in the key file.
+///
+/// By default the store key is encrypted using a key that is derived from a
+/// passphrase. A custom wrapper can e.g. seal the store key to a TPM or a
+/// Secure Enclave, so it can't be unwrapped on a different device, see
+/// `EncryptedMmapDirectory::open_or_create_with_key_wrapper()`.
+pub trait KeyWrapper: std::fmt::Debug + Send + Sync {
+    /// The name of the wrapper, it's recorded in the key file and checked
+    /// before the store key is unwrapped.
+    fn name(&self) -> &str;
+
+    /// Wrap the given store key.
+    fn wrap(&self, store_key: &[u8]) -> std::io::Result<Vec<u8>>;
+
+    /// Unwrap a store key that was wrapped by this wrapper.
+    ///
+    /// Should return an error if the wrapped key was tampered with.
+    fn unwrap(&self, wrapped_key: &[u8]) -> std::io::Result<Zeroizing<Vec<u8>>>;
+}
+
+// The default key wrapper, the wrapped key is a passphrase protected key file.
+impl KeyWrapper for WrappingKey {
+    fn name(&self) -> &str {
+        PASSPHRASE_WRAPPER
+    }
+
+    fn wrap(&self, store_key: &[u8]) -> std::io::Result<Vec<u8>> {
+        let iv = EncryptedMmapDirectory::generate_iv(Cipher::Aes256Ctr)?;
+        Ok(self.encrypt(store_key, &iv)?)
+    }
+
+    fn unwrap(&self, wrapped_key: &[u8]) -> std::io::Result<Zeroizing<Vec<u8>>> {
+        let key_file = KeyFile::parse(wrapped_key)?;
+        Ok(self.decrypt(&key_file, true)?)
+    }
+}
+
+/// A key wrapper that wraps the store key using a raw key the caller
+/// provides, e.g. one that is kept in the keychain of the platform.
+///
+/// The encryption and MAC keys are expanded from the raw key, the store key
+/// is sealed like an atomically written file.
+#[derive(Debug)]
+pub(super) struct KeyBytesWrapper {
+    pub(super) provider: Arc<dyn AeadProvider>,
+}
+
+impl KeyWrapper for KeyBytesWrapper {
+    fn name(&self) -> &str {
+        KEY_BYTES_WRAPPER
+    }
+
+    fn wrap(&self, store_key: &[u8]) -> std::io::Result<Vec<u8>> {
+        StoreState::seal_file(&*self.provider, store_key, WriteMode::Atomic)
+    }
+
+    fn unwrap(&self, wrapped_key: &[u8]) -> std::io::Result<Zeroizing<Vec<u8>>> {
+        Ok(Zeroizing::new(StoreState::open(
+            &*self.provider,
+            wrapped_key,
+            Some(WriteMode::Atomic),
+        )?))
+    }
+}
+
+impl EncryptedMmapDirectory {
+    /// Read the header of the key file of a store.
+    ///
+    /// This only reads the first couple of bytes of the key file, the store
+    /// key isn't decrypted, which makes it possible to identify the format of
+    /// the key file even if the rest of the store is corrupt.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    #[allow(dead_code)]
+    pub fn key_file_header<P: AsRef<Path>>(path: P) -> std::io::Result<KeyFileHeader> {
+        EncryptedMmapDirectory::key_file_header_with_config(path, &StoreConfig::new())
+    }
+
+    /// Read the header of the key file of a store that uses the given store
+    /// configuration, see `key_file_header()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `config` - The configuration of the files we store next to the
+    /// Tantivy files.
+    #[allow(dead_code)]
+    pub fn key_file_header_with_config<P: AsRef<Path>>(
+        path: P,
+        config: &StoreConfig,
+    ) -> std::io::Result<KeyFileHeader> {
+        let key_file = File::open(path.as_ref().join(config.key_file()))?;
+        EncryptedMmapDirectory::read_key_file_header(key_file)
+    }
+
+    /// Check whether the store in the given path protects its data using
+    /// authenticated encryption, e.g. for compliance reports.
+    ///
+    /// Only the header of the key file is read, see `key_file_header()`, no
+    /// passphrase is needed. Every key file version so far authenticates the
+    /// store key using HMAC-SHA256 and the files are sealed by an AEAD
+    /// provider, the store never had an unauthenticated mode, so this
+    /// returns `true` for every key file that can be read. Key files of an
+    /// unknown version are reported as an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    #[allow(dead_code)]
+    pub fn uses_authenticated_encryption<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
+        let header = EncryptedMmapDirectory::key_file_header(path)?;
+
+        Ok(match header.protection {
+            KeyProtection::Passphrase(cipher) => match cipher {
+                Cipher::Aes256Ctr => true,
+            },
+            // The store key is sealed by the wrapper, the files are still
+            // sealed by an AEAD provider.
+            KeyProtection::KeyWrapper(_) => true,
+        })
+    }
+
+    /// Change the passphrase that is used to encrypt the store key.
+    /// This will decrypt and re-encrypt the store key using the new passphrase.
+    ///
+    /// The store key itself doesn't change, so this is safe to call while
+    /// the store is being read, e.g. while searches are in flight. Open
+    /// directories keep the store key in memory and aren't affected, the key
+    /// file is replaced atomically, so a store that is opened concurrently
+    /// either needs the old or the new passphrase.
+    ///
+    /// Changing the passphrase waits for a running store key rotation to
+    /// finish, and fails if a rotation was interrupted, the store needs to be
+    /// opened first to finish it. A writer that has the store open wraps the
+    /// store key using the passphrase it was opened with if it rotates the
+    /// store key later on, writers should be reopened after the passphrase
+    /// was changed.
+    ///
+    /// Only the passphrase of the key file can be changed, the passphrase of
+    /// a key slot doesn't unlock it. The key slots are removed, see
+    /// `add_key_slot()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `old_passphrase` - The currently used passphrase.
+    /// * `new_passphrase` - The passphrase that should be used from now on.
+    /// * `new_key_derivation_count` - The key derivation count that should be
+    /// used for the re-encrypted store key.
+    pub fn change_passphrase<P: AsRef<Path>>(
+        path: P,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        new_key_derivation_count: u32,
+    ) -> Result<(), OpenDirectoryError> {
+        EncryptedMmapDirectory::change_passphrase_with_config(
+            path,
+            old_passphrase,
+            new_passphrase,
+            new_key_derivation_count,
+            &StoreConfig::new(),
+        )
+    }
+
+    /// Change the passphrase of a store that uses the given store
+    /// configuration.
+    ///
+    /// This behaves exactly like `change_passphrase()`.
+    pub fn change_passphrase_with_config<P: AsRef<Path>>(
+        path: P,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        new_key_derivation_count: u32,
+        config: &StoreConfig,
+    ) -> Result<(), OpenDirectoryError> {
+        config.check_passphrase(old_passphrase)?;
+        config.check_passphrase(new_passphrase)?;
+        if new_key_derivation_count == 0 {
+            return Err(IoError::new(ErrorKind::Other, "invalid key derivation count").into());
+        }
+
+        // A store key rotation can't start while we replace the key file, a
+        // rotation that started before our check would otherwise replace the
+        // store key underneath us.
+        let _key_lock = EncryptedMmapDirectory::lock_key_file(path.as_ref(), config)?;
+
+        if path.as_ref().join(config.journal_file()).exists() {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                "a store key rotation was interrupted, open the store to finish it",
+            )
+            .into());
+        }
+
+        let key_path = path.as_ref().join(config.key_file());
+        let key_file = File::open(&key_path)?;
+
+        // Load our store key using the old passphrase.
+        let (_, store_key) = EncryptedMmapDirectory::load_store_key(key_file, old_passphrase)?;
+        // Derive new encryption keys using the new passphrase.
+        let wrapping_key =
+            EncryptedMmapDirectory::derive_key(new_passphrase, new_key_derivation_count)?;
+        // Re-encrypt our store key using the newly derived keys.
+        EncryptedMmapDirectory::encrypt_store_key(&wrapping_key, &store_key, &key_path, config)?;
+
+        // The key slots hold the keys that were derived from the old
+        // passphrase.
+        EncryptedMmapDirectory::remove_key_slots(path.as_ref(), config)?;
+
+        Ok(())
+    }
+
+    /// Estimate how long it takes to unlock a store on this device if the
+    /// given key derivation count is used.
+    ///
+    /// A key is derived from a throwaway passphrase and salt using a small
+    /// calibration count, the time this takes is scaled to the given count.
+    /// Useful to let users balance the work factor against the time they
+    /// need to wait when the store is opened.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_derivation_count` - The number of iterations that our key
+    /// derivation function would use.
+    #[allow(dead_code)]
+    pub fn estimate_unlock_cost(key_derivation_count: u32) -> Duration {
+        let start = Instant::now();
+        let _ = WrappingKey::derive("", &[0u8; SALT_SIZE], KDF_CALIBRATION_COUNT);
+        let elapsed = start.elapsed();
+
+        elapsed * key_derivation_count / KDF_CALIBRATION_COUNT
+    }
+
+    /// Change the key derivation count of the store key, keeping the
+    /// passphrase.
+    ///
+    /// This re-derives the key that encrypts the store key using the new
+    /// count and rewrites the key file, the store key and the encrypted files
+    /// stay untouched. Useful to increase the work factor as hardware
+    /// improves.
+    ///
+    /// PBKDF2 is the only key derivation function the key file supports, the
+    /// key file records the count but not the function. A different function
+    /// would need a new key file version.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    /// * `new_key_derivation_count` - The key derivation count that should be
+    /// used from now on.
+    #[allow(dead_code)]
+    pub fn upgrade_kdf<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+        new_key_derivation_count: u32,
+    ) -> Result<(), OpenDirectoryError> {
+        EncryptedMmapDirectory::change_passphrase(
+            path,
+            passphrase,
+            passphrase,
+            new_key_derivation_count,
+        )
+    }
+
+    /// Re-wrap the store key using a fresh salt and IV, keeping the
+    /// passphrase and the key derivation count.
+    ///
+    /// The salt and IV of the key file are otherwise only replaced if the
+    /// passphrase changes. Refreshing them periodically limits what can be
+    /// learned from key files of stores that share a passphrase. The store
+    /// key and the encrypted files stay untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    #[allow(dead_code)]
+    pub fn refresh_wrapping_material<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+    ) -> Result<(), OpenDirectoryError> {
+        let key_path = path.as_ref().join(StoreConfig::new().key_file());
+
+        let mut data = Vec::new();
+        File::open(&key_path)?
+            .take(KEY_FILE_MAX_SIZE as u64)
+            .read_to_end(&mut data)?;
+        let key_file = KeyFile::parse(&data).map_err(IoError::from)?;
+
+        // Changing the passphrase derives the wrapping keys using a new salt
+        // and encrypts the store key using a new IV.
+        EncryptedMmapDirectory::change_passphrase(
+            path,
+            passphrase,
+            passphrase,
+            key_file.pbkdf_count,
+        )
+    }
+
+    /// Lock the key file of the store in the given path, blocks for a while
+    /// if the key file is already locked.
+    ///
+    /// The lock is held while the key file is replaced by a passphrase change
+    /// or a store key rotation, so they don't interleave.
+    pub(super) fn lock_key_file(
+        path: &Path,
+        config: &StoreConfig,
+    ) -> std::io::Result<DirectoryLock> {
+        let dir = tantivy::directory::MmapDirectory::open(path)
+            .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))?;
+
+        let lock = Lock {
+            filepath: config.key_lock_file(),
+            is_blocking: true,
+        };
+
+        dir.acquire_lock(&lock).map_err(|e| match e {
+            LockError::LockBusy => IoError::new(
+                ErrorKind::WouldBlock,
+                "the key file is locked by a store key rotation",
+            ),
+            LockError::IOError(e) => e,
+        })
+    }
+
+    /// Wrap the store key using an additional key wrapper, e.g. while the
+    /// key wrapping scheme of a store is migrated.
+    ///
+    /// The key file stays as it is, versions that only know the key wrapper
+    /// of the key file can still open the store. The additional wrapping is
+    /// stored next to the key file, `open_or_create_with_key_wrapper()`
+    /// falls back to it if the key file wasn't written by the given key
+    /// wrapper. A previous wrapping by a key wrapper of the same name is
+    /// replaced. The wrappings hold the current store key, rotating the
+    /// store key removes them, they need to be added again afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_wrapper` - The key wrapper that should wrap the store key as
+    /// well.
+    #[allow(dead_code)]
+    pub fn add_key_wrapper(&self, key_wrapper: &dyn KeyWrapper) -> std::io::Result<()> {
+        self.check_committed()?;
+
+        // A store key rotation would leave the wrapping behind with the old
+        // store key.
+        let _key_lock = EncryptedMmapDirectory::lock_key_file(&self.path, &self.config)?;
+
+        let key_file = File::open(self.path.join(self.config.key_file()))?;
+        let current_wrapper = self.state.key_wrapper()?;
+
+        let store_key = if self.custom_wrapper {
+            EncryptedMmapDirectory::unwrap_any_store_key(
+                key_file,
+                &self.path,
+                &self.config,
+                &*current_wrapper,
+            )?
+        } else {
+            let mut data = Vec::new();
+            key_file
+                .take(KEY_FILE_MAX_SIZE as u64)
+                .read_to_end(&mut data)?;
+            current_wrapper.unwrap(&data)?
+        };
+
+        let mut wrapped_keys = EncryptedMmapDirectory::read_wrapped_keys(&self.path, &self.config)?;
+
+        wrapped_keys.retain(|wrapped_key| {
+            match EncryptedMmapDirectory::read_key_file_header(&wrapped_key[..]) {
+                Ok(KeyFileHeader {
+                    protection: KeyProtection::KeyWrapper(name),
+                    ..
+                }) => name != key_wrapper.name(),
+                _ => true,
+            }
+        });
+        wrapped_keys.push(EncryptedMmapDirectory::wrap_store_key(
+            key_wrapper,
+            &store_key,
+        )?);
+
+        self.config.write_atomically(
+            &self.path.join(self.config.wrapped_keys_file()),
+            &serde_json::to_vec(&wrapped_keys)?,
+        )
+    }
+
+    /// Load a store key from the given file and decrypt it using the given
+    /// passphrase.
+    pub(super) fn load_store_key<R: Read>(
+        key_file: R,
+        passphrase: &str,
+    ) -> Result<(WrappingKey, KeyBuffer), OpenDirectoryError> {
+        EncryptedMmapDirectory::read_store_key(key_file, passphrase, true, &StoreConfig::new())
+    }
+
+    /// Read the store key from the given file and decrypt it using the given
+    /// passphrase, optionally skipping the check of the MAC.
+    pub(super) fn read_store_key<R: Read>(
+        key_file: R,
+        passphrase: &str,
+        verify_mac: bool,
+        config: &StoreConfig,
+    ) -> Result<(WrappingKey, KeyBuffer), OpenDirectoryError> {
+        // Read at most as much as a key file can contain so we don't end up
+        // filling up memory unnecessarily if someone modifies the file.
+        let mut data = Vec::new();
+        key_file
+            .take(KEY_FILE_MAX_SIZE as u64)
+            .read_to_end(&mut data)?;
+
+        let key_file = KeyFile::parse(&data).map_err(IoError::from)?;
+        config.check_key_derivation_count(key_file.pbkdf_count)?;
+
+        // Re-derive our key using the passphrase and salt.
+        debug!(
+            "Deriving the key of a version {} key file using PBKDF2 with {} iterations",
+            key_file.version, key_file.pbkdf_count
+        );
+        let wrapping_key = WrappingKey::derive(passphrase, &key_file.salt, key_file.pbkdf_count);
+        let store_key = wrapping_key.decrypt(&key_file, verify_mac).map_err(|e| {
+            debug!("Failed to decrypt the store key: {}", e);
+            IoError::from(e)
+        })?;
+
+        if verify_mac {
+            debug!("Verified the MAC of the key file");
+        } else {
+            warn!("Decrypted the store key without verifying the MAC of the key file");
+        }
+
+        Ok((wrapping_key, store_key))
+    }
+
+    /// Read the header of a key file, nothing past the header is read.
+    pub(super) fn read_key_file_header<R: Read>(mut key_file: R) -> std::io::Result<KeyFileHeader> {
+        let mut header = vec![0u8; 1];
+        key_file.read_exact(&mut header)?;
+
+        // The version of a wrapped key file is followed by the name of the
+        // key wrapper, the one of a newer passphrase protected key file by
+        // the length of its header. The whole declared header is read, so
+        // fields we don't understand are noticed.
+        let has_length = header[0] == WRAPPED_VERSION
+            || (header[0] >= HEADER_LENGTH_VERSION && Cipher::for_version(header[0]).is_some());
+
+        if has_length {
+            let length = key_file.read_u8()?;
+            header.push(length);
+            header.resize(2 + length as usize, 0);
+            key_file.read_exact(&mut header[2..])?;
+        }
+
+        Ok(KeyFileHeader::parse(&header)?)
+    }
+
+    /// Read a key file that was written by a custom key wrapper and unwrap
+    /// the store key.
+    fn unwrap_store_key<R: Read>(
+        mut key_file: R,
+        key_wrapper: &dyn KeyWrapper,
+    ) -> std::io::Result<KeyBuffer> {
+        let header = EncryptedMmapDirectory::read_key_file_header(&mut key_file)?;
+
+        let name = match header.protection {
+            KeyProtection::KeyWrapper(name) => name,
+            KeyProtection::Passphrase(_) => PASSPHRASE_WRAPPER.to_owned(),
+        };
+
+        if name != key_wrapper.name() {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                format!("the store key was wrapped by the {} key wrapper", name),
+            ));
+        }
+
+        let mut wrapped_key = Vec::new();
+        key_file.read_to_end(&mut wrapped_key)?;
+
+        // Any key can be expanded into file keys, a corrupted key file that
+        // unwraps to a key of the wrong size would go unnoticed until the
+        // first file fails to decrypt.
+        let store_key = key_wrapper.unwrap(&wrapped_key)?;
+
+        if store_key.len() != KEY_SIZE {
+            return Err(CryptoError::InvalidKeySize.into());
+        }
+
+        Ok(store_key)
+    }
+
+    /// Unwrap the store key using the given key wrapper, if the key file
+    /// wasn't written by it the additional wrappings of the store key are
+    /// tried, see `add_key_wrapper()`.
+    pub(super) fn unwrap_any_store_key<R: Read>(
+        key_file: R,
+        path: &Path,
+        config: &StoreConfig,
+        key_wrapper: &dyn KeyWrapper,
+    ) -> std::io::Result<KeyBuffer> {
+        let error = match EncryptedMmapDirectory::unwrap_store_key(key_file, key_wrapper) {
+            Ok(store_key) => return Ok(store_key),
+            Err(e) => e,
+        };
+
+        for wrapped_key in EncryptedMmapDirectory::read_wrapped_keys(path, config)? {
+            if let Ok(store_key) =
+                EncryptedMmapDirectory::unwrap_store_key(&wrapped_key[..], key_wrapper)
+            {
+                debug!(
+                    "Unwrapped the store key using the additional {} key wrapper",
+                    key_wrapper.name()
+                );
+                return Ok(store_key);
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Read the additional wrappings of the store key, each of them has the
+    /// format of a key file that was written by a custom key wrapper.
+    fn read_wrapped_keys(path: &Path, config: &StoreConfig) -> std::io::Result<Vec<Vec<u8>>> {
+        match std::fs::read(path.join(config.wrapped_keys_file())) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Wrap the given store key using a custom key wrapper, the result is
+    /// the content of a key file.
+    pub(super) fn wrap_store_key(
+        key_wrapper: &dyn KeyWrapper,
+        store_key: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        let name = key_wrapper.name();
+
+        if name.len() > u8::MAX as usize {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                "the name of the key wrapper is too long",
+            ));
+        }
+
+        let mut key_file = vec![WRAPPED_VERSION, name.len() as u8];
+        key_file.extend_from_slice(name.as_bytes());
+        key_file.extend_from_slice(&key_wrapper.wrap(store_key)?);
+
+        Ok(key_file)
+    }
+
+    /// Create a new store key, encrypt it with the given passphrase and store
+    /// it in the given path.
+    pub(super) fn create_new_store(
+        key_path: &Path,
+        passphrase: &str,
+        pbkdf_count: u32,
+        config: &StoreConfig,
+    ) -> Result<(WrappingKey, KeyBuffer), OpenDirectoryError> {
+        let (wrapping_key, store_key, key_file) =
+            EncryptedMmapDirectory::generate_store_key(passphrase, pbkdf_count)?;
+
+        // Save the encrypted store key to a file.
+        EncryptedMmapDirectory::persist_key_file(key_path, &key_file, config)?;
+
+        Ok((wrapping_key, store_key))
+    }
+
+    /// Generate the store key of a new store, returns the key that protects
+    /// the store key, the store key, and the content of the key file.
+    pub(super) fn generate_store_key(
+        passphrase: &str,
+        pbkdf_count: u32,
+    ) -> Result<(WrappingKey, KeyBuffer, Vec<u8>), OpenDirectoryError> {
+        // Derive a AES key from our passphrase using a randomly generated salt
+        // to prevent bruteforce attempts using rainbow tables.
+        let wrapping_key = EncryptedMmapDirectory::derive_key(passphrase, pbkdf_count)?;
+        // Generate a new random store key. This key will encrypt our Tantivy
+        // indexing files. The key itself is stored encrypted using the derived
+        // key.
+        let store_key = EncryptedMmapDirectory::generate_key()?;
+
+        let iv = EncryptedMmapDirectory::generate_iv(Cipher::Aes256Ctr)?;
+        let key_file = wrapping_key
+            .encrypt(&store_key, &iv)
+            .map_err(IoError::from)?;
+
+        Ok((wrapping_key, store_key, key_file))
+    }
+
+    /// Persist the key file of a new store in the given path.
+    ///
+    /// The key file is written atomically, a crash while the key file is
+    /// written won't leave a truncated key file behind.
+    fn persist_key_file(
+        key_path: &Path,
+        key_file: &[u8],
+        config: &StoreConfig,
+    ) -> Result<(), OpenDirectoryError> {
+        config.write_atomically(key_path, key_file)?;
+
+        Ok(())
+    }
+
+    /// Encrypt the given store key and save it in the given path.
+    ///
+    /// The key file is replaced atomically, a crash while the key file is
+    /// written won't leave a truncated key file behind.
+    fn encrypt_store_key(
+        wrapping_key: &WrappingKey,
+        store_key: &[u8],
+        key_path: &Path,
+        config: &StoreConfig,
+    ) -> Result<(), OpenDirectoryError> {
+        let iv = EncryptedMmapDirectory::generate_iv(Cipher::Aes256Ctr)?;
+        let key_file = wrapping_key
+            .encrypt(store_key, &iv)
+            .map_err(IoError::from)?;
+        config.write_atomically(key_path, &key_file)?;
+
+        Ok(())
+    }
+
+    /// Wrap the given store key using the key wrapper of the directory and
+    /// replace the key file.
+    pub(super) fn write_key_file(&self, store_key: &[u8]) -> std::io::Result<()> {
+        let key_wrapper = self.state.key_wrapper()?;
+        let key_file = self.wrap_key_file(&*key_wrapper, store_key)?;
+
+        self.config
+            .write_atomically(&self.path.join(self.config.key_file()), &key_file)
+    }
+
+    /// Wrap the given store key using the given key wrapper, the result is
+    /// the content of a key file.
+    pub(super) fn wrap_key_file(
+        &self,
+        key_wrapper: &dyn KeyWrapper,
+        store_key: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        if self.custom_wrapper {
+            EncryptedMmapDirectory::wrap_store_key(key_wrapper, store_key)
+        } else {
+            key_wrapper.wrap(store_key)
+        }
+    }
+
+    /// Generate a random IV for the given cipher.
+    pub(super) fn generate_iv(cipher: Cipher) -> std::io::Result<Vec<u8>> {
+        EncryptedMmapDirectory::generate_nonce(cipher.iv_size())
+    }
+
+    /// Generate a random nonce of the given size.
+    pub(super) fn generate_nonce(size: usize) -> std::io::Result<Vec<u8>> {
+        let mut nonce = vec![0u8; size];
+        fill_random(&mut nonce[..])
+            .map_err(|e| IoError::new(ErrorKind::Other, format!("error generating iv: {:?}", e)))?;
+        Ok(nonce)
+    }
+
+    /// Generate a random key.
+    pub(super) fn generate_key() -> std::io::Result<KeyBuffer> {
+        let mut key = Zeroizing::new(vec![0u8; KEY_SIZE]);
+        fill_random(&mut key[..]).map_err(|e| {
+            IoError::new(ErrorKind::Other, format!("error generating key: {:?}", e))
+        })?;
+        Ok(key)
+    }
+
+    /// Generate a random passphrase, the hex encoding of a random key.
+    pub(super) fn generate_passphrase() -> std::io::Result<Zeroizing<String>> {
+        let key = EncryptedMmapDirectory::generate_key()?;
+        let mut passphrase = Zeroizing::new(String::with_capacity(2 * key.len()));
+
+        for byte in key.iter() {
+            passphrase.push_str(&format!("{:02x}", byte));
+        }
+
+        Ok(passphrase)
+    }
+
+    /// Generate a random salt and derive two keys from the salt and the given
+    /// passphrase.
+    pub(super) fn derive_key(
+        passphrase: &str,
+        pbkdf_count: u32,
+    ) -> Result<WrappingKey, OpenDirectoryError> {
+        let mut salt = vec![0u8; SALT_SIZE];
+        fill_random(&mut salt[..]).map_err(|e| {
+            IoError::new(ErrorKind::Other, format!("error generating salt: {:?}", e))
+        })?;
+
+        Ok(WrappingKey::derive(passphrase, &salt, pbkdf_count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::encrypted_dir::tests::MockHardwareWrapper;
+    use tempfile::tempdir;
+
+    #[test]
+    fn change_passphrase() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+
+        drop(dir);
+        EncryptedMmapDirectory::change_passphrase(
+            tmpdir.path(),
+            "wordpass",
+            "password",
+            PBKDF_COUNT,
+        )
+        .expect("Can't change passphrase");
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass");
+        assert!(
+            dir.is_err(),
+            "Opened an existing store with the old passphrase"
+        );
+        let _ = EncryptedMmapDirectory::open(tmpdir.path(), "password")
+            .expect("Can't open the store with the new passphrase");
+    }
+
+    #[test]
+    fn upgrade_key_derivation_count() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        drop(dir);
+
+        EncryptedMmapDirectory::upgrade_kdf(tmpdir.path(), "wordpass", PBKDF_COUNT * 10)
+            .expect("Can't upgrade the key derivation count");
+
+        let key_file = File::open(tmpdir.path().join(KEYFILE)).unwrap();
+        let (wrapping_key, _) = EncryptedMmapDirectory::load_store_key(key_file, "wordpass")
+            .expect("Can't load the store key with the upgraded count");
+        assert_eq!(wrapping_key.pbkdf_count, PBKDF_COUNT * 10);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the store after the upgrade");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+
+        // Rewriting the key file keeps the upgraded count.
+        dir.rotate_store_key().unwrap();
+        let key_file = File::open(tmpdir.path().join(KEYFILE)).unwrap();
+        let (wrapping_key, _) =
+            EncryptedMmapDirectory::load_store_key(key_file, "wordpass").unwrap();
+        assert_eq!(wrapping_key.pbkdf_count, PBKDF_COUNT * 10);
+    }
+
+    #[test]
+    fn reject_key_file_with_mismatched_iv_length() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+        drop(dir);
+
+        // Replace the IV of the version 5, AES-CTR, key file with a 12 byte
+        // nonce as AES-GCM would use.
+        let key_path = tmpdir.path().join(KEYFILE);
+        let key_file = std::fs::read(&key_path).unwrap();
+        let mut modified = key_file[..3].to_vec();
+        modified.extend_from_slice(&[0u8; 12]);
+        modified.extend_from_slice(&key_file[3 + IV_SIZE..]);
+        std::fs::write(&key_path, modified).unwrap();
+
+        match EncryptedMmapDirectory::open(tmpdir.path(), "wordpass") {
+            Ok(_) => panic!("Opened a store with a mismatched IV length"),
+            Err(OpenDirectoryError::IoError(e)) => assert_eq!(
+                e.to_string(),
+                "invalid IV length 12 for Aes256Ctr, expected 16"
+            ),
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn wrap_store_key_using_hardware() {
+        let tmpdir = tempdir().unwrap();
+        let wrapper = Arc::new(MockHardwareWrapper::default());
+
+        let (mut dir, opened) =
+            EncryptedMmapDirectory::open_or_create_with_key_wrapper(tmpdir.path(), wrapper.clone())
+                .expect("Can't create a new store using a key wrapper");
+        assert_eq!(opened, Opened::Created);
+
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        dir.rotate_store_key().unwrap();
+        drop(dir);
+
+        let key_file = std::fs::read(tmpdir.path().join(KEYFILE)).unwrap();
+        assert_eq!(key_file[0], WRAPPED_VERSION);
+        assert_eq!(&key_file[2..2 + key_file[1] as usize], b"mock-hardware");
+
+        assert!(
+            EncryptedMmapDirectory::open_or_create_with_key_wrapper(tmpdir.path(), wrapper.clone())
+                .is_err(),
+            "Unwrapped the store key without the device"
+        );
+        assert!(
+            EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").is_err(),
+            "Opened a hardware wrapped store using a passphrase"
+        );
+
+        wrapper.device_present.store(true, Ordering::SeqCst);
+        let (dir, opened) =
+            EncryptedMmapDirectory::open_or_create_with_key_wrapper(tmpdir.path(), wrapper)
+                .expect("Can't open the store with the device present");
+        assert_eq!(opened, Opened::Existing);
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+    }
+
+    #[test]
+    fn read_key_file_header() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+        drop(dir);
+
+        let header =
+            EncryptedMmapDirectory::key_file_header(tmpdir.path()).expect("Can't read the header");
+        assert_eq!(header.version, 5);
+        assert_eq!(
+            header.protection,
+            KeyProtection::Passphrase(Cipher::Aes256Ctr)
+        );
+
+        std::fs::write(tmpdir.path().join(KEYFILE), b"").unwrap();
+        match EncryptedMmapDirectory::key_file_header(tmpdir.path()) {
+            Ok(_) => panic!("Read the header of an empty key file"),
+            Err(e) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+        }
+
+        // Stores with a custom basename have their key file elsewhere.
+        let other = tempdir().unwrap();
+        let config = StoreConfig::new().set_key_file_basename("custom");
+        EncryptedMmapDirectory::open_or_create_with_config(
+            other.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            &config,
+        )
+        .expect("Can't create a new store");
+
+        assert!(EncryptedMmapDirectory::key_file_header(other.path()).is_err());
+        let header = EncryptedMmapDirectory::key_file_header_with_config(other.path(), &config)
+            .expect("Can't read the header");
+        assert_eq!(
+            header.protection,
+            KeyProtection::Passphrase(Cipher::Aes256Ctr)
+        );
+    }
+
+    #[test]
+    fn refresh_wrapping_material() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        drop(dir);
+
+        let read_key_file =
+            || KeyFile::parse(&std::fs::read(tmpdir.path().join(KEYFILE)).unwrap()).unwrap();
+        let old = read_key_file();
+
+        EncryptedMmapDirectory::refresh_wrapping_material(tmpdir.path(), "wordpass")
+            .expect("Can't refresh the wrapping material");
+
+        let new = read_key_file();
+        assert_ne!(old.salt, new.salt);
+        assert_ne!(old.iv, new.iv);
+        assert_eq!(old.pbkdf_count, new.pbkdf_count);
+
+        assert!(
+            EncryptedMmapDirectory::refresh_wrapping_material(tmpdir.path(), "password").is_err(),
+            "Refreshed the wrapping material using the wrong passphrase"
+        );
+
+        // The store key didn't change, the files can still be decrypted.
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the store after refreshing the wrapping material");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+    }
+
+    #[test]
+    fn estimate_unlock_cost() {
+        let cheap = EncryptedMmapDirectory::estimate_unlock_cost(1000);
+        let expensive = EncryptedMmapDirectory::estimate_unlock_cost(1_000_000);
+
+        assert!(cheap > Duration::from_secs(0));
+        assert!(expensive > cheap);
+        assert_eq!(
+            EncryptedMmapDirectory::estimate_unlock_cost(0),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn reject_short_key_bytes() {
+        let tmpdir = tempdir().unwrap();
+
+        match EncryptedMmapDirectory::open_with_key_bytes(tmpdir.path(), &[0x42; 4]) {
+            Ok(_) => panic!("Created a store using a 4 byte key"),
+            Err(OpenDirectoryError::IoError(e)) => assert_eq!(e.kind(), ErrorKind::InvalidInput),
+            Err(e) => panic!("Unexpected error: {}", e),
+        }
+        assert!(!tmpdir.path().join(KEYFILE).exists());
+
+        let key = [0x42; KEY_SIZE];
+        let (mut dir, opened) = EncryptedMmapDirectory::open_with_key_bytes(tmpdir.path(), &key)
+            .expect("Can't create a store using a full length key");
+        assert_eq!(opened, Opened::Created);
+
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        drop(dir);
+
+        let (dir, opened) = EncryptedMmapDirectory::open_with_key_bytes(tmpdir.path(), &key)
+            .expect("Can't reopen the store");
+        assert_eq!(opened, Opened::Existing);
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        drop(dir);
+
+        assert!(
+            EncryptedMmapDirectory::open_with_key_bytes(tmpdir.path(), &[0x24; KEY_SIZE]).is_err()
+        );
+    }
+
+    #[test]
+    fn check_the_canary_of_the_store_key() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+        drop(dir);
+
+        let key_path = tmpdir.path().join(KEYFILE);
+        let key_file = std::fs::read(&key_path).unwrap();
+        let (wrapping_key, mut store_key) =
+            EncryptedMmapDirectory::load_store_key(&key_file[..], "wordpass").unwrap();
+
+        // Wrap a subtly different store key, the MAC of the key file is valid
+        // but the canary still belongs to the original store key.
+        store_key[0] ^= 1;
+        let parsed = KeyFile::parse(&key_file).unwrap();
+        let mut forged = wrapping_key.encrypt(&store_key, &parsed.iv).unwrap();
+        let canary = parsed.canary.expect("The key file doesn't have a canary");
+        let canary_start = forged.len() - canary.len();
+        forged[canary_start..].copy_from_slice(&canary);
+        std::fs::write(&key_path, &forged).unwrap();
+
+        match EncryptedMmapDirectory::open(tmpdir.path(), "wordpass") {
+            Ok(_) => panic!("Opened a store whose store key doesn't match its canary"),
+            Err(OpenDirectoryError::IoError(e)) => assert_eq!(
+                e.to_string(),
+                "the store key doesn't decrypt the canary of the key file"
+            ),
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn change_passphrase_while_reading() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let dir = dir.clone();
+                let done = done.clone();
+
+                std::thread::spawn(move || {
+                    let mut reads = 0;
+
+                    while !done.load(Ordering::SeqCst) || reads == 0 {
+                        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+                        reads += 1;
+                    }
+                })
+            })
+            .collect();
+
+        EncryptedMmapDirectory::change_passphrase(
+            tmpdir.path(),
+            "wordpass",
+            "password",
+            PBKDF_COUNT,
+        )
+        .expect("Can't change the passphrase while reading");
+        done.store(true, Ordering::SeqCst);
+
+        for reader in readers {
+            reader
+                .join()
+                .expect("A read failed during the passphrase change");
+        }
+
+        // The open directory keeps on working, new opens need the new passphrase.
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        drop(dir);
+        assert!(EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").is_err());
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "password")
+            .expect("Can't open the store using the new passphrase");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+    }
+
+    #[test]
+    fn reject_store_keys_of_the_wrong_size() {
+        let tmpdir = tempdir().unwrap();
+        let wrapper = Arc::new(MockHardwareWrapper::default());
+        wrapper.device_present.store(true, Ordering::SeqCst);
+
+        let (dir, _) =
+            EncryptedMmapDirectory::open_or_create_with_key_wrapper(tmpdir.path(), wrapper.clone())
+                .expect("Can't create a new store using a key wrapper");
+        drop(dir);
+
+        // The mock wrapper unwraps whatever it's given, a truncated key file
+        // unwraps to a shorter key.
+        let key_path = tmpdir.path().join(KEYFILE);
+        let key_file = std::fs::read(&key_path).unwrap();
+        std::fs::write(&key_path, &key_file[..key_file.len() - 1]).unwrap();
+
+        match EncryptedMmapDirectory::open_or_create_with_key_wrapper(tmpdir.path(), wrapper) {
+            Ok(_) => panic!("Opened a store using a truncated store key"),
+            Err(OpenDirectoryError::IoError(e)) => {
+                assert_eq!(e.to_string(), CryptoError::InvalidKeySize.to_string())
+            }
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn create_a_store_with_a_generated_passphrase() {
+        let tmpdir = tempdir().unwrap();
+
+        let (mut dir, passphrase) =
+            EncryptedMmapDirectory::create_with_generated_passphrase(tmpdir.path(), PBKDF_COUNT)
+                .expect("Can't create a new store");
+        assert_eq!(passphrase.len(), 2 * KEY_SIZE);
+
+        dir.atomic_write(Path::new("meta.json"), b"{}").unwrap();
+        drop(dir);
+
+        // Existing stores need their passphrase.
+        assert!(EncryptedMmapDirectory::create_with_generated_passphrase(
+            tmpdir.path(),
+            PBKDF_COUNT
+        )
+        .is_err());
+
+        let (dir, opened) = EncryptedMmapDirectory::open_or_create_reporting(
+            tmpdir.path(),
+            &passphrase,
+            PBKDF_COUNT,
+        )
+        .expect("Can't reopen the store using the generated passphrase");
+        assert_eq!(opened, Opened::Existing);
+        assert_eq!(dir.atomic_read(Path::new("meta.json")).unwrap(), b"{}");
+
+        // Every store gets its own passphrase.
+        let other = tempdir().unwrap();
+        let (_, other_passphrase) =
+            EncryptedMmapDirectory::create_with_generated_passphrase(other.path(), PBKDF_COUNT)
+                .unwrap();
+        assert_ne!(other_passphrase, passphrase);
+    }
+
+    #[test]
+    fn report_authenticated_encryption() {
+        let tmpdir = tempdir().unwrap();
+        drop(
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store"),
+        );
+        assert!(EncryptedMmapDirectory::uses_authenticated_encryption(tmpdir.path()).unwrap());
+
+        let wrapped = tempdir().unwrap();
+        let wrapper = Arc::new(MockHardwareWrapper::default());
+        wrapper.device_present.store(true, Ordering::SeqCst);
+        drop(
+            EncryptedMmapDirectory::open_or_create_with_key_wrapper(wrapped.path(), wrapper)
+                .expect("Can't create a new store using a key wrapper"),
+        );
+        assert!(EncryptedMmapDirectory::uses_authenticated_encryption(wrapped.path()).unwrap());
+
+        // Key files of an unknown version can't be judged.
+        let key_path = tmpdir.path().join(KEYFILE);
+        let mut key_file = std::fs::read(&key_path).unwrap();
+        key_file[0] = 0x7f;
+        std::fs::write(&key_path, &key_file).unwrap();
+        assert!(EncryptedMmapDirectory::uses_authenticated_encryption(tmpdir.path()).is_err());
+
+        let empty = tempdir().unwrap();
+        assert!(EncryptedMmapDirectory::uses_authenticated_encryption(empty.path()).is_err());
+    }
+
+    #[test]
+    fn refuse_key_derivation_counts_over_budget() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+        drop(dir);
+
+        let config = StoreConfig::new().set_max_key_derivation_count(PBKDF_COUNT - 1);
+
+        match EncryptedMmapDirectory::open_with_config(tmpdir.path(), "wordpass", &config) {
+            Err(OpenDirectoryError::IoError(e)) => {
+                assert!(KdfBudgetExceeded::matches(&e));
+                assert_eq!(
+                    e.get_ref().unwrap().downcast_ref::<KdfBudgetExceeded>(),
+                    Some(&KdfBudgetExceeded {
+                        key_derivation_count: PBKDF_COUNT,
+                        budget: PBKDF_COUNT - 1,
+                    })
+                );
+            }
+            _ => panic!("Opened a store whose key derivation count exceeds the budget"),
+        }
+
+        match EncryptedMmapDirectory::open_or_create_with_config(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            &config,
+        ) {
+            Err(OpenDirectoryError::IoError(e)) => assert!(KdfBudgetExceeded::matches(&e)),
+            _ => panic!("Opened a store whose key derivation count exceeds the budget"),
+        }
+
+        // New stores are held to the budget as well.
+        let other = tempdir().unwrap();
+        assert!(EncryptedMmapDirectory::open_or_create_with_config(
+            other.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            &config,
+        )
+        .is_err());
+        assert!(!other.path().join(KEYFILE).exists());
+
+        let config = StoreConfig::new().set_max_key_derivation_count(PBKDF_COUNT);
+        EncryptedMmapDirectory::open_with_config(tmpdir.path(), "wordpass", &config)
+            .expect("Can't open a store within the budget");
+    }
+
+    #[test]
+    fn open_a_store_using_an_additional_key_wrapper() {
+        let tmpdir = tempdir().unwrap();
+        let hardware = Arc::new(MockHardwareWrapper::default());
+        hardware.device_present.store(true, Ordering::SeqCst);
+
+        let key = [7u8; KEY_SIZE];
+        let key_bytes = KeyBytesWrapper {
+            provider: EncryptedMmapDirectory::create_provider_for(
+                &key,
+                KEY_BYTES_WRAPPER.as_bytes(),
+            )
+            .unwrap(),
+        };
+
+        let (mut dir, _) = EncryptedMmapDirectory::open_or_create_with_key_wrapper(
+            tmpdir.path(),
+            hardware.clone(),
+        )
+        .expect("Can't create a new store using a key wrapper");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+
+        let key_file = std::fs::read(tmpdir.path().join(KEYFILE)).unwrap();
+        dir.add_key_wrapper(&key_bytes).unwrap();
+        dir.add_key_wrapper(&key_bytes).unwrap();
+        assert_eq!(
+            EncryptedMmapDirectory::read_wrapped_keys(tmpdir.path(), &StoreConfig::new())
+                .unwrap()
+                .len(),
+            1
+        );
+        drop(dir);
+
+        // Versions that only know the key wrapper of the key file can still
+        // open the store.
+        assert_eq!(
+            std::fs::read(tmpdir.path().join(KEYFILE)).unwrap(),
+            key_file
+        );
+        let (dir, _) = EncryptedMmapDirectory::open_or_create_with_key_wrapper(
+            tmpdir.path(),
+            hardware.clone(),
+        )
+        .unwrap();
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        drop(dir);
+
+        // So can versions that only know the new key wrapper.
+        let (dir, opened) = EncryptedMmapDirectory::open_with_key_bytes(tmpdir.path(), &key)
+            .expect("Can't open the store using the additional key wrapper");
+        assert_eq!(opened, Opened::Existing);
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        drop(dir);
+
+        assert!(
+            EncryptedMmapDirectory::open_with_key_bytes(tmpdir.path(), &[8u8; KEY_SIZE]).is_err()
+        );
+
+        // Rotating the store key removes the additional wrappings, the key file
+        // is written by the key wrapper the store was opened with.
+        let (dir, _) = EncryptedMmapDirectory::open_with_key_bytes(tmpdir.path(), &key).unwrap();
+        dir.rotate_store_key().unwrap();
+        drop(dir);
+
+        assert!(!tmpdir.path().join("seshat-index.keys").exists());
+        assert!(
+            EncryptedMmapDirectory::open_or_create_with_key_wrapper(tmpdir.path(), hardware)
+                .is_err()
+        );
+        let (dir, _) = EncryptedMmapDirectory::open_with_key_bytes(tmpdir.path(), &key).unwrap();
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        drop(dir);
+
+        // Passphrase protected stores can be wrapped by a key wrapper as well.
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        dir.atomic_write(path, b"content").unwrap();
+        dir.add_key_wrapper(&key_bytes).unwrap();
+        drop(dir);
+
+        let (dir, _) = EncryptedMmapDirectory::open_with_key_bytes(tmpdir.path(), &key).unwrap();
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        drop(dir);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+    }
+}
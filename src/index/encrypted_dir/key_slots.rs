@@ -0,0 +1,523 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Key slots, additional passphrases that unlock the key file.
+
+use super::*;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// The key slots of a store and whether they are intact, see
+/// `EncryptedMmapDirectory::verify_key_slots()`.
+pub struct KeySlotReport {
+    /// The slots that authenticated.
+    pub intact: Vec<usize>,
+    /// The slots that were modified, or that hold keys which don't unlock
+    /// the key file anymore.
+    pub corrupt: Vec<usize>,
+}
+
+impl KeySlotReport {
+    /// Did every key slot of the store authenticate.
+    pub fn is_intact(&self) -> bool {
+        self.corrupt.is_empty()
+    }
+}
+
+impl EncryptedMmapDirectory {
+    /// Check whether the given passphrase unlocks the store in the given
+    /// path, without opening the store.
+    ///
+    /// This is a shorthand for `which_slot()`, any key slot will do.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `passphrase` - The passphrase that should be checked.
+    #[allow(dead_code)]
+    pub fn passphrase_unlocks<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+    ) -> Result<bool, OpenDirectoryError> {
+        Ok(EncryptedMmapDirectory::which_slot(path, passphrase)?.is_some())
+    }
+
+    /// Find the key slot the given passphrase unlocks, without opening the
+    /// store, e.g. to manage the slots of a store, see `add_key_slot()`.
+    ///
+    /// The store key is decrypted and confirmed like it would be when the
+    /// store is opened, but no lock is taken and no file is touched, so this
+    /// works while the store is in use. Returns the index of the slot, the
+    /// key file itself is slot 0, or `None` if the passphrase unlocks no
+    /// slot. Errors about the key file itself, e.g. a truncated key file,
+    /// are returned as errors.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `passphrase` - The passphrase that should be checked.
+    #[allow(dead_code)]
+    pub fn which_slot<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+    ) -> Result<Option<usize>, OpenDirectoryError> {
+        let config = StoreConfig::new();
+
+        match EncryptedMmapDirectory::unlock_key_slot_of(path.as_ref(), passphrase, &config) {
+            Ok((_, _, slot)) => Ok(Some(slot)),
+            Err(e) => match e.get_ref().and_then(|e| e.downcast_ref::<CryptoError>()) {
+                Some(CryptoError::InvalidMac) | Some(CryptoError::InvalidCanary) => Ok(None),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    /// Add a key slot, so the store can be unlocked using an additional
+    /// passphrase, e.g. one for every user of a shared device.
+    ///
+    /// The slot holds the keys that protect the key file, encrypted using
+    /// the new passphrase. Wherever the store is opened or unlocked using a
+    /// passphrase, the passphrase of a slot works as well. Store key
+    /// rotations keep the slots, changing the passphrase of the key file
+    /// removes them, they need to be added again afterwards.
+    ///
+    /// Returns the index of the new slot, the key file itself is slot 0. The
+    /// index of a removed slot gets reused.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - A passphrase that unlocks the store.
+    /// * `new_passphrase` - The passphrase that should unlock the new slot.
+    /// * `key_derivation_count` - The number of iterations that our key
+    /// derivation function should use for the new slot.
+    #[allow(dead_code)]
+    pub fn add_key_slot(
+        &self,
+        passphrase: &str,
+        new_passphrase: &str,
+        key_derivation_count: u32,
+    ) -> std::io::Result<usize> {
+        self.config.check_passphrase(new_passphrase)?;
+
+        if key_derivation_count == 0 {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                "invalid key derivation count",
+            ));
+        }
+
+        self.config
+            .check_key_derivation_count(key_derivation_count)?;
+        self.check_committed()?;
+
+        // A passphrase change would leave the slot behind with keys that
+        // don't unlock the new key file.
+        let _key_lock = EncryptedMmapDirectory::lock_key_file(&self.path, &self.config)?;
+
+        let (keys, _) = self.unlock_own_key_file(passphrase)?;
+        let slot_key = EncryptedMmapDirectory::derive_key(new_passphrase, key_derivation_count)
+            .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))?;
+        let iv = EncryptedMmapDirectory::generate_iv(Cipher::Aes256Ctr)?;
+        let slot = slot_key.seal_slot(&keys, &iv)?;
+
+        let mut slots = EncryptedMmapDirectory::read_key_slots(&self.path, &self.config)?;
+
+        let index = match slots.iter().position(Option::is_none) {
+            Some(index) => {
+                slots[index] = Some(slot);
+                index
+            }
+            None => {
+                slots.push(Some(slot));
+                slots.len() - 1
+            }
+        };
+
+        self.write_key_slots(&slots)?;
+
+        Ok(index + 1)
+    }
+
+    /// Remove a key slot, its passphrase doesn't unlock the store anymore.
+    ///
+    /// The indices of the other slots stay as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - A passphrase that unlocks the store, it might be the
+    /// one of the removed slot.
+    /// * `slot` - The index of the slot that should be removed.
+    #[allow(dead_code)]
+    pub fn remove_key_slot(&self, passphrase: &str, slot: usize) -> std::io::Result<()> {
+        if slot == 0 {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                "the key file can't be removed",
+            ));
+        }
+
+        self.check_committed()?;
+
+        let _key_lock = EncryptedMmapDirectory::lock_key_file(&self.path, &self.config)?;
+        self.unlock_own_key_file(passphrase)?;
+
+        let mut slots = EncryptedMmapDirectory::read_key_slots(&self.path, &self.config)?;
+
+        match slots.get_mut(slot - 1) {
+            Some(s) if s.is_some() => *s = None,
+            _ => return Err(IoError::new(ErrorKind::NotFound, "no such key slot")),
+        }
+
+        while let Some(None) = slots.last() {
+            slots.pop();
+        }
+
+        self.write_key_slots(&slots)
+    }
+
+    /// Check that the key slots of the store are intact.
+    ///
+    /// Every slot is authenticated using the keys that protect the key file,
+    /// the passphrases of the slots aren't needed. A corrupted slot doesn't
+    /// keep the other slots from unlocking the store, but its passphrase
+    /// doesn't unlock it anymore.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - A passphrase that unlocks the store.
+    #[allow(dead_code)]
+    pub fn verify_key_slots(&self, passphrase: &str) -> std::io::Result<KeySlotReport> {
+        let (keys, _) = self.unlock_own_key_file(passphrase)?;
+        let mut report = KeySlotReport::default();
+
+        for (index, slot) in EncryptedMmapDirectory::read_key_slots(&self.path, &self.config)?
+            .iter()
+            .enumerate()
+        {
+            let slot = match slot {
+                Some(s) => s,
+                None => continue,
+            };
+
+            match KeySlot::parse(slot).and_then(|s| s.verify(&keys)) {
+                Ok(()) => report.intact.push(index + 1),
+                Err(e) => {
+                    debug!("Key slot {} failed to authenticate: {}", index + 1, e);
+                    report.corrupt.push(index + 1);
+                }
+            }
+        }
+
+        if report.is_intact() {
+            debug!("Verified {} key slots", report.intact.len());
+        } else {
+            warn!(
+                "The key slots {:?} of the store in {} are corrupted",
+                report.corrupt,
+                self.path.display()
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Unlock the key file of the store using the given passphrase, see
+    /// `unlock_key_file()`.
+    pub(super) fn unlock_own_key_file(
+        &self,
+        passphrase: &str,
+    ) -> std::io::Result<(WrappingKey, KeyBuffer)> {
+        EncryptedMmapDirectory::unlock_key_file(&self.path, passphrase, &self.config)
+            .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))
+    }
+
+    /// Replace the key slots of the store, the file is removed if there are
+    /// no slots left.
+    fn write_key_slots(&self, slots: &[Option<Vec<u8>>]) -> std::io::Result<()> {
+        if slots.is_empty() {
+            EncryptedMmapDirectory::remove_key_slots(&self.path, &self.config)
+        } else {
+            self.config.write_atomically(
+                &self.path.join(self.config.key_slots_file()),
+                &serde_json::to_vec(slots)?,
+            )
+        }
+    }
+
+    /// Unlock the key file of the store in the given path using the given
+    /// passphrase, if the passphrase doesn't unlock the key file itself the
+    /// key slots are tried, see `add_key_slot()`.
+    ///
+    /// Returns the keys that protect the key file, the store key, and the
+    /// index of the slot that was unlocked, the key file itself is slot 0. A
+    /// corrupted slot is skipped, if no slot unlocks the key file the error
+    /// of the key file itself is returned.
+    fn unlock_key_slot_of(
+        path: &Path,
+        passphrase: &str,
+        config: &StoreConfig,
+    ) -> std::io::Result<(WrappingKey, KeyBuffer, usize)> {
+        let mut data = Vec::new();
+        File::open(path.join(config.key_file()))?
+            .take(KEY_FILE_MAX_SIZE as u64)
+            .read_to_end(&mut data)?;
+
+        let key_file = KeyFile::parse(&data)?;
+        config.check_key_derivation_count(key_file.pbkdf_count)?;
+
+        debug!(
+            "Deriving the key of a version {} key file using PBKDF2 with {} iterations",
+            key_file.version, key_file.pbkdf_count
+        );
+        let keys = WrappingKey::derive(passphrase, &key_file.salt, key_file.pbkdf_count);
+
+        let error = match keys.decrypt(&key_file, true) {
+            Ok(store_key) => {
+                debug!("Verified the MAC of the key file");
+                return Ok((keys, store_key, 0));
+            }
+            Err(e) if e == CryptoError::InvalidMac || e == CryptoError::InvalidCanary => e,
+            Err(e) => return Err(e.into()),
+        };
+
+        for (index, slot) in EncryptedMmapDirectory::read_key_slots(path, config)?
+            .iter()
+            .enumerate()
+        {
+            let slot = match slot {
+                Some(s) => s,
+                None => continue,
+            };
+
+            match EncryptedMmapDirectory::unlock_key_slot(slot, passphrase, &key_file, config) {
+                Ok((keys, store_key)) => {
+                    debug!("Unlocked the store using key slot {}", index + 1);
+                    return Ok((keys, store_key, index + 1));
+                }
+                Err(e) => debug!("Key slot {} doesn't unlock the store: {}", index + 1, e),
+            }
+        }
+
+        debug!("Failed to decrypt the store key: {}", error);
+
+        Err(error.into())
+    }
+
+    /// Unlock the given key file using the given key slot.
+    fn unlock_key_slot(
+        slot: &[u8],
+        passphrase: &str,
+        key_file: &KeyFile,
+        config: &StoreConfig,
+    ) -> std::io::Result<(WrappingKey, KeyBuffer)> {
+        let slot = KeySlot::parse(slot)?;
+        config.check_key_derivation_count(slot.pbkdf_count)?;
+
+        let slot_key = WrappingKey::derive(passphrase, &slot.salt, slot.pbkdf_count);
+        let keys = slot_key.open_slot(&slot, &key_file.salt, key_file.pbkdf_count)?;
+        let store_key = keys.decrypt(key_file, true)?;
+
+        Ok((keys, store_key))
+    }
+
+    /// Unlock the key file like `unlock_key_slot_of()` does, the keys that
+    /// protect the key file and the store key are returned.
+    pub(super) fn unlock_key_file(
+        path: &Path,
+        passphrase: &str,
+        config: &StoreConfig,
+    ) -> Result<(WrappingKey, KeyBuffer), OpenDirectoryError> {
+        let (keys, store_key, _) =
+            EncryptedMmapDirectory::unlock_key_slot_of(path, passphrase, config)?;
+
+        Ok((keys, store_key))
+    }
+
+    /// Read the key slots of the store in the given path, removed slots are
+    /// `None`.
+    fn read_key_slots(path: &Path, config: &StoreConfig) -> std::io::Result<Vec<Option<Vec<u8>>>> {
+        match std::fs::read(path.join(config.key_slots_file())) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Remove the key slots of the store in the given path, e.g. because the
+    /// keys they hold don't unlock the key file anymore.
+    pub(super) fn remove_key_slots(path: &Path, config: &StoreConfig) -> std::io::Result<()> {
+        match std::fs::remove_file(path.join(config.key_slots_file())) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn unlock_the_store_using_key_slots() {
+        let tmpdir = tempdir().unwrap();
+        let slots_path = tmpdir.path().join("seshat-index.slots");
+        let path = Path::new("file");
+
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        dir.atomic_write(path, b"content").unwrap();
+
+        // The passphrase of a slot can add slots as well.
+        assert_eq!(
+            dir.add_key_slot("wordpass", "password", PBKDF_COUNT)
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            dir.add_key_slot("password", "passphrase", PBKDF_COUNT)
+                .unwrap(),
+            2
+        );
+        assert!(dir
+            .add_key_slot("wrong", "passphrase", PBKDF_COUNT)
+            .is_err());
+        drop(dir);
+
+        for passphrase in ["wordpass", "password", "passphrase"].iter() {
+            let dir = EncryptedMmapDirectory::open(tmpdir.path(), passphrase)
+                .expect("Can't open the store using a key slot");
+            assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        }
+        assert!(EncryptedMmapDirectory::open(tmpdir.path(), "wrong").is_err());
+
+        // Corrupting a slot only locks out its own passphrase.
+        let mut slots: Vec<Option<Vec<u8>>> =
+            serde_json::from_slice(&std::fs::read(&slots_path).unwrap()).unwrap();
+        slots[0].as_mut().unwrap()[IV_SIZE] ^= 1;
+        std::fs::write(&slots_path, serde_json::to_vec(&slots).unwrap()).unwrap();
+
+        assert!(EncryptedMmapDirectory::open(tmpdir.path(), "password").is_err());
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "passphrase")
+            .expect("Can't open the store using an intact key slot");
+
+        let report = dir.verify_key_slots("passphrase").unwrap();
+        assert!(!report.is_intact());
+        assert_eq!(report.intact, vec![2]);
+        assert_eq!(report.corrupt, vec![1]);
+
+        // Rotating the store key keeps the slots.
+        dir.rotate_store_key().unwrap();
+        assert_eq!(dir.verify_key_slots("wordpass").unwrap().intact, vec![2]);
+
+        // The index of a removed slot gets reused, the others stay as they are.
+        dir.remove_key_slot("passphrase", 1).unwrap();
+        assert!(dir.remove_key_slot("passphrase", 1).is_err());
+        assert!(dir.remove_key_slot("passphrase", 0).is_err());
+        assert_eq!(
+            dir.add_key_slot("wordpass", "password", PBKDF_COUNT)
+                .unwrap(),
+            1
+        );
+
+        let report = dir.verify_key_slots("password").unwrap();
+        assert!(report.is_intact());
+        assert_eq!(report.intact, vec![1, 2]);
+        drop(dir);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "password")
+            .expect("Can't open the store using a key slot after the rotation");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        drop(dir);
+
+        // Only the passphrase of the key file can be changed, the slots hold the
+        // keys of the old key file and are removed.
+        assert!(EncryptedMmapDirectory::change_passphrase(
+            tmpdir.path(),
+            "password",
+            "new passphrase",
+            PBKDF_COUNT
+        )
+        .is_err());
+        EncryptedMmapDirectory::change_passphrase(
+            tmpdir.path(),
+            "wordpass",
+            "new passphrase",
+            PBKDF_COUNT,
+        )
+        .unwrap();
+        assert!(!slots_path.exists());
+        assert!(EncryptedMmapDirectory::open(tmpdir.path(), "passphrase").is_err());
+        EncryptedMmapDirectory::open(tmpdir.path(), "new passphrase").unwrap();
+    }
+
+    #[test]
+    fn find_the_key_slot_of_a_passphrase() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+
+        assert_eq!(
+            dir.add_key_slot("wordpass", "password", PBKDF_COUNT)
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            dir.add_key_slot("wordpass", "passphrase", PBKDF_COUNT)
+                .unwrap(),
+            2
+        );
+
+        // The writer doesn't need to close the store.
+        let which_slot = |passphrase| EncryptedMmapDirectory::which_slot(tmpdir.path(), passphrase);
+        assert_eq!(which_slot("wordpass").unwrap(), Some(0));
+        assert_eq!(which_slot("password").unwrap(), Some(1));
+        assert_eq!(which_slot("passphrase").unwrap(), Some(2));
+        assert_eq!(which_slot("wrong").unwrap(), None);
+
+        dir.remove_key_slot("wordpass", 1).unwrap();
+        assert_eq!(which_slot("password").unwrap(), None);
+        assert_eq!(which_slot("passphrase").unwrap(), Some(2));
+        assert!(EncryptedMmapDirectory::passphrase_unlocks(tmpdir.path(), "passphrase").unwrap());
+        drop(dir);
+
+        std::fs::write(tmpdir.path().join(KEYFILE), b"").unwrap();
+        assert!(which_slot("wordpass").is_err());
+    }
+
+    #[test]
+    fn check_passphrase_without_opening() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+
+        // The writer doesn't need to close the store.
+        assert!(EncryptedMmapDirectory::passphrase_unlocks(tmpdir.path(), "wordpass").unwrap());
+        assert!(!EncryptedMmapDirectory::passphrase_unlocks(tmpdir.path(), "password").unwrap());
+        drop(dir);
+
+        EncryptedMmapDirectory::change_passphrase(
+            tmpdir.path(),
+            "wordpass",
+            "password",
+            PBKDF_COUNT,
+        )
+        .unwrap();
+        assert!(!EncryptedMmapDirectory::passphrase_unlocks(tmpdir.path(), "wordpass").unwrap());
+        assert!(EncryptedMmapDirectory::passphrase_unlocks(tmpdir.path(), "password").unwrap());
+
+        std::fs::write(tmpdir.path().join(KEYFILE), b"").unwrap();
+        assert!(EncryptedMmapDirectory::passphrase_unlocks(tmpdir.path(), "password").is_err());
+    }
+}
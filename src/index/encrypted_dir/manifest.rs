@@ -0,0 +1,212 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The sealed manifest that lists the files of a store.
+
+use super::*;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+/// The list of files of a store and the SHA-256 hashes of their encrypted
+/// content.
+struct Manifest {
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// The differences between the sealed manifest of a store and the files that
+/// the store currently contains.
+pub struct ManifestReport {
+    /// Files that aren't part of the manifest.
+    pub added: Vec<PathBuf>,
+    /// Files of the manifest that don't exist anymore.
+    pub removed: Vec<PathBuf>,
+    /// Files whose content changed since the manifest was sealed.
+    pub modified: Vec<PathBuf>,
+}
+
+impl ManifestReport {
+    /// Does the store still match the manifest.
+    pub fn is_intact(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+impl EncryptedMmapDirectory {
+    /// Write down a manifest of the files the store currently contains.
+    ///
+    /// The manifest contains the names of all the files and a hash of their
+    /// content, it's encrypted and authenticated using keys that are derived
+    /// from the passphrase. This makes it possible to detect files that were
+    /// added, removed or modified behind our back, see `verify_manifest()`.
+    ///
+    /// The manifest is a snapshot, every commit, store key rotation or
+    /// passphrase change makes it outdated, it needs to be sealed again
+    /// afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    #[allow(dead_code)]
+    pub fn seal_manifest(&self, passphrase: &str) -> std::io::Result<()> {
+        let provider = self.manifest_provider(passphrase)?;
+        let manifest = self.create_manifest()?;
+        let manifest = serde_json::to_vec(&manifest)?;
+
+        let sealed = StoreState::seal_file(&*provider, &manifest, WriteMode::Atomic)?;
+
+        self.config
+            .write_atomically(&self.path.join(self.config.manifest_file()), &sealed)
+    }
+
+    /// Compare the files the store contains with the sealed manifest.
+    ///
+    /// Returns an error if no manifest was sealed or if the manifest fails to
+    /// authenticate, otherwise a report of the files that were added,
+    /// removed, or modified since the manifest was sealed.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    #[allow(dead_code)]
+    pub fn verify_manifest(&self, passphrase: &str) -> std::io::Result<ManifestReport> {
+        let provider = self.manifest_provider(passphrase)?;
+
+        let sealed = std::fs::read(self.path.join(self.config.manifest_file()))?;
+        let manifest = StoreState::open(&*provider, &sealed, Some(WriteMode::Atomic))?;
+        let sealed: Manifest = serde_json::from_slice(&manifest)?;
+
+        let current = self.create_manifest()?;
+        let mut report = ManifestReport::default();
+
+        for (file, hash) in &current.files {
+            match sealed.files.get(file) {
+                Some(h) if h == hash => (),
+                Some(_) => report.modified.push(PathBuf::from(file)),
+                None => report.added.push(PathBuf::from(file)),
+            }
+        }
+
+        for file in sealed.files.keys() {
+            if !current.files.contains_key(file) {
+                report.removed.push(PathBuf::from(file));
+            }
+        }
+
+        if !report.is_intact() {
+            warn!(
+                "The store in {} doesn't match its manifest",
+                self.path.display()
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Create the provider that encrypts the manifest, the keys are derived
+    /// from the passphrase by re-deriving the keys that protect the store
+    /// key.
+    fn manifest_provider(&self, passphrase: &str) -> std::io::Result<Arc<dyn AeadProvider>> {
+        let (wrapping_key, _) = self.unlock_own_key_file(passphrase)?;
+
+        EncryptedMmapDirectory::wrapping_provider(&wrapping_key, b"manifest")
+    }
+
+    /// Hash all the files of the store.
+    fn create_manifest(&self) -> std::io::Result<Manifest> {
+        let mut manifest = Manifest::default();
+
+        for file in self.disk_files()? {
+            let data = std::fs::read(self.path.join(&file))?;
+            manifest.files.insert(
+                file.to_string_lossy().into_owned(),
+                Sha256::digest(&data).to_vec(),
+            );
+        }
+
+        Ok(manifest)
+    }
+
+    /// Update the hashes of the given files in the sealed manifest, if the
+    /// store has one.
+    ///
+    /// Every file comes with its hash before and after it was replaced. Only
+    /// entries that still match the previous content of a file are updated,
+    /// a file that was modified behind our back keeps being reported by
+    /// `verify_manifest()`.
+    pub(super) fn update_manifest(
+        &self,
+        passphrase: &str,
+        hashes: &[(String, Vec<u8>, Vec<u8>)],
+    ) -> std::io::Result<()> {
+        let manifest_path = self.path.join(self.config.manifest_file());
+
+        if self.custom_directory || !manifest_path.exists() {
+            return Ok(());
+        }
+
+        let provider = self.manifest_provider(passphrase)?;
+        let sealed = std::fs::read(&manifest_path)?;
+        let manifest = StoreState::open(&*provider, &sealed, Some(WriteMode::Atomic))?;
+        let mut manifest: Manifest = serde_json::from_slice(&manifest)?;
+
+        for (file, previous, hash) in hashes {
+            if let Some(entry) = manifest.files.get_mut(file) {
+                if entry == previous {
+                    *entry = hash.clone();
+                }
+            }
+        }
+
+        let manifest = serde_json::to_vec(&manifest)?;
+        let sealed = StoreState::seal_file(&*provider, &manifest, WriteMode::Atomic)?;
+
+        self.config.write_atomically(&manifest_path, &sealed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn seal_and_verify_manifest() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        for file in ["first", "second", "third"].iter() {
+            dir.atomic_write(Path::new(file), file.as_bytes()).unwrap();
+        }
+
+        dir.seal_manifest("wordpass")
+            .expect("Can't seal the manifest");
+        assert!(dir.verify_manifest("wordpass").unwrap().is_intact());
+        assert!(
+            dir.verify_manifest("password").is_err(),
+            "Verified the manifest with the wrong passphrase"
+        );
+
+        std::fs::remove_file(tmpdir.path().join("second")).unwrap();
+        dir.atomic_write(Path::new("third"), b"modified").unwrap();
+        dir.atomic_write(Path::new("fourth"), b"fourth").unwrap();
+
+        let report = dir.verify_manifest("wordpass").unwrap();
+        assert!(!report.is_intact());
+        assert_eq!(report.removed, vec![PathBuf::from("second")]);
+        assert_eq!(report.modified, vec![PathBuf::from("third")]);
+        assert_eq!(report.added, vec![PathBuf::from("fourth")]);
+    }
+}
@@ -0,0 +1,7091 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod file_names;
+mod key_file;
+mod key_slots;
+mod manifest;
+mod provider;
+mod rotation;
+mod state;
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::Error as IoError;
+use std::io::{BufWriter, Cursor, ErrorKind, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher};
+use aes_ctr::Aes256Ctr;
+use crypto_mac::Mac;
+use hmac::Hmac;
+use sha2::Digest;
+use sha2::Sha256;
+
+use tantivy::directory::error::IOError as TvIoError;
+use tantivy::directory::error::{
+    DeleteError, LockError, OpenDirectoryError, OpenReadError, OpenWriteError,
+};
+use tantivy::directory::Directory;
+use tantivy::directory::WatchHandle;
+use tantivy::directory::{
+    AntiCallToken, DirectoryLock, Lock, ReadOnlySource, TerminatingWrite, WatchCallback, WritePtr,
+    INDEX_WRITER_LOCK, META_LOCK,
+};
+
+use log::{debug, info, trace, warn};
+use uuid::Uuid;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::index::crypto::{
+    self, expand_key, Cipher, CryptoError, KeyBuffer, KeyFile, KeyFileHeader, KeyProtection,
+    KeySlot, WrappingKey, CANARY_VERSION, HEADER_LENGTH_VERSION, IV_SIZE, KEY_FILE_MAX_SIZE,
+    KEY_SIZE, MAC_LENGTH, SALT_SIZE, WRAPPED_VERSION,
+};
+use crate::index::encrypted_stream::{authentication_error, fill_random, AesReader, AesWriter};
+
+use self::file_names::FileNames;
+use self::key_file::{KeyBytesWrapper, KeyWrapper};
+use self::provider::{AeadProvider, LockedKeys, SealStream, StartedStream};
+use self::state::{DecryptedData, Padding, SealingWriter, StoreState, WriteMode};
+
+// The default basename of the files we store next to the Tantivy files:
+//
+// - `seshat-index.key`, the key file.
+// - `seshat-index.keys`, additional wrappings of the store key.
+// - `seshat-index.slots`, the key slots, additional passphrases that unlock
+//   the key file.
+// - `seshat-index.counter`, the number of bytes that were encrypted under the
+//   current store key.
+// - `seshat-index.created`, the creation time of the current store key.
+// - `seshat-index.lock`, makes sure that only a single writer opens the store.
+// - `seshat-index.keylock`, serializes replacing the key file with store key
+//   rotations.
+// - `seshat-index.manifest`, the sealed manifest of the store, if one is
+//   sealed.
+// - `seshat-index.journal`, the journal of an ongoing store key rotation.
+// - `seshat-index.names`, the key that hides the file names of the store, if
+//   they're hidden.
+// - `seshat-index.schema`, the fingerprint of the schema of the index, if one
+//   was recorded.
+// - `seshat-index.metadata`, the metadata of the store, or
+//   `seshat-index.<id>.metadata` for every index that shares the store key.
+//
+// Tantivy names its files using UUIDs, Tantivy will never produce a file named
+// like this.
+const DEFAULT_BASENAME: &str = "seshat-index";
+// The name of our default, passphrase based, key wrapper.
+const PASSPHRASE_WRAPPER: &str = "passphrase";
+// The name of the key wrapper that wraps the store key using raw key bytes.
+const KEY_BYTES_WRAPPER: &str = "key-bytes";
+// The extension of the temporary files that files are written to before
+// they're moved into place.
+const TEMP_EXTENSION: &str = "tmp";
+// The extension of the files that large decrypted files are spilled into,
+// see `EncryptedMmapDirectory::with_spill_threshold()`.
+const SPILL_EXTENSION: &str = "spill";
+// The file Tantivy stores its metadata in, watches are triggered when it
+// changes.
+const META_FILE: &str = "meta.json";
+// The second copy of the metadata file, see
+// `EncryptedMmapDirectory::with_meta_backup()`.
+const META_BACKUP_FILE: &str = "meta.json.bak";
+// The file Tantivy keeps track of the files it manages in, like the metadata
+// file it's written atomically.
+const MANAGED_FILE: &str = ".managed.json";
+// The size of the tag in front of every encrypted file that records how the
+// file was written.
+const WRITE_MODE_SIZE: usize = 1;
+// The bit of the write mode tag that marks a file as padded.
+const PADDED_FLAG: u8 = 0x80;
+// The bits of the write mode tag that record the size of a truncated
+// authentication tag, unset if the tag wasn't truncated.
+const TAG_SIZE_BITS: u8 = 0x60;
+// The authentication tag sizes files can be truncated to and the bits that
+// record them in the write mode tag.
+const TRUNCATED_TAG_SIZES: [(u8, usize); 2] = [(0x20, 24), (0x40, 16)];
+// The size of the chunks files with truncated tags are decrypted in.
+const TRUNCATED_TAG_BUFFER_SIZE: usize = 8 * 1024;
+// The tag in front of files whose write mode tag is hidden, it can't be
+// mistaken for a write mode tag.
+const HIDDEN_HEADER_TAG: u8 = 0x48;
+// The size of the length of the original data that prefixes padded data.
+const PADDING_LENGTH_SIZE: usize = 8;
+// How many times a replica re-reads a file that failed to decrypt because it
+// might have been rewritten while we were reading it.
+const SNAPSHOT_READ_RETRIES: usize = 3;
+// The size of the chunks a pipelined reader decrypts ahead of its consumer.
+const PIPELINE_CHUNK_SIZE: usize = 64 * 1024;
+// How many decrypted chunks a pipelined reader keeps ready.
+const PIPELINE_DEPTH: usize = 4;
+// How many background threads all the pipelined readers of the process may
+// use at once, further readers decrypt their file up front.
+const PIPELINE_THREADS: usize = 4;
+// The key derivation count that is timed to estimate the unlock cost of other
+// counts.
+const KDF_CALIBRATION_COUNT: u32 = 1000;
+
+/// A conservative number of bytes after which the store key should be rotated.
+///
+/// Every file gets a random 128 bit IV and AES-CTR increments it for every
+/// block, after 2^36 blocks the chance that two files share a part of the
+/// keystream is still below 2^-56.
+pub const DEFAULT_REKEY_THRESHOLD: u64 = 1 << 40;
+
+/// The lowest key derivation count that isn't reported as weak, see
+/// `EncryptedMmapDirectory::security_advisory()`.
+pub const RECOMMENDED_PBKDF_COUNT: u32 = 10_000;
+
+#[cfg(test)]
+// Tests don't need to protect against brute force attacks.
+pub(crate) const PBKDF_COUNT: u32 = 10;
+
+#[cfg(not(test))]
+// This is quite a bit lower than the spec since the encrypted key and index
+// will not be public. An attacker would need to get hold of the encrypted index,
+// its key and only then move on to bruteforcing the key. Even if the attacker
+// decrypts the index he wouldn't get to the events itself, only to the index
+// of them.
+pub(crate) const PBKDF_COUNT: u32 = 10_000;
+
+#[cfg(test)]
+const KEYFILE: &str = "seshat-index.key";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Configuration for the files an `EncryptedMmapDirectory` stores next to the
+/// Tantivy files.
+pub struct StoreConfig {
+    basename: String,
+    allow_whitespace_passphrase: bool,
+    cache_decrypted_files: bool,
+    temp_dir: Option<PathBuf>,
+    refuse_orphan_key_file: bool,
+    // The id of the index if the store key is shared by several indexes,
+    // see `EncryptedMmapDirectory::open_or_create_index()`.
+    index_id: Option<String>,
+    max_key_derivation_count: Option<u32>,
+    remove_interrupted_writes: bool,
+    rotate_automatically: bool,
+    rekey_threshold: u64,
+}
+
+impl StoreConfig {
+    /// Create a new default store configuration.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the basename of the key file.
+    ///
+    /// The key file will be called `<basename>.key`, the other files we store
+    /// are named using the same basename. The default is `seshat-index`.
+    ///
+    /// # Arguments
+    ///
+    /// * `basename` - The basename, pick one that Tantivy won't produce.
+    #[allow(dead_code)]
+    pub fn set_key_file_basename<B: Into<String>>(mut self, basename: B) -> Self {
+        self.basename = basename.into();
+        self
+    }
+
+    /// Allow passphrases that consist only of whitespace.
+    ///
+    /// Such passphrases are rejected by default since they usually are the
+    /// result of an input bug. Empty passphrases are always rejected.
+    ///
+    /// # Arguments
+    ///
+    /// * `allow` - Should whitespace only passphrases be accepted.
+    #[allow(dead_code)]
+    pub fn allow_whitespace_passphrase(mut self, allow: bool) -> Self {
+        self.allow_whitespace_passphrase = allow;
+        self
+    }
+
+    /// Should readers of the same file share its decrypted content.
+    ///
+    /// A file that is still held by a reader isn't decrypted again when it's
+    /// opened, the cache doesn't keep the plaintext alive once the last
+    /// reader drops it. Turning the cache off gives every read its own copy
+    /// of the plaintext, the copy is dropped as soon as that read is done
+    /// with it. Single reads can bypass the cache using
+    /// `EncryptedMmapDirectory::open_read_uncached()`. Read replicas never
+    /// share decrypted files. The default is to cache decrypted files.
+    ///
+    /// # Arguments
+    ///
+    /// * `cache` - Should decrypted files be shared between readers.
+    #[allow(dead_code)]
+    pub fn cache_decrypted_files(mut self, cache: bool) -> Self {
+        self.cache_decrypted_files = cache;
+        self
+    }
+
+    /// Set the directory that files are written to before they're moved
+    /// into place.
+    ///
+    /// By default the temporary files are written next to the file they
+    /// replace, e.g. the key file or files that are re-encrypted by a store
+    /// key rotation. The directory needs to be on the same file system as
+    /// the store, otherwise files can't be moved into place atomically and
+    /// the store refuses to open. Temporary files that a crash leaves behind
+    /// in the directory aren't removed, the directory might be shared.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the directory for temporary files.
+    #[allow(dead_code)]
+    pub fn set_temp_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.temp_dir = Some(path.into());
+        self
+    }
+
+    /// Refuse to open an existing store that has a key file but no index.
+    ///
+    /// By default such a store opens as an empty index under the existing
+    /// store key. A key file without an index usually means that the store
+    /// was only partially deleted, e.g. a wipe that didn't remove the key
+    /// file, with this set opening it returns an error instead. A store that
+    /// was created but never committed to by Tantivy looks the same and is
+    /// refused as well. Creating a new store is not affected.
+    ///
+    /// # Arguments
+    ///
+    /// * `refuse` - Should a key file without an index be refused.
+    #[allow(dead_code)]
+    pub fn refuse_orphan_key_file(mut self, refuse: bool) -> Self {
+        self.refuse_orphan_key_file = refuse;
+        self
+    }
+
+    /// Remove the files that writers left behind unfinished when a writer
+    /// opens the store.
+    ///
+    /// By default such files are only reported, see
+    /// `EncryptedMmapDirectory::recovery()`. They can't be read, Tantivy
+    /// only needs them if the segment they belong to was committed, which
+    /// can't happen before all of its files are written. With this set they
+    /// are removed instead, so they don't break e.g. a store key rotation.
+    ///
+    /// # Arguments
+    ///
+    /// * `remove` - Should unfinished files be removed.
+    #[allow(dead_code)]
+    pub fn remove_interrupted_writes(mut self, remove: bool) -> Self {
+        self.remove_interrupted_writes = remove;
+        self
+    }
+
+    /// Refuse to derive keys using more than the given number of iterations.
+    ///
+    /// The key derivation count is stored in the key file, unlocking a store
+    /// whose count exceeds what a constrained device can afford would stall
+    /// the device for a long time. With a budget set, such a store isn't
+    /// unlocked, an error that can be checked using
+    /// `KdfBudgetExceeded::matches()` is returned before any key is derived.
+    /// New stores can't be created with a count that exceeds the budget
+    /// either. PBKDF2 only costs time, it doesn't need a notable amount of
+    /// memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The highest key derivation count that may be used.
+    #[allow(dead_code)]
+    pub fn set_max_key_derivation_count(mut self, count: u32) -> Self {
+        self.max_key_derivation_count = Some(count);
+        self
+    }
+
+    /// Rotate the store key automatically once the rekey threshold is
+    /// reached, see `EncryptedMmapDirectory::with_rekey_threshold()`.
+    ///
+    /// Only writers rotate the store key, read replicas ignore this.
+    ///
+    /// # Arguments
+    ///
+    /// * `rotate` - Should the store key be rotated automatically.
+    #[allow(dead_code)]
+    pub fn rotate_store_key_automatically(mut self, rotate: bool) -> Self {
+        self.rotate_automatically = rotate;
+        self
+    }
+
+    /// Set the number of bytes that may be encrypted using a single store key
+    /// if the store key is rotated automatically.
+    ///
+    /// The default is `DEFAULT_REKEY_THRESHOLD`.
+    ///
+    /// # Arguments
+    ///
+    /// * `threshold` - The number of bytes that may be encrypted using a
+    /// single store key.
+    #[allow(dead_code)]
+    pub fn set_rekey_threshold(mut self, threshold: u64) -> Self {
+        self.rekey_threshold = threshold;
+        self
+    }
+
+    /// Check that the given key derivation count is within the budget.
+    fn check_key_derivation_count(&self, count: u32) -> std::io::Result<()> {
+        match self.max_key_derivation_count {
+            Some(budget) if count > budget => Err(KdfBudgetExceeded::error(count, budget)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the given passphrase is acceptable.
+    fn check_passphrase(&self, passphrase: &str) -> std::io::Result<()> {
+        if passphrase.is_empty() {
+            Err(IoError::new(ErrorKind::Other, "empty passphrase"))
+        } else if !self.allow_whitespace_passphrase && passphrase.trim().is_empty() {
+            Err(IoError::new(
+                ErrorKind::Other,
+                "passphrase consists only of whitespace",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The info string the keys of the Tantivy files are expanded from the
+    /// store key with, every index that shares the store key gets its own.
+    fn data_key_info(&self) -> Vec<u8> {
+        match &self.index_id {
+            Some(id) => [&b"index\0"[..], id.as_bytes()].concat(),
+            None => Vec::new(),
+        }
+    }
+
+    fn key_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.key", self.basename))
+    }
+
+    fn counter_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.counter", self.basename))
+    }
+
+    fn lock_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.lock", self.basename))
+    }
+
+    fn key_lock_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.keylock", self.basename))
+    }
+
+    fn manifest_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.manifest", self.basename))
+    }
+
+    fn journal_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.journal", self.basename))
+    }
+
+    fn names_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.names", self.basename))
+    }
+
+    fn schema_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.schema", self.basename))
+    }
+
+    fn key_created_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.created", self.basename))
+    }
+
+    fn wrapped_keys_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.keys", self.basename))
+    }
+
+    fn key_slots_file(&self) -> PathBuf {
+        PathBuf::from(format!("{}.slots", self.basename))
+    }
+
+    /// The metadata is sealed using the keys of the Tantivy files, every
+    /// index that shares the store key gets its own.
+    fn metadata_file(&self) -> PathBuf {
+        match &self.index_id {
+            Some(id) => PathBuf::from(format!("{}.{}.metadata", self.basename, id)),
+            None => PathBuf::from(format!("{}.metadata", self.basename)),
+        }
+    }
+
+    /// Is the file one of ours, as opposed to a Tantivy file.
+    ///
+    /// This covers the temporary files that files are written to before
+    /// they're moved into place as well, a crash might leave them behind.
+    fn is_seshat_metadata(&self, path: &Path) -> bool {
+        let files = [
+            self.key_file(),
+            self.counter_file(),
+            self.lock_file(),
+            self.key_lock_file(),
+            self.manifest_file(),
+            self.journal_file(),
+            self.names_file(),
+            self.schema_file(),
+            self.key_created_file(),
+            self.metadata_file(),
+            self.wrapped_keys_file(),
+            self.key_slots_file(),
+        ];
+
+        // The metadata of the other indexes that share the store key.
+        let index_metadata = path.to_str().map_or(false, |p| {
+            p.starts_with(&format!("{}.", self.basename)) && p.ends_with(".metadata")
+        });
+
+        files.iter().any(|file| path == file) || index_metadata || StoreConfig::is_temporary(path)
+    }
+
+    /// Is the file a temporary file, see `write_atomically()`.
+    fn is_temporary(path: &Path) -> bool {
+        path.extension().map_or(false, |e| e == TEMP_EXTENSION)
+    }
+
+    /// Write the given data to a temporary file and move it into place.
+    ///
+    /// Every write uses a temporary file with a unique name, which is
+    /// removed if the write fails, so a retry starts from scratch. The data
+    /// hits the disk before the file is moved into place, readers either see
+    /// the old or the new file.
+    fn write_atomically(&self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+        temp_name.push(format!(
+            ".{}.{}",
+            Uuid::new_v4().to_simple(),
+            TEMP_EXTENSION
+        ));
+
+        let temp_path = match &self.temp_dir {
+            Some(temp_dir) => {
+                StoreConfig::check_same_file_system(temp_dir, path.parent())?;
+                temp_dir.join(temp_name)
+            }
+            None => path.with_file_name(temp_name),
+        };
+
+        let result = File::create(&temp_path)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&temp_path, path));
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+
+        result
+    }
+
+    /// Return an error if the temporary directory isn't on the same file
+    /// system as the given directory, files can't be renamed atomically
+    /// across file systems.
+    #[cfg(unix)]
+    fn check_same_file_system(temp_dir: &Path, dir: Option<&Path>) -> std::io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = match dir {
+            Some(d) if d != Path::new("") => d,
+            _ => Path::new("."),
+        };
+
+        if std::fs::metadata(temp_dir)?.dev() == std::fs::metadata(dir)?.dev() {
+            Ok(())
+        } else {
+            Err(IoError::new(
+                ErrorKind::Other,
+                "the temporary directory isn't on the same file system as the store",
+            ))
+        }
+    }
+
+    /// Other platforms have no cheap way to tell, renaming the file fails if
+    /// it crosses file systems.
+    #[cfg(not(unix))]
+    fn check_same_file_system(_temp_dir: &Path, _dir: Option<&Path>) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            basename: DEFAULT_BASENAME.to_owned(),
+            allow_whitespace_passphrase: false,
+            cache_decrypted_files: true,
+            temp_dir: None,
+            refuse_orphan_key_file: false,
+            index_id: None,
+            max_key_derivation_count: None,
+            remove_interrupted_writes: false,
+            rotate_automatically: false,
+            rekey_threshold: DEFAULT_REKEY_THRESHOLD,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+/// The maintenance operations that `EncryptedMmapDirectory::maintain()`
+/// should perform on a store.
+#[allow(dead_code)]
+pub struct MaintenancePlan {
+    new_passphrase: Option<Zeroizing<String>>,
+    key_derivation_count: Option<u32>,
+    rotate_store_key: bool,
+    migrate_cipher: bool,
+}
+
+impl MaintenancePlan {
+    /// Create a new plan that doesn't perform any operation.
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Change the passphrase of the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that should be used from now on.
+    #[allow(dead_code)]
+    pub fn change_passphrase(mut self, passphrase: &str) -> Self {
+        self.new_passphrase = Some(Zeroizing::new(passphrase.to_owned()));
+        self
+    }
+
+    /// Change the key derivation count of the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_derivation_count` - The key derivation count that should be
+    /// used from now on.
+    #[allow(dead_code)]
+    pub fn upgrade_kdf(mut self, key_derivation_count: u32) -> Self {
+        self.key_derivation_count = Some(key_derivation_count);
+        self
+    }
+
+    /// Replace the store key with a newly generated one.
+    #[allow(dead_code)]
+    pub fn rotate_store_key(mut self) -> Self {
+        self.rotate_store_key = true;
+        self
+    }
+
+    /// Rewrite the key file using the newest supported cipher.
+    #[allow(dead_code)]
+    pub fn migrate_cipher(mut self) -> Self {
+        self.migrate_cipher = true;
+        self
+    }
+}
+
+// The new passphrase is left out, plans might end up in logs.
+impl std::fmt::Debug for MaintenancePlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MaintenancePlan")
+            .field("change_passphrase", &self.new_passphrase.is_some())
+            .field("key_derivation_count", &self.key_derivation_count)
+            .field("rotate_store_key", &self.rotate_store_key)
+            .field("migrate_cipher", &self.migrate_cipher)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+/// A handle to cancel long running maintenance operations, e.g. when the
+/// application shuts down.
+///
+/// Clones of a token share its state, the operation is handed one clone and
+/// another one is kept to cancel it, possibly from a different thread. The
+/// operations check the token between files, see
+/// `EncryptedMmapDirectory::rotate_store_key_cancellable()`.
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Create a new token that isn't cancelled.
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Ask the operations that were handed the token to stop.
+    #[allow(dead_code)]
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Was the token cancelled.
+    #[allow(dead_code)]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Return an error of the `Interrupted` kind if the token was
+    /// cancelled.
+    fn check(token: Option<&CancellationToken>) -> std::io::Result<()> {
+        if token.map_or(false, |t| t.is_cancelled()) {
+            Err(IoError::new(
+                ErrorKind::Interrupted,
+                "the operation was cancelled",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A source of passphrases for applications that manage their secrets in a
+/// central place.
+///
+/// Once installed using `EncryptedMmapDirectory::set_passphrase_provider()`
+/// a store can be opened by its path alone, see the `TryFrom<&Path>`
+/// implementation of `EncryptedMmapDirectory`.
+pub trait PassphraseProvider: Send + Sync {
+    /// Get the passphrase of the store that resides in the given path.
+    fn passphrase(&self, path: &Path) -> std::io::Result<String>;
+}
+
+lazy_static! {
+    /// The globally installed passphrase provider, there is none by default.
+    static ref PASSPHRASE_PROVIDER: RwLock<Option<Arc<dyn PassphraseProvider>>> = RwLock::new(None);
+}
+
+impl From<CryptoError> for IoError {
+    fn from(e: CryptoError) -> Self {
+        match e {
+            CryptoError::Truncated => IoError::new(ErrorKind::UnexpectedEof, e),
+            _ => IoError::new(ErrorKind::Other, e),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Describes what happened to the store when a directory was opened.
+pub enum Opened {
+    /// No key file was found, a new store key was generated and written down.
+    Created,
+    /// An existing key file was found and the store key was loaded from it.
+    Existing,
+}
+
+/// A policy that decides which files may be written to a store, e.g. to
+/// make sure that a compromised process can't smuggle arbitrary data into
+/// the store, see `EncryptedMmapDirectory::with_write_policy()`.
+pub trait WritePolicy: std::fmt::Debug + Send + Sync {
+    /// Is Tantivy allowed to write the file at the given path, relative to
+    /// the directory.
+    fn allows_write(&self, path: &Path) -> bool;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The error that reads and writes return while the store is locked, see
+/// `EncryptedMmapDirectory::with_auto_lock()`.
+///
+/// It's wrapped in an IO error of the `PermissionDenied` kind.
+pub struct Locked;
+
+impl Locked {
+    /// Does the given IO error report that the store is locked.
+    #[allow(dead_code)]
+    pub fn matches(error: &IoError) -> bool {
+        error.get_ref().map_or(false, |e| e.is::<Locked>())
+    }
+
+    fn error() -> IoError {
+        IoError::new(ErrorKind::PermissionDenied, Locked)
+    }
+}
+
+impl std::fmt::Display for Locked {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("the store is locked")
+    }
+}
+
+impl std::error::Error for Locked {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The error that is returned if a store is opened using a different schema
+/// than the one it was created with, see
+/// `EncryptedMmapDirectory::with_schema()`.
+///
+/// It's wrapped in an IO error of the `InvalidData` kind.
+pub struct SchemaMismatch;
+
+impl SchemaMismatch {
+    /// Does the given IO error report a schema mismatch.
+    #[allow(dead_code)]
+    pub fn matches(error: &IoError) -> bool {
+        error.get_ref().map_or(false, |e| e.is::<SchemaMismatch>())
+    }
+
+    fn error() -> IoError {
+        IoError::new(ErrorKind::InvalidData, SchemaMismatch)
+    }
+}
+
+impl std::fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("the schema doesn't match the schema of the store")
+    }
+}
+
+impl std::error::Error for SchemaMismatch {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The error that is returned if the key derivation count of a store exceeds
+/// the budget of the device, see `StoreConfig::set_max_key_derivation_count()`.
+///
+/// It's wrapped in an IO error of the `Other` kind.
+pub struct KdfBudgetExceeded {
+    /// The key derivation count of the store.
+    pub key_derivation_count: u32,
+    /// The highest key derivation count the device allows.
+    pub budget: u32,
+}
+
+impl KdfBudgetExceeded {
+    /// Does the given IO error report a key derivation count that exceeds
+    /// the budget.
+    #[allow(dead_code)]
+    pub fn matches(error: &IoError) -> bool {
+        error
+            .get_ref()
+            .map_or(false, |e| e.is::<KdfBudgetExceeded>())
+    }
+
+    fn error(key_derivation_count: u32, budget: u32) -> IoError {
+        IoError::new(
+            ErrorKind::Other,
+            KdfBudgetExceeded {
+                key_derivation_count,
+                budget,
+            },
+        )
+    }
+}
+
+impl std::fmt::Display for KdfBudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "the key derivation count {} exceeds the budget of {}",
+            self.key_derivation_count, self.budget
+        )
+    }
+}
+
+impl std::error::Error for KdfBudgetExceeded {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The error that is returned if the write policy of the store refuses a
+/// write, see `EncryptedMmapDirectory::with_write_policy()`.
+///
+/// It's wrapped in an IO error of the `PermissionDenied` kind.
+pub struct WriteRefused {
+    /// The path of the file, relative to the directory.
+    pub path: PathBuf,
+}
+
+impl WriteRefused {
+    /// Does the given IO error report a write that was refused.
+    #[allow(dead_code)]
+    pub fn matches(error: &IoError) -> bool {
+        error.get_ref().map_or(false, |e| e.is::<WriteRefused>())
+    }
+
+    fn error(path: &Path) -> IoError {
+        IoError::new(
+            ErrorKind::PermissionDenied,
+            WriteRefused {
+                path: path.to_owned(),
+            },
+        )
+    }
+}
+
+impl std::fmt::Display for WriteRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "the write policy refused to write the file {}",
+            self.path.display()
+        )
+    }
+}
+
+impl std::error::Error for WriteRefused {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The error that is returned if a file fails to authenticate when it's
+/// read, e.g. because it was corrupted or tampered with.
+///
+/// It's wrapped in an IO error of the `InvalidData` kind, the caller can
+/// quarantine the file and fetch it again instead of giving up on the whole
+/// store. Files are authenticated as a whole, there's no offset within the
+/// file that could be reported.
+pub struct IntegrityError {
+    /// The path of the file, relative to the directory.
+    pub path: PathBuf,
+}
+
+impl IntegrityError {
+    /// Get the integrity error behind the given IO error, if the IO error
+    /// reports a file that failed to authenticate.
+    #[allow(dead_code)]
+    pub fn from_io_error(error: &IoError) -> Option<&IntegrityError> {
+        error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<IntegrityError>())
+    }
+
+    fn error(path: &Path) -> IoError {
+        IoError::new(
+            ErrorKind::InvalidData,
+            IntegrityError {
+                path: path.to_owned(),
+            },
+        )
+    }
+}
+
+impl std::fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "the file {} failed to authenticate", self.path.display())
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// The lock that makes sure that only a single writer opens a store.
+///
+/// Tantivy's writer lock only covers the Tantivy files, the key file and the
+/// number of bytes that were encrypted using the store key need to be
+/// protected as well.
+struct WriterLock {
+    _lock: DirectoryLock,
+}
+
+impl std::fmt::Debug for WriterLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("WriterLock")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// The result of a health check of a store, see
+/// `EncryptedMmapDirectory::dry_run_open()`.
+pub struct HealthReport {
+    /// The number of encrypted files the store contains.
+    pub total: usize,
+    /// The number of files that authenticated.
+    pub readable: usize,
+    /// The number of files that failed to authenticate or couldn't be read.
+    pub corrupt: usize,
+}
+
+impl HealthReport {
+    /// Did every file of the store authenticate.
+    pub fn is_healthy(&self) -> bool {
+        self.corrupt == 0
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// What opening a store cleaned up after operations that were interrupted,
+/// see `EncryptedMmapDirectory::recovery()`.
+pub struct Recovery {
+    /// The temporary files of interrupted writes that were removed.
+    pub removed_temporary_files: Vec<PathBuf>,
+    /// Was an interrupted store key rotation finished.
+    pub resumed_rotation: bool,
+    /// Was the journal of a store key rotation that had already finished
+    /// removed.
+    pub removed_stale_journal: bool,
+    /// The files that writers left behind unfinished, they were removed if
+    /// the store configuration asks for it, see
+    /// `StoreConfig::remove_interrupted_writes()`.
+    pub interrupted_writes: Vec<PathBuf>,
+}
+
+impl Recovery {
+    /// Was there nothing to clean up.
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.removed_temporary_files.is_empty()
+            && !self.resumed_rotation
+            && !self.removed_stale_journal
+            && self.interrupted_writes.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// The number of bytes that were read and written through the `Directory`
+/// trait, see `EncryptedMmapDirectory::io_stats()`.
+///
+/// The difference between the ciphertext and the plaintext sizes is the
+/// overhead of the encryption, the write mode tag, the nonce, the
+/// authentication tag, and the padding, if enabled.
+pub struct IoStats {
+    /// The number of decrypted bytes that were returned by reads.
+    pub plaintext_read: u64,
+    /// The number of encrypted bytes that were read.
+    pub ciphertext_read: u64,
+    /// The number of bytes that were passed to writes.
+    pub plaintext_written: u64,
+    /// The number of encrypted bytes that were written.
+    pub ciphertext_written: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// The weak parameters a store uses, see
+/// `EncryptedMmapDirectory::security_advisory()`.
+///
+/// None of them make the store unusable, they're reported so users can be
+/// asked to upgrade the store.
+pub struct SecurityAdvisory {
+    /// The key derivation count of the key file, if it's lower than
+    /// `RECOMMENDED_PBKDF_COUNT`, see `upgrade_kdf()`.
+    pub low_key_derivation_count: Option<u32>,
+    /// The key file lacks the canary that confirms the unwrapped store key,
+    /// changing the passphrase rewrites it in the current version.
+    pub outdated_key_file: bool,
+    /// Files that aren't bound to the mode they were written in are
+    /// accepted, see `with_legacy_files()` and `migrate_legacy_files()`.
+    pub unbound_write_mode: bool,
+}
+
+impl SecurityAdvisory {
+    /// Does the store use any weak parameters.
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self == &SecurityAdvisory::default()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The encryption parameters of a single file of the store, they're read
+/// from the file header without decrypting the file.
+pub struct FileCryptoMeta {
+    /// The name of the AEAD provider the file is sealed with. Files don't
+    /// record the algorithm, this is the algorithm of the store.
+    pub cipher: String,
+    /// How the file was written, `atomic` or `streaming`.
+    pub write_mode: &'static str,
+    /// The nonce the file was sealed with.
+    pub nonce: Vec<u8>,
+    /// The length of the sealed plaintext. For padded files this includes
+    /// the length prefix and the padding.
+    pub plaintext_length: u64,
+    /// Is the file padded, see `with_padding()`.
+    pub padded: bool,
+    /// The size of the authentication tag of the file, smaller than the
+    /// tag size of the algorithm if it was truncated, see `with_tag_size()`.
+    pub tag_size: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The physical layout of a single Tantivy file of the store, see
+/// `EncryptedMmapDirectory::list_segment_files()`.
+pub struct SegmentFileInfo {
+    /// The name Tantivy knows the file by.
+    pub name: PathBuf,
+    /// The size of the encrypted file on disk.
+    pub encrypted_size: u64,
+    /// The size of the decrypted file, derived from the size of the
+    /// encrypted file. For padded files this includes the padding.
+    pub decrypted_size: u64,
+    /// The name of the AEAD provider the file is sealed with.
+    pub cipher: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A nonce that was used to seal more than one file.
+pub struct NonceReuse {
+    /// The reused nonce.
+    pub nonce: Vec<u8>,
+    /// The files that were sealed using the nonce.
+    pub files: Vec<PathBuf>,
+}
+
+// The number of background threads pipelined readers use right now, see
+// `PIPELINE_THREADS`.
+static PIPELINE_THREADS_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// A writer that hands the decrypted data over to a `PipelinedReader`.
+struct ChunkSender {
+    sender: SyncSender<std::io::Result<Vec<u8>>>,
+    chunk_size: usize,
+}
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Writes that are larger than the buffer bypass it, split them up so
+        // no chunk exceeds the configured read ahead.
+        let len = cmp::min(buf.len(), self.chunk_size);
+        self.sender
+            .send(Ok(buf[..len].to_vec()))
+            .map_err(|_| IoError::new(ErrorKind::BrokenPipe, "the pipelined reader was dropped"))?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A reader that decrypts a file on a background thread.
+///
+/// The background thread decrypts the next couple of chunks while the
+/// current one is being consumed, this overlaps the decryption with the work
+/// the consumer does. Like for every other read the whole file is
+/// authenticated before the first chunk is handed out.
+///
+/// The readers of a process share at most `PIPELINE_THREADS` background
+/// threads. A reader that is created while all of them are busy decrypts the
+/// whole file right away, on the thread that creates it, instead of waiting
+/// for a thread that might only finish once the reader is consumed.
+///
+/// A reader is created using `EncryptedMmapDirectory::pipelined_reader()`.
+pub struct PipelinedReader {
+    receiver: Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl PipelinedReader {
+    /// Run the given decryption on a background thread, the plaintext it
+    /// writes is handed out in chunks of the given size.
+    fn spawn<F>(chunk_size: usize, decrypt: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write) -> std::io::Result<u64> + Send + 'static,
+    {
+        if PIPELINE_THREADS_RUNNING.fetch_add(1, Ordering::SeqCst) >= PIPELINE_THREADS {
+            PIPELINE_THREADS_RUNNING.fetch_sub(1, Ordering::SeqCst);
+            return PipelinedReader::decrypt_at_once(decrypt);
+        }
+
+        let (sender, receiver) = sync_channel(PIPELINE_DEPTH);
+
+        std::thread::spawn(move || {
+            let _thread = PipelineThread;
+
+            let mut writer = BufWriter::with_capacity(
+                chunk_size,
+                ChunkSender {
+                    sender: sender.clone(),
+                    chunk_size,
+                },
+            );
+
+            let result = decrypt(&mut writer).and_then(|_| writer.flush());
+
+            if let Err(e) = result {
+                // The reader might be gone already, nobody to report to.
+                let _ = sender.send(Err(e));
+            }
+        });
+
+        PipelinedReader {
+            receiver,
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Run the given decryption on the current thread, the plaintext is
+    /// handed out as a single chunk.
+    fn decrypt_at_once<F>(decrypt: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write) -> std::io::Result<u64>,
+    {
+        let (sender, receiver) = sync_channel(1);
+        let mut plaintext = Vec::new();
+
+        let chunk = decrypt(&mut plaintext).map(|_| plaintext);
+        sender
+            .send(chunk)
+            .expect("The receiver of the pipelined reader was dropped");
+
+        PipelinedReader {
+            receiver,
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+/// Marks one of the `PIPELINE_THREADS` as running until it's dropped, even
+/// if the decryption panics.
+struct PipelineThread;
+
+impl Drop for PipelineThread {
+    fn drop(&mut self) {
+        PIPELINE_THREADS_RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Read for PipelinedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                }
+                // The background thread is done and there are no chunks
+                // left.
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let read = cmp::min(buf.len(), self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+
+        Ok(read)
+    }
+}
+
+/// The directory that holds the encrypted Tantivy files, an mmap directory in
+/// the store path unless a custom directory is used.
+#[derive(Debug)]
+struct InnerDirectory(Box<dyn Directory>);
+
+impl Clone for InnerDirectory {
+    fn clone(&self) -> Self {
+        InnerDirectory(self.0.box_clone())
+    }
+}
+
+impl Deref for InnerDirectory {
+    type Target = dyn Directory;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl DerefMut for InnerDirectory {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut *self.0
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Where and from which size on files get decrypted into a spill file
+/// instead of memory.
+struct Spill {
+    threshold: u64,
+    path: PathBuf,
+    mmap_dir: tantivy::directory::MmapDirectory,
+}
+
+/// A decrypted file that was spilled to disk, the spill file is deleted once
+/// the last source that reads from it is dropped.
+struct SpilledFile {
+    source: Option<ReadOnlySource>,
+    path: PathBuf,
+}
+
+impl Deref for SpilledFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.source.as_ref().map(|s| s.as_slice()).unwrap_or(&[])
+    }
+}
+
+impl Drop for SpilledFile {
+    fn drop(&mut self) {
+        // Unmap the file first, some platforms don't allow us to delete
+        // mapped files.
+        self.source.take();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Describes how the encrypted files are read before they get decrypted.
+pub enum ReadMode {
+    /// The files are decrypted directly from the memory mapped file.
+    Mmap,
+    /// The files are copied into memory before they get authenticated and
+    /// decrypted. If the authentication fails the file is re-read, this makes
+    /// sure that a writer rewriting files underneath us won't result in
+    /// transient decryption failures.
+    Snapshot,
+}
+
+#[derive(Clone, Debug)]
+/// A Directory implementation that wraps a MmapDirectory and adds [AES][aes]
+/// based encryption to the file read/write operations.
+///
+/// When a new EncryptedMmapDirectory is created a random 256 bit AES key is
+/// generated, the store key. This key is encrypted using a key that will be
+/// derived with the user provided passphrase using [PBKDF2][pbkdf]. We generate
+/// a random 128 bit salt and derive a 512 bit key using PBKDF2:
+///
+/// ```text
+///     derived_key = PBKDF2(SHA512, passphrase, salt, count, 512)
+/// ```
+///
+/// After key derivation, the key is split into a 256 bit AES encryption key and
+/// a 256 bit MAC key.
+///
+/// The store key will be encrypted using AES-CTR with the encryption key that
+/// was derived before, a random IV will be generated before encrypting the
+/// store_key:
+///
+/// ```text
+///     ciphertext = AES256-CTR(iv, store_key)
+/// ```
+///
+/// A MAC of the encrypted ciphertext will be created using
+/// the derived MAC key and [HMAC-SHA256][hmac]:
+///
+/// ```text
+///     mac = HMAC-SHA256(mac_key, version || iv || salt || ciphertext)
+/// ```
+///
+/// The store key will be written to a file concatenated with a store version,
+/// the length of the header, the size of the salt, IV, salt, PBKDF count, and
+/// MAC. The PBKDF count will be stored using the big endian byte order:
+///
+/// ```text
+///     key_file = (version || header_length || salt_size || iv || salt ||
+///                 pbkdf_count || mac || key_ciphertext || canary)
+/// ```
+///
+/// The header length covers the size of the salt, the IV, the salt, and the
+/// PBKDF count. A key file whose header is longer than the one we understand
+/// was written by a newer version, it's refused instead of ignoring the fields
+/// it added. Key files before version 4 don't record the header length, key
+/// files before version 5 don't record the size of the salt.
+///
+/// The MAC only proves that the key file wasn't modified, to confirm that the
+/// store key was unwrapped correctly a known plaintext is encrypted using a
+/// key that is expanded from the store key, the canary. It's decrypted and
+/// checked every time the store key is unwrapped. Key files of version 1
+/// don't have a canary:
+///
+/// ```text
+///     canary = AES256-CTR(HKDF(SHA512, store_key, "canary"), iv, plaintext)
+/// ```
+///
+/// Our store key will be used to encrypt the many files that Tantivy generates.
+/// For this, the store key will be expanded into an 256 bit encryption key and
+/// a 256 bit MAC key using [HKDF][hkdf].
+///
+/// ```text
+///     encryption_key, mac_key = HKDF(SHA512, store_key, "", 512)
+/// ```
+///
+/// Those two keys are used to encrypt and authenticate the Tantivy files. The
+/// encryption scheme is similar to the one used for the store key, AES-CTR mode
+/// for encryption and HMAC-SHA256 for authentication. For every encrypted file
+/// a new random 128 bit IV will be generated.
+///
+/// Every file starts with a byte that records whether it was written using
+/// `atomic_write()` or `open_write()`. The MAC will be calculated on this
+/// byte, the IV, and the ciphertext:
+///
+/// ```text
+///     mac = HMAC-SHA256(mac_key, mode || iv || ciphertext)
+/// ```
+///
+/// The file format differs a bit, the MAC will be at the end of the file and
+/// the ciphertext is between the IV and the MAC:
+///
+/// ```text
+///     file_data = (mode || iv || ciphertext || mac)
+/// ```
+///
+/// There is no padding involved since AES-CTR is a stream cipher, an empty
+/// file consists only of the mode, the IV, and the MAC. If padding is enabled,
+/// see `with_padding()`, the plaintext is prefixed by its length and padded
+/// with zeros before it's encrypted and the mode marks the file as padded.
+/// The MAC of
+/// a file is verified before decryption starts and any authentication failure,
+/// be it a truncated file or a MAC mismatch, results in the same error, so the
+/// reads don't expose a padding oracle.
+///
+/// The scheme used for the Tantivy files can be replaced using a custom
+/// `AeadProvider`, see `with_provider()`.
+///
+/// Instead of a passphrase, the store key can be protected by a custom
+/// `KeyWrapper`, e.g. one that seals it to the hardware of the device, see
+/// `open_or_create_with_key_wrapper()`. The key file then records the name of
+/// the wrapper next to the wrapped key:
+///
+/// ```text
+///     key_file = (version || name_length || name || wrapped_key)
+/// ```
+///
+/// The algorithm that wraps the store key and the one that encrypts the
+/// Tantivy files are chosen independently, a store can combine any key wrapper
+/// with any provider. By default both use AES-CTR and HMAC-SHA256. Only the
+/// key wrapper is recorded in the key file, the files of a store need to be
+/// read using the provider that wrote them.
+///
+/// The store key can be rotated, see `rotate_store_key()`, this generates a
+/// new store key, re-encrypts all the files and the key file. The directory
+/// can do this automatically once enough data was encrypted using a single
+/// store key, see `with_rekey_threshold()`.
+///
+/// The encrypted files are stored in a mmap directory by default, any other
+/// Tantivy directory can be used instead, see
+/// `open_or_create_with_directory()`.
+///
+/// The directory is passed to Tantivy like any other directory, e.g. using
+/// `tantivy::Index::open_or_create()`. On the first run the key file is
+/// written when the directory is opened, before Tantivy writes its first
+/// `meta.json`, so every file Tantivy writes is encrypted.
+///
+/// The directory is `Send` and `Sync` since Tantivy reads files from many
+/// threads at once. Clones share the encryption state of the store, the
+/// provider is behind a `RwLock` that is only locked for writing while the
+/// store key is rotated and the count of encrypted bytes is atomic, so
+/// concurrent reads don't wait for each other.
+///
+/// Readers of the same file share its decrypted content, a file is only
+/// decrypted again once the last source that Tantivy got for it is dropped,
+/// see `StoreConfig::cache_decrypted_files()`. The plaintext of a file lives
+/// only as long as those sources, or as long as the caller holds on to the
+/// data of an atomic read.
+///
+/// [aes]: https://en.wikipedia.org/wiki/Advanced_Encryption_Standard
+/// [pbkdf]: https://en.wikipedia.org/wiki/PBKDF2
+/// [hkdf]: https://en.wikipedia.org/wiki/HKDF
+/// [hmac]: https://en.wikipedia.org/wiki/HMAC
+pub struct EncryptedMmapDirectory {
+    path: PathBuf,
+    inner_dir: InnerDirectory,
+    custom_directory: bool,
+    state: Arc<StoreState>,
+    custom_wrapper: bool,
+    custom_provider: bool,
+    rekey_threshold: Option<u64>,
+    read_mode: ReadMode,
+    config: StoreConfig,
+    fallback_provider: Option<Arc<dyn AeadProvider>>,
+    spill: Option<Spill>,
+    legacy_files: bool,
+    refuse_symlink_escapes: bool,
+    read_ahead: usize,
+    write_policy: Option<Arc<dyn WritePolicy>>,
+    recovery: Recovery,
+    // The key file of a store that was created without persisting it, see
+    // `create_uncommitted()`.
+    pending_key_file: Option<Arc<Vec<u8>>>,
+    _writer_lock: Option<Arc<WriterLock>>,
+}
+
+impl EncryptedMmapDirectory {
+    /// Create a new directory for the given store key.
+    ///
+    /// Unless the directory is opened as a read replica, the writer lock of
+    /// the store is acquired. Returns an error of the `WouldBlock` kind if
+    /// another writer holds the lock.
+    fn new(
+        store_key: KeyBuffer,
+        wrapping_key: WrappingKey,
+        path: &Path,
+        read_mode: ReadMode,
+        config: StoreConfig,
+    ) -> Result<Self, OpenDirectoryError> {
+        EncryptedMmapDirectory::with_key_wrapper(
+            store_key,
+            Arc::new(wrapping_key),
+            false,
+            path,
+            None,
+            read_mode,
+            config,
+        )
+    }
+
+    /// Create a new directory for the given store key, the key file will be
+    /// rewritten using the given key wrapper.
+    ///
+    /// The Tantivy files are stored in the given directory, or in an mmap
+    /// directory in the store path if none is given.
+    fn with_key_wrapper(
+        store_key: KeyBuffer,
+        key_wrapper: Arc<dyn KeyWrapper>,
+        custom_wrapper: bool,
+        path: &Path,
+        directory: Option<Box<dyn Directory>>,
+        read_mode: ReadMode,
+        config: StoreConfig,
+    ) -> Result<Self, OpenDirectoryError> {
+        let provider =
+            EncryptedMmapDirectory::create_provider_for(&store_key, &config.data_key_info())?;
+        let journal_provider = EncryptedMmapDirectory::create_provider_for(&store_key, b"journal")?;
+
+        EncryptedMmapDirectory::with_state(
+            StoreState::new(provider, journal_provider, key_wrapper),
+            custom_wrapper,
+            path,
+            directory,
+            read_mode,
+            config,
+        )
+    }
+
+    /// Create a new directory using the given encryption state, the state
+    /// might be locked.
+    fn with_state(
+        state: StoreState,
+        custom_wrapper: bool,
+        path: &Path,
+        directory: Option<Box<dyn Directory>>,
+        read_mode: ReadMode,
+        config: StoreConfig,
+    ) -> Result<Self, OpenDirectoryError> {
+        #[cfg(feature = "self-test")]
+        EncryptedMmapDirectory::run_self_test_once()?;
+
+        // Refuse a temporary directory that would break our atomic writes
+        // right away, instead of on the first write.
+        if let Some(temp_dir) = &config.temp_dir {
+            StoreConfig::check_same_file_system(temp_dir, Some(path))?;
+        }
+
+        // Open our underlying bare Tantivy mmap based directory, unless
+        // we were given a different one.
+        let custom_directory = directory.is_some();
+        let inner_dir = match directory {
+            Some(d) => InnerDirectory(d),
+            None => InnerDirectory(Box::new(tantivy::directory::MmapDirectory::open(&path)?)),
+        };
+
+        let writer_lock = match read_mode {
+            ReadMode::Mmap => {
+                let lock = Lock {
+                    filepath: config.lock_file(),
+                    is_blocking: false,
+                };
+
+                let lock = inner_dir.acquire_lock(&lock).map_err(|e| match e {
+                    LockError::LockBusy => IoError::new(
+                        ErrorKind::WouldBlock,
+                        "the store is already opened by another writer",
+                    ),
+                    LockError::IOError(e) => e,
+                })?;
+
+                Some(Arc::new(WriterLock { _lock: lock }))
+            }
+            ReadMode::Snapshot => None,
+        };
+
+        let mut dir = EncryptedMmapDirectory {
+            path: PathBuf::from(&path),
+            inner_dir,
+            custom_directory,
+            state: Arc::new(state),
+            custom_wrapper,
+            custom_provider: false,
+            rekey_threshold: None,
+            read_mode,
+            config,
+            fallback_provider: None,
+            spill: None,
+            legacy_files: false,
+            refuse_symlink_escapes: false,
+            read_ahead: PIPELINE_CHUNK_SIZE,
+            write_policy: None,
+            recovery: Recovery::default(),
+            pending_key_file: None,
+            _writer_lock: writer_lock,
+        };
+
+        if read_mode == ReadMode::Mmap && !custom_directory {
+            dir.recovery.removed_temporary_files = dir.remove_temporary_files()?;
+        }
+
+        // The files are cross-checked against the cipher the key file
+        // declares whenever they are sealed or opened.
+        let header = File::open(path.join(dir.config.key_file()))
+            .and_then(EncryptedMmapDirectory::read_key_file_header);
+
+        if let Ok(header) = header {
+            if let KeyProtection::Passphrase(cipher) = header.protection {
+                *dir.state.key_file_cipher.write().unwrap() = Some(cipher);
+            }
+        }
+
+        // The journal can't be read before a locked store gets unlocked.
+        if dir.state.check_unlocked().is_err() {
+            return Ok(dir);
+        }
+
+        // Unfinished files are dealt with first, they would break a store
+        // key rotation that needs to be finished.
+        if read_mode == ReadMode::Mmap && !custom_directory {
+            dir.recovery.interrupted_writes = dir.find_interrupted_writes()?;
+        }
+
+        // A store key rotation was interrupted, writers finish the rotation
+        // while read replicas need to be able to read files using either of
+        // the keys.
+        let had_journal = dir.path.join(dir.config.journal_file()).exists();
+
+        if let Some(journal) = dir.read_journal()? {
+            info!("Found an interrupted store key rotation");
+
+            match read_mode {
+                ReadMode::Mmap => {
+                    dir.rotate_store_key()?;
+                    dir.check_rekeyed()?;
+                    dir.recovery.resumed_rotation = true;
+                }
+                ReadMode::Snapshot => {
+                    dir.fallback_provider = Some(EncryptedMmapDirectory::create_provider_for(
+                        &journal.store_key,
+                        &dir.config.data_key_info(),
+                    )?);
+                }
+            }
+        } else {
+            dir.recovery.removed_stale_journal = had_journal;
+        }
+
+        dir.load_file_names()?;
+        dir.load_store_metadata()?;
+        dir.record_key_creation()?;
+
+        if dir.config.rotate_automatically && read_mode == ReadMode::Mmap {
+            let threshold = dir.config.rekey_threshold;
+            dir = dir.with_rekey_threshold(threshold)?;
+        }
+
+        debug!(
+            "Opened the store in {} in {:?} mode",
+            dir.path.display(),
+            dir.read_mode
+        );
+
+        Ok(dir)
+    }
+
+    /// Set the size of the chunks that pipelined readers decrypt ahead of
+    /// their consumer, see `pipelined_reader()`.
+    ///
+    /// A reader keeps a couple of chunks ready, larger chunks decrypt more
+    /// of a file in one go when it's scanned sequentially at the cost of
+    /// memory. The default is 64 KiB.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk_size` - The number of bytes that are decrypted at once.
+    #[allow(dead_code)]
+    pub fn with_read_ahead(mut self, chunk_size: usize) -> std::io::Result<Self> {
+        if chunk_size == 0 {
+            return Err(IoError::new(ErrorKind::Other, "invalid read ahead size"));
+        }
+
+        self.read_ahead = chunk_size;
+
+        Ok(self)
+    }
+
+    /// Decrypt files that are larger than the given threshold into a
+    /// temporary spill file instead of memory.
+    ///
+    /// The spill file is memory mapped, this keeps the memory usage bounded
+    /// when large segments are read on memory constrained devices. The spill
+    /// file is deleted once the file that was read is dropped.
+    ///
+    /// **The spill file contains the decrypted data.** Since it's read using
+    /// a memory map it can't be encrypted, the spill directory should reside
+    /// on storage that is at least as trusted as memory, e.g. a tmpfs or an
+    /// encrypted volume. Spill files are only accessible by their owner, and
+    /// spill files that a previous process left behind, e.g. because it
+    /// crashed, are removed from the spill directory when spilling is
+    /// enabled.
+    ///
+    /// Only used if the files are read using `ReadMode::Mmap`.
+    ///
+    /// # Arguments
+    ///
+    /// * `threshold` - The size, in bytes, of the encrypted file from which
+    /// on the file is spilled.
+    /// * `spill_path` - The directory the spill files should be put in.
+    #[allow(dead_code)]
+    pub fn with_spill_threshold<P: AsRef<Path>>(
+        mut self,
+        threshold: u64,
+        spill_path: P,
+    ) -> Result<Self, OpenDirectoryError> {
+        let path = spill_path.as_ref().to_path_buf();
+        let mmap_dir = tantivy::directory::MmapDirectory::open(&path)?;
+
+        EncryptedMmapDirectory::remove_spill_files(&path)?;
+
+        self.spill = Some(Spill {
+            threshold,
+            path,
+            mmap_dir,
+        });
+
+        Ok(self)
+    }
+
+    /// Pad the files that get written from now on using the given padding
+    /// scheme.
+    ///
+    /// The size of an encrypted file reveals the size of the data it
+    /// contains, padding files to a fixed set of sizes hides it at the cost
+    /// of disk space. The length of the original data is stored and
+    /// authenticated with the padded data, so reads return the exact data
+    /// that was written. Files that were written before stay as they are.
+    ///
+    /// Returns an error if the bucket size of the padding is 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `padding` - The padding scheme that should be used.
+    #[allow(dead_code)]
+    pub fn with_padding(self, padding: Padding) -> Result<Self, OpenDirectoryError> {
+        if padding == Padding::Bucket(0) {
+            return Err(IoError::new(ErrorKind::Other, "invalid padding bucket size").into());
+        }
+
+        *self.state.padding.write().unwrap() = Some(padding);
+
+        Ok(self)
+    }
+
+    /// Hide the headers of the files that get written from now on.
+    ///
+    /// Every encrypted file starts with a tag that records how it was
+    /// written and if it's padded, the tag is authenticated but readable by
+    /// anyone. With hidden headers the tag is masked using a key that is
+    /// derived from the store key, only a fixed marker that tells readers to
+    /// unmask the tag stays in the clear. Files that were written before
+    /// stay as they are, both kinds of files can be read.
+    ///
+    /// Returns an error if the AEAD provider of the store doesn't support
+    /// hidden headers, see `AeadProvider::header_mask()`.
+    #[allow(dead_code)]
+    pub fn with_hidden_headers(self) -> Result<Self, OpenDirectoryError> {
+        {
+            let provider = self.state.provider()?;
+            provider.header_mask(&vec![0u8; provider.nonce_size()])?;
+        }
+
+        self.state.hidden_headers.store(true, Ordering::SeqCst);
+
+        Ok(self)
+    }
+
+    /// Keep a second copy of Tantivy's metadata file.
+    ///
+    /// The metadata file ties the segments together, if it gets corrupted
+    /// the whole index is lost. With a backup every write of the metadata
+    /// file writes a separately encrypted copy to `meta.json.bak` as well,
+    /// and reads fall back to the copy if the metadata file fails to
+    /// authenticate. The copy is written first, it's never older than the
+    /// metadata file.
+    #[allow(dead_code)]
+    pub fn with_meta_backup(self) -> Result<Self, OpenDirectoryError> {
+        self.state.meta_backup.store(true, Ordering::SeqCst);
+
+        Ok(self)
+    }
+
+    /// Truncate the authentication tags of the files that get written from
+    /// now on to the given size, in bytes.
+    ///
+    /// Every encrypted file ends with an authentication tag, 32 bytes for
+    /// the HMAC-SHA256 tags of the default provider. Stores with lots of
+    /// small files can truncate the tags to 24 or 16 bytes, the size is
+    /// recorded in the header of every file, so files with different tag
+    /// sizes can be read. Shorter tags make forgeries more likely, a 16 byte
+    /// tag still leaves a forger a chance of 2^-128 per attempt. Passing the
+    /// full tag size of the algorithm turns truncation off again.
+    ///
+    /// Returns an error if the size isn't supported or if the AEAD provider
+    /// of the store doesn't support truncated tags, see
+    /// `AeadProvider::seal_truncated()`.
+    #[allow(dead_code)]
+    pub fn with_tag_size(self, tag_size: usize) -> Result<Self, OpenDirectoryError> {
+        let bits = {
+            let provider = self.state.provider()?;
+
+            if tag_size == provider.tag_size()? {
+                0
+            } else {
+                let bits = TRUNCATED_TAG_SIZES
+                    .iter()
+                    .find(|(_, size)| *size == tag_size)
+                    .map(|(bits, _)| *bits)
+                    .ok_or_else(|| IoError::new(ErrorKind::Other, "unsupported tag size"))?;
+
+                let nonce = vec![0u8; provider.nonce_size()];
+                provider.seal_truncated(&nonce, &[], &[], tag_size)?;
+
+                bits
+            }
+        };
+
+        self.state.tag_size_bits.store(bits, Ordering::SeqCst);
+
+        Ok(self)
+    }
+
+    /// Read files that were written by versions of the store that didn't tag
+    /// files with their write mode yet.
+    ///
+    /// Those files are encrypted using the store key as well, but they lack
+    /// the header that binds the file to the mode it was written in. Files
+    /// that fail to authenticate are tried in the old format as well. New
+    /// files are always written in the current format, existing files can be
+    /// converted using `migrate_legacy_files()`.
+    ///
+    /// The MAC of a file covers its header and its nonce as one run of
+    /// bytes. A legacy file whose random nonce starts with a write mode tag,
+    /// about one in 128 files, authenticates in the current format as well
+    /// and decrypts to garbage. The index needs to be rebuilt if Tantivy
+    /// fails to read such a file.
+    #[allow(dead_code)]
+    pub fn with_legacy_files(mut self) -> Self {
+        self.legacy_files = true;
+        self
+    }
+
+    /// Check every file Tantivy writes against the given write policy.
+    ///
+    /// Writes through `open_write()` and `atomic_write()` that the policy
+    /// doesn't allow are refused with an error that can be checked using
+    /// `WriteRefused::matches()`, before anything is written. By default
+    /// every write is allowed. The policy only sees the paths Tantivy uses,
+    /// not the names files are stored under if file names are hidden. Our
+    /// own files aren't subject to it, e.g. files that are re-encrypted by a
+    /// store key rotation.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The policy that decides which files may be written.
+    #[allow(dead_code)]
+    pub fn with_write_policy(mut self, policy: Arc<dyn WritePolicy>) -> Self {
+        self.write_policy = Some(policy);
+        self
+    }
+
+    /// Return an error if the write policy doesn't allow writing the file at
+    /// the given path.
+    fn check_write_policy(&self, path: &Path) -> std::io::Result<()> {
+        match &self.write_policy {
+            Some(policy) if !policy.allows_write(path) => {
+                warn!("The write policy refused to write {}", path.display());
+                Err(WriteRefused::error(path))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rewrite the files that were written in the old format, see
+    /// `with_legacy_files()`, in the current format.
+    ///
+    /// The write mode of the files is derived from their names, Tantivy only
+    /// writes its metadata files atomically. Returns the number of files
+    /// that were rewritten, files that fail to authenticate in both formats
+    /// are reported as an error. Only writers can migrate a store.
+    #[allow(dead_code)]
+    pub fn migrate_legacy_files(&self) -> std::io::Result<usize> {
+        self.migrate_legacy_files_with(None)
+    }
+
+    /// Rewrite the files that were written in the old format, stopping
+    /// before the next file once the given token gets cancelled.
+    ///
+    /// This behaves exactly like `migrate_legacy_files()`, a cancelled
+    /// migration returns an error of the `Interrupted` kind. Files that were
+    /// already rewritten stay in the current format, calling this again
+    /// migrates the rest.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token that cancels the migration.
+    #[allow(dead_code)]
+    pub fn migrate_legacy_files_cancellable(
+        &self,
+        token: &CancellationToken,
+    ) -> std::io::Result<usize> {
+        self.migrate_legacy_files_with(Some(token))
+    }
+
+    fn migrate_legacy_files_with(
+        &self,
+        token: Option<&CancellationToken>,
+    ) -> std::io::Result<usize> {
+        if self.read_mode == ReadMode::Snapshot {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                "the files of a read replica can't be migrated",
+            ));
+        }
+
+        // Hold on to the provider, a store key rotation waits for us.
+        let provider = self.state.provider()?;
+        let mut migrated = 0;
+
+        for file in self.disk_files()? {
+            CancellationToken::check(token)?;
+
+            let data = std::fs::read(self.path.join(&file))?;
+
+            if data.is_empty() || StoreState::open(&**provider, &data, None).is_ok() {
+                continue;
+            }
+
+            let decrypted = Zeroizing::new(StoreState::open_legacy(&**provider, &data)?);
+
+            let mode = if file == Path::new(META_FILE) || file == Path::new(MANAGED_FILE) {
+                WriteMode::Atomic
+            } else {
+                WriteMode::Streaming
+            };
+
+            let encrypted = self.state.seal(&**provider, &decrypted, mode)?;
+            self.config
+                .write_atomically(&self.path.join(&file), &encrypted)?;
+            migrated += 1;
+        }
+
+        Ok(migrated)
+    }
+
+    /// Try to decrypt a file that failed to authenticate in the old format,
+    /// if legacy files are enabled. The given error is returned otherwise.
+    fn open_legacy(
+        &self,
+        provider: &dyn AeadProvider,
+        data: &[u8],
+        error: IoError,
+    ) -> std::io::Result<Vec<u8>> {
+        if !self.legacy_files {
+            return Err(error);
+        }
+
+        StoreState::open_legacy(provider, data).map_err(|_| error)
+    }
+
+    /// Check that the store was created for the given Tantivy schema.
+    ///
+    /// Opening an index using a different schema than the one it was created
+    /// with leads to confusing query failures. The first time this is called
+    /// a SHA-256 fingerprint of the schema is encrypted using the store key
+    /// and stored next to the key file, afterwards the fingerprint of the
+    /// given schema needs to match it. Returns an error that matches
+    /// `SchemaMismatch` if it doesn't.
+    ///
+    /// # Arguments
+    ///
+    /// * `schema` - The schema the index of the store uses.
+    #[allow(dead_code)]
+    pub fn with_schema(self, schema: &tantivy::schema::Schema) -> Result<Self, OpenDirectoryError> {
+        let schema = serde_json::to_vec(schema).map_err(IoError::from)?;
+        let fingerprint = Sha256::digest(&schema).to_vec();
+        let schema_path = self.path.join(self.config.schema_file());
+
+        match std::fs::read(&schema_path) {
+            Ok(sealed) => {
+                if self.decrypt(&sealed, Some(WriteMode::Atomic))? != fingerprint {
+                    return Err(SchemaMismatch::error().into());
+                }
+            }
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+
+                let provider = self.state.provider()?;
+                let sealed = StoreState::seal_file(&**provider, &fingerprint, WriteMode::Atomic)?;
+                self.config.write_atomically(&schema_path, &sealed)?;
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Lock the store once it wasn't used for the given amount of time.
+    ///
+    /// Locking drops the store key and every key that is derived from it or
+    /// that protects it, the keys are zeroized as soon as the last read or
+    /// write that uses them is done. Every read and write of a locked store,
+    /// including ones through clones of the directory, returns a `Locked`
+    /// error until the store is unlocked again using `unlock()`. Reads and
+    /// writes reset the timer.
+    ///
+    /// The timer runs on a background thread, which exits once the last
+    /// clone of the directory is dropped. Only passphrase protected stores
+    /// that use our default provider can be locked, read replicas can't be
+    /// locked either.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long the store may be unused before it gets locked.
+    #[allow(dead_code)]
+    pub fn with_auto_lock(self, timeout: Duration) -> Result<Self, OpenDirectoryError> {
+        self.check_lockable()?;
+
+        let state = Arc::downgrade(&self.state);
+
+        std::thread::Builder::new()
+            .name("seshat-auto-lock".to_owned())
+            .spawn(move || loop {
+                let wait = match state.upgrade() {
+                    Some(state) => {
+                        let idle_time = state.idle_time();
+
+                        if state.check_unlocked().is_err() {
+                            timeout
+                        } else if idle_time >= timeout {
+                            state.lock();
+                            timeout
+                        } else {
+                            timeout - idle_time
+                        }
+                    }
+                    None => return,
+                };
+
+                std::thread::sleep(wait);
+            })?;
+
+        Ok(self)
+    }
+
+    /// Return an error if the store can't be locked, the keys of custom
+    /// wrappers and providers can't be restored using a passphrase and
+    /// read replicas might need the keys of an interrupted rotation.
+    fn check_lockable(&self) -> std::io::Result<()> {
+        if self.custom_wrapper || self.custom_provider || self.read_mode == ReadMode::Snapshot {
+            Err(IoError::new(
+                ErrorKind::Other,
+                "only passphrase protected writers can be locked",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Lock the store, see `with_auto_lock()`.
+    ///
+    /// Locking a store that is already locked has no effect. Waits for a
+    /// running store key rotation to finish.
+    #[allow(dead_code)]
+    pub fn lock(&self) -> std::io::Result<()> {
+        self.check_lockable()?;
+        self.state.lock();
+
+        Ok(())
+    }
+
+    /// Unlock a store that was locked, see `with_auto_lock()`, `lock()` and
+    /// `open_locked()`.
+    ///
+    /// The store key is unwrapped again using the given passphrase, the
+    /// store stays locked if the passphrase is wrong. Unlocking a store that
+    /// isn't locked only checks the passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    #[allow(dead_code)]
+    pub fn unlock(&self, passphrase: &str) -> Result<(), OpenDirectoryError> {
+        self.check_lockable()?;
+
+        let (wrapping_key, store_key) =
+            EncryptedMmapDirectory::unlock_key_file(&self.path, passphrase, &self.config)?;
+
+        self.state.unlock(
+            EncryptedMmapDirectory::create_provider_for(&store_key, &self.config.data_key_info())?,
+            EncryptedMmapDirectory::create_provider_for(&store_key, b"journal")?,
+            Arc::new(wrapping_key),
+        );
+
+        // A store that was opened locked might have an interrupted store key
+        // rotation to finish.
+        if self.read_journal()?.is_some() {
+            self.rotate_store_key()?;
+            self.check_rekeyed()?;
+        }
+
+        self.load_file_names()?;
+        self.load_store_metadata()?;
+        self.record_key_creation()?;
+
+        Ok(())
+    }
+
+    /// Create a new store without persisting its key file, e.g. to build the
+    /// initial index before the store is set up for good.
+    ///
+    /// The store key is generated and protected by the passphrase like
+    /// `open_or_create()` does it, but the key file is only written once
+    /// `commit_key_file()` is called. Until then the store can't be reopened,
+    /// its store key can't be rotated and its passphrase can't be changed. If
+    /// the setup fails, the directory can be removed without leaving a key
+    /// file behind that points at a broken index.
+    ///
+    /// Returns an error if there already is a key file in the path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory should reside in.
+    /// * `passphrase` - The passphrase that will be used to encrypt our
+    /// directory.
+    /// * `key_derivation_count` - The number of iterations that our key
+    /// derivation function should use.
+    #[allow(dead_code)]
+    pub fn create_uncommitted<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+        key_derivation_count: u32,
+    ) -> Result<Self, OpenDirectoryError> {
+        let config = StoreConfig::new();
+        config.check_passphrase(passphrase)?;
+
+        if key_derivation_count == 0 {
+            return Err(IoError::new(ErrorKind::Other, "invalid key derivation count").into());
+        }
+
+        if path.as_ref().join(config.key_file()).exists() {
+            return Err(IoError::new(
+                ErrorKind::AlreadyExists,
+                "there already is a store in the given path",
+            )
+            .into());
+        }
+
+        let (wrapping_key, store_key, key_file) =
+            EncryptedMmapDirectory::generate_store_key(passphrase, key_derivation_count)?;
+
+        let mut dir = EncryptedMmapDirectory::new(
+            store_key,
+            wrapping_key,
+            path.as_ref(),
+            ReadMode::Mmap,
+            config,
+        )?;
+        dir.pending_key_file = Some(Arc::new(key_file));
+
+        Ok(dir)
+    }
+
+    /// Persist the key file of a store that was created using
+    /// `create_uncommitted()`.
+    ///
+    /// Committing the key file again has no effect, neither has committing
+    /// the key file of a store that was opened in any other way. Returns an
+    /// error if a different key file was written in the meantime.
+    #[allow(dead_code)]
+    pub fn commit_key_file(&self) -> std::io::Result<()> {
+        let key_file = match &self.pending_key_file {
+            Some(k) => k,
+            None => return Ok(()),
+        };
+
+        let key_path = self.path.join(self.config.key_file());
+
+        match std::fs::read(&key_path) {
+            Ok(existing) => {
+                if existing == **key_file {
+                    Ok(())
+                } else {
+                    Err(IoError::new(
+                        ErrorKind::AlreadyExists,
+                        "a different key file was written for the store",
+                    ))
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                info!("Committing the key file of {}", self.path.display());
+                self.config.write_atomically(&key_path, key_file)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Return an error if the key file of the store wasn't committed yet.
+    fn check_committed(&self) -> std::io::Result<()> {
+        if self.pending_key_file.is_some() && !self.path.join(self.config.key_file()).exists() {
+            Err(IoError::new(
+                ErrorKind::Other,
+                "the key file of the store wasn't committed yet",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Open a encrypted mmap directory. If the directory is empty a new
+    /// directory key will be generated and encrypted with the given passphrase.
+    ///
+    /// If a new store is created, this method will randomly generated a new
+    /// store key and encrypted using the given passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory should reside in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory
+    /// or the one that will be used to encrypt our directory.
+    /// * `key_derivation_count` - The number of iterations that our key
+    /// derivation function should use. Can't be lower than 1, should be chosen
+    /// as high as possible, depending on how much time is acceptable for the
+    /// caller to wait. Is only used when a new store is created. The count will
+    /// be stored with the store key.
+    ///
+    /// Returns an error if the path does not exist, if it is not a directory or
+    /// if there was an error when trying to decrypt the directory key e.g. the
+    /// given passphrase was incorrect.
+    pub fn open_or_create<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+        key_derivation_count: u32,
+    ) -> Result<Self, OpenDirectoryError> {
+        let (dir, _) = EncryptedMmapDirectory::open_or_create_reporting(
+            path,
+            passphrase,
+            key_derivation_count,
+        )?;
+        Ok(dir)
+    }
+
+    /// Open or create a encrypted mmap directory and report which of the two
+    /// happened.
+    ///
+    /// This behaves exactly like `open_or_create()` but additionally returns
+    /// an `Opened` value, which lets callers know if the store was newly
+    /// created, e.g. to trigger an initial full reindex, without having to
+    /// check for the key file themselves.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory should reside in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory
+    /// or the one that will be used to encrypt our directory.
+    /// * `key_derivation_count` - The number of iterations that our key
+    /// derivation function should use, only used when a new store is created.
+    pub fn open_or_create_reporting<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+        key_derivation_count: u32,
+    ) -> Result<(Self, Opened), OpenDirectoryError> {
+        EncryptedMmapDirectory::open_or_create_with_config(
+            path,
+            passphrase,
+            key_derivation_count,
+            &StoreConfig::new(),
+        )
+    }
+
+    /// Open or create a encrypted mmap directory using the given store
+    /// configuration.
+    ///
+    /// This behaves exactly like `open_or_create_reporting()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory should reside in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory
+    /// or the one that will be used to encrypt our directory.
+    /// * `key_derivation_count` - The number of iterations that our key
+    /// derivation function should use, only used when a new store is created.
+    /// * `config` - The configuration of the files we store next to the
+    /// Tantivy files.
+    pub fn open_or_create_with_config<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+        key_derivation_count: u32,
+        config: &StoreConfig,
+    ) -> Result<(Self, Opened), OpenDirectoryError> {
+        EncryptedMmapDirectory::open_or_create_in(
+            path.as_ref(),
+            passphrase,
+            key_derivation_count,
+            config,
+            None,
+        )
+    }
+
+    /// Create a encrypted mmap directory protected by a generated
+    /// passphrase.
+    ///
+    /// A random passphrase with 256 bits of entropy is generated and
+    /// returned, the caller needs to persist it, the store can't be opened
+    /// without it. Returns an error if the store already exists, use
+    /// `open_or_create_reporting()` to open it.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory should reside in.
+    /// * `key_derivation_count` - The number of iterations that our key
+    /// derivation function should use.
+    #[allow(dead_code)]
+    pub fn create_with_generated_passphrase<P: AsRef<Path>>(
+        path: P,
+        key_derivation_count: u32,
+    ) -> Result<(Self, Zeroizing<String>), OpenDirectoryError> {
+        let config = StoreConfig::new();
+
+        if path.as_ref().join(config.key_file()).exists() {
+            return Err(IoError::new(
+                ErrorKind::AlreadyExists,
+                "the store already exists, it needs a passphrase to be opened",
+            )
+            .into());
+        }
+
+        let passphrase = EncryptedMmapDirectory::generate_passphrase()?;
+        let (dir, _) = EncryptedMmapDirectory::open_or_create_in(
+            path.as_ref(),
+            &passphrase,
+            key_derivation_count,
+            &config,
+            None,
+        )?;
+
+        Ok((dir, passphrase))
+    }
+
+    /// Open or create a encrypted directory that stores the encrypted Tantivy
+    /// files in the given directory instead of an mmap directory.
+    ///
+    /// This behaves exactly like `open_or_create_reporting()`, the key file
+    /// and the rest of our own files are still kept in the store path. The
+    /// given directory doesn't need to support memory mapping, files are read
+    /// only through its `open_read()` and `atomic_read()` methods. Since the
+    /// files of such a directory can't be listed, `files()` and
+    /// `disk_usage()` return an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the key file should reside in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory
+    /// or the one that will be used to encrypt our directory.
+    /// * `key_derivation_count` - The number of iterations that our key
+    /// derivation function should use, only used when a new store is created.
+    /// * `directory` - The directory that will hold the encrypted Tantivy
+    /// files.
+    #[allow(dead_code)]
+    pub fn open_or_create_with_directory<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+        key_derivation_count: u32,
+        directory: Box<dyn Directory>,
+    ) -> Result<(Self, Opened), OpenDirectoryError> {
+        EncryptedMmapDirectory::open_or_create_in(
+            path.as_ref(),
+            passphrase,
+            key_derivation_count,
+            &StoreConfig::new(),
+            Some(directory),
+        )
+    }
+
+    /// Open or create one of several indexes that share the store key of the
+    /// store in the given path.
+    ///
+    /// The Tantivy files of the index are kept in a subdirectory of the
+    /// store that is named after the index id, the key file and the rest of
+    /// our own files are shared. The keys of the files of every index are
+    /// expanded from the store key using the index id, a key that leaks for
+    /// one index doesn't expose the files of the others, while a single
+    /// passphrase unlocks all of them. Each index has its own writer lock.
+    ///
+    /// The indexes behave like stores that use a custom directory, see
+    /// `open_or_create_with_directory()`. The store key can't be rotated
+    /// since the files of the other indexes would become unreadable.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the key file should reside in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory
+    /// or the one that will be used to encrypt our directory.
+    /// * `index_id` - The id of the index, it needs to be a valid directory
+    /// name.
+    /// * `key_derivation_count` - The number of iterations that our key
+    /// derivation function should use, only used when a new store is created.
+    #[allow(dead_code)]
+    pub fn open_or_create_index<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+        index_id: &str,
+        key_derivation_count: u32,
+    ) -> Result<(Self, Opened), OpenDirectoryError> {
+        let (config, index_path) = EncryptedMmapDirectory::index_config(path.as_ref(), index_id)?;
+        std::fs::create_dir_all(&index_path)?;
+
+        let directory = tantivy::directory::MmapDirectory::open(&index_path)?;
+
+        EncryptedMmapDirectory::open_or_create_in(
+            path.as_ref(),
+            passphrase,
+            key_derivation_count,
+            &config,
+            Some(Box::new(directory)),
+        )
+    }
+
+    /// Open one of several indexes that share the store key of the store in
+    /// the given path, see `open_or_create_index()`.
+    ///
+    /// Returns an error if the store or the index doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the key file resides in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    /// * `index_id` - The id of the index.
+    #[allow(dead_code)]
+    pub fn open_index<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+        index_id: &str,
+    ) -> Result<Self, OpenDirectoryError> {
+        let (config, index_path) = EncryptedMmapDirectory::index_config(path.as_ref(), index_id)?;
+        config.check_passphrase(passphrase)?;
+
+        let (wrapping_key, store_key) =
+            EncryptedMmapDirectory::unlock_key_file(path.as_ref(), passphrase, &config)?;
+        let directory = tantivy::directory::MmapDirectory::open(&index_path)?;
+
+        EncryptedMmapDirectory::with_key_wrapper(
+            store_key,
+            Arc::new(wrapping_key),
+            false,
+            path.as_ref(),
+            Some(Box::new(directory)),
+            ReadMode::Mmap,
+            config,
+        )
+    }
+
+    /// Get the store configuration and the path of the index with the given
+    /// id, the id needs to be a plain directory name.
+    fn index_config(path: &Path, index_id: &str) -> std::io::Result<(StoreConfig, PathBuf)> {
+        let mut components = Path::new(index_id).components();
+
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => (),
+            _ => return Err(IoError::new(ErrorKind::InvalidInput, "invalid index id")),
+        }
+
+        let config = StoreConfig {
+            index_id: Some(index_id.to_owned()),
+            ..StoreConfig::new()
+        };
+
+        if config.is_seshat_metadata(Path::new(index_id)) {
+            return Err(IoError::new(ErrorKind::InvalidInput, "invalid index id"));
+        }
+
+        Ok((config, path.join(index_id)))
+    }
+
+    /// Open or create a encrypted directory, the Tantivy files are stored in
+    /// the given directory or in an mmap directory if none is given.
+    fn open_or_create_in(
+        path: &Path,
+        passphrase: &str,
+        key_derivation_count: u32,
+        config: &StoreConfig,
+        directory: Option<Box<dyn Directory>>,
+    ) -> Result<(Self, Opened), OpenDirectoryError> {
+        config.check_passphrase(passphrase)?;
+
+        if key_derivation_count == 0 {
+            return Err(IoError::new(ErrorKind::Other, "invalid key derivation count").into());
+        }
+
+        let key_path = path.join(config.key_file());
+        let key_file = File::open(&key_path);
+
+        // Either load a store key or create a new store key if the key file
+        // doesn't exist.
+        let (wrapping_key, store_key, opened) = match key_file {
+            Ok(_) => {
+                debug!("Found the key file of the store in {}", path.display());
+                let (wrapping_key, key) =
+                    EncryptedMmapDirectory::unlock_key_file(path, passphrase, config)?;
+                (wrapping_key, key, Opened::Existing)
+            }
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+                config.check_key_derivation_count(key_derivation_count)?;
+                info!("Creating a new store in {}", path.display());
+                let (wrapping_key, key) = EncryptedMmapDirectory::create_new_store(
+                    &key_path,
+                    passphrase,
+                    key_derivation_count,
+                    config,
+                )?;
+                (wrapping_key, key, Opened::Created)
+            }
+        };
+        let dir = EncryptedMmapDirectory::with_key_wrapper(
+            store_key,
+            Arc::new(wrapping_key),
+            false,
+            path,
+            directory,
+            ReadMode::Mmap,
+            config.clone(),
+        )?;
+
+        if opened == Opened::Existing {
+            dir.check_orphan_key_file()?;
+        }
+
+        Ok((dir, opened))
+    }
+
+    /// Open a encrypted mmap directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory should reside in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    ///
+    /// Returns an error if the path does not exist, if it is not a directory or
+    /// if there was an error when trying to decrypt the directory key e.g. the
+    /// given passphrase was incorrect.
+
+    // This isn't currently used anywhere, but it will make sense if the
+    // EncryptedMmapDirectory gets upstreamed.
+    #[allow(dead_code)]
+    pub fn open<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<Self, OpenDirectoryError> {
+        EncryptedMmapDirectory::open_with_config(path, passphrase, &StoreConfig::new())
+    }
+
+    /// Open a encrypted mmap directory without unlocking it.
+    ///
+    /// The key file is read, but no key is derived until the store is
+    /// unlocked using `unlock()`. Every read and write returns a `Locked`
+    /// error until then. The writer lock of the store is acquired right
+    /// away, so the directory can be kept around across lock and unlock
+    /// cycles, see `lock()`.
+    ///
+    /// Returns an error if the key file doesn't exist or if the store key
+    /// isn't protected by a passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    #[allow(dead_code)]
+    pub fn open_locked<P: AsRef<Path>>(path: P) -> Result<Self, OpenDirectoryError> {
+        let config = StoreConfig::new();
+
+        let mut data = Vec::new();
+        File::open(path.as_ref().join(config.key_file()))?
+            .take(KEY_FILE_MAX_SIZE as u64)
+            .read_to_end(&mut data)?;
+        KeyFile::parse(&data).map_err(IoError::from)?;
+
+        EncryptedMmapDirectory::with_state(
+            StoreState::locked(),
+            false,
+            path.as_ref(),
+            None,
+            ReadMode::Mmap,
+            config,
+        )
+    }
+
+    /// Open a encrypted mmap directory using the given store configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory should reside in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    /// * `config` - The configuration of the files we store next to the
+    /// Tantivy files.
+    #[allow(dead_code)]
+    pub fn open_with_config<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+        config: &StoreConfig,
+    ) -> Result<Self, OpenDirectoryError> {
+        config.check_passphrase(passphrase)?;
+
+        let (wrapping_key, store_key) =
+            EncryptedMmapDirectory::unlock_key_file(path.as_ref(), passphrase, config)?;
+        let dir = EncryptedMmapDirectory::new(
+            store_key,
+            wrapping_key,
+            path.as_ref(),
+            ReadMode::Mmap,
+            config.clone(),
+        )?;
+
+        dir.check_orphan_key_file()?;
+
+        Ok(dir)
+    }
+
+    /// Open a encrypted mmap directory reading the store key from an already
+    /// opened key file.
+    ///
+    /// This is useful if the process isn't allowed to open the key file
+    /// itself, e.g. in a sandbox where the key file is handed over as a file
+    /// descriptor.
+    ///
+    /// Operations that rewrite the key file, e.g. a store key rotation, still
+    /// write it to its usual place in the directory, the default store
+    /// configuration is used.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_file` - The key file of the store, positioned at its start.
+    /// * `path` - The path where the directory resides in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    #[allow(dead_code)]
+    pub fn open_with_key_file<R: Read, P: AsRef<Path>>(
+        key_file: R,
+        path: P,
+        passphrase: &str,
+    ) -> Result<Self, OpenDirectoryError> {
+        StoreConfig::new().check_passphrase(passphrase)?;
+
+        // Expand the store key into a encryption and MAC key.
+        let (wrapping_key, store_key) =
+            EncryptedMmapDirectory::load_store_key(key_file, passphrase)?;
+        EncryptedMmapDirectory::new(
+            store_key,
+            wrapping_key,
+            path.as_ref(),
+            ReadMode::Mmap,
+            StoreConfig::new(),
+        )
+    }
+
+    /// Open a encrypted mmap directory without checking the MAC of the store
+    /// key.
+    ///
+    /// **This is unsafe and meant only as a best-effort recovery measure.** If
+    /// the MAC of the key file fails to verify, e.g. because of a single
+    /// flipped bit, this will still attempt to decrypt the store key. Since
+    /// the passphrase can't be verified without the MAC, a wrong passphrase
+    /// results in a garbage store key and every subsequent file read will
+    /// fail.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    #[allow(dead_code)]
+    pub fn open_force<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+    ) -> Result<Self, OpenDirectoryError> {
+        StoreConfig::new().check_passphrase(passphrase)?;
+
+        let key_path = path.as_ref().join(StoreConfig::new().key_file());
+        let key_file = File::open(&key_path)?;
+
+        let (wrapping_key, store_key) = EncryptedMmapDirectory::read_store_key(
+            key_file,
+            passphrase,
+            false,
+            &StoreConfig::new(),
+        )?;
+        EncryptedMmapDirectory::new(
+            store_key,
+            wrapping_key,
+            path.as_ref(),
+            ReadMode::Mmap,
+            StoreConfig::new(),
+        )
+    }
+
+    /// Install the passphrase provider that is used to open stores by their
+    /// path alone.
+    ///
+    /// Replaces the previously installed provider, `None` uninstalls it.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - The provider that should be used from now on.
+    #[allow(dead_code)]
+    pub fn set_passphrase_provider(provider: Option<Arc<dyn PassphraseProvider>>) {
+        *PASSPHRASE_PROVIDER.write().unwrap() = provider;
+    }
+
+    /// Open a encrypted mmap directory as a read replica.
+    ///
+    /// A read replica snapshots every file it reads, see `ReadMode::Snapshot`,
+    /// which makes it safe to use while a different process is committing to
+    /// the same store. Unlike the other ways to open a store, a read replica
+    /// doesn't take the writer lock of the store.
+    ///
+    /// Nor does it wait for the lock that operations replacing the key file
+    /// hold. A passphrase change leaves the store key alone, a replica can
+    /// be opened and searched while the passphrase changes, it's opened using
+    /// whichever passphrase the key file holds at that moment. A replica
+    /// that is opened during a store key rotation reads files using either
+    /// store key.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    #[allow(dead_code)]
+    pub fn open_replica<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+    ) -> Result<Self, OpenDirectoryError> {
+        StoreConfig::new().check_passphrase(passphrase)?;
+
+        let config = StoreConfig::new();
+        let (wrapping_key, store_key) =
+            EncryptedMmapDirectory::unlock_key_file(path.as_ref(), passphrase, &config)?;
+        EncryptedMmapDirectory::new(
+            store_key,
+            wrapping_key,
+            path.as_ref(),
+            ReadMode::Snapshot,
+            config,
+        )
+    }
+
+    /// Open or create a encrypted mmap directory whose store key is wrapped
+    /// by the given key wrapper instead of a passphrase.
+    ///
+    /// The name of the wrapper is recorded in the key file, a store can only
+    /// be opened using the kind of wrapper that created it. Operations that
+    /// rewrite the key file, e.g. a store key rotation, wrap the store key
+    /// using the same wrapper. Operations that need a passphrase, e.g.
+    /// `change_passphrase()` or `seal_manifest()`, aren't supported by such a
+    /// store.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory should reside in.
+    /// * `key_wrapper` - The key wrapper that wraps the store key.
+    #[allow(dead_code)]
+    pub fn open_or_create_with_key_wrapper<P: AsRef<Path>>(
+        path: P,
+        key_wrapper: Arc<dyn KeyWrapper>,
+    ) -> Result<(Self, Opened), OpenDirectoryError> {
+        let config = StoreConfig::new();
+        let key_path = path.as_ref().join(config.key_file());
+
+        let (store_key, opened) = match File::open(&key_path) {
+            Ok(k) => (
+                EncryptedMmapDirectory::unwrap_any_store_key(
+                    k,
+                    path.as_ref(),
+                    &config,
+                    &*key_wrapper,
+                )?,
+                Opened::Existing,
+            ),
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+
+                let store_key = EncryptedMmapDirectory::generate_key()?;
+                let key_file = EncryptedMmapDirectory::wrap_store_key(&*key_wrapper, &store_key)?;
+                config.write_atomically(&key_path, &key_file)?;
+
+                (store_key, Opened::Created)
+            }
+        };
+
+        let dir = EncryptedMmapDirectory::with_key_wrapper(
+            store_key,
+            key_wrapper,
+            true,
+            path.as_ref(),
+            None,
+            ReadMode::Mmap,
+            config,
+        )?;
+
+        Ok((dir, opened))
+    }
+
+    /// Open or create a encrypted mmap directory whose store key is wrapped
+    /// by the given raw key instead of a passphrase.
+    ///
+    /// No key derivation function is involved, the key needs to be uniformly
+    /// random and at least as long as the keys of our cipher, shorter keys
+    /// are rejected instead of being stretched.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory should reside in.
+    /// * `key` - The raw key that wraps the store key, at least 32 bytes
+    /// long.
+    #[allow(dead_code)]
+    pub fn open_with_key_bytes<P: AsRef<Path>>(
+        path: P,
+        key: &[u8],
+    ) -> Result<(Self, Opened), OpenDirectoryError> {
+        if key.len() < KEY_SIZE {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!("the key needs to be at least {} bytes long", KEY_SIZE),
+            )
+            .into());
+        }
+
+        let wrapper = KeyBytesWrapper {
+            provider: EncryptedMmapDirectory::create_provider_for(
+                key,
+                KEY_BYTES_WRAPPER.as_bytes(),
+            )?,
+        };
+
+        EncryptedMmapDirectory::open_or_create_with_key_wrapper(path, Arc::new(wrapper))
+    }
+
+    /// Export the store as a plaintext Tantivy index.
+    ///
+    /// Every file of the store gets decrypted and written into the
+    /// destination directory, which can afterwards be opened using a plain
+    /// `MmapDirectory`. This is meant for users that want to stop using an
+    /// encrypted store, the store itself stays untouched.
+    ///
+    /// The store is opened for writing, so it can't be modified while it's
+    /// being exported. Every file is written to a temporary file and moved
+    /// into place, a crash won't leave a partially written file behind.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `dest` - The path where the plaintext index should be written to,
+    /// it's created if it doesn't exist.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    #[allow(dead_code)]
+    pub fn decrypt_to<P: AsRef<Path>, D: AsRef<Path>>(
+        path: P,
+        dest: D,
+        passphrase: &str,
+    ) -> Result<(), OpenDirectoryError> {
+        let dir = EncryptedMmapDirectory::open(path, passphrase)?;
+        let dest = dest.as_ref();
+
+        std::fs::create_dir_all(dest)?;
+
+        // Files are exported no matter which mode they were written in.
+        for file in dir.files()? {
+            let encrypted = dir
+                .inner_dir
+                .atomic_read(&dir.disk_path(&file)?)
+                .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))?;
+            let data = Zeroizing::new(dir.decrypt(&encrypted, None)?);
+            dir.config.write_atomically(&dest.join(&file), &data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Destroy the store in the given path.
+    ///
+    /// The passphrase is verified first, so the wrong directory can't be
+    /// wiped by accident. The key file is removed before any other file,
+    /// without it the remaining files can't be decrypted anymore even if
+    /// removing them fails halfway. Every file of the directory is removed,
+    /// the directory itself is left behind empty.
+    ///
+    /// The store is opened for writing, so it can't be wiped while another
+    /// writer uses it.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    /// * `overwrite` - Should the files be overwritten with zeroes before
+    /// they're removed. Whether this reaches the disk depends on the file
+    /// system, e.g. copy on write file systems and SSDs may keep the old
+    /// blocks around.
+    #[allow(dead_code)]
+    pub fn wipe<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+        overwrite: bool,
+    ) -> Result<(), OpenDirectoryError> {
+        let config = StoreConfig::new();
+        let dir = EncryptedMmapDirectory::open_with_config(path.as_ref(), passphrase, &config)?;
+        let key_lock = EncryptedMmapDirectory::lock_key_file(path.as_ref(), &config)?;
+
+        let mut files = vec![config.key_file()];
+
+        for entry in std::fs::read_dir(path.as_ref())? {
+            let entry = entry?;
+            let file = PathBuf::from(entry.file_name());
+
+            // The lock files are removed after we release the locks.
+            if !entry.metadata()?.is_file()
+                || file == config.key_file()
+                || file == config.lock_file()
+                || file == config.key_lock_file()
+            {
+                continue;
+            }
+
+            files.push(file);
+        }
+
+        for file in files {
+            let file = path.as_ref().join(file);
+
+            if overwrite {
+                EncryptedMmapDirectory::overwrite_file(&file)?;
+            }
+
+            std::fs::remove_file(file)?;
+        }
+
+        drop(key_lock);
+        drop(dir);
+
+        for file in &[config.lock_file(), config.key_lock_file()] {
+            match std::fs::remove_file(path.as_ref().join(file)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Overwrite the content of the file at the given path with zeroes.
+    fn overwrite_file(path: &Path) -> std::io::Result<()> {
+        let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+        let mut remaining = file.metadata()?.len();
+        let zeroes = [0u8; 4096];
+
+        while remaining > 0 {
+            let length = cmp::min(remaining, zeroes.len() as u64) as usize;
+            file.write_all(&zeroes[..length])?;
+            remaining -= length as u64;
+        }
+
+        file.sync_all()
+    }
+
+    /// Perform a set of maintenance operations on a store in one go.
+    ///
+    /// The store is opened for writing once, so no other writer can modify
+    /// it while the plan is executed. If requested, the store key is rotated
+    /// first, re-encrypting all the files. The key file is replaced last and
+    /// only once, it reflects the new passphrase, key derivation count,
+    /// store key, and cipher at the same time. If the maintenance gets
+    /// interrupted, the rotation is resumed the next time the store is opened
+    /// using the old passphrase. The new key file is put in place once the
+    /// rotation is done, opening the store fails afterwards and it needs to
+    /// be opened using the new passphrase.
+    ///
+    /// The key file always gets rewritten using the newest supported cipher,
+    /// migrating the cipher only forces the key file to be rewritten if no
+    /// other operation is requested. Rewriting the key file removes the key
+    /// slots, see `add_key_slot()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `old_passphrase` - The passphrase that was used to encrypt our
+    /// directory.
+    /// * `plan` - The operations that should be performed.
+    #[allow(dead_code)]
+    pub fn maintain<P: AsRef<Path>>(
+        path: P,
+        old_passphrase: &str,
+        plan: MaintenancePlan,
+    ) -> Result<(), OpenDirectoryError> {
+        let config = StoreConfig::new();
+        config.check_passphrase(old_passphrase)?;
+
+        let passphrase = match &plan.new_passphrase {
+            Some(p) => {
+                config.check_passphrase(p)?;
+                p.as_str()
+            }
+            None => old_passphrase,
+        };
+
+        if plan.key_derivation_count == Some(0) {
+            return Err(IoError::new(ErrorKind::Other, "invalid key derivation count").into());
+        }
+
+        let key_file = File::open(path.as_ref().join(config.key_file()))?;
+        let (old_wrapping_key, store_key) =
+            EncryptedMmapDirectory::load_store_key(key_file, old_passphrase)?;
+
+        let needs_rewrite = plan.new_passphrase.is_some()
+            || plan.key_derivation_count.is_some()
+            || plan.rotate_store_key
+            || plan.migrate_cipher;
+
+        if !needs_rewrite {
+            return Ok(());
+        }
+
+        let count = plan
+            .key_derivation_count
+            .unwrap_or(old_wrapping_key.pbkdf_count);
+        let wrapping_key = EncryptedMmapDirectory::derive_key(passphrase, count)?;
+
+        let dir = EncryptedMmapDirectory::new(
+            store_key.clone(),
+            old_wrapping_key,
+            path.as_ref(),
+            ReadMode::Mmap,
+            config,
+        )?;
+
+        // The new key file is prepared before the rotation starts, so an
+        // interrupted rotation still ends with the new passphrase.
+        if plan.rotate_store_key {
+            dir.rotate(Some(Arc::new(wrapping_key)), None, |_, _| ())?;
+        } else {
+            let key_file = dir.wrap_key_file(&wrapping_key, &store_key)?;
+            dir.config
+                .write_atomically(&dir.path.join(dir.config.key_file()), &key_file)?;
+            EncryptedMmapDirectory::remove_key_slots(&dir.path, &dir.config)?;
+        }
+
+        Ok(())
+    }
+
+    /// Perform the given maintenance operations on a copy of the store,
+    /// leaving the store itself untouched.
+    ///
+    /// The store is copied into the destination, which must not contain a
+    /// store yet, and the copy is maintained like `maintain()` would. The
+    /// copy is then opened using the passphrase it ends up with and every
+    /// file of it is decrypted, it needs to contain the same files as the
+    /// store. Replacing the store with the verified copy is left to the
+    /// caller. If anything fails, the destination is left as it is for
+    /// inspection.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `dest` - The path where the maintained copy should be written to,
+    /// it's created if it doesn't exist.
+    /// * `old_passphrase` - The passphrase that was used to encrypt our
+    /// directory.
+    /// * `plan` - The operations that should be performed on the copy.
+    #[allow(dead_code)]
+    pub fn maintain_to<P: AsRef<Path>, D: AsRef<Path>>(
+        path: P,
+        dest: D,
+        old_passphrase: &str,
+        plan: MaintenancePlan,
+    ) -> Result<(), OpenDirectoryError> {
+        let dest = dest.as_ref();
+        let passphrase = plan
+            .new_passphrase
+            .clone()
+            .unwrap_or_else(|| Zeroizing::new(old_passphrase.to_owned()));
+
+        let dir = EncryptedMmapDirectory::open(path, old_passphrase)?;
+        let mut files = dir.files()?;
+        files.sort();
+
+        std::fs::create_dir_all(dest)?;
+        let inner = tantivy::directory::MmapDirectory::open(dest)?;
+        drop(dir.copy_to(dest, Box::new(inner))?);
+        drop(dir);
+
+        EncryptedMmapDirectory::maintain(dest, old_passphrase, plan)?;
+
+        let copy = EncryptedMmapDirectory::open(dest, &passphrase)?;
+        let mut copied_files = copy.files()?;
+        copied_files.sort();
+
+        if copied_files != files {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "the maintained copy doesn't contain the files of the store",
+            )
+            .into());
+        }
+
+        for file in copied_files {
+            let encrypted = copy
+                .inner_dir
+                .atomic_read(&copy.disk_path(&file)?)
+                .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))?;
+            let _plaintext = Zeroizing::new(copy.decrypt(&encrypted, None)?);
+        }
+
+        Ok(())
+    }
+
+    /// Get the paths of all the encrypted files in the directory.
+    ///
+    /// The paths are relative to the directory, the key file and the lock
+    /// files aren't included. If the file names are hidden, the original
+    /// names are returned. Returns an error if the files are stored in a
+    /// custom directory, see `open_or_create_with_directory()`.
+    pub fn files(&self) -> std::io::Result<Vec<PathBuf>> {
+        let files = self.disk_files()?;
+
+        match &*self.state.file_names.read().unwrap() {
+            Some(names) => Ok(files.iter().filter_map(|f| names.reveal(f)).collect()),
+            None => Ok(files),
+        }
+    }
+
+    /// Get the names the encrypted files of the directory are stored under.
+    fn disk_files(&self) -> std::io::Result<Vec<PathBuf>> {
+        self.check_enumerable()?;
+        let mut files = Vec::new();
+
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+
+            if !entry.metadata()?.is_file() {
+                continue;
+            }
+
+            let path = PathBuf::from(entry.file_name());
+
+            if !self.is_unencrypted_file(&path) {
+                files.push(path);
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Return an error if the store has a key file but no index and the
+    /// store configuration refuses such stores.
+    fn check_orphan_key_file(&self) -> std::io::Result<()> {
+        if self.config.refuse_orphan_key_file && !self.exists(Path::new(META_FILE)) {
+            Err(IoError::new(
+                ErrorKind::NotFound,
+                "the store has a key file but no index, it might have been partially deleted",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return an error if the files are stored in a custom directory, the
+    /// `Directory` trait provides no way to list them.
+    fn check_enumerable(&self) -> std::io::Result<()> {
+        if self.custom_directory {
+            Err(IoError::new(
+                ErrorKind::Other,
+                "the files of a custom directory can't be listed",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Load and authenticate the metadata of the store, if any was attached
+    /// to it.
+    fn load_store_metadata(&self) -> std::io::Result<()> {
+        let metadata_file = self.config.metadata_file();
+
+        let sealed = match std::fs::read(self.path.join(&metadata_file)) {
+            Ok(s) => s,
+            Err(e) => {
+                if e.kind() == ErrorKind::NotFound {
+                    return Ok(());
+                }
+                return Err(e);
+            }
+        };
+
+        let metadata = self
+            .decrypt(&sealed, Some(WriteMode::Atomic))
+            .map_err(|_| IntegrityError::error(&metadata_file))?;
+        *self.state.metadata.write().unwrap() = serde_json::from_slice(&metadata)?;
+
+        Ok(())
+    }
+
+    /// Is the file one that we store next to the Tantivy files, or a lock
+    /// file, as opposed to an encrypted Tantivy file.
+    fn is_unencrypted_file(&self, path: &Path) -> bool {
+        self.config.is_seshat_metadata(path)
+            || path == INDEX_WRITER_LOCK.filepath
+            || path == META_LOCK.filepath
+    }
+
+    /// The error that is returned if Tantivy tries to write to a file that we
+    /// use.
+    fn reserved_file_error() -> IoError {
+        IoError::new(
+            ErrorKind::PermissionDenied,
+            "the file name is reserved by the encrypted store",
+        )
+    }
+
+    /// Remove the temporary files that failed writes left behind.
+    ///
+    /// Only writers do this since they hold the writer lock, nobody else can
+    /// be in the middle of a write.
+    ///
+    /// Returns the names of the removed files.
+    fn remove_temporary_files(&self) -> std::io::Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
+
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let path = PathBuf::from(entry.file_name());
+
+            if StoreConfig::is_temporary(&path) && entry.metadata()?.is_file() {
+                info!("Removing the stale temporary file {}", path.display());
+                std::fs::remove_file(entry.path())?;
+                removed.push(path);
+            }
+        }
+
+        removed.sort();
+
+        Ok(removed)
+    }
+
+    /// Find the files that writers left behind unfinished and remove them if
+    /// the store configuration asks for it.
+    ///
+    /// Files are sealed once their writer gets terminated, a writer that
+    /// never got terminated leaves an empty file behind, one that got
+    /// interrupted while the sealed data was written a truncated one. Only
+    /// files that are too short to hold a header, a nonce, and the shortest
+    /// tag are recognized, telling a longer truncated file apart from a
+    /// corrupt one would require every file to be authenticated.
+    fn find_interrupted_writes(&self) -> std::io::Result<Vec<PathBuf>> {
+        let minimum_size = {
+            let provider = self.state.provider()?;
+            let tag_size = TRUNCATED_TAG_SIZES
+                .iter()
+                .map(|(_, size)| *size)
+                .fold(provider.tag_size()?, cmp::min);
+
+            (WRITE_MODE_SIZE + provider.nonce_size() + tag_size) as u64
+        };
+
+        let mut interrupted = Vec::new();
+
+        for file in self.disk_files()? {
+            let path = self.path.join(&file);
+
+            if std::fs::metadata(&path)?.len() >= minimum_size {
+                continue;
+            }
+
+            if self.config.remove_interrupted_writes {
+                info!("Removing the unfinished file {}", file.display());
+                std::fs::remove_file(&path)?;
+            } else {
+                warn!("Found the unfinished file {}", file.display());
+            }
+
+            interrupted.push(file);
+        }
+
+        interrupted.sort();
+
+        Ok(interrupted)
+    }
+
+    /// Get what opening the store cleaned up after operations that were
+    /// interrupted.
+    ///
+    /// Writers remove the temporary files that interrupted writes left
+    /// behind and finish interrupted store key rotations, a journal that
+    /// outlived its rotation is removed. Files that writers left behind
+    /// unfinished are reported, or removed if the store configuration asks
+    /// for it. Read replicas leave temporary files, unfinished files, and
+    /// interrupted rotations alone, stores in a custom directory leave
+    /// temporary and unfinished files alone, so do stores that are opened
+    /// locked. Clones of the directory report the recovery of the original.
+    #[allow(dead_code)]
+    pub fn recovery(&self) -> &Recovery {
+        &self.recovery
+    }
+
+    /// Get the underlying, unencrypted, directory.
+    ///
+    /// **Bypassing the encryption is unsafe.** Files read through the inner
+    /// directory are returned encrypted and files written through it are
+    /// stored in plaintext, which Tantivy won't be able to read through the
+    /// encrypted directory anymore. This is meant for Tantivy specific
+    /// operations that aren't covered by the `Directory` trait.
+    #[allow(dead_code)]
+    pub fn inner(&self) -> &dyn Directory {
+        &*self.inner_dir
+    }
+
+    /// Copy the store into a different directory, e.g. to move an index from
+    /// an mmap directory into a RAM directory.
+    ///
+    /// The files are copied as they are, nothing is decrypted. The encrypted
+    /// Tantivy files are written into the given directory, the key file and
+    /// the rest of our own files into the given path, the copy can be opened
+    /// using the same passphrase, see `open_or_create_with_directory()`.
+    /// Returns a directory for the copy that shares the keys of this one.
+    ///
+    /// The store key can't be rotated while the store is copied. Fails if the
+    /// files of this store can't be listed, or if there already is a store in
+    /// the given path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the key file of the copy should reside in.
+    /// * `directory` - The directory that will hold the encrypted Tantivy
+    /// files of the copy.
+    #[allow(dead_code)]
+    pub fn copy_to<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mut directory: Box<dyn Directory>,
+    ) -> Result<EncryptedMmapDirectory, OpenDirectoryError> {
+        let path = path.as_ref();
+        let provider = self.state.provider()?;
+
+        std::fs::create_dir_all(path)?;
+
+        if path.join(self.config.key_file()).exists() {
+            return Err(IoError::new(
+                ErrorKind::AlreadyExists,
+                "the destination already contains a store",
+            )
+            .into());
+        }
+
+        for file in self.disk_files()? {
+            let data = self
+                .inner_dir
+                .atomic_read(&file)
+                .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))?;
+            directory.atomic_write(&file, &data)?;
+        }
+
+        // The key file goes last, the copy can't be opened before all of its
+        // files are in place.
+        let files = [
+            self.config.counter_file(),
+            self.config.manifest_file(),
+            self.config.names_file(),
+            self.config.schema_file(),
+            self.config.key_created_file(),
+            self.config.metadata_file(),
+            self.config.wrapped_keys_file(),
+            self.config.key_slots_file(),
+            self.config.key_file(),
+        ];
+
+        for file in &files {
+            let data = match std::fs::read(self.path.join(file)) {
+                Ok(d) => d,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            self.config.write_atomically(&path.join(file), &data)?;
+        }
+
+        let state = StoreState::new(
+            provider.clone(),
+            self.state.journal_provider.read().unwrap().clone(),
+            self.state.key_wrapper.read().unwrap().clone(),
+        );
+        *state.padding.write().unwrap() = *self.state.padding.read().unwrap();
+        state.hidden_headers.store(
+            self.state.hidden_headers.load(Ordering::SeqCst),
+            Ordering::SeqCst,
+        );
+        state.tag_size_bits.store(
+            self.state.tag_size_bits.load(Ordering::SeqCst),
+            Ordering::SeqCst,
+        );
+
+        let mut copy = EncryptedMmapDirectory::with_state(
+            state,
+            self.custom_wrapper,
+            path,
+            Some(directory),
+            ReadMode::Mmap,
+            self.config.clone(),
+        )?;
+        copy.custom_provider = self.custom_provider;
+        copy.legacy_files = self.legacy_files;
+
+        Ok(copy)
+    }
+
+    /// Attach a piece of metadata to the store, e.g. the version of the
+    /// application's data format.
+    ///
+    /// The metadata is a map of strings that is encrypted and authenticated
+    /// using the store key and stored next to the key file. It's verified
+    /// whenever the store is opened, a store whose metadata was tampered with
+    /// refuses to open with an `IntegrityError`, and metadata taken from
+    /// another store fails to authenticate as well. Changing the metadata
+    /// requires the passphrase, having the store open isn't enough.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key the value should be stored under, an existing value
+    /// gets replaced.
+    /// * `value` - The value that should be stored.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    #[allow(dead_code)]
+    pub fn set_store_metadata(
+        &self,
+        key: &str,
+        value: &str,
+        passphrase: &str,
+    ) -> std::io::Result<()> {
+        self.unlock_own_key_file(passphrase)?;
+
+        let provider = self.state.provider()?;
+        let mut metadata = self.state.metadata.write().unwrap();
+
+        let mut updated = metadata.clone();
+        updated.insert(key.to_owned(), value.to_owned());
+
+        let sealed = StoreState::seal_file(
+            &**provider,
+            &serde_json::to_vec(&updated)?,
+            WriteMode::Atomic,
+        )?;
+        self.config
+            .write_atomically(&self.path.join(self.config.metadata_file()), &sealed)?;
+
+        *metadata = updated;
+
+        Ok(())
+    }
+
+    /// Get a piece of metadata that was attached to the store using
+    /// `set_store_metadata()`.
+    ///
+    /// Returns `None` if no value is stored under the given key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key the value is stored under.
+    #[allow(dead_code)]
+    pub fn get_store_metadata(&self, key: &str) -> Option<String> {
+        self.state.metadata.read().unwrap().get(key).cloned()
+    }
+
+    /// Check the health of the store in the given path without keeping it
+    /// open.
+    ///
+    /// The key file is verified using the passphrase and every encrypted file
+    /// is authenticated. Files are memory mapped and decrypted into the void,
+    /// nothing is held in memory. The store is opened like a read replica,
+    /// see `open_replica()`, so no lock is taken and nothing is written,
+    /// this works while the store is in use. Files that are still being
+    /// written are empty and aren't counted.
+    ///
+    /// Returns an error if the key file can't be read or the passphrase is
+    /// wrong, corrupt files are only counted in the report.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    #[allow(dead_code)]
+    pub fn dry_run_open<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+    ) -> Result<HealthReport, OpenDirectoryError> {
+        let dir = EncryptedMmapDirectory::open_replica(path, passphrase)?;
+        let mut report = HealthReport::default();
+
+        for file in dir.disk_files()? {
+            let source = match dir.inner_dir.open_read(&file) {
+                Ok(s) => s,
+                Err(_) => {
+                    report.total += 1;
+                    report.corrupt += 1;
+                    continue;
+                }
+            };
+
+            if source.as_slice().is_empty() {
+                continue;
+            }
+
+            report.total += 1;
+
+            if dir.authenticate(source.as_slice()).is_ok() {
+                report.readable += 1;
+            } else {
+                report.corrupt += 1;
+            }
+        }
+
+        if !report.is_healthy() {
+            warn!(
+                "Found {} corrupt files in the store in {}",
+                report.corrupt,
+                dir.path.display()
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Authenticate the given encrypted file data without keeping the
+    /// plaintext around.
+    ///
+    /// If a store key rotation is in progress, the new store key is tried as
+    /// well.
+    fn authenticate(&self, data: &[u8]) -> std::io::Result<()> {
+        let provider = self.state.provider()?;
+
+        let error = match StoreState::open_into(&**provider, data, None, &mut std::io::sink()) {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+
+        match &self.fallback_provider {
+            Some(p) => StoreState::open_into(&**p, data, None, &mut std::io::sink()).map(|_| ()),
+            None => Err(error),
+        }
+    }
+
+    /// Get the number of bytes the store occupies on disk.
+    ///
+    /// This is the sum of the sizes of all the encrypted files in the
+    /// directory, including the key file. Returns an error if the files are
+    /// stored in a custom directory.
+    #[allow(dead_code)]
+    pub fn disk_usage(&self) -> std::io::Result<u64> {
+        self.check_enumerable()?;
+        let mut total = 0;
+
+        for entry in std::fs::read_dir(&self.path)? {
+            let metadata = entry?.metadata()?;
+
+            if metadata.is_file() {
+                total += metadata.len();
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Get the number of bytes that were read and written through the
+    /// `Directory` trait since the store was opened.
+    ///
+    /// Both the plaintext and the ciphertext sizes are counted, e.g. to track
+    /// the overhead of the encryption. Files that are re-encrypted by a store
+    /// key rotation aren't counted. The counts are shared by all the clones
+    /// of the directory.
+    #[allow(dead_code)]
+    pub fn io_stats(&self) -> IoStats {
+        self.state.io_counters.stats()
+    }
+
+    /// Check the store for weak parameters.
+    ///
+    /// The key file is read again, so upgrades of the key file show up
+    /// without reopening the store. Nothing is upgraded, it's up to the
+    /// caller to ask the user to upgrade the store. Stores whose key is
+    /// wrapped by a custom key wrapper don't derive keys from a passphrase,
+    /// only their files are checked.
+    #[allow(dead_code)]
+    pub fn security_advisory(&self) -> std::io::Result<SecurityAdvisory> {
+        let mut data = Vec::new();
+        File::open(self.path.join(self.config.key_file()))?
+            .take(KEY_FILE_MAX_SIZE as u64)
+            .read_to_end(&mut data)?;
+
+        let mut advisory = SecurityAdvisory {
+            unbound_write_mode: self.legacy_files,
+            ..SecurityAdvisory::default()
+        };
+
+        if data.first() != Some(&WRAPPED_VERSION) {
+            let key_file = KeyFile::parse(&data).map_err(IoError::from)?;
+
+            if key_file.pbkdf_count < RECOMMENDED_PBKDF_COUNT {
+                advisory.low_key_derivation_count = Some(key_file.pbkdf_count);
+            }
+
+            advisory.outdated_key_file = key_file.version < CANARY_VERSION;
+        }
+
+        Ok(advisory)
+    }
+
+    /// Get the number of bytes a file with the given plaintext length grows
+    /// by once it's encrypted, e.g. to estimate the size of an encrypted
+    /// index from the size of its plaintext.
+    ///
+    /// The overhead consists of the header, the nonce and the
+    /// authentication tag of the file, and the padding if the store pads its
+    /// files. It's calculated for files that get written using the current
+    /// settings of the store, see `with_padding()`, `with_hidden_headers()`
+    /// and `with_tag_size()`, existing files might have been written using
+    /// different ones. Nothing is read from disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `plaintext_length` - The length of the plaintext of the file.
+    #[allow(dead_code)]
+    pub fn ciphertext_overhead(&self, plaintext_length: u64) -> std::io::Result<u64> {
+        let provider = self.state.provider()?;
+        let overhead = self.state.overhead(&**provider)? as u64;
+
+        match *self.state.padding.read().unwrap() {
+            Some(padding) => {
+                let size = overhead + PADDING_LENGTH_SIZE as u64 + plaintext_length;
+                Ok(padding.padded_size(size) - plaintext_length)
+            }
+            None => Ok(overhead),
+        }
+    }
+
+    /// Get the encryption parameters of the file at the given path.
+    ///
+    /// Only the header of the file is read, the file isn't authenticated.
+    /// Empty files, files that are still being written, have no header and
+    /// are reported as an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file, relative to the directory.
+    #[allow(dead_code)]
+    pub fn file_crypto_meta(&self, path: &Path) -> std::io::Result<FileCryptoMeta> {
+        let provider = self.state.provider()?;
+        let source = self
+            .inner_dir
+            .open_read(&self.disk_path(path)?)
+            .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))?;
+
+        let too_short = || {
+            IoError::new(
+                ErrorKind::InvalidData,
+                "the file is too short to be an encrypted file",
+            )
+        };
+
+        let first = source.slice(0, cmp::min(WRITE_MODE_SIZE, source.len()));
+        let header_size = StoreState::header_size(first.as_slice()) + provider.nonce_size();
+
+        if source.len() < header_size {
+            return Err(too_short());
+        }
+
+        let header = source.slice(0, header_size);
+        let (header, _) = StoreState::split(&**provider, header.as_slice(), None)?;
+
+        // The header records if the authentication tag was truncated.
+        let tag_size = StoreState::tag_size(&**provider, header.tag)?;
+        let overhead = header_size + tag_size;
+
+        if source.len() < overhead {
+            return Err(too_short());
+        }
+
+        let write_mode = match WriteMode::from_tag(header.tag) {
+            Some(WriteMode::Atomic) => "atomic",
+            Some(WriteMode::Streaming) => "streaming",
+            None => return Err(authentication_error()),
+        };
+
+        Ok(FileCryptoMeta {
+            cipher: provider.name().to_owned(),
+            write_mode,
+            nonce: header.nonce.to_vec(),
+            plaintext_length: (source.len() - overhead) as u64,
+            padded: header.tag & PADDED_FLAG != 0,
+            tag_size,
+        })
+    }
+
+    /// List the Tantivy files of the store together with their sizes.
+    ///
+    /// Only the headers of the files are read, nothing is decrypted, e.g. to
+    /// find out what takes up the space of a large index. Our own files
+    /// aren't listed, neither are files that are still being written.
+    /// Returns an error if the files are stored in a custom directory.
+    #[allow(dead_code)]
+    pub fn list_segment_files(&self) -> std::io::Result<Vec<SegmentFileInfo>> {
+        let mut infos = Vec::new();
+
+        for file in self.files()? {
+            let encrypted_size = std::fs::metadata(self.path.join(self.disk_path(&file)?))?.len();
+
+            if encrypted_size == 0 {
+                continue;
+            }
+
+            let meta = self.file_crypto_meta(&file)?;
+
+            infos.push(SegmentFileInfo {
+                name: file,
+                encrypted_size,
+                decrypted_size: meta.plaintext_length,
+                cipher: meta.cipher,
+            });
+        }
+
+        Ok(infos)
+    }
+
+    /// Re-encrypt a single file of the store using a fresh nonce.
+    ///
+    /// The file is decrypted and sealed again using the current store key,
+    /// e.g. to remediate a file that was sealed with a reused nonce, see
+    /// `audit_nonces()`. This is a lot cheaper than a store key rotation,
+    /// which re-encrypts every file. The file keeps its write mode and the
+    /// new version replaces the old one atomically, a crash leaves either
+    /// of the two behind. Other reads and writes carry on while the file is
+    /// re-encrypted, a store key rotation waits until it's replaced. The
+    /// file itself must not be written in the meantime, e.g. `meta.json` by
+    /// a commit, the re-encrypted old content would replace the new one.
+    ///
+    /// Re-encrypting `meta.json` re-encrypts its backup as well, and a
+    /// sealed manifest is updated so it keeps matching the store, see
+    /// `seal_manifest()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file, relative to the directory.
+    /// * `passphrase` - The passphrase that was used to encrypt our directory.
+    #[allow(dead_code)]
+    pub fn reencrypt_path(&self, path: &Path, passphrase: &str) -> std::io::Result<()> {
+        if self.config.is_seshat_metadata(path) {
+            return Err(EncryptedMmapDirectory::reserved_file_error());
+        }
+
+        self.unlock_own_key_file(passphrase)?;
+
+        let provider = self.state.provider()?;
+
+        let disk_path = self.disk_path(path)?;
+        let mut inner_dir = self.inner_dir.clone();
+        let data = inner_dir
+            .atomic_read(&disk_path)
+            .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))?;
+
+        if data.is_empty() {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "the file is still being written",
+            ));
+        }
+
+        let decrypted = Zeroizing::new(StoreState::open(&**provider, &data, None)?);
+        let mode = StoreState::write_mode(&**provider, &data)?;
+
+        // The backup gets the same content as the metadata file, like it
+        // does when the metadata file is written, and it goes first.
+        let mut paths = Vec::new();
+
+        if path == Path::new(META_FILE) && self.state.meta_backup.load(Ordering::SeqCst) {
+            paths.push(self.disk_path(Path::new(META_BACKUP_FILE))?);
+        }
+
+        paths.push(disk_path);
+
+        let mut hashes = Vec::new();
+
+        for path in &paths {
+            let encrypted = self.state.seal(&**provider, &decrypted, mode)?;
+            let previous = std::fs::read(self.path.join(path)).ok();
+
+            if self.custom_directory {
+                inner_dir.atomic_write(path, &encrypted)?;
+            } else {
+                self.config
+                    .write_atomically(&self.path.join(path), &encrypted)?;
+            }
+
+            if let Some(previous) = previous {
+                hashes.push((
+                    path.to_string_lossy().into_owned(),
+                    Sha256::digest(&previous).to_vec(),
+                    Sha256::digest(&encrypted).to_vec(),
+                ));
+            }
+        }
+
+        self.update_manifest(passphrase, &hashes)
+    }
+
+    /// Check that no nonce was used to seal more than one file of the store.
+    ///
+    /// Returns the nonces that were reused together with the files that were
+    /// sealed using them, the result is empty if every file uses a unique
+    /// nonce. Returns an error if the files are stored in a custom directory.
+    #[allow(dead_code)]
+    pub fn audit_nonces(&self) -> std::io::Result<Vec<NonceReuse>> {
+        let mut nonces: BTreeMap<Vec<u8>, Vec<PathBuf>> = BTreeMap::new();
+
+        for file in self.files()? {
+            // Files that are still being written have no nonce yet.
+            if std::fs::metadata(self.path.join(self.disk_path(&file)?))?.len() == 0 {
+                continue;
+            }
+
+            let meta = self.file_crypto_meta(&file)?;
+            nonces.entry(meta.nonce).or_default().push(file);
+        }
+
+        Ok(nonces
+            .into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .map(|(nonce, files)| NonceReuse { nonce, files })
+            .collect())
+    }
+
+    /// Authenticate and decrypt the given encrypted file data.
+    ///
+    /// If a store key rotation is in progress, the new store key is tried as
+    /// well.
+    fn decrypt(&self, data: &[u8], expected: Option<WriteMode>) -> std::io::Result<Vec<u8>> {
+        let provider = self.state.provider()?;
+
+        let error = match StoreState::open(&**provider, data, expected) {
+            Ok(d) => return Ok(d),
+            Err(e) => e,
+        };
+
+        if let Some(p) = &self.fallback_provider {
+            if let Ok(d) = StoreState::open(&**p, data, expected) {
+                return Ok(d);
+            }
+        }
+
+        self.open_legacy(&**provider, data, error)
+    }
+
+    /// Map the file at the given path into memory and get the provider it
+    /// was sealed with.
+    ///
+    /// The provider guard is only held while the file is opened, a store key
+    /// rotation that replaces the file afterwards doesn't affect the mapped
+    /// version. Concurrent reads decrypt their files without holding the
+    /// guard, so a waiting rotation doesn't block new reads for longer than
+    /// necessary.
+    fn open_mapped(
+        &self,
+        path: &Path,
+    ) -> Result<(Arc<dyn AeadProvider>, ReadOnlySource), OpenReadError> {
+        let provider = self.state.provider().map_err(TvIoError::from)?;
+        let path = self.disk_path(path).map_err(TvIoError::from)?;
+        let source = self.inner_dir.open_read(&path)?;
+
+        Ok((provider.clone(), source))
+    }
+
+    /// Decrypt the file at the given path and stream the plaintext into the
+    /// given writer.
+    ///
+    /// Unlike `atomic_read()` this avoids holding the whole decrypted file in
+    /// memory, unless a custom `AeadProvider` requires it. Files written in
+    /// either mode can be read. Returns the number of plaintext bytes that
+    /// were written.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file, relative to the directory.
+    /// * `sink` - The writer the decrypted file should be written to.
+    #[allow(dead_code)]
+    pub fn read_decrypted_into(
+        &self,
+        path: &Path,
+        sink: &mut dyn Write,
+    ) -> Result<u64, OpenReadError> {
+        let (provider, source) = self.open_mapped(path)?;
+
+        Ok(
+            StoreState::open_into(&*provider, source.as_slice(), None, sink)
+                .map_err(TvIoError::from)?,
+        )
+    }
+
+    /// Get a reader that decrypts the file at the given path on a background
+    /// thread, ahead of the reads.
+    ///
+    /// This is a faster alternative to `read_decrypted_into()` for sequential
+    /// reads where the consumer does a fair amount of work with every chunk.
+    /// The size of the chunks is set using `with_read_ahead()`.
+    /// The file stays readable even if the store key is rotated while the
+    /// reader is in use.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file, relative to the directory.
+    #[allow(dead_code)]
+    pub fn pipelined_reader(&self, path: &Path) -> Result<PipelinedReader, OpenReadError> {
+        // The memory map and the provider keep the current version of the
+        // file decryptable even if it gets replaced.
+        let (provider, source) = self.open_mapped(path)?;
+
+        Ok(PipelinedReader::spawn(self.read_ahead, move |writer| {
+            StoreState::open_into(&*provider, source.as_slice(), None, writer)
+        }))
+    }
+
+    /// Decrypt the given encrypted file data into a new spill file and map
+    /// it into memory.
+    fn spill(
+        spill: &Spill,
+        provider: &dyn AeadProvider,
+        data: &[u8],
+    ) -> Result<Arc<DecryptedData>, OpenReadError> {
+        let name = PathBuf::from(format!(
+            "{}.{}",
+            Uuid::new_v4().to_simple(),
+            SPILL_EXTENSION
+        ));
+        let path = spill.path.join(&name);
+
+        let result = EncryptedMmapDirectory::create_spill_file(&path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            StoreState::open_into(provider, data, Some(WriteMode::Streaming), &mut writer)?;
+            writer.flush()
+        });
+
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&path);
+            return Err(TvIoError::from(e).into());
+        }
+
+        let source = match spill.mmap_dir.open_read(&name) {
+            Ok(s) => s,
+            Err(e) => {
+                let _ = std::fs::remove_file(&path);
+                return Err(e);
+            }
+        };
+
+        let spilled: DecryptedData = Box::new(SpilledFile {
+            source: Some(source),
+            path,
+        });
+
+        Ok(Arc::new(spilled))
+    }
+
+    /// Create a new spill file that only its owner can access.
+    fn create_spill_file(path: &Path) -> std::io::Result<File> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        options.open(path)
+    }
+
+    /// Remove the spill files that were left behind in the given spill
+    /// directory.
+    fn remove_spill_files(path: &Path) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(path)? {
+            let path = entry?.path();
+
+            if path.extension().map_or(false, |e| e == SPILL_EXTENSION) {
+                debug!("Removing a leftover spill file {:?}", path);
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decrypt a file that Tantivy reads.
+    fn decrypt_file(&self, path: &Path) -> Result<Arc<DecryptedData>, OpenReadError> {
+        let decrypted = match self.read_mode {
+            ReadMode::Mmap => {
+                let (provider, source) = self.open_mapped(path)?;
+
+                if let Some(spill) = &self.spill {
+                    if source.len() as u64 > spill.threshold {
+                        // Legacy files are decrypted in memory.
+                        match EncryptedMmapDirectory::spill(spill, &*provider, &source) {
+                            Ok(spilled) => {
+                                trace!("Spilled a file of {} bytes to disk", source.len());
+                                self.state
+                                    .io_counters
+                                    .record_read(spilled.len(), source.len());
+                                return Ok(spilled);
+                            }
+                            Err(e) => {
+                                if !self.legacy_files {
+                                    return Err(e);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                let decrypted =
+                    StoreState::open(&*provider, source.as_slice(), Some(WriteMode::Streaming))
+                        .or_else(|e| self.open_legacy(&*provider, source.as_slice(), e))
+                        .map_err(|e| {
+                            debug!("Failed to decrypt a file: {}", e);
+                            TvIoError::from(IntegrityError::error(path))
+                        })?;
+                self.state
+                    .io_counters
+                    .record_read(decrypted.len(), source.len());
+                trace!(
+                    "Decrypted a file of {} bytes into {} bytes",
+                    source.len(),
+                    decrypted.len()
+                );
+
+                decrypted
+            }
+            ReadMode::Snapshot => {
+                let disk_path = self.disk_path(path).map_err(TvIoError::from)?;
+                self.read_snapshot(path, WriteMode::Streaming, || {
+                    self.inner_dir.atomic_read(&disk_path)
+                })?
+            }
+        };
+
+        Ok(Arc::new(Box::new(decrypted)))
+    }
+
+    /// Open a file for reading like `Directory::open_read()` does, the
+    /// decrypted content of the file is neither taken from nor put into the
+    /// cache of decrypted files.
+    ///
+    /// Meant for files that are sensitive enough that their plaintext should
+    /// only live as long as the returned source, see
+    /// `StoreConfig::cache_decrypted_files()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file that should be read.
+    #[allow(dead_code)]
+    pub fn open_read_uncached(&self, path: &Path) -> Result<ReadOnlySource, OpenReadError> {
+        if self.config.is_seshat_metadata(path) {
+            return Err(OpenReadError::FileDoesNotExist(path.to_owned()));
+        }
+
+        Ok(ReadOnlySource::from(self.decrypt_file(path)?))
+    }
+
+    /// Wrap a watch callback so that it's only called if the metadata file
+    /// can be decrypted.
+    ///
+    /// The callback is called on the watcher thread of the inner directory,
+    /// a panicking callback would tear the thread down and no further changes
+    /// would be reported, so panics are caught as well.
+    ///
+    /// The wrapped callback only holds on to the inner directory, which keeps
+    /// weak references to its callbacks, holding on to our directory would
+    /// keep the writer lock around until the callback is dropped.
+    fn guard_watch_callback(&self, callback: WatchCallback) -> WatchCallback {
+        let state = self.state.clone();
+        let fallback_provider = self.fallback_provider.clone();
+        let inner_dir = self.inner_dir.clone();
+        let meta_file = self
+            .disk_path(Path::new(META_FILE))
+            .unwrap_or_else(|_| PathBuf::from(META_FILE));
+
+        Box::new(move || {
+            let data = match inner_dir.atomic_read(&meta_file) {
+                Ok(d) => d,
+                Err(_) => return,
+            };
+
+            let provider = match state.provider() {
+                Ok(p) => p.clone(),
+                Err(_) => return,
+            };
+
+            let mode = Some(WriteMode::Atomic);
+            let decrypted =
+                StoreState::open(&*provider, &data, mode).or_else(|e| match &fallback_provider {
+                    Some(p) => StoreState::open(&**p, &data, mode),
+                    None => Err(e),
+                });
+
+            if decrypted.is_ok() {
+                let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| callback()));
+            }
+        })
+    }
+
+    /// Decrypt a snapshot of a file, if decryption fails a new snapshot will
+    /// be taken using the `read` closure and decryption is retried.
+    ///
+    /// The file needs to be written in the given mode, the given path is
+    /// reported if it keeps failing to authenticate.
+    fn read_snapshot<F>(
+        &self,
+        path: &Path,
+        mode: WriteMode,
+        mut read: F,
+    ) -> Result<Vec<u8>, OpenReadError>
+    where
+        F: FnMut() -> Result<Vec<u8>, OpenReadError>,
+    {
+        let mut retries = 0;
+
+        loop {
+            let data = read()?;
+
+            match self.decrypt(&data, Some(mode)) {
+                Ok(decrypted) => {
+                    self.state
+                        .io_counters
+                        .record_read(decrypted.len(), data.len());
+                    return Ok(decrypted);
+                }
+                Err(e) => {
+                    if retries >= SNAPSHOT_READ_RETRIES {
+                        debug!("Failed to decrypt a file: {}", e);
+                        return Err(TvIoError::from(IntegrityError::error(path)).into());
+                    }
+                    retries += 1;
+                }
+            }
+        }
+    }
+
+    /// Read and decrypt a file that was written using `atomic_write()`.
+    fn read_atomic_file(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
+        let disk_path = &self.disk_path(path).map_err(TvIoError::from)?;
+
+        match self.read_mode {
+            ReadMode::Mmap => {
+                let (provider, data) = {
+                    let provider = self.state.provider().map_err(TvIoError::from)?;
+                    (provider.clone(), self.inner_dir.atomic_read(disk_path)?)
+                };
+
+                let decrypted = StoreState::open(&*provider, &data, Some(WriteMode::Atomic))
+                    .or_else(|e| self.open_legacy(&*provider, &data, e))
+                    .map_err(|e| {
+                        debug!("Failed to decrypt a file: {}", e);
+                        TvIoError::from(IntegrityError::error(path))
+                    })?;
+                self.state
+                    .io_counters
+                    .record_read(decrypted.len(), data.len());
+
+                Ok(decrypted)
+            }
+            ReadMode::Snapshot => self.read_snapshot(path, WriteMode::Atomic, || {
+                self.inner_dir.atomic_read(disk_path)
+            }),
+        }
+    }
+}
+
+// Open a store using the passphrase of the installed passphrase provider.
+impl TryFrom<&Path> for EncryptedMmapDirectory {
+    type Error = OpenDirectoryError;
+
+    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+        let provider = PASSPHRASE_PROVIDER.read().unwrap().clone().ok_or_else(|| {
+            IoError::new(ErrorKind::NotFound, "no passphrase provider is installed")
+        })?;
+
+        let passphrase = Zeroizing::new(provider.passphrase(path)?);
+        EncryptedMmapDirectory::open(path, &passphrase)
+    }
+}
+
+// The Directory trait[dr] implementation for our EncryptedMmapDirectory.
+// [dr] https://docs.rs/tantivy/0.10.2/tantivy/directory/trait.Directory.html
+//
+// Every method of the trait is implemented here, including the ones that have
+// a default implementation, so none of them can reach the inner directory
+// without going through the encryption.
+impl Directory for EncryptedMmapDirectory {
+    fn open_read(&self, path: &Path) -> Result<ReadOnlySource, OpenReadError> {
+        // The writer might replace a file underneath a replica, so replicas
+        // decrypt every read.
+        if !self.config.cache_decrypted_files || self.read_mode == ReadMode::Snapshot {
+            return self.open_read_uncached(path);
+        }
+
+        if self.config.is_seshat_metadata(path) {
+            return Err(OpenReadError::FileDoesNotExist(path.to_owned()));
+        }
+
+        self.state.check_unlocked().map_err(TvIoError::from)?;
+
+        if let Some(data) = self.state.cached_file(path) {
+            trace!("Reusing a decrypted file of {} bytes", data.len());
+            return Ok(ReadOnlySource::from(data));
+        }
+
+        let data = self.decrypt_file(path)?;
+        self.state.cache_file(path, &data);
+
+        Ok(ReadOnlySource::from(data))
+    }
+
+    // The files we store next to the Tantivy files are hidden from Tantivy,
+    // they can't be read, written or deleted through the Directory trait.
+    fn delete(&self, path: &Path) -> Result<(), DeleteError> {
+        if self.config.is_seshat_metadata(path) {
+            return Err(DeleteError::FileDoesNotExist(path.to_owned()));
+        }
+
+        let disk_path = self.disk_path(path).map_err(TvIoError::from)?;
+        self.state.forget_file(path);
+
+        // Report the path Tantivy knows about.
+        match self.inner_dir.delete(&disk_path) {
+            Err(DeleteError::FileDoesNotExist(_)) => {
+                Err(DeleteError::FileDoesNotExist(path.to_owned()))
+            }
+            result => result,
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        !self.config.is_seshat_metadata(path)
+            && self
+                .disk_path(path)
+                .map_or(false, |p| self.inner_dir.exists(&p))
+    }
+
+    // Files are always written from scratch, appending to an existing file
+    // isn't possible since the whole file is authenticated by a single tag.
+    // If the file already exists it's replaced.
+    fn open_write(&mut self, path: &Path) -> Result<WritePtr, OpenWriteError> {
+        if self.config.is_seshat_metadata(path) {
+            return Err(TvIoError::from(EncryptedMmapDirectory::reserved_file_error()).into());
+        }
+
+        self.check_write_policy(path).map_err(TvIoError::from)?;
+        self.state.forget_file(path);
+
+        let path = &self.disk_path(path).map_err(TvIoError::from)?;
+
+        let writer = match self.inner_dir.open_write(path) {
+            Ok(w) => w,
+            Err(OpenWriteError::FileAlreadyExists(_)) => {
+                match self.inner_dir.delete(path) {
+                    Ok(()) | Err(DeleteError::FileDoesNotExist(_)) => (),
+                    Err(e) => {
+                        let error = IoError::new(ErrorKind::Other, e.to_string());
+                        return Err(TvIoError::from(error).into());
+                    }
+                }
+
+                self.inner_dir.open_write(path)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        let file = match writer.into_inner() {
+            Ok(f) => f,
+            Err(e) => {
+                let error = IoError::from(e);
+                return Err(TvIoError::from(error).into());
+            }
+        };
+
+        let writer = SealingWriter::new(file, self.state.clone());
+        Ok(BufWriter::new(Box::new(writer)))
+    }
+
+    fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
+        if self.config.is_seshat_metadata(path) {
+            return Err(OpenReadError::FileDoesNotExist(path.to_owned()));
+        }
+
+        match self.read_atomic_file(path) {
+            Err(OpenReadError::IOError(e))
+                if path == Path::new(META_FILE)
+                    && self.state.meta_backup.load(Ordering::SeqCst) =>
+            {
+                warn!("Failed to read the metadata file, reading its backup");
+                self.read_atomic_file(Path::new(META_BACKUP_FILE))
+                    .map_err(|_| OpenReadError::IOError(e))
+            }
+            result => result,
+        }
+    }
+
+    fn atomic_write(&mut self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        if self.config.is_seshat_metadata(path) {
+            return Err(EncryptedMmapDirectory::reserved_file_error());
+        }
+
+        self.check_write_policy(path)?;
+
+        // Every copy is sealed using its own nonce, the backup goes first.
+        let mut paths = Vec::new();
+
+        if path == Path::new(META_FILE) && self.state.meta_backup.load(Ordering::SeqCst) {
+            paths.push(self.disk_path(Path::new(META_BACKUP_FILE))?);
+        }
+
+        paths.push(self.disk_path(path)?);
+
+        {
+            let provider = self.state.provider()?;
+
+            for path in &paths {
+                let encrypted = self.state.seal(&**provider, data, WriteMode::Atomic)?;
+
+                // The mmap directory neither syncs its temporary files nor
+                // gives them names that can be told apart from Tantivy
+                // files, failed writes would leave files behind that fail to
+                // decrypt.
+                if self.custom_directory {
+                    self.inner_dir.atomic_write(path, &encrypted)?;
+                } else {
+                    self.config
+                        .write_atomically(&self.path.join(path), &encrypted)?;
+                }
+
+                self.state
+                    .io_counters
+                    .record_write(data.len(), encrypted.len());
+            }
+        }
+
+        self.maybe_rotate_store_key()
+    }
+
+    // Every failure to set up the watch is reported as an IO error, the
+    // callback only gets called once the new metadata can be decrypted.
+    fn watch(&self, watch_callback: WatchCallback) -> Result<WatchHandle, tantivy::TantivyError> {
+        let callback = self.guard_watch_callback(watch_callback);
+
+        self.inner_dir.watch(callback).map_err(|e| {
+            TvIoError::from(IoError::new(
+                ErrorKind::Other,
+                format!("unable to watch the encrypted store: {}", e),
+            ))
+            .into()
+        })
+    }
+
+    fn acquire_lock(&self, lock: &Lock) -> Result<DirectoryLock, LockError> {
+        // Releasing a lock deletes the lock file, a lock on one of our files
+        // would delete e.g. the key file.
+        if self.config.is_seshat_metadata(&lock.filepath) {
+            return Err(LockError::IOError(
+                EncryptedMmapDirectory::reserved_file_error(),
+            ));
+        }
+
+        // The lock files aren't encrypted, this is fine since they won't
+        // contain any data. For the mmap directory they will be an empty
+        // file and a lock will be
+        // placed on them using e.g. flock(2) on macOS and Linux.
+        self.inner_dir.acquire_lock(lock)
+    }
+}
+
+// This Tantivy trait is used to indicate when no more writes are expected to be
+// done on a writer. Finalizing writes the MAC, if that fails Tantivy needs to
+// know about it since the file would be unreadable.
+impl<E: NewStreamCipher + SyncStreamCipher, M: Mac, W: Write> TerminatingWrite
+    for AesWriter<E, M, W>
+{
+    fn terminate_ref(&mut self, _: AntiCallToken) -> std::io::Result<()> {
+        self.finalize()
+    }
+}
+
+#[cfg(any(test, feature = "fuzz"))]
+/// Entry points for the fuzz targets in the `fuzz` directory.
+///
+/// Every entry point feeds arbitrary bytes to our parsers and ignores the
+/// result, malformed input needs to produce an error, never a panic.
+pub mod fuzz {
+    use super::*;
+
+    /// The passphrase the key files of the seed corpus were created with.
+    pub const PASSPHRASE: &str = "wordpass";
+    /// The store key the data files of the seed corpus were sealed with.
+    pub const STORE_KEY: [u8; KEY_SIZE] = [0x42; KEY_SIZE];
+    /// Key files with a higher key derivation count are only parsed,
+    /// deriving keys using an arbitrary count would stall the fuzzer.
+    pub const MAX_PBKDF_COUNT: u32 = 100;
+
+    /// Parse the given bytes as a key file and try to decrypt the store key.
+    pub fn key_file(data: &[u8]) {
+        let _ = KeyFileHeader::parse(data);
+        let _ = EncryptedMmapDirectory::read_key_file_header(data);
+
+        if let Ok(key_file) = KeyFile::parse(data) {
+            if key_file.pbkdf_count <= MAX_PBKDF_COUNT {
+                let _ = EncryptedMmapDirectory::load_store_key(data, PASSPHRASE);
+            }
+        }
+    }
+
+    /// Authenticate and decrypt the given bytes as an encrypted data file,
+    /// both as a whole and streamed.
+    pub fn data_file(data: &[u8]) {
+        let provider = EncryptedMmapDirectory::create_provider(&STORE_KEY)
+            .expect("Can't create the provider for the fuzzed files");
+
+        let _ = StoreState::open(&*provider, data, None);
+
+        let _ = StoreState::open_into(&*provider, data, None, &mut std::io::sink());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tempfile::tempdir;
+
+    #[test]
+    fn create_new_store_and_reopen() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+        drop(dir);
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the existing store");
+        drop(dir);
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "password");
+        assert!(
+            dir.is_err(),
+            "Opened an existing store with the wrong passphrase"
+        );
+    }
+
+    #[test]
+    fn create_store_with_empty_passphrase() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "");
+        assert!(
+            dir.is_err(),
+            "Opened an existing store with the wrong passphrase"
+        );
+    }
+
+    #[test]
+    fn report_created_and_existing_store() {
+        let tmpdir = tempdir().unwrap();
+        let (dir, opened) = EncryptedMmapDirectory::open_or_create_reporting(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+        )
+        .expect("Can't create a new store");
+        assert_eq!(opened, Opened::Created);
+        drop(dir);
+
+        let (_, opened) = EncryptedMmapDirectory::open_or_create_reporting(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+        )
+        .expect("Can't open the existing store");
+        assert_eq!(opened, Opened::Existing);
+    }
+
+    #[test]
+    fn replica_retries_files_rewritten_while_reading() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+
+        dir.atomic_write(path, b"old content").unwrap();
+        let old = dir.inner_dir.atomic_read(path).unwrap();
+
+        let replica = EncryptedMmapDirectory::open_replica(tmpdir.path(), "wordpass")
+            .expect("Can't open the store as a replica");
+
+        let mut attempts = 0;
+        let data = replica
+            .read_snapshot(path, WriteMode::Atomic, || {
+                attempts += 1;
+
+                if attempts == 1 {
+                    // Simulate the writer replacing the file while the replica
+                    // is in the middle of reading it.
+                    dir.atomic_write(path, b"new content").unwrap();
+                    let new = dir.inner_dir.atomic_read(path).unwrap();
+
+                    let mut torn = old[..old.len() / 2].to_vec();
+                    torn.extend_from_slice(&new[new.len() / 2..]);
+                    Ok(torn)
+                } else {
+                    replica.inner_dir.atomic_read(path)
+                }
+            })
+            .expect("Replica didn't recover from a rewritten file");
+
+        assert_eq!(attempts, 2);
+        assert_eq!(data, b"new content");
+        assert_eq!(replica.atomic_read(path).unwrap(), b"new content");
+    }
+
+    #[test]
+    fn report_disk_usage() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let files = ["first", "second", "third"];
+
+        for (i, file) in files.iter().enumerate() {
+            dir.atomic_write(Path::new(file), &vec![0u8; i * 100])
+                .unwrap();
+        }
+
+        let expected: u64 = files
+            .iter()
+            .chain(&[KEYFILE, "seshat-index.created"])
+            .map(|f| std::fs::metadata(tmpdir.path().join(f)).unwrap().len())
+            .sum();
+
+        assert_eq!(dir.disk_usage().unwrap(), expected);
+    }
+
+    #[test]
+    fn force_open_store_with_corrupted_mac() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        drop(dir);
+
+        // Flip a bit of the MAC, it's stored after the version, the header
+        // length, the salt size, IV, salt, and the PBKDF count.
+        let key_path = tmpdir.path().join(KEYFILE);
+        let mut key_file = std::fs::read(&key_path).unwrap();
+        key_file[3 + IV_SIZE + SALT_SIZE + 4] ^= 1;
+        std::fs::write(&key_path, key_file).unwrap();
+
+        assert!(
+            EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").is_err(),
+            "Opened a store with a corrupted MAC"
+        );
+
+        let dir = EncryptedMmapDirectory::open_force(tmpdir.path(), "wordpass")
+            .expect("Can't force open the store");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+    }
+
+    #[test]
+    fn stream_decrypted_file_into_writer() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        // Make the file span a couple of copy buffers.
+        let content: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+        let path = Path::new("segment");
+        let mut writer = dir.open_write(path).unwrap();
+        writer.write_all(&content).unwrap();
+        writer.terminate().unwrap();
+
+        assert_eq!(dir.files().unwrap(), vec![PathBuf::from(path)]);
+
+        let mut sink = Vec::new();
+        let written = dir
+            .read_decrypted_into(path, &mut sink)
+            .expect("Can't stream the decrypted file");
+
+        assert_eq!(written, content.len() as u64);
+        assert_eq!(sink, content);
+    }
+
+    #[test]
+    fn open_store_from_key_file_handle() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        drop(dir);
+
+        let key_file = File::open(tmpdir.path().join(KEYFILE)).unwrap();
+        let dir = EncryptedMmapDirectory::open_with_key_file(key_file, tmpdir.path(), "wordpass")
+            .expect("Can't open the store using a key file handle");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+
+        let key_file = File::open(tmpdir.path().join(KEYFILE)).unwrap();
+        assert!(
+            EncryptedMmapDirectory::open_with_key_file(key_file, tmpdir.path(), "password")
+                .is_err(),
+            "Opened a store from a key file handle with the wrong passphrase"
+        );
+    }
+
+    #[test]
+    fn reject_concurrent_writers() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+
+        match EncryptedMmapDirectory::open(tmpdir.path(), "wordpass") {
+            Ok(_) => panic!("Opened a store that is already opened by a writer"),
+            Err(OpenDirectoryError::IoError(e)) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+            Err(e) => panic!(
+                "Opening a second writer failed with an unexpected error {}",
+                e
+            ),
+        }
+
+        // Read replicas don't need the writer lock.
+        let _replica = EncryptedMmapDirectory::open_replica(tmpdir.path(), "wordpass")
+            .expect("Can't open a replica next to a writer");
+
+        // Clones share the lock, it's only released once all of them are gone.
+        let clone = dir.clone();
+        drop(dir);
+        assert!(EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").is_err());
+        drop(clone);
+
+        let _ = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the store after the first writer was closed");
+    }
+
+    #[test]
+    fn pipelined_reader() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let content: Vec<u8> = (0..(PIPELINE_CHUNK_SIZE * 3 + 17) as u32)
+            .map(|i| i as u8)
+            .collect();
+        let path = Path::new("segment");
+        dir.atomic_write(path, &content).unwrap();
+
+        let mut decrypted = Vec::new();
+        dir.pipelined_reader(path)
+            .expect("Can't create a pipelined reader")
+            .read_to_end(&mut decrypted)
+            .expect("Can't read the file using a pipelined reader");
+        assert_eq!(decrypted, content);
+
+        // Corrupt the last byte of the nonce, that follows the write mode tag,
+        // and a byte of the ciphertext. The reader shouldn't hand out any data.
+        let original = std::fs::read(tmpdir.path().join(path)).unwrap();
+
+        for position in &[IV_SIZE, 1 + IV_SIZE] {
+            let mut encrypted = original.clone();
+            encrypted[*position] ^= 1;
+            std::fs::write(tmpdir.path().join(path), encrypted).unwrap();
+
+            let mut decrypted = Vec::new();
+            let result = dir
+                .pipelined_reader(path)
+                .expect("Can't create a pipelined reader")
+                .read_to_end(&mut decrypted);
+            assert!(result.is_err(), "Read a corrupted file");
+            assert!(decrypted.is_empty());
+        }
+    }
+
+    #[test]
+    fn limit_the_threads_of_pipelined_readers() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let content: Vec<u8> = (0..(PIPELINE_CHUNK_SIZE * (PIPELINE_DEPTH + 2)) as u32)
+            .map(|i| i as u8)
+            .collect();
+        let path = Path::new("segment");
+        dir.atomic_write(path, &content).unwrap();
+
+        // None of the readers is consumed before all of them are created,
+        // their background threads block once their chunks are ready.
+        let readers: Vec<_> = (0..2 * PIPELINE_THREADS)
+            .map(|_| {
+                let reader = dir
+                    .pipelined_reader(path)
+                    .expect("Can't create a pipelined reader");
+                assert!(PIPELINE_THREADS_RUNNING.load(Ordering::SeqCst) <= PIPELINE_THREADS);
+                reader
+            })
+            .collect();
+
+        for mut reader in readers {
+            let mut decrypted = Vec::new();
+            reader
+                .read_to_end(&mut decrypted)
+                .expect("Can't read the file using a pipelined reader");
+            assert_eq!(decrypted, content);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    // Run using `cargo test --release -- --ignored --nocapture bench_pipelined`.
+    fn bench_pipelined_reader() {
+        use std::time::Instant;
+
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let content = vec![0u8; 64 * 1024 * 1024];
+        let path = Path::new("segment");
+        dir.atomic_write(path, &content).unwrap();
+
+        // The consumer hashes the data, so there is some work to overlap the
+        // decryption with.
+        let consume = |reader: &mut dyn Read| {
+            let mut hasher = Sha256::new();
+            let mut buffer = vec![0u8; PIPELINE_CHUNK_SIZE];
+
+            loop {
+                let read = reader.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                hasher.input(&buffer[..read]);
+            }
+
+            hasher.result()
+        };
+
+        let megabytes = content.len() as f64 / (1024.0 * 1024.0);
+
+        let start = Instant::now();
+        let mut decrypted = Vec::new();
+        dir.read_decrypted_into(path, &mut decrypted).unwrap();
+        let synchronous = consume(&mut Cursor::new(decrypted));
+        let synchronous_time = start.elapsed();
+
+        let start = Instant::now();
+        let pipelined = consume(&mut dir.pipelined_reader(path).unwrap());
+        let pipelined_time = start.elapsed();
+
+        assert_eq!(synchronous, pipelined);
+
+        println!(
+            "synchronous: {:.1} MB/s, pipelined: {:.1} MB/s",
+            megabytes / synchronous_time.as_secs_f64(),
+            megabytes / pipelined_time.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn custom_key_file_name() {
+        let tmpdir = tempdir().unwrap();
+        let config = StoreConfig::new().set_key_file_basename("custom");
+        let (mut dir, _) = EncryptedMmapDirectory::open_or_create_with_config(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            &config,
+        )
+        .expect("Can't create a new store with a custom key file name");
+
+        assert!(tmpdir.path().join("custom.key").exists());
+        assert!(!tmpdir.path().join(KEYFILE).exists());
+
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+
+        // Our files are hidden from Tantivy.
+        let key_file = Path::new("custom.key");
+        assert!(!dir.exists(key_file));
+        assert!(dir.delete(key_file).is_err());
+        assert!(dir.atomic_write(key_file, b"").is_err());
+        assert!(dir.open_write(key_file).is_err());
+        assert!(dir.atomic_read(key_file).is_err());
+        assert!(tmpdir.path().join(key_file).exists());
+        assert_eq!(dir.files().unwrap(), vec![PathBuf::from(path)]);
+        drop(dir);
+
+        assert!(
+            EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").is_err(),
+            "Opened a store with a custom key file name using the default name"
+        );
+
+        let dir = EncryptedMmapDirectory::open_with_config(tmpdir.path(), "wordpass", &config)
+            .expect("Can't open the store with a custom key file name");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+    }
+
+    #[test]
+    fn reject_whitespace_passphrases() {
+        let tmpdir = tempdir().unwrap();
+
+        for passphrase in ["   ", "\t\n", " \r\n "].iter() {
+            match EncryptedMmapDirectory::open_or_create(tmpdir.path(), passphrase, PBKDF_COUNT) {
+                Ok(_) => panic!("Created a store with a whitespace only passphrase"),
+                Err(OpenDirectoryError::IoError(e)) => {
+                    assert_eq!(e.to_string(), "passphrase consists only of whitespace")
+                }
+                Err(e) => panic!("Unexpected error {}", e),
+            }
+        }
+        assert!(!tmpdir.path().join(KEYFILE).exists());
+
+        let config = StoreConfig::new().allow_whitespace_passphrase(true);
+        let (dir, _) = EncryptedMmapDirectory::open_or_create_with_config(
+            tmpdir.path(),
+            "\t\n",
+            PBKDF_COUNT,
+            &config,
+        )
+        .expect("Can't create a store with an allowed whitespace passphrase");
+        drop(dir);
+
+        assert!(EncryptedMmapDirectory::open(tmpdir.path(), "\t\n").is_err());
+        let _ = EncryptedMmapDirectory::open_with_config(tmpdir.path(), "\t\n", &config)
+            .expect("Can't open a store with an allowed whitespace passphrase");
+
+        assert!(
+            EncryptedMmapDirectory::open_with_config(tmpdir.path(), "", &config).is_err(),
+            "Opened a store with an empty passphrase"
+        );
+    }
+
+    #[test]
+    fn access_inner_directory() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+
+        let inner = dir.inner();
+        assert!(inner.exists(path));
+
+        let encrypted = inner.atomic_read(path).unwrap();
+        let header_size = WRITE_MODE_SIZE + IV_SIZE;
+        assert_eq!(encrypted.len(), header_size + b"content".len() + MAC_LENGTH);
+        assert_ne!(&encrypted[header_size..header_size + 7], b"content");
+    }
+
+    #[test]
+    fn open_store_using_passphrase_provider() {
+        struct StaticProvider;
+
+        impl PassphraseProvider for StaticProvider {
+            fn passphrase(&self, _path: &Path) -> std::io::Result<String> {
+                Ok("wordpass".to_owned())
+            }
+        }
+
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        drop(dir);
+
+        assert!(
+            EncryptedMmapDirectory::try_from(tmpdir.path()).is_err(),
+            "Opened a store without a passphrase provider"
+        );
+
+        EncryptedMmapDirectory::set_passphrase_provider(Some(Arc::new(StaticProvider)));
+        let dir = EncryptedMmapDirectory::try_from(tmpdir.path())
+            .expect("Can't open the store using the passphrase provider");
+        EncryptedMmapDirectory::set_passphrase_provider(None);
+
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+    }
+
+    #[test]
+    fn export_store_as_plaintext() {
+        use tantivy::collector::Count;
+        use tantivy::query::TermQuery;
+        use tantivy::schema::{IndexRecordOption, Schema, STRING};
+        use tantivy::Term;
+
+        let tmpdir = tempdir().unwrap();
+        let mut schema = Schema::builder();
+        let field = schema.add_text_field("body", STRING);
+        let schema = schema.build();
+
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+        let index = tantivy::Index::create(dir, schema).unwrap();
+        let mut writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        writer.add_document(tantivy::doc!(field => "test"));
+        writer.commit().unwrap();
+        drop(writer);
+        drop(index);
+
+        let plaintext = tempdir().unwrap();
+        EncryptedMmapDirectory::decrypt_to(tmpdir.path(), plaintext.path(), "wordpass")
+            .expect("Can't export the store");
+        assert!(!plaintext.path().join(KEYFILE).exists());
+
+        let dir = tantivy::directory::MmapDirectory::open(plaintext.path()).unwrap();
+        let index = tantivy::Index::open(dir).expect("Can't open the exported index");
+        let searcher = index.reader().unwrap().searcher();
+        let query = TermQuery::new(
+            Term::from_field_text(field, "test"),
+            IndexRecordOption::Basic,
+        );
+        assert_eq!(searcher.search(&query, &Count).unwrap(), 1);
+    }
+
+    #[test]
+    fn share_decrypted_files_unless_a_read_bypasses_the_cache() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let path = Path::new("segment");
+        let mut writer = dir.open_write(path).unwrap();
+        writer.write_all(b"Hello world").unwrap();
+        writer.terminate().unwrap();
+
+        // A read that bypasses the cache doesn't populate it.
+        let uncached = dir.open_read_uncached(path).unwrap();
+        assert_eq!(uncached.as_slice(), b"Hello world");
+        assert!(dir.state.cached_file(path).is_none());
+
+        // A normal read of the same directory does.
+        let first = dir.open_read(path).unwrap();
+        assert!(dir.state.cached_file(path).is_some());
+        assert_ne!(first.as_slice().as_ptr(), uncached.as_slice().as_ptr());
+
+        let second = dir.open_read(path).unwrap();
+        assert_eq!(first.as_slice().as_ptr(), second.as_slice().as_ptr());
+
+        // Nor does it take the plaintext from the cache.
+        let uncached = dir.open_read_uncached(path).unwrap();
+        assert_ne!(first.as_slice().as_ptr(), uncached.as_slice().as_ptr());
+
+        // The cache doesn't keep the plaintext alive.
+        drop(first);
+        drop(second);
+        assert!(dir.state.cached_file(path).is_none());
+
+        // A replaced file isn't read from the cache.
+        let old = dir.open_read(path).unwrap();
+        let mut writer = dir.open_write(path).unwrap();
+        writer.write_all(b"Hello there").unwrap();
+        writer.terminate().unwrap();
+
+        assert_eq!(dir.open_read(path).unwrap().as_slice(), b"Hello there");
+        assert_eq!(old.as_slice(), b"Hello world");
+    }
+
+    #[test]
+    fn turn_the_cache_of_decrypted_files_off() {
+        let tmpdir = tempdir().unwrap();
+        let config = StoreConfig::new().cache_decrypted_files(false);
+        let (mut dir, _) = EncryptedMmapDirectory::open_or_create_with_config(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            &config,
+        )
+        .expect("Can't create a new store");
+
+        let path = Path::new("segment");
+        let mut writer = dir.open_write(path).unwrap();
+        writer.write_all(b"Hello world").unwrap();
+        writer.terminate().unwrap();
+
+        let first = dir.open_read(path).unwrap();
+        let second = dir.open_read(path).unwrap();
+        assert!(dir.state.cached_file(path).is_none());
+        assert_eq!(second.as_slice(), b"Hello world");
+        assert_ne!(first.as_slice().as_ptr(), second.as_slice().as_ptr());
+    }
+
+    #[test]
+    fn spill_large_files_to_disk() {
+        let tmpdir = tempdir().unwrap();
+        let spilldir = tempdir().unwrap();
+
+        // A spill file that a crashed process left behind.
+        let leftover = spilldir.path().join("leftover.spill");
+        std::fs::write(&leftover, b"decrypted").unwrap();
+
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store")
+                .with_spill_threshold(1024, spilldir.path())
+                .expect("Can't enable spilling");
+        assert!(!leftover.exists());
+
+        let small = Path::new("small");
+        let large = Path::new("large");
+        let content: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+
+        for (path, data) in [(small, &b"content"[..]), (large, &content[..])].iter() {
+            let mut writer = dir.open_write(path).unwrap();
+            writer.write_all(data).unwrap();
+            writer.terminate().unwrap();
+        }
+
+        let spill_files = || std::fs::read_dir(spilldir.path()).unwrap().count();
+
+        let source = dir.open_read(small).unwrap();
+        assert_eq!(source.as_slice(), b"content");
+        assert_eq!(spill_files(), 0);
+
+        // The decrypted file is backed by the spill file, not by memory.
+        let source = dir.open_read(large).unwrap();
+        assert_eq!(spill_files(), 1);
+        let spill_file = std::fs::read_dir(spilldir.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(spill_file.metadata().unwrap().len(), content.len() as u64);
+        assert_eq!(source.as_slice(), &content[..]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = spill_file.metadata().unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let slice = source.slice(10, 20);
+        drop(source);
+        assert_eq!(spill_files(), 1);
+        assert_eq!(slice.as_slice(), &content[10..20]);
+
+        drop(slice);
+        assert_eq!(spill_files(), 0);
+    }
+
+    #[derive(Debug, Default)]
+    /// A test key wrapper that only unwraps the store key if the device that
+    /// holds its secret is present.
+    pub(super) struct MockHardwareWrapper {
+        pub(super) device_present: std::sync::atomic::AtomicBool,
+    }
+
+    impl KeyWrapper for MockHardwareWrapper {
+        fn name(&self) -> &str {
+            "mock-hardware"
+        }
+
+        fn wrap(&self, store_key: &[u8]) -> std::io::Result<Vec<u8>> {
+            Ok(store_key.iter().map(|b| b ^ 0x5a).collect())
+        }
+
+        fn unwrap(&self, wrapped_key: &[u8]) -> std::io::Result<Zeroizing<Vec<u8>>> {
+            if !self.device_present.load(Ordering::SeqCst) {
+                return Err(IoError::new(ErrorKind::NotFound, "device not present"));
+            }
+
+            Ok(Zeroizing::new(
+                wrapped_key.iter().map(|b| b ^ 0x5a).collect(),
+            ))
+        }
+    }
+
+    #[test]
+    fn watch_callback_survives_decryption_failures() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let callback = dir.guard_watch_callback(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            panic!("The callback failed to reload the index");
+        }));
+
+        // A metadata file that fails to decrypt doesn't reach the callback.
+        std::fs::write(tmpdir.path().join(META_FILE), b"garbage").unwrap();
+        callback();
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // A panicking callback doesn't take the watcher down with it.
+        dir.atomic_write(Path::new(META_FILE), b"{}").unwrap();
+        callback();
+        callback();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let _handle = dir
+            .watch(Box::new(|| ()))
+            .expect("Can't watch the encrypted store");
+    }
+
+    #[test]
+    fn open_write_replaces_existing_files() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("segment");
+
+        let mut writer = dir.open_write(path).unwrap();
+        writer.write_all(b"the first, longer, content").unwrap();
+        writer.terminate().unwrap();
+
+        let mut writer = dir
+            .open_write(path)
+            .expect("Can't open an existing file for writing");
+        writer.write_all(b"second").unwrap();
+        writer.terminate().unwrap();
+
+        assert_eq!(
+            std::fs::metadata(tmpdir.path().join(path)).unwrap().len(),
+            (WRITE_MODE_SIZE + IV_SIZE + b"second".len() + MAC_LENGTH) as u64
+        );
+        assert_eq!(dir.open_read(path).unwrap().as_slice(), b"second");
+    }
+
+    #[test]
+    fn fuzz_malformed_files() {
+        // Enough iterations to hit every mutation of every seed a couple of
+        // times, the fuzz targets explore further.
+        const ITERATIONS: usize = 1000;
+
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fuzz")
+            .join("corpus");
+        let seeds = |target: &str| -> Vec<Vec<u8>> {
+            let mut paths: Vec<PathBuf> = std::fs::read_dir(corpus.join(target))
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect();
+            paths.sort();
+            paths.iter().map(|p| std::fs::read(p).unwrap()).collect()
+        };
+
+        let key_files = seeds("key_file");
+        let data_files = seeds("data_file");
+
+        // The fuzzer won't get past the MAC checks without valid seeds.
+        assert!(key_files
+            .iter()
+            .any(|k| EncryptedMmapDirectory::load_store_key(&k[..], fuzz::PASSPHRASE).is_ok()));
+        let provider = EncryptedMmapDirectory::create_provider(&fuzz::STORE_KEY).unwrap();
+        assert!(data_files
+            .iter()
+            .any(|d| StoreState::open(&*provider, d, None).is_ok()));
+
+        // A xorshift generator keeps the mutations reproducible.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+
+        let targets = [
+            (&key_files, fuzz::key_file as fn(&[u8])),
+            (&data_files, fuzz::data_file as fn(&[u8])),
+        ];
+
+        for i in 0..ITERATIONS {
+            for (inputs, target) in targets.iter() {
+                let mut input = inputs[i % inputs.len()].clone();
+
+                match next() % 4 {
+                    0 if !input.is_empty() => {
+                        let position = next() % input.len();
+                        input[position] ^= 1 << (next() % 8);
+                    }
+                    1 => {
+                        let length = next() % (input.len() + 1);
+                        input.truncate(length);
+                    }
+                    2 => {
+                        let count = next() % 64;
+                        input.extend((0..count).map(|_| next() as u8));
+                    }
+                    _ if !input.is_empty() => {
+                        let position = next() % input.len();
+                        input[position] = next() as u8;
+                    }
+                    _ => (),
+                }
+
+                target(&input[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn directory_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<EncryptedMmapDirectory>();
+    }
+
+    #[test]
+    fn concurrent_reads() {
+        const THREADS: usize = 16;
+        const FILES: usize = 8;
+        const READS: usize = 50;
+
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let content = |file: usize| -> Vec<u8> { (0..10_000).map(|i| (i * file) as u8).collect() };
+
+        for file in 0..FILES {
+            let mut writer = dir.open_write(Path::new(&file.to_string())).unwrap();
+            writer.write_all(&content(file)).unwrap();
+            writer.terminate().unwrap();
+        }
+
+        let dir = Arc::new(dir);
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let dir = dir.clone();
+
+                std::thread::spawn(move || {
+                    for read in 0..READS {
+                        let file = (thread + read) % FILES;
+                        let source = dir
+                            .open_read(Path::new(&file.to_string()))
+                            .expect("Can't read a file concurrently");
+                        assert_eq!(source.as_slice(), &content(file)[..]);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().expect("A reader thread failed");
+        }
+    }
+
+    #[test]
+    fn maintain_store_using_a_combined_plan() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        let old_file = std::fs::read(tmpdir.path().join(path)).unwrap();
+        drop(dir);
+
+        let key_file = File::open(tmpdir.path().join(KEYFILE)).unwrap();
+        let (_, old_store_key) =
+            EncryptedMmapDirectory::load_store_key(key_file, "wordpass").unwrap();
+
+        let plan = MaintenancePlan::new()
+            .change_passphrase("password")
+            .upgrade_kdf(PBKDF_COUNT * 2)
+            .rotate_store_key()
+            .migrate_cipher();
+        assert!(!format!("{:?}", plan).contains("password"));
+
+        EncryptedMmapDirectory::maintain(tmpdir.path(), "wordpass", plan)
+            .expect("Can't maintain the store");
+
+        assert!(
+            EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").is_err(),
+            "Opened the store using the old passphrase"
+        );
+
+        let key_file = std::fs::read(tmpdir.path().join(KEYFILE)).unwrap();
+        let header = KeyFileHeader::parse(&key_file).unwrap();
+        assert_eq!(
+            header.protection,
+            KeyProtection::Passphrase(Cipher::Aes256Ctr)
+        );
+
+        let (wrapping_key, store_key) =
+            EncryptedMmapDirectory::load_store_key(&key_file[..], "password")
+                .expect("Can't load the store key using the new passphrase");
+        assert_eq!(wrapping_key.pbkdf_count, PBKDF_COUNT * 2);
+        assert_ne!(&store_key[..], &old_store_key[..]);
+
+        // The files were re-encrypted using the new store key.
+        assert_ne!(std::fs::read(tmpdir.path().join(path)).unwrap(), old_file);
+        assert!(!tmpdir.path().join("seshat-index.journal").exists());
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "password")
+            .expect("Can't open the store using the new passphrase");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+    }
+
+    #[test]
+    fn hide_seshat_metadata() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        dir.seal_manifest("wordpass").unwrap();
+
+        // A temporary key file that a crash left behind.
+        std::fs::write(tmpdir.path().join("seshat-index.key.tmp"), b"key").unwrap();
+
+        for file in &[
+            KEYFILE,
+            "seshat-index.key.tmp",
+            "seshat-index.lock",
+            "seshat-index.manifest",
+            "seshat-index.journal",
+        ] {
+            let file = Path::new(file);
+            assert!(dir.config.is_seshat_metadata(file));
+            assert!(!dir.exists(file), "{:?} is visible to Tantivy", file);
+        }
+
+        assert!(tmpdir.path().join(KEYFILE).exists());
+        assert!(dir.exists(path));
+        assert_eq!(dir.files().unwrap(), vec![PathBuf::from("meta.json")]);
+    }
+
+    #[test]
+    fn keep_secrets_out_of_debug_and_error_output() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+
+        let key_file = std::fs::read(tmpdir.path().join(KEYFILE)).unwrap();
+        let parsed = KeyFile::parse(&key_file).unwrap();
+        let (wrapping_key, store_key) =
+            EncryptedMmapDirectory::load_store_key(&key_file[..], "wordpass").unwrap();
+        let (encryption_key, mac_key) = expand_key(&store_key, &[]).unwrap();
+
+        let secrets: Vec<Vec<u8>> = vec![
+            store_key.to_vec(),
+            encryption_key.to_vec(),
+            mac_key.to_vec(),
+            wrapping_key.key.to_vec(),
+            wrapping_key.mac_key.to_vec(),
+            parsed.salt.clone(),
+            parsed.iv.clone(),
+        ];
+
+        // Secrets could show up as a list of numbers or hex encoded.
+        let assert_no_secrets = |output: &str| {
+            assert!(
+                !output.contains("wordpass"),
+                "{} contains the passphrase",
+                output
+            );
+
+            for secret in &secrets {
+                let hex: String = secret.iter().map(|b| format!("{:02x}", b)).collect();
+                let list = format!("{:?}", &secret[..4]);
+                let list = &list[1..list.len() - 1];
+
+                assert!(!output.contains(&hex), "{} contains a secret", output);
+                assert!(!output.contains(list), "{} contains a secret", output);
+            }
+        };
+
+        assert_no_secrets(&format!("{:?}", dir));
+        assert_no_secrets(&format!("{:?}", wrapping_key));
+        assert_no_secrets(&format!("{:?}", dir.state.provider()));
+        drop(dir);
+
+        // A wrong passphrase.
+        let error = EncryptedMmapDirectory::open(tmpdir.path(), "password").unwrap_err();
+        assert_no_secrets(&format!("{} {:?}", error, error));
+
+        // A corrupted data file.
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        let mut encrypted = std::fs::read(tmpdir.path().join(path)).unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        std::fs::write(tmpdir.path().join(path), encrypted).unwrap();
+        let error = dir.atomic_read(path).unwrap_err();
+        assert_no_secrets(&format!("{} {:?}", error, error));
+        drop(dir);
+
+        // A corrupted key file.
+        let mut corrupted = key_file.clone();
+        corrupted[1] ^= 1;
+        std::fs::write(tmpdir.path().join(KEYFILE), corrupted).unwrap();
+        let error = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap_err();
+        assert_no_secrets(&format!("{} {:?}", error, error));
+    }
+
+    /// A in-memory directory that takes a while to respond, like a directory
+    /// that is backed by a network or a database would.
+    #[derive(Clone, Debug)]
+    struct SlowDirectory(tantivy::directory::RAMDirectory);
+
+    impl SlowDirectory {
+        const LATENCY: Duration = Duration::from_millis(1);
+
+        fn new() -> Self {
+            SlowDirectory(tantivy::directory::RAMDirectory::create())
+        }
+    }
+
+    impl Directory for SlowDirectory {
+        fn open_read(&self, path: &Path) -> Result<ReadOnlySource, OpenReadError> {
+            std::thread::sleep(SlowDirectory::LATENCY);
+            self.0.open_read(path)
+        }
+
+        fn delete(&self, path: &Path) -> Result<(), DeleteError> {
+            std::thread::sleep(SlowDirectory::LATENCY);
+            self.0.delete(path)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            std::thread::sleep(SlowDirectory::LATENCY);
+            self.0.exists(path)
+        }
+
+        fn open_write(&mut self, path: &Path) -> Result<WritePtr, OpenWriteError> {
+            std::thread::sleep(SlowDirectory::LATENCY);
+            self.0.open_write(path)
+        }
+
+        fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
+            std::thread::sleep(SlowDirectory::LATENCY);
+            self.0.atomic_read(path)
+        }
+
+        fn atomic_write(&mut self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+            std::thread::sleep(SlowDirectory::LATENCY);
+            self.0.atomic_write(path, data)
+        }
+
+        fn watch(
+            &self,
+            watch_callback: WatchCallback,
+        ) -> Result<WatchHandle, tantivy::TantivyError> {
+            self.0.watch(watch_callback)
+        }
+    }
+
+    #[test]
+    fn store_files_in_a_slow_directory() {
+        use tantivy::collector::Count;
+        use tantivy::query::TermQuery;
+        use tantivy::schema::{IndexRecordOption, Schema, STRING};
+        use tantivy::Term;
+
+        let tmpdir = tempdir().unwrap();
+        let inner = SlowDirectory::new();
+
+        let (mut dir, opened) = EncryptedMmapDirectory::open_or_create_with_directory(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            Box::new(inner.clone()),
+        )
+        .expect("Can't create a new store");
+        assert_eq!(opened, Opened::Created);
+
+        let atomic = Path::new("meta.json");
+        dir.atomic_write(atomic, b"atomic content").unwrap();
+        assert_eq!(dir.atomic_read(atomic).unwrap(), b"atomic content");
+
+        let streamed = Path::new("segment");
+        let mut writer = dir.open_write(streamed).unwrap();
+        writer.write_all(b"streamed content").unwrap();
+        writer.terminate().unwrap();
+        assert_eq!(
+            dir.open_read(streamed).unwrap().as_slice(),
+            b"streamed content"
+        );
+
+        // The files only end up in the inner directory, encrypted.
+        assert!(!tmpdir.path().join(streamed).exists());
+        let encrypted = inner.atomic_read(streamed).unwrap();
+        assert!(!encrypted
+            .windows(b"streamed content".len())
+            .any(|w| w == b"streamed content"));
+
+        assert!(dir.files().is_err());
+        assert!(dir.disk_usage().is_err());
+
+        dir.delete(atomic).unwrap();
+        dir.delete(streamed).unwrap();
+        assert!(!dir.exists(streamed));
+        drop(dir);
+
+        let mut schema = Schema::builder();
+        let field = schema.add_text_field("body", STRING);
+        let schema = schema.build();
+
+        let dir = EncryptedMmapDirectory::open_or_create_with_directory(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            Box::new(inner),
+        )
+        .map(|(dir, _)| dir)
+        .expect("Can't reopen the store");
+        let index = tantivy::Index::create(dir, schema).unwrap();
+        let mut writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        writer.add_document(tantivy::doc!(field => "test"));
+        writer.commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let query = TermQuery::new(
+            Term::from_field_text(field, "test"),
+            IndexRecordOption::Basic,
+        );
+        assert_eq!(searcher.search(&query, &Count).unwrap(), 1);
+    }
+
+    #[test]
+    fn lock_the_store_after_inactivity() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store")
+                .with_auto_lock(Duration::from_millis(100))
+                .expect("Can't enable the auto lock");
+        let path = Path::new("meta.json");
+
+        dir.atomic_write(path, b"content").unwrap();
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+
+        std::thread::sleep(Duration::from_millis(500));
+
+        let error = dir
+            .atomic_write(path, b"new content")
+            .expect_err("Wrote to a locked store");
+        assert!(Locked::matches(&error));
+        assert!(dir.atomic_read(path).is_err());
+        assert!(dir.clone().atomic_read(path).is_err());
+
+        assert!(dir.unlock("password").is_err());
+        assert!(dir.atomic_read(path).is_err());
+
+        dir.unlock("wordpass").expect("Can't unlock the store");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        dir.atomic_write(path, b"new content").unwrap();
+        assert_eq!(dir.atomic_read(path).unwrap(), b"new content");
+    }
+
+    #[test]
+    fn lock_and_unlock_on_demand() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        drop(dir);
+
+        let mut dir =
+            EncryptedMmapDirectory::open_locked(tmpdir.path()).expect("Can't open a locked store");
+        assert!(dir.atomic_read(path).is_err());
+        assert!(Locked::matches(
+            &dir.atomic_write(path, b"content").unwrap_err()
+        ));
+
+        dir.unlock("wordpass").expect("Can't unlock the store");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+
+        dir.lock().unwrap();
+        assert!(Locked::matches(
+            &dir.atomic_write(path, b"content").unwrap_err()
+        ));
+        assert!(Locked::matches(&dir.rotate_store_key().unwrap_err()));
+
+        dir.unlock("wordpass")
+            .expect("Can't unlock the store again");
+        dir.atomic_write(path, b"new content").unwrap();
+        assert_eq!(dir.atomic_read(path).unwrap(), b"new content");
+    }
+
+    #[test]
+    fn retry_failed_atomic_writes() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+
+        // A directory in place of the file makes the final rename fail.
+        std::fs::create_dir(tmpdir.path().join(path)).unwrap();
+        std::fs::write(tmpdir.path().join(path).join("file"), b"").unwrap();
+        assert!(dir.atomic_write(path, b"content").is_err());
+
+        let temporaries = || {
+            std::fs::read_dir(tmpdir.path())
+                .unwrap()
+                .map(|e| PathBuf::from(e.unwrap().file_name()))
+                .filter(|p| StoreConfig::is_temporary(p))
+                .count()
+        };
+        assert_eq!(temporaries(), 0);
+
+        std::fs::remove_dir_all(tmpdir.path().join(path)).unwrap();
+        dir.atomic_write(path, b"content")
+            .expect("Can't retry the write");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        assert_eq!(dir.files().unwrap(), vec![PathBuf::from("meta.json")]);
+        drop(dir);
+
+        // A crash in the middle of a write leaves the temporary file behind.
+        std::fs::write(tmpdir.path().join("meta.json.0123.tmp"), b"half").unwrap();
+        assert_eq!(temporaries(), 1);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert_eq!(temporaries(), 0);
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+    }
+
+    #[test]
+    fn audit_file_crypto_meta() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let first = Path::new("first.json");
+        let second = Path::new("second.json");
+        dir.atomic_write(first, b"first").unwrap();
+        dir.atomic_write(second, b"second file").unwrap();
+
+        let meta = dir.file_crypto_meta(first).unwrap();
+        let data = std::fs::read(tmpdir.path().join(first)).unwrap();
+        assert_eq!(meta.cipher, "aes-256-ctr-hmac-sha256");
+        assert_eq!(meta.write_mode, "atomic");
+        assert_eq!(
+            meta.nonce,
+            &data[WRITE_MODE_SIZE..WRITE_MODE_SIZE + IV_SIZE]
+        );
+        assert_eq!(meta.plaintext_length, 5);
+        assert!(!meta.padded);
+        assert_eq!(dir.file_crypto_meta(second).unwrap().plaintext_length, 11);
+
+        assert!(dir.audit_nonces().unwrap().is_empty());
+
+        // Reuse the nonce of the first file for the second one.
+        let mut other = std::fs::read(tmpdir.path().join(second)).unwrap();
+        other[WRITE_MODE_SIZE..WRITE_MODE_SIZE + IV_SIZE]
+            .copy_from_slice(&data[WRITE_MODE_SIZE..WRITE_MODE_SIZE + IV_SIZE]);
+        std::fs::write(tmpdir.path().join(second), &other).unwrap();
+
+        let reuse = dir.audit_nonces().unwrap();
+        assert_eq!(reuse.len(), 1);
+        assert_eq!(reuse[0].nonce, meta.nonce);
+
+        let mut files = reuse[0].files.clone();
+        files.sort();
+        assert_eq!(files, vec![first.to_path_buf(), second.to_path_buf()]);
+    }
+
+    #[test]
+    fn index_open_or_create_end_to_end() {
+        use tantivy::collector::TopDocs;
+        use tantivy::query::QueryParser;
+        use tantivy::schema::{Schema, STORED, TEXT};
+
+        let tmpdir = tempdir().unwrap();
+        let mut schema = Schema::builder();
+        let field = schema.add_text_field("body", TEXT | STORED);
+        let schema = schema.build();
+
+        let (dir, opened) = EncryptedMmapDirectory::open_or_create_reporting(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+        )
+        .expect("Can't create a new store");
+        assert_eq!(opened, Opened::Created);
+
+        let index = tantivy::Index::open_or_create(dir, schema.clone()).unwrap();
+        let mut writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        writer.add_document(tantivy::doc!(field => "the quick brown fox"));
+        writer.add_document(tantivy::doc!(field => "a lazy dog"));
+        writer.commit().unwrap();
+        drop(writer);
+        drop(index);
+
+        // Tantivy's meta.json went through our atomic_write().
+        let meta = std::fs::read(tmpdir.path().join("meta.json")).unwrap();
+        assert!(serde_json::from_slice::<serde_json::Value>(&meta).is_err());
+
+        let (dir, opened) = EncryptedMmapDirectory::open_or_create_reporting(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+        )
+        .expect("Can't reopen the store");
+        assert_eq!(opened, Opened::Existing);
+
+        let index = tantivy::Index::open_or_create(dir, schema).unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let query = QueryParser::for_index(&index, vec![field])
+            .parse_query("fox")
+            .unwrap();
+        let results = searcher.search(&query, &TopDocs::with_limit(10)).unwrap();
+        assert_eq!(results.len(), 1);
+
+        let doc = searcher.doc(results[0].1).unwrap();
+        assert_eq!(
+            doc.get_first(field).and_then(|v| v.text()),
+            Some("the quick brown fox")
+        );
+    }
+
+    /// A test writer that fails every write.
+    pub(super) struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(IoError::new(ErrorKind::Other, "disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TerminatingWrite for FailingWriter {
+        fn terminate_ref(&mut self, _: AntiCallToken) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A in-memory directory whose writers fail once their buffer gets flushed,
+    /// as soon as the failure is armed.
+    #[derive(Clone, Debug)]
+    struct FailingWriteDirectory(tantivy::directory::RAMDirectory, Arc<AtomicBool>);
+
+    impl Directory for FailingWriteDirectory {
+        fn open_read(&self, path: &Path) -> Result<ReadOnlySource, OpenReadError> {
+            self.0.open_read(path)
+        }
+
+        fn delete(&self, path: &Path) -> Result<(), DeleteError> {
+            self.0.delete(path)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            self.0.exists(path)
+        }
+
+        fn open_write(&mut self, path: &Path) -> Result<WritePtr, OpenWriteError> {
+            if !self.1.load(Ordering::SeqCst) {
+                return self.0.open_write(path);
+            }
+
+            let mut writer: WritePtr = BufWriter::new(Box::new(FailingWriter));
+            writer.write_all(b"buffered").unwrap();
+            Ok(writer)
+        }
+
+        fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
+            self.0.atomic_read(path)
+        }
+
+        fn atomic_write(&mut self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+            self.0.atomic_write(path, data)
+        }
+
+        fn watch(
+            &self,
+            watch_callback: WatchCallback,
+        ) -> Result<WatchHandle, tantivy::TantivyError> {
+            self.0.watch(watch_callback)
+        }
+    }
+
+    #[test]
+    fn report_failures_to_set_up_a_writer() {
+        let tmpdir = tempdir().unwrap();
+        let armed = Arc::new(AtomicBool::new(false));
+        let (mut dir, _) = EncryptedMmapDirectory::open_or_create_with_directory(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            Box::new(FailingWriteDirectory(
+                tantivy::directory::RAMDirectory::create(),
+                armed.clone(),
+            )),
+        )
+        .expect("Can't create a new store");
+
+        // Only the writer of the segment fails, setting up the store succeeds.
+        armed.store(true, Ordering::SeqCst);
+
+        match dir.open_write(Path::new("segment")) {
+            Ok(_) => panic!("Opened a writer whose buffer can't be flushed"),
+            Err(OpenWriteError::IOError(e)) => assert!(e.to_string().contains("disk full")),
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+
+        // The directory stays usable.
+        dir.atomic_write(Path::new("meta.json"), b"content")
+            .unwrap();
+        assert_eq!(dir.atomic_read(Path::new("meta.json")).unwrap(), b"content");
+    }
+
+    #[test]
+    fn detect_schema_mismatch() {
+        use tantivy::schema::{Schema, STRING, TEXT};
+
+        let mut schema = Schema::builder();
+        schema.add_text_field("body", TEXT);
+        let schema = schema.build();
+
+        let mut other = Schema::builder();
+        other.add_text_field("body", STRING);
+        let other = other.build();
+
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store")
+            .with_schema(&schema)
+            .expect("Can't record the schema");
+        drop(dir);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .unwrap()
+            .with_schema(&schema)
+            .expect("Can't open the store using the same schema");
+
+        // The fingerprint survives a store key rotation.
+        dir.rotate_store_key().unwrap();
+        drop(dir);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        match dir.with_schema(&other) {
+            Ok(_) => panic!("Opened a store using a different schema"),
+            Err(OpenDirectoryError::IoError(e)) => assert!(SchemaMismatch::matches(&e)),
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert!(dir.with_schema(&schema).is_ok());
+    }
+
+    #[test]
+    fn read_and_migrate_legacy_files() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+        drop(dir);
+
+        // Files used to be stored as the nonce followed by the sealed data.
+        let key_file = std::fs::read(tmpdir.path().join(KEYFILE)).unwrap();
+        let (_, store_key) =
+            EncryptedMmapDirectory::load_store_key(&key_file[..], "wordpass").unwrap();
+        let provider = EncryptedMmapDirectory::create_provider(&store_key).unwrap();
+        let legacy = |data: &[u8]| {
+            // A nonce that starts with a write mode tag would authenticate in
+            // the current format as well, see `with_legacy_files()`.
+            let nonce = loop {
+                let nonce = EncryptedMmapDirectory::generate_nonce(provider.nonce_size()).unwrap();
+
+                if WriteMode::from_tag(nonce[0]).is_none() {
+                    break nonce;
+                }
+            };
+            let sealed = provider.seal(&nonce, &[], data).unwrap();
+            let mut file = nonce;
+            file.extend_from_slice(&sealed);
+            file
+        };
+
+        let meta = Path::new(META_FILE);
+        let segment = Path::new("segment.idx");
+        std::fs::write(tmpdir.path().join(meta), legacy(b"metadata")).unwrap();
+        std::fs::write(tmpdir.path().join(segment), legacy(b"segment")).unwrap();
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert!(dir.atomic_read(meta).is_err());
+        drop(dir);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .unwrap()
+            .with_legacy_files();
+        assert_eq!(dir.atomic_read(meta).unwrap(), b"metadata");
+        assert_eq!(dir.open_read(segment).unwrap().as_slice(), b"segment");
+
+        assert_eq!(dir.migrate_legacy_files().unwrap(), 2);
+        assert_eq!(dir.migrate_legacy_files().unwrap(), 0);
+        drop(dir);
+
+        // The migrated files are readable without the compatibility path, in
+        // the mode Tantivy reads them in.
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert_eq!(dir.atomic_read(meta).unwrap(), b"metadata");
+        assert_eq!(dir.open_read(segment).unwrap().as_slice(), b"segment");
+        assert!(dir.atomic_read(segment).is_err());
+    }
+
+    #[test]
+    fn wipe_store() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        dir.atomic_write(Path::new(META_FILE), b"metadata").unwrap();
+        let mut writer = dir.open_write(Path::new("segment.idx")).unwrap();
+        writer.write_all(b"segment").unwrap();
+        writer.terminate().unwrap();
+        drop(dir);
+
+        // A wrong passphrase leaves the store untouched.
+        assert!(EncryptedMmapDirectory::wipe(tmpdir.path(), "password", true).is_err());
+        assert!(tmpdir.path().join(KEYFILE).exists());
+
+        // The store can't be wiped while a writer uses it.
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert!(EncryptedMmapDirectory::wipe(tmpdir.path(), "wordpass", true).is_err());
+        drop(dir);
+
+        EncryptedMmapDirectory::wipe(tmpdir.path(), "wordpass", true)
+            .expect("Can't wipe the store");
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+
+        match EncryptedMmapDirectory::open(tmpdir.path(), "wordpass") {
+            Ok(_) => panic!("Opened a wiped store"),
+            Err(OpenDirectoryError::IoError(e)) => assert_eq!(e.kind(), ErrorKind::NotFound),
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn copy_store_into_a_ram_directory() {
+        let tmpdir = tempdir().unwrap();
+        let dest = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let meta = Path::new(META_FILE);
+        let segment = Path::new("segment.idx");
+        dir.atomic_write(meta, b"metadata").unwrap();
+        let mut writer = dir.open_write(segment).unwrap();
+        writer.write_all(b"segment").unwrap();
+        writer.terminate().unwrap();
+
+        let ram = tantivy::directory::RAMDirectory::create();
+        let copy = dir
+            .copy_to(dest.path(), Box::new(ram.clone()))
+            .expect("Can't copy the store");
+        assert_eq!(copy.atomic_read(meta).unwrap(), b"metadata");
+        assert_eq!(copy.open_read(segment).unwrap().as_slice(), b"segment");
+
+        // The files weren't decrypted on the way.
+        assert_eq!(
+            ram.atomic_read(segment).unwrap(),
+            dir.inner().atomic_read(segment).unwrap()
+        );
+
+        // Copying twice into the same path is refused.
+        assert!(dir
+            .copy_to(
+                dest.path(),
+                Box::new(tantivy::directory::RAMDirectory::create())
+            )
+            .is_err());
+        drop(copy);
+
+        let (copy, opened) = EncryptedMmapDirectory::open_or_create_with_directory(
+            dest.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            Box::new(ram),
+        )
+        .expect("Can't open the copied store");
+        assert_eq!(opened, Opened::Existing);
+        assert_eq!(copy.atomic_read(meta).unwrap(), b"metadata");
+        assert_eq!(copy.open_read(segment).unwrap().as_slice(), b"segment");
+    }
+
+    #[test]
+    fn report_weak_store_parameters() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+
+        let advisory = dir.security_advisory().unwrap();
+        assert_eq!(advisory.low_key_derivation_count, Some(PBKDF_COUNT));
+        assert!(!advisory.outdated_key_file);
+        assert!(!advisory.unbound_write_mode);
+
+        let dir = dir.with_legacy_files();
+        assert!(dir.security_advisory().unwrap().unbound_write_mode);
+        drop(dir);
+
+        EncryptedMmapDirectory::upgrade_kdf(tmpdir.path(), "wordpass", RECOMMENDED_PBKDF_COUNT)
+            .unwrap();
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert!(dir.security_advisory().unwrap().is_empty());
+
+        // Key files of the first version lack the header length, the salt size,
+        // and the canary.
+        let key_path = tmpdir.path().join(KEYFILE);
+        let key_file = std::fs::read(&key_path).unwrap();
+        let mut old = vec![crypto::VERSION];
+        old.extend_from_slice(&key_file[3..key_file.len() - 32]);
+        std::fs::write(&key_path, old).unwrap();
+
+        let advisory = dir.security_advisory().unwrap();
+        assert!(advisory.outdated_key_file);
+        assert!(!advisory.is_empty());
+    }
+
+    #[test]
+    fn write_temporary_files_into_a_custom_directory() {
+        let tmpdir = tempdir().unwrap();
+        let store = tmpdir.path().join("store");
+        let temp = tmpdir.path().join("temp");
+        std::fs::create_dir(&store).unwrap();
+        std::fs::create_dir(&temp).unwrap();
+
+        let config = StoreConfig::new().set_temp_dir(&temp);
+        let (mut dir, _) = EncryptedMmapDirectory::open_or_create_with_config(
+            &store,
+            "wordpass",
+            PBKDF_COUNT,
+            &config,
+        )
+        .expect("Can't create a store using a temporary directory");
+
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        dir.atomic_write(path, b"replaced").unwrap();
+        dir.rotate_store_key().unwrap();
+        assert_eq!(dir.atomic_read(path).unwrap(), b"replaced");
+        drop(dir);
+
+        EncryptedMmapDirectory::change_passphrase_with_config(
+            &store,
+            "wordpass",
+            "password",
+            PBKDF_COUNT,
+            &config,
+        )
+        .unwrap();
+
+        let dir = EncryptedMmapDirectory::open_with_config(&store, "password", &config).unwrap();
+        assert_eq!(dir.atomic_read(path).unwrap(), b"replaced");
+
+        // Every temporary file was moved into place.
+        assert_eq!(std::fs::read_dir(&temp).unwrap().count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuse_temporary_directory_on_another_file_system() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmpdir = tempdir().unwrap();
+        let temp = match tempfile::tempdir_in("/dev/shm") {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+
+        // There's nothing to test if both live on the same file system.
+        if std::fs::metadata(tmpdir.path()).unwrap().dev()
+            == std::fs::metadata(temp.path()).unwrap().dev()
+        {
+            return;
+        }
+
+        let config = StoreConfig::new().set_temp_dir(temp.path());
+        match EncryptedMmapDirectory::open_or_create_with_config(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            &config,
+        ) {
+            Ok(_) => panic!("Created a store using a temporary directory on another file system"),
+            Err(OpenDirectoryError::IoError(e)) => assert_eq!(e.kind(), ErrorKind::Other),
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+
+        // Nothing was written, not even the key file.
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn list_segment_files() {
+        use tantivy::schema::{Schema, STORED, TEXT};
+
+        let tmpdir = tempdir().unwrap();
+        let mut schema = Schema::builder();
+        let field = schema.add_text_field("body", TEXT | STORED);
+        let schema = schema.build();
+
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+        let index = tantivy::Index::open_or_create(dir.clone(), schema).unwrap();
+        let mut writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        writer.add_document(tantivy::doc!(field => "the quick brown fox"));
+        writer.commit().unwrap();
+        drop(writer);
+        drop(index);
+
+        let files = dir.list_segment_files().unwrap();
+        let has = |extension: &str| {
+            files
+                .iter()
+                .any(|f| f.name.extension().map_or(false, |e| e == extension))
+        };
+        assert!(files.iter().any(|f| f.name == Path::new(META_FILE)));
+        assert!(has("store"));
+        assert!(has("term"));
+        assert!(!files.iter().any(|f| f.name == Path::new(KEYFILE)));
+
+        for file in files {
+            assert_eq!(file.cipher, "aes-256-ctr-hmac-sha256");
+            assert_eq!(
+                file.encrypted_size,
+                file.decrypted_size + (WRITE_MODE_SIZE + IV_SIZE + MAC_LENGTH) as u64
+            );
+
+            // Tantivy's metadata files are written atomically.
+            if file.name.extension().map_or(false, |e| e != "json") {
+                assert_eq!(
+                    dir.open_read(&file.name).unwrap().len() as u64,
+                    file.decrypted_size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn maintain_a_copy_of_the_store() {
+        let tmpdir = tempdir().unwrap();
+        let dest = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let meta = Path::new(META_FILE);
+        let segment = Path::new("segment.idx");
+        dir.atomic_write(meta, b"metadata").unwrap();
+        let mut writer = dir.open_write(segment).unwrap();
+        writer.write_all(b"segment").unwrap();
+        writer.terminate().unwrap();
+        drop(dir);
+
+        let key_file = std::fs::read(tmpdir.path().join(KEYFILE)).unwrap();
+        let encrypted = std::fs::read(tmpdir.path().join(segment)).unwrap();
+
+        let plan = MaintenancePlan::new()
+            .change_passphrase("password")
+            .rotate_store_key();
+        EncryptedMmapDirectory::maintain_to(tmpdir.path(), dest.path(), "wordpass", plan)
+            .expect("Can't maintain a copy of the store");
+
+        let copy = EncryptedMmapDirectory::open(dest.path(), "password").unwrap();
+        assert_eq!(copy.atomic_read(meta).unwrap(), b"metadata");
+        assert_eq!(copy.open_read(segment).unwrap().as_slice(), b"segment");
+        assert_ne!(std::fs::read(dest.path().join(segment)).unwrap(), encrypted);
+        drop(copy);
+
+        // The store itself is untouched.
+        assert_eq!(
+            std::fs::read(tmpdir.path().join(KEYFILE)).unwrap(),
+            key_file
+        );
+        assert_eq!(
+            std::fs::read(tmpdir.path().join(segment)).unwrap(),
+            encrypted
+        );
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert_eq!(dir.atomic_read(meta).unwrap(), b"metadata");
+        assert_eq!(dir.open_read(segment).unwrap().as_slice(), b"segment");
+        drop(dir);
+
+        // A destination that already holds a store is refused.
+        let plan = MaintenancePlan::new().rotate_store_key();
+        assert!(
+            EncryptedMmapDirectory::maintain_to(tmpdir.path(), dest.path(), "wordpass", plan)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn open_replica_while_the_key_file_is_locked() {
+        use tantivy::collector::TopDocs;
+        use tantivy::query::QueryParser;
+        use tantivy::schema::{Schema, STORED, TEXT};
+
+        let tmpdir = tempdir().unwrap();
+        let mut schema = Schema::builder();
+        let field = schema.add_text_field("body", TEXT | STORED);
+        let schema = schema.build();
+
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+        let index = tantivy::Index::open_or_create(dir, schema).unwrap();
+        let mut writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        writer.add_document(tantivy::doc!(field => "the quick brown fox"));
+        writer.commit().unwrap();
+
+        // Hold both the writer lock and the key lock, like a passphrase change
+        // in a different process would.
+        let config = StoreConfig::new();
+        let key_lock = EncryptedMmapDirectory::lock_key_file(tmpdir.path(), &config).unwrap();
+
+        let replica = EncryptedMmapDirectory::open_replica(tmpdir.path(), "wordpass")
+            .expect("Can't open a replica while the key file is locked");
+        let replica = tantivy::Index::open(replica).unwrap();
+        let searcher = replica.reader().unwrap().searcher();
+        let query = QueryParser::for_index(&replica, vec![field])
+            .parse_query("fox")
+            .unwrap();
+        assert_eq!(
+            searcher
+                .search(&query, &TopDocs::with_limit(10))
+                .unwrap()
+                .len(),
+            1
+        );
+
+        drop(key_lock);
+        drop(writer);
+    }
+
+    #[test]
+    fn configure_read_ahead() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let content: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let path = Path::new("segment");
+        let mut writer = dir.open_write(path).unwrap();
+        writer.write_all(&content).unwrap();
+        writer.terminate().unwrap();
+
+        assert!(dir.clone().with_read_ahead(0).is_err());
+
+        for chunk_size in [1000, 4096, 1 << 20].iter() {
+            let dir = dir.clone().with_read_ahead(*chunk_size).unwrap();
+            let mut reader = dir.pipelined_reader(path).unwrap();
+
+            // Reads are served from chunks of the configured size.
+            let mut buffer = vec![0u8; 2 << 20];
+            let read = reader.read(&mut buffer).unwrap();
+            assert_eq!(read, cmp::min(*chunk_size, content.len()));
+
+            let mut decrypted = buffer[..read].to_vec();
+            reader.read_to_end(&mut decrypted).unwrap();
+            assert_eq!(decrypted, content);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    // Run using `cargo test --release -- --ignored --nocapture bench_read_ahead`.
+    fn bench_read_ahead() {
+        use std::time::Instant;
+
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let content = vec![0u8; 64 * 1024 * 1024];
+        let path = Path::new("segment");
+        let mut writer = dir.open_write(path).unwrap();
+        writer.write_all(&content).unwrap();
+        writer.terminate().unwrap();
+
+        let megabytes = content.len() as f64 / (1024.0 * 1024.0);
+
+        for chunk_size in [4 * 1024, 64 * 1024, 1024 * 1024].iter() {
+            let dir = dir.clone().with_read_ahead(*chunk_size).unwrap();
+
+            // A sequential scan in the small reads Tantivy does.
+            let start = Instant::now();
+            let mut reader = dir.pipelined_reader(path).unwrap();
+            let mut buffer = [0u8; 4096];
+            while reader.read(&mut buffer).unwrap() != 0 {}
+            let elapsed = start.elapsed();
+
+            println!(
+                "read ahead {} KiB: {:.1} MB/s",
+                chunk_size / 1024,
+                megabytes / elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    #[cfg(feature = "deterministic-rng")]
+    /// Read the contents of all the files in the given directory.
+    fn read_store_files(path: &Path) -> BTreeMap<String, Vec<u8>> {
+        std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (
+                    entry.file_name().to_string_lossy().into_owned(),
+                    std::fs::read(entry.path()).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "deterministic-rng")]
+    /// Create a small store deterministically and return the contents of all of
+    /// its files.
+    fn deterministic_store(path: &Path) -> BTreeMap<String, Vec<u8>> {
+        crate::index::encrypted_stream::reset_deterministic_rng();
+
+        {
+            let mut dir = EncryptedMmapDirectory::open_or_create(path, "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+            dir.atomic_write(Path::new("meta.json"), b"{\"segments\": []}")
+                .unwrap();
+
+            let mut writer = dir.open_write(Path::new("segment.idx")).unwrap();
+            let content: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+            writer.write_all(&content).unwrap();
+            writer.terminate().unwrap();
+        }
+
+        read_store_files(path)
+    }
+
+    #[test]
+    #[cfg(feature = "deterministic-rng")]
+    // Tantivy names its segments using random UUIDs that we don't control, so the
+    // snapshot is written through the directory directly instead of building an
+    // index. Set SESHAT_UPDATE_SNAPSHOTS to regenerate the golden directory after
+    // an intended format change.
+    fn deterministic_store_snapshot() {
+        let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("data/encrypted-snapshot");
+
+        let first = tempdir().unwrap();
+        let second = tempdir().unwrap();
+        let snapshot = deterministic_store(first.path());
+
+        assert!(!snapshot.is_empty());
+        assert_eq!(snapshot, deterministic_store(second.path()));
+
+        if std::env::var_os("SESHAT_UPDATE_SNAPSHOTS").is_some() {
+            let _ = std::fs::remove_dir_all(&golden);
+            std::fs::create_dir_all(&golden).unwrap();
+
+            for (name, content) in &snapshot {
+                std::fs::write(golden.join(name), content).unwrap();
+            }
+        }
+
+        assert!(
+            golden.exists(),
+            "The golden directory is missing, set SESHAT_UPDATE_SNAPSHOTS to create it"
+        );
+        let expected = read_store_files(&golden);
+
+        assert_eq!(snapshot, expected, "The encrypted store format changed");
+    }
+
+    #[test]
+    fn directory_methods_never_expose_plaintext() {
+        let marker = b"a plaintext marker that must never hit the disk";
+        let content: Vec<u8> = marker.iter().cycle().take(10_000).cloned().collect();
+
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let segment = Path::new("segment");
+        let meta = Path::new("meta.json");
+
+        let mut writer = dir.open_write(segment).unwrap();
+        writer.write_all(&content).unwrap();
+        writer.terminate().unwrap();
+        dir.atomic_write(meta, marker).unwrap();
+
+        assert!(dir.exists(segment));
+        assert!(dir.exists(meta));
+        assert_eq!(dir.open_read(segment).unwrap().as_slice(), &content[..]);
+        assert_eq!(dir.atomic_read(meta).unwrap(), marker.to_vec());
+
+        let handle = dir.watch(Box::new(|| {})).unwrap();
+        drop(handle);
+
+        let lock = Lock {
+            filepath: PathBuf::from(".tantivy-writer.lock"),
+            is_blocking: false,
+        };
+        drop(dir.acquire_lock(&lock).unwrap());
+
+        // Locks on our own files are refused, releasing them would delete the
+        // files.
+        let key_lock = Lock {
+            filepath: dir.config.key_file(),
+            is_blocking: false,
+        };
+        assert!(dir.acquire_lock(&key_lock).is_err());
+        assert!(tmpdir.path().join(dir.config.key_file()).exists());
+
+        let key_file = dir.config.key_file();
+        assert!(!dir.exists(&key_file));
+        assert!(dir.open_read(&key_file).is_err());
+        assert!(dir.atomic_read(&key_file).is_err());
+        assert!(dir.delete(&key_file).is_err());
+        assert!(dir.open_write(&key_file).is_err());
+        assert!(dir.atomic_write(&key_file, b"").is_err());
+
+        for entry in std::fs::read_dir(tmpdir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            let data = std::fs::read(&path).unwrap();
+            assert!(
+                !data.windows(marker.len()).any(|w| w == &marker[..]),
+                "{:?} contains plaintext",
+                path
+            );
+        }
+
+        dir.delete(segment).unwrap();
+        assert!(!dir.exists(segment));
+        assert!(dir.open_read(segment).is_err());
+    }
+
+    #[test]
+    fn open_a_key_file_without_an_index() {
+        let tmpdir = tempdir().unwrap();
+        drop(
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store"),
+        );
+
+        // By default the key file is reused for a fresh index.
+        let (dir, opened) = EncryptedMmapDirectory::open_or_create_with_config(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            &StoreConfig::new(),
+        )
+        .unwrap();
+        assert_eq!(opened, Opened::Existing);
+        drop(dir);
+
+        let config = StoreConfig::new().refuse_orphan_key_file(true);
+
+        match EncryptedMmapDirectory::open_or_create_with_config(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            &config,
+        ) {
+            Err(OpenDirectoryError::IoError(e)) => assert_eq!(e.kind(), ErrorKind::NotFound),
+            _ => panic!("Opened a key file without an index"),
+        }
+
+        match EncryptedMmapDirectory::open_with_config(tmpdir.path(), "wordpass", &config) {
+            Err(OpenDirectoryError::IoError(e)) => assert_eq!(e.kind(), ErrorKind::NotFound),
+            _ => panic!("Opened a key file without an index"),
+        }
+
+        // A store that has an index opens fine.
+        let mut dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        dir.atomic_write(Path::new(META_FILE), b"{}").unwrap();
+        drop(dir);
+
+        EncryptedMmapDirectory::open_with_config(tmpdir.path(), "wordpass", &config).unwrap();
+
+        // Creating a new store isn't affected.
+        let other = tempdir().unwrap();
+        let (_, opened) = EncryptedMmapDirectory::open_or_create_with_config(
+            other.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(opened, Opened::Created);
+    }
+
+    #[test]
+    fn reencrypt_a_single_file() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store")
+                .with_meta_backup()
+                .unwrap();
+
+        let content: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let segment = Path::new("segment");
+        let mut writer = dir.open_write(segment).unwrap();
+        writer.write_all(&content).unwrap();
+        writer.terminate().unwrap();
+
+        let meta = Path::new("meta.json");
+        dir.atomic_write(meta, b"{}").unwrap();
+        dir.seal_manifest("wordpass").unwrap();
+
+        let read = |dir: &EncryptedMmapDirectory, path: &Path| {
+            if path == meta {
+                dir.atomic_read(path).unwrap()
+            } else {
+                dir.open_read(path).unwrap().as_slice().to_vec()
+            }
+        };
+
+        for path in &[segment, meta] {
+            let before = dir.file_crypto_meta(path).unwrap();
+            let expected = read(&dir, path);
+
+            assert!(dir.reencrypt_path(path, "password").is_err());
+            assert_eq!(dir.file_crypto_meta(path).unwrap().nonce, before.nonce);
+
+            dir.reencrypt_path(path, "wordpass").unwrap();
+
+            let after = dir.file_crypto_meta(path).unwrap();
+            assert_ne!(after.nonce, before.nonce);
+            assert_eq!(after.write_mode, before.write_mode);
+            assert_eq!(read(&dir, path), expected);
+
+            // The manifest is updated together with the file.
+            assert!(dir.verify_manifest("wordpass").unwrap().modified.is_empty());
+        }
+
+        // The backup of the metadata file got a fresh nonce as well and still
+        // holds the same content.
+        let backup = Path::new(META_BACKUP_FILE);
+        assert_ne!(
+            dir.file_crypto_meta(backup).unwrap().nonce,
+            dir.file_crypto_meta(meta).unwrap().nonce
+        );
+        assert_eq!(
+            dir.read_atomic_file(backup).unwrap(),
+            dir.atomic_read(meta).unwrap()
+        );
+
+        assert!(dir.audit_nonces().unwrap().is_empty());
+        assert!(dir
+            .reencrypt_path(&dir.config.key_file(), "wordpass")
+            .is_err());
+
+        drop(dir);
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert_eq!(dir.open_read(segment).unwrap().as_slice(), &content[..]);
+    }
+
+    /// A logger that keeps every message, so tests can check what gets logged.
+    struct CapturingLogger(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", record.level(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    lazy_static! {
+        static ref LOGGER: CapturingLogger = CapturingLogger(std::sync::Mutex::new(Vec::new()));
+    }
+
+    #[test]
+    fn log_the_store_lifecycle() {
+        if log::set_logger(&*LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+
+        let passphrase = "a secret wordpass";
+        let content = b"some secret content of the store";
+
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().display().to_string();
+
+        {
+            let mut dir =
+                EncryptedMmapDirectory::open_or_create(tmpdir.path(), passphrase, PBKDF_COUNT)
+                    .expect("Can't create a new store");
+            let mut writer = dir.open_write(Path::new("segment")).unwrap();
+            writer.write_all(content).unwrap();
+            writer.terminate().unwrap();
+        }
+
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), passphrase, PBKDF_COUNT)
+            .expect("Can't reopen the store");
+        assert_eq!(
+            dir.open_read(Path::new("segment")).unwrap().as_slice(),
+            &content[..]
+        );
+        assert!(EncryptedMmapDirectory::open(tmpdir.path(), "password").is_err());
+
+        let messages = LOGGER.0.lock().unwrap().clone();
+        let logged = |expected: &str| messages.iter().any(|m| m.contains(expected));
+
+        assert!(logged(&format!("INFO Creating a new store in {}", path)));
+        assert!(logged(&format!(
+            "DEBUG Found the key file of the store in {}",
+            path
+        )));
+        assert!(logged("using PBKDF2 with"));
+        assert!(logged("DEBUG Verified the MAC of the key file"));
+        assert!(logged("DEBUG Failed to decrypt the store key"));
+        assert!(logged(&format!("DEBUG Opened the store in {}", path)));
+        assert!(logged(&format!(
+            "TRACE Decrypted a file of {} bytes",
+            WRITE_MODE_SIZE + IV_SIZE + content.len() + MAC_LENGTH
+        )));
+
+        // Other tests log concurrently, none of them uses our secrets.
+        for message in &messages {
+            assert!(!message.contains(passphrase));
+            assert!(!message.contains("secret content"));
+        }
+    }
+
+    #[test]
+    fn check_the_health_of_a_store() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        for name in &["first", "second", "third"] {
+            let mut writer = dir.open_write(Path::new(name)).unwrap();
+            writer.write_all(&vec![7u8; 10_000]).unwrap();
+            writer.terminate().unwrap();
+        }
+        dir.atomic_write(Path::new("meta.json"), b"{}").unwrap();
+
+        // A file that is still being written.
+        let _writer = dir.open_write(Path::new("fourth")).unwrap();
+
+        let report = EncryptedMmapDirectory::dry_run_open(tmpdir.path(), "wordpass").unwrap();
+        assert_eq!(
+            report,
+            HealthReport {
+                total: 4,
+                readable: 4,
+                corrupt: 0,
+            }
+        );
+        assert!(report.is_healthy());
+
+        let path = tmpdir.path().join("second");
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        std::fs::write(&path, &data).unwrap();
+
+        // The check works while the store is open.
+        let report = EncryptedMmapDirectory::dry_run_open(tmpdir.path(), "wordpass").unwrap();
+        assert_eq!(
+            report,
+            HealthReport {
+                total: 4,
+                readable: 3,
+                corrupt: 1,
+            }
+        );
+        assert!(!report.is_healthy());
+
+        assert!(EncryptedMmapDirectory::dry_run_open(tmpdir.path(), "password").is_err());
+    }
+
+    #[test]
+    fn clean_up_after_interrupted_operations() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        assert!(dir.recovery().is_empty());
+
+        let files = ["first", "second", "third"];
+        for file in files.iter() {
+            dir.atomic_write(Path::new(file), file.as_bytes()).unwrap();
+        }
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            dir.rotate_store_key_with_progress(|done, _| {
+                if done == 1 {
+                    panic!("Aborting the rotation");
+                }
+            })
+        }));
+        assert!(result.is_err());
+        drop(dir);
+
+        let journal_path = tmpdir.path().join("seshat-index.journal");
+        let journal = std::fs::read(&journal_path).unwrap();
+        std::fs::write(tmpdir.path().join("first.0123.tmp"), b"half").unwrap();
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the store after an interrupted rotation");
+        assert_eq!(
+            dir.recovery(),
+            &Recovery {
+                removed_temporary_files: vec![PathBuf::from("first.0123.tmp")],
+                resumed_rotation: true,
+                removed_stale_journal: false,
+                interrupted_writes: Vec::new(),
+            }
+        );
+        assert!(!tmpdir.path().join("first.0123.tmp").exists());
+        assert!(!journal_path.exists());
+
+        for file in files.iter() {
+            assert_eq!(dir.atomic_read(Path::new(file)).unwrap(), file.as_bytes());
+        }
+        drop(dir);
+
+        // The old journal is sealed with the keys of the previous key file, it
+        // belongs to a rotation that already finished.
+        std::fs::write(&journal_path, &journal).unwrap();
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the store with a stale journal");
+        assert_eq!(
+            dir.recovery(),
+            &Recovery {
+                removed_stale_journal: true,
+                ..Recovery::default()
+            }
+        );
+        assert!(!journal_path.exists());
+
+        for file in files.iter() {
+            assert_eq!(dir.atomic_read(Path::new(file)).unwrap(), file.as_bytes());
+        }
+    }
+
+    #[test]
+    fn isolate_indexes_that_share_a_store_key() {
+        let tmpdir = tempdir().unwrap();
+        let (mut first, opened) = EncryptedMmapDirectory::open_or_create_index(
+            tmpdir.path(),
+            "wordpass",
+            "first-room",
+            PBKDF_COUNT,
+        )
+        .expect("Can't create the first index");
+        assert_eq!(opened, Opened::Created);
+
+        let (mut second, opened) = EncryptedMmapDirectory::open_or_create_index(
+            tmpdir.path(),
+            "wordpass",
+            "second-room",
+            PBKDF_COUNT,
+        )
+        .expect("Can't create the second index");
+        assert_eq!(opened, Opened::Existing);
+
+        let path = Path::new("meta.json");
+        first.atomic_write(path, b"first").unwrap();
+        second.atomic_write(path, b"second").unwrap();
+
+        assert!(tmpdir.path().join(KEYFILE).exists());
+        assert!(!tmpdir.path().join(path).exists());
+
+        // Both indexes are encrypted using the same store key, but a file of one
+        // index can't be read as a file of the other.
+        let first_path = tmpdir.path().join("first-room").join(path);
+        let second_path = tmpdir.path().join("second-room").join(path);
+        std::fs::copy(&first_path, &second_path).unwrap();
+        assert!(second.atomic_read(path).is_err());
+        assert_eq!(first.atomic_read(path).unwrap(), b"first");
+
+        // The store key is shared, rotating it would break the other indexes.
+        assert!(first.rotate_store_key().is_err());
+        drop(first);
+
+        let first = EncryptedMmapDirectory::open_index(tmpdir.path(), "wordpass", "first-room")
+            .expect("Can't reopen the first index");
+        assert_eq!(first.atomic_read(path).unwrap(), b"first");
+        assert!(
+            EncryptedMmapDirectory::open_index(tmpdir.path(), "password", "first-room").is_err()
+        );
+        assert!(EncryptedMmapDirectory::open_index(tmpdir.path(), "wordpass", "missing").is_err());
+
+        for id in ["", "..", "a/b", "seshat-index.key"].iter() {
+            assert!(EncryptedMmapDirectory::open_or_create_index(
+                tmpdir.path(),
+                "wordpass",
+                id,
+                PBKDF_COUNT
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn commit_the_key_file_after_the_setup() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::create_uncommitted(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create an uncommitted store");
+
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"initial index").unwrap();
+        assert_eq!(dir.atomic_read(path).unwrap(), b"initial index");
+
+        assert!(!tmpdir.path().join(KEYFILE).exists());
+        assert!(dir.rotate_store_key().is_err());
+
+        dir.commit_key_file().expect("Can't commit the key file");
+        assert!(tmpdir.path().join(KEYFILE).exists());
+        dir.commit_key_file()
+            .expect("Can't commit the key file twice");
+        drop(dir);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the committed store");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"initial index");
+        drop(dir);
+
+        assert!(
+            EncryptedMmapDirectory::create_uncommitted(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .is_err()
+        );
+
+        // A store whose setup failed leaves no key file behind.
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::create_uncommitted(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create an uncommitted store");
+        dir.atomic_write(path, b"half an index").unwrap();
+        drop(dir);
+
+        assert!(EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").is_err());
+    }
+
+    #[test]
+    fn identify_files_that_fail_to_authenticate() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        for i in 0..4 {
+            let path = PathBuf::from(format!("segment-{}", i));
+            let mut writer = dir.open_write(&path).unwrap();
+            writer.write_all(b"segment data").unwrap();
+            writer.terminate().unwrap();
+        }
+        dir.atomic_write(Path::new("meta.json"), b"{}").unwrap();
+
+        let corrupt = |name: &str| {
+            let path = tmpdir.path().join(name);
+            let mut encrypted = std::fs::read(&path).unwrap();
+            let last = encrypted.len() - 1;
+            encrypted[last] ^= 0x01;
+            std::fs::write(&path, encrypted).unwrap();
+        };
+        corrupt("segment-2");
+        corrupt("meta.json");
+
+        for i in [0, 1, 3].iter() {
+            let path = PathBuf::from(format!("segment-{}", i));
+            assert_eq!(dir.open_read(&path).unwrap().as_slice(), b"segment data");
+        }
+
+        let replica = EncryptedMmapDirectory::open_replica(tmpdir.path(), "wordpass").unwrap();
+
+        for dir in [&dir, &replica].iter() {
+            let error = dir.open_read(Path::new("segment-2")).err().unwrap();
+            assert!(error
+                .to_string()
+                .contains("the file segment-2 failed to authenticate"));
+
+            let error = dir.atomic_read(Path::new("meta.json")).unwrap_err();
+            assert!(error
+                .to_string()
+                .contains("the file meta.json failed to authenticate"));
+        }
+
+        let error = IntegrityError::error(Path::new("segment-2"));
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            IntegrityError::from_io_error(&error),
+            Some(&IntegrityError {
+                path: PathBuf::from("segment-2")
+            })
+        );
+        assert_eq!(IntegrityError::from_io_error(&authentication_error()), None);
+    }
+
+    #[test]
+    fn authenticate_the_metadata_of_the_store() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+
+        assert_eq!(dir.get_store_metadata("format"), None);
+        assert!(dir.set_store_metadata("format", "3", "password").is_err());
+
+        dir.set_store_metadata("format", "3", "wordpass").unwrap();
+        dir.set_store_metadata("app", "chat", "wordpass").unwrap();
+        dir.set_store_metadata("format", "4", "wordpass").unwrap();
+        assert_eq!(dir.get_store_metadata("format"), Some("4".to_owned()));
+        drop(dir);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert_eq!(dir.get_store_metadata("format"), Some("4".to_owned()));
+        assert_eq!(dir.get_store_metadata("app"), Some("chat".to_owned()));
+
+        dir.rotate_store_key().unwrap();
+        drop(dir);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert_eq!(dir.get_store_metadata("format"), Some("4".to_owned()));
+        drop(dir);
+
+        let metadata_path = tmpdir.path().join("seshat-index.metadata");
+        let sealed = std::fs::read(&metadata_path).unwrap();
+
+        let mut tampered = sealed.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        std::fs::write(&metadata_path, tampered).unwrap();
+
+        match EncryptedMmapDirectory::open(tmpdir.path(), "wordpass") {
+            Err(OpenDirectoryError::IoError(e)) => assert_eq!(
+                IntegrityError::from_io_error(&e),
+                Some(&IntegrityError {
+                    path: PathBuf::from("seshat-index.metadata")
+                })
+            ),
+            _ => panic!("Opened a store with tampered metadata"),
+        }
+
+        // The metadata of another store doesn't authenticate either.
+        let other = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(other.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+        drop(dir);
+        std::fs::write(other.path().join("seshat-index.metadata"), sealed).unwrap();
+
+        assert!(EncryptedMmapDirectory::open(other.path(), "wordpass").is_err());
+    }
+
+    #[test]
+    fn recover_the_metadata_file_from_its_backup() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store")
+                .with_meta_backup()
+                .unwrap();
+
+        let meta = Path::new(META_FILE);
+        dir.atomic_write(meta, b"first").unwrap();
+        dir.atomic_write(meta, b"second").unwrap();
+
+        let primary = std::fs::read(tmpdir.path().join(META_FILE)).unwrap();
+        let backup = std::fs::read(tmpdir.path().join(META_BACKUP_FILE)).unwrap();
+        assert_ne!(primary, backup);
+
+        let mut corrupted = primary.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0x01;
+        std::fs::write(tmpdir.path().join(META_FILE), &corrupted).unwrap();
+
+        assert_eq!(dir.atomic_read(meta).unwrap(), b"second");
+
+        let replica = EncryptedMmapDirectory::open_replica(tmpdir.path(), "wordpass")
+            .unwrap()
+            .with_meta_backup()
+            .unwrap();
+        assert_eq!(replica.atomic_read(meta).unwrap(), b"second");
+
+        // Without the backup the corruption is reported.
+        let other = EncryptedMmapDirectory::open_replica(tmpdir.path(), "wordpass").unwrap();
+        let error = other.atomic_read(meta).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("the file meta.json failed to authenticate"));
+
+        // Both copies can't be lost.
+        std::fs::write(tmpdir.path().join(META_BACKUP_FILE), &corrupted).unwrap();
+        let error = dir.atomic_read(meta).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("the file meta.json failed to authenticate"));
+
+        // The next write repairs both copies.
+        dir.atomic_write(meta, b"third").unwrap();
+        assert_eq!(dir.atomic_read(meta).unwrap(), b"third");
+        std::fs::write(tmpdir.path().join(META_FILE), &corrupted).unwrap();
+        assert_eq!(dir.atomic_read(meta).unwrap(), b"third");
+    }
+
+    #[test]
+    fn handle_files_of_interrupted_writers() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let mut writer = dir.open_write(Path::new("complete")).unwrap();
+        writer.write_all(b"segment data").unwrap();
+        writer.terminate().unwrap();
+
+        // A writer that never got terminated leaves an empty file behind.
+        let writer = dir.open_write(Path::new("empty")).unwrap();
+        std::mem::forget(writer);
+        drop(dir);
+
+        // A writer that got interrupted while the sealed data was written.
+        let complete = std::fs::read(tmpdir.path().join("complete")).unwrap();
+        std::fs::write(
+            tmpdir.path().join("truncated"),
+            &complete[..WRITE_MODE_SIZE + IV_SIZE + 4],
+        )
+        .unwrap();
+
+        let unfinished = vec![PathBuf::from("empty"), PathBuf::from("truncated")];
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open a store with unfinished files");
+        assert_eq!(dir.recovery().interrupted_writes, unfinished);
+        assert!(tmpdir.path().join("empty").exists());
+        assert!(tmpdir.path().join("truncated").exists());
+        drop(dir);
+
+        // Read replicas leave them alone.
+        let replica = EncryptedMmapDirectory::open_replica(tmpdir.path(), "wordpass").unwrap();
+        assert!(replica.recovery().is_empty());
+        drop(replica);
+
+        let config = StoreConfig::new().remove_interrupted_writes(true);
+        let dir = EncryptedMmapDirectory::open_with_config(tmpdir.path(), "wordpass", &config)
+            .expect("Can't open a store with unfinished files");
+        assert_eq!(dir.recovery().interrupted_writes, unfinished);
+        assert!(!tmpdir.path().join("empty").exists());
+        assert!(!tmpdir.path().join("truncated").exists());
+
+        assert_eq!(
+            dir.open_read(Path::new("complete")).unwrap().as_slice(),
+            b"segment data"
+        );
+        dir.rotate_store_key().unwrap();
+        drop(dir);
+
+        let dir =
+            EncryptedMmapDirectory::open_with_config(tmpdir.path(), "wordpass", &config).unwrap();
+        assert!(dir.recovery().is_empty());
+    }
+
+    #[derive(Debug)]
+    /// A test write policy that only allows the files of Tantivy indexes.
+    struct TantivyFilesOnly;
+
+    impl WritePolicy for TantivyFilesOnly {
+        fn allows_write(&self, path: &Path) -> bool {
+            const EXTENSIONS: [&str; 8] = [
+                "idx",
+                "pos",
+                "term",
+                "store",
+                "fast",
+                "fieldnorm",
+                "del",
+                "json",
+            ];
+
+            path == Path::new(MANAGED_FILE)
+                || path
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .map_or(false, |e| EXTENSIONS.contains(&e))
+        }
+    }
+
+    #[test]
+    fn refuse_writes_the_write_policy_disallows() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store")
+                .with_write_policy(Arc::new(TantivyFilesOnly));
+
+        dir.atomic_write(Path::new(META_FILE), b"{}").unwrap();
+        let mut writer = dir.open_write(Path::new("segment.idx")).unwrap();
+        writer.write_all(b"segment data").unwrap();
+        writer.terminate().unwrap();
+
+        let error = dir
+            .atomic_write(Path::new("exfil.bin"), b"secrets")
+            .unwrap_err();
+        assert!(WriteRefused::matches(&error));
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+        match dir.open_write(Path::new("exfil.bin")) {
+            Ok(_) => panic!("Opened a writer the write policy disallows"),
+            Err(OpenWriteError::IOError(e)) => assert!(e
+                .to_string()
+                .contains("the write policy refused to write the file exfil.bin")),
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+        assert!(!tmpdir.path().join("exfil.bin").exists());
+
+        // Clones keep the policy.
+        let mut clone = dir.clone();
+        assert!(clone
+            .atomic_write(Path::new("exfil.bin"), b"secrets")
+            .is_err());
+
+        assert_eq!(dir.atomic_read(Path::new(META_FILE)).unwrap(), b"{}");
+        assert_eq!(
+            dir.open_read(Path::new("segment.idx")).unwrap().as_slice(),
+            b"segment data"
+        );
+
+        // Our own files aren't subject to the policy.
+        dir.rotate_store_key().unwrap();
+        assert_eq!(dir.atomic_read(Path::new(META_FILE)).unwrap(), b"{}");
+    }
+}
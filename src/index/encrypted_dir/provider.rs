@@ -0,0 +1,987 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The AEAD providers that seal and open the files of a store.
+
+use super::*;
+
+/// Set once the self test passed, it only needs to run once per process.
+#[cfg(feature = "self-test")]
+static SELF_TEST_PASSED: AtomicBool = AtomicBool::new(false);
+
+/// A source of the data key that encrypts the Tantivy files of a store, the
+/// key is resolved while files are read and written instead of being held
+/// by the directory.
+///
+/// This supports architectures where the data key never persists locally,
+/// e.g. envelope encryption where the data key is fetched from a remote KMS,
+/// see `EncryptedMmapDirectory::with_key_provider()`.
+pub trait KeyProvider: std::fmt::Debug + Send + Sync {
+    /// Fetch the data key, it needs to be `KEY_SIZE` bytes long.
+    ///
+    /// Files that were sealed using a different data key can't be read, the
+    /// provider needs to return the same key every time.
+    fn data_key(&self) -> std::io::Result<Zeroizing<Vec<u8>>>;
+}
+
+/// An authenticated encryption algorithm that is used to encrypt and
+/// authenticate the files of an `EncryptedMmapDirectory`.
+///
+/// This decouples the choice of the algorithm from the directory logic. The
+/// directory generates a random nonce for every file it writes and stores it,
+/// together with a tag that records how the file was written, in front of
+/// the sealed data. The tag is used as the additional authenticated data:
+///
+/// ```text
+///     file_data = (mode || nonce || seal(nonce, mode, plaintext))
+/// ```
+pub trait AeadProvider: std::fmt::Debug + Send + Sync {
+    /// The size of the nonce, in bytes, the algorithm expects.
+    fn nonce_size(&self) -> usize;
+
+    /// An identifier of the algorithm, it's reported when the files of a
+    /// store are audited, see `EncryptedMmapDirectory::file_crypto_meta()`.
+    fn name(&self) -> &str {
+        "custom"
+    }
+
+    /// The cipher the algorithm uses, if it's one of the ciphers a key file
+    /// can declare.
+    ///
+    /// A store whose key file declares a different cipher, or IVs of a
+    /// different size than the nonces of the algorithm, refuses to read and
+    /// write its files with a cipher mismatch. The default implementation
+    /// doesn't use any of them, the files aren't cross-checked.
+    fn cipher(&self) -> Option<Cipher> {
+        None
+    }
+
+    /// Encrypt and authenticate the given plaintext.
+    ///
+    /// The returned ciphertext needs to include the authentication tag.
+    fn seal(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> std::io::Result<Vec<u8>>;
+
+    /// Authenticate and decrypt the given ciphertext.
+    ///
+    /// Should return an error if the ciphertext, the nonce, or the additional
+    /// authenticated data was tampered with.
+    fn open(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> std::io::Result<Vec<u8>>;
+
+    /// Authenticate and decrypt the given ciphertext into the given writer.
+    ///
+    /// Returns the number of plaintext bytes that were written. Nothing
+    /// should be written if the authentication fails. The default
+    /// implementation decrypts the whole ciphertext in memory using `open()`.
+    fn open_into(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+        sink: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        let plaintext = self.open(nonce, aad, ciphertext)?;
+        sink.write_all(&plaintext)?;
+        Ok(plaintext.len() as u64)
+    }
+
+    /// Encrypt and authenticate the plaintext the given reader produces, the
+    /// ciphertext is streamed into the given writer.
+    ///
+    /// Returns the number of plaintext bytes that were sealed. The default
+    /// implementation reads the whole plaintext into memory and uses
+    /// `seal()`.
+    fn seal_into(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &mut dyn Read,
+        sink: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        let mut buffer = Zeroizing::new(Vec::new());
+        plaintext.read_to_end(&mut buffer)?;
+        sink.write_all(&self.seal(nonce, aad, &buffer)?)?;
+        Ok(buffer.len() as u64)
+    }
+
+    /// Start sealing a plaintext that is handed over piece by piece, e.g. by
+    /// the writers `EncryptedMmapDirectory::open_write()` hands out.
+    ///
+    /// Every piece is encrypted in place, the ciphertext needs to be followed
+    /// by the tag the stream returns once it's finished. The default
+    /// implementation doesn't support this, the plaintext is collected in
+    /// memory and sealed using `seal()` instead.
+    fn seal_stream(
+        &self,
+        _nonce: &[u8],
+        _aad: &[u8],
+    ) -> std::io::Result<Option<Box<dyn SealStream>>> {
+        Ok(None)
+    }
+
+    /// The number of bytes sealing adds to the plaintext, usually the size of
+    /// the authentication tag.
+    ///
+    /// Needed to pad files to a given size. The default implementation seals
+    /// an empty plaintext to find out.
+    fn tag_size(&self) -> std::io::Result<usize> {
+        let nonce = vec![0u8; self.nonce_size()];
+        Ok(self.seal(&nonce, &[], &[])?.len())
+    }
+
+    /// Derive the mask that hides the write mode tag of a file from the
+    /// nonce of the file, see `EncryptedMmapDirectory::with_hidden_headers()`.
+    ///
+    /// The mask needs to be derived using a key that isn't used for sealing.
+    /// The default implementation doesn't support hidden headers.
+    fn header_mask(&self, _nonce: &[u8]) -> std::io::Result<u8> {
+        Err(IoError::new(
+            ErrorKind::Other,
+            "the AEAD provider doesn't support hidden headers",
+        ))
+    }
+
+    /// Encrypt and authenticate the given plaintext like `seal()`, but
+    /// truncate the authentication tag to the given size.
+    ///
+    /// Only algorithms whose tags can be safely truncated should support
+    /// this, see `EncryptedMmapDirectory::with_tag_size()`. The default
+    /// implementation doesn't support truncated tags.
+    fn seal_truncated(
+        &self,
+        _nonce: &[u8],
+        _aad: &[u8],
+        _plaintext: &[u8],
+        _tag_size: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        Err(IoError::new(
+            ErrorKind::Other,
+            "the AEAD provider doesn't support truncated tags",
+        ))
+    }
+
+    /// Authenticate a ciphertext whose tag was truncated to the given size
+    /// and decrypt it into the given writer, see `seal_truncated()`.
+    ///
+    /// Returns the number of plaintext bytes that were written. Nothing
+    /// should be written if the authentication fails.
+    fn open_truncated_into(
+        &self,
+        _nonce: &[u8],
+        _aad: &[u8],
+        _ciphertext: &[u8],
+        _tag_size: usize,
+        _sink: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        Err(IoError::new(
+            ErrorKind::Other,
+            "the AEAD provider doesn't support truncated tags",
+        ))
+    }
+}
+
+/// A plaintext that is being sealed piece by piece, see
+/// `AeadProvider::seal_stream()`.
+pub trait SealStream {
+    /// Encrypt the given piece of the plaintext in place and authenticate
+    /// it.
+    fn update(&mut self, data: &mut [u8]) -> std::io::Result<()>;
+
+    /// Finish sealing the plaintext, returns the authentication tag.
+    fn finish(self: Box<Self>) -> std::io::Result<Vec<u8>>;
+}
+
+/// The header of a file and the stream that seals its content, see
+/// `StoreState::start_stream()`.
+pub(super) type StartedStream = (Vec<u8>, Box<dyn SealStream>);
+
+/// The default AEAD provider, it uses AES-CTR for encryption and HMAC-SHA256
+/// for authentication.
+///
+/// The MAC is calculated over the additional authenticated data, the nonce,
+/// and the ciphertext and is appended to the ciphertext.
+struct AesCtrHmacProvider {
+    encryption_key: KeyBuffer,
+    mac_key: KeyBuffer,
+    header_key: KeyBuffer,
+}
+
+// The keys are left out, the provider is part of the debug output of the
+// directory.
+impl std::fmt::Debug for AesCtrHmacProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("AesCtrHmacProvider")
+    }
+}
+
+impl AeadProvider for AesCtrHmacProvider {
+    fn nonce_size(&self) -> usize {
+        Cipher::Aes256Ctr.iv_size()
+    }
+
+    fn name(&self) -> &str {
+        "aes-256-ctr-hmac-sha256"
+    }
+
+    fn cipher(&self) -> Option<Cipher> {
+        Some(Cipher::Aes256Ctr)
+    }
+
+    fn seal(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+        Cipher::Aes256Ctr.check_iv(nonce)?;
+
+        let mut sealed = Vec::new();
+        {
+            let mut writer = AesWriter::<Aes256Ctr, Hmac<Sha256>, _>::with_iv(
+                &mut sealed,
+                &self.encryption_key,
+                &self.mac_key,
+                nonce,
+                aad,
+            )?;
+            writer.write_all(plaintext)?;
+            writer.finalize()?;
+        }
+
+        Ok(sealed)
+    }
+
+    fn open(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        self.open_into(nonce, aad, ciphertext, &mut plaintext)?;
+
+        Ok(plaintext)
+    }
+
+    fn open_into(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+        sink: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        // The reader checks the MAC of the whole ciphertext before it starts
+        // decrypting.
+        let mut reader = AesReader::<Aes256Ctr, _>::with_iv::<Hmac<Sha256>>(
+            Cursor::new(ciphertext),
+            &self.encryption_key,
+            &self.mac_key,
+            nonce,
+            aad,
+            MAC_LENGTH,
+        )?;
+
+        std::io::copy(&mut reader, sink)
+    }
+
+    fn seal_into(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &mut dyn Read,
+        sink: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        Cipher::Aes256Ctr.check_iv(nonce)?;
+
+        let mut writer = AesWriter::<Aes256Ctr, Hmac<Sha256>, _>::with_iv(
+            sink,
+            &self.encryption_key,
+            &self.mac_key,
+            nonce,
+            aad,
+        )?;
+        let sealed = std::io::copy(plaintext, &mut writer)?;
+        writer.finalize()?;
+
+        Ok(sealed)
+    }
+
+    fn seal_stream(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> std::io::Result<Option<Box<dyn SealStream>>> {
+        Cipher::Aes256Ctr.check_iv(nonce)?;
+
+        let cipher = Aes256Ctr::new_var(&self.encryption_key, nonce)
+            .map_err(|_| IoError::from(CryptoError::InvalidKey))?;
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.mac_key)
+            .map_err(|_| IoError::from(CryptoError::InvalidKey))?;
+        mac.input(aad);
+        mac.input(nonce);
+
+        Ok(Some(Box::new(AesCtrHmacStream { cipher, mac })))
+    }
+
+    fn tag_size(&self) -> std::io::Result<usize> {
+        Ok(MAC_LENGTH)
+    }
+
+    fn header_mask(&self, nonce: &[u8]) -> std::io::Result<u8> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.header_key)
+            .map_err(|_| IoError::from(CryptoError::InvalidKey))?;
+        mac.input(nonce);
+
+        Ok(mac.result().code()[0])
+    }
+
+    // HMAC-SHA256 tags can be truncated by dropping their trailing bytes.
+    fn seal_truncated(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+        tag_size: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        if tag_size > MAC_LENGTH {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "the tag can't be longer than the MAC",
+            ));
+        }
+
+        let mut sealed = self.seal(nonce, aad, plaintext)?;
+        sealed.truncate(sealed.len() - (MAC_LENGTH - tag_size));
+
+        Ok(sealed)
+    }
+
+    fn open_truncated_into(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+        tag_size: usize,
+        sink: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        Cipher::Aes256Ctr.check_iv(nonce)?;
+
+        if tag_size > MAC_LENGTH || ciphertext.len() < tag_size {
+            return Err(authentication_error());
+        }
+
+        let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - tag_size);
+
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.mac_key)
+            .map_err(|_| IoError::from(CryptoError::InvalidKey))?;
+        mac.input(aad);
+        mac.input(nonce);
+        mac.input(ciphertext);
+        let code = mac.result().code();
+
+        // Compare the truncated tags in constant time.
+        let difference = code[..tag_size]
+            .iter()
+            .zip(tag)
+            .fold(0u8, |difference, (a, b)| difference | (a ^ b));
+
+        if difference != 0 {
+            return Err(authentication_error());
+        }
+
+        let mut cipher = Aes256Ctr::new_var(&self.encryption_key, nonce)
+            .map_err(|_| IoError::from(CryptoError::InvalidKey))?;
+        let mut buffer = Zeroizing::new(vec![0u8; TRUNCATED_TAG_BUFFER_SIZE]);
+
+        for chunk in ciphertext.chunks(TRUNCATED_TAG_BUFFER_SIZE) {
+            let buffer = &mut buffer[..chunk.len()];
+            buffer.copy_from_slice(chunk);
+            cipher
+                .try_apply_keystream(buffer)
+                .map_err(|_| IoError::from(CryptoError::KeystreamEnd))?;
+            sink.write_all(buffer)?;
+        }
+
+        Ok(ciphertext.len() as u64)
+    }
+}
+
+/// A plaintext that is being sealed by the default AEAD provider.
+struct AesCtrHmacStream {
+    cipher: Aes256Ctr,
+    mac: Hmac<Sha256>,
+}
+
+impl SealStream for AesCtrHmacStream {
+    fn update(&mut self, data: &mut [u8]) -> std::io::Result<()> {
+        self.cipher
+            .try_apply_keystream(data)
+            .map_err(|_| IoError::from(CryptoError::KeystreamEnd))?;
+        self.mac.input(data);
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> std::io::Result<Vec<u8>> {
+        Ok(self.mac.result().code().to_vec())
+    }
+}
+
+/// An AEAD provider that resolves its keys using a `KeyProvider`, the
+/// default scheme is used with keys that are expanded from the data key.
+///
+/// The expanded keys are cached for a while, so that not every file needs
+/// a call to the key provider. The cache is locked while the key provider is
+/// called, concurrent reads share a single call.
+struct ResolvingProvider {
+    key_provider: Box<dyn KeyProvider>,
+    cache_duration: Duration,
+    cached: Mutex<Option<(Instant, Arc<dyn AeadProvider>)>>,
+}
+
+impl ResolvingProvider {
+    /// Get the provider for the current data key, the key provider is only
+    /// called if the cached keys expired.
+    fn resolve(&self) -> std::io::Result<Arc<dyn AeadProvider>> {
+        let mut cached = self.cached.lock().unwrap();
+
+        if let Some((fetched, provider)) = &*cached {
+            if fetched.elapsed() < self.cache_duration {
+                return Ok(provider.clone());
+            }
+        }
+
+        let data_key = self.key_provider.data_key()?;
+
+        if data_key.len() != KEY_SIZE {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "invalid data key size",
+            ));
+        }
+
+        trace!("Fetched the data key from the key provider");
+
+        let provider = EncryptedMmapDirectory::create_provider(&data_key)?;
+        *cached = Some((Instant::now(), provider.clone()));
+
+        Ok(provider)
+    }
+}
+
+// The cached keys are left out, the provider is part of the debug output of
+// the directory.
+impl std::fmt::Debug for ResolvingProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ResolvingProvider")
+            .field("key_provider", &self.key_provider)
+            .field("cache_duration", &self.cache_duration)
+            .finish()
+    }
+}
+
+impl AeadProvider for ResolvingProvider {
+    fn nonce_size(&self) -> usize {
+        Cipher::Aes256Ctr.iv_size()
+    }
+
+    fn name(&self) -> &str {
+        "aes-256-ctr-hmac-sha256"
+    }
+
+    fn cipher(&self) -> Option<Cipher> {
+        Some(Cipher::Aes256Ctr)
+    }
+
+    fn seal(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.resolve()?.seal(nonce, aad, plaintext)
+    }
+
+    fn open(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.resolve()?.open(nonce, aad, ciphertext)
+    }
+
+    fn open_into(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+        sink: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        self.resolve()?.open_into(nonce, aad, ciphertext, sink)
+    }
+
+    fn seal_into(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &mut dyn Read,
+        sink: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        self.resolve()?.seal_into(nonce, aad, plaintext, sink)
+    }
+
+    fn seal_stream(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> std::io::Result<Option<Box<dyn SealStream>>> {
+        self.resolve()?.seal_stream(nonce, aad)
+    }
+
+    fn tag_size(&self) -> std::io::Result<usize> {
+        Ok(MAC_LENGTH)
+    }
+
+    fn header_mask(&self, nonce: &[u8]) -> std::io::Result<u8> {
+        self.resolve()?.header_mask(nonce)
+    }
+
+    fn seal_truncated(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+        tag_size: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        self.resolve()?
+            .seal_truncated(nonce, aad, plaintext, tag_size)
+    }
+
+    fn open_truncated_into(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+        tag_size: usize,
+        sink: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        self.resolve()?
+            .open_truncated_into(nonce, aad, ciphertext, tag_size, sink)
+    }
+}
+
+/// Takes the place of the keys of a locked store, every use of it fails.
+#[derive(Debug)]
+pub(super) struct LockedKeys;
+
+impl AeadProvider for LockedKeys {
+    fn nonce_size(&self) -> usize {
+        0
+    }
+
+    fn seal(&self, _nonce: &[u8], _aad: &[u8], _plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+        Err(Locked::error())
+    }
+
+    fn open(&self, _nonce: &[u8], _aad: &[u8], _ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+        Err(Locked::error())
+    }
+}
+
+impl KeyWrapper for LockedKeys {
+    fn name(&self) -> &str {
+        "locked"
+    }
+
+    fn wrap(&self, _store_key: &[u8]) -> std::io::Result<Vec<u8>> {
+        Err(Locked::error())
+    }
+
+    fn unwrap(&self, _wrapped_key: &[u8]) -> std::io::Result<Zeroizing<Vec<u8>>> {
+        Err(Locked::error())
+    }
+}
+
+impl EncryptedMmapDirectory {
+    /// Create our default AEAD provider for the given store key.
+    pub(super) fn create_provider(store_key: &[u8]) -> std::io::Result<Arc<dyn AeadProvider>> {
+        EncryptedMmapDirectory::create_provider_for(store_key, &[])
+    }
+
+    /// Create our default AEAD provider, the keys of the provider are
+    /// expanded from the given key using the given info string.
+    pub(super) fn create_provider_for(
+        key: &[u8],
+        info: &[u8],
+    ) -> std::io::Result<Arc<dyn AeadProvider>> {
+        // Expand the key into a encryption and MAC key.
+        let (encryption_key, mac_key) = expand_key(key, info)?;
+        let (header_key, _) = expand_key(key, &[info, b"header"].concat())?;
+
+        Ok(Arc::new(AesCtrHmacProvider {
+            encryption_key,
+            mac_key,
+            header_key,
+        }))
+    }
+
+    /// Check the cryptographic primitives of the store and our default
+    /// `AeadProvider` against known answers.
+    ///
+    /// Returns an error if any of them produces an unexpected result, a store
+    /// must not be used in that case. With the `self-test` feature enabled
+    /// this runs before the first store is opened.
+    #[allow(dead_code)]
+    pub fn self_test() -> std::io::Result<()> {
+        const SEALED: [u8; 48] = [
+            0x89, 0x23, 0xb9, 0xce, 0xc0, 0x8b, 0xb9, 0x28, 0x36, 0xcf, 0x07, 0xfd, 0x19, 0x8e,
+            0xeb, 0xad, 0xca, 0x78, 0x68, 0x3f, 0x6f, 0x39, 0x17, 0x07, 0x75, 0x42, 0x14, 0x29,
+            0xb7, 0x8e, 0x6c, 0xee, 0xf8, 0x49, 0x56, 0x8d, 0x5a, 0xaf, 0xfc, 0xe4, 0xcb, 0xa1,
+            0x7e, 0x68, 0xfa, 0x74, 0x0a, 0x6e,
+        ];
+
+        crypto::self_test()?;
+
+        let provider = EncryptedMmapDirectory::create_provider(&[0x0b; 22])?;
+        let nonce: Vec<u8> = (0..provider.nonce_size() as u8).collect();
+        let sealed = provider.seal(&nonce, &[WriteMode::Atomic as u8], b"seshat self test")?;
+
+        if sealed[..] != SEALED[..] {
+            return Err(CryptoError::SelfTest("AES-256-CTR-HMAC-SHA256").into());
+        }
+
+        EncryptedMmapDirectory::self_test_provider(&*provider)
+    }
+
+    /// Check that the given `AeadProvider` encrypts, decrypts, and rejects
+    /// modified data.
+    ///
+    /// Custom providers can't be checked against known answers, this makes
+    /// sure that they at least behave like an AEAD.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - The provider that should be checked.
+    #[allow(dead_code)]
+    pub fn self_test_provider(provider: &dyn AeadProvider) -> std::io::Result<()> {
+        let error = || IoError::from(CryptoError::SelfTest("AEAD provider"));
+        let plaintext = b"seshat self test";
+        let nonce = vec![0u8; provider.nonce_size()];
+        let aad = [WriteMode::Atomic as u8];
+
+        let sealed = provider.seal(&nonce, &aad, plaintext)?;
+
+        if sealed.windows(plaintext.len()).any(|w| w == plaintext) {
+            return Err(error());
+        }
+
+        if provider.open(&nonce, &aad, &sealed)? != plaintext {
+            return Err(error());
+        }
+
+        let mut modified = sealed.clone();
+        if let Some(byte) = modified.last_mut() {
+            *byte ^= 1;
+        }
+
+        if provider.open(&nonce, &aad, &modified).is_ok()
+            || provider
+                .open(&nonce, &[WriteMode::Streaming as u8], &sealed)
+                .is_ok()
+        {
+            return Err(error());
+        }
+
+        Ok(())
+    }
+
+    /// Run the self test, unless it already passed.
+    #[cfg(feature = "self-test")]
+    pub(super) fn run_self_test_once() -> std::io::Result<()> {
+        if !SELF_TEST_PASSED.load(Ordering::SeqCst) {
+            EncryptedMmapDirectory::self_test()?;
+            SELF_TEST_PASSED.store(true, Ordering::SeqCst);
+        }
+
+        Ok(())
+    }
+
+    /// Use the given AEAD provider to encrypt and decrypt the Tantivy files
+    /// instead of the default AES-CTR and HMAC-SHA256 based scheme.
+    ///
+    /// The provider is responsible for its own keys, files that were written
+    /// using a different provider won't be readable anymore. A provider that
+    /// doesn't match the cipher of the key file fails to read and write any
+    /// file, see `AeadProvider::cipher()`.
+    #[allow(dead_code)]
+    pub fn with_provider(mut self, provider: Arc<dyn AeadProvider>) -> Self {
+        *self.state.provider.write().unwrap() = provider;
+        self.custom_provider = true;
+        self
+    }
+
+    /// Resolve the data key that encrypts the Tantivy files using the given
+    /// key provider instead of holding it.
+    ///
+    /// The files are encrypted using the default AES-CTR and HMAC-SHA256
+    /// based scheme, the keys are expanded from the data key. The key
+    /// provider is called when a file is read or written and the keys are
+    /// cached for the given duration, a zero duration calls it for every
+    /// file. The store key in the key file isn't used for the Tantivy files
+    /// anymore, like with any custom provider files that were written using
+    /// a different key won't be readable and the store key can't be rotated,
+    /// see `with_provider()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_provider` - The source of the data key.
+    /// * `cache_duration` - How long the keys are cached after they were
+    /// fetched.
+    #[allow(dead_code)]
+    pub fn with_key_provider(
+        self,
+        key_provider: Box<dyn KeyProvider>,
+        cache_duration: Duration,
+    ) -> Self {
+        self.with_provider(Arc::new(ResolvingProvider {
+            key_provider,
+            cache_duration,
+            cached: Mutex::new(None),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::encrypted_dir::tests::MockHardwareWrapper;
+    use std::sync::atomic::AtomicUsize;
+    use tempfile::tempdir;
+
+    #[derive(Debug, Default)]
+    /// A test provider that XORs the data and counts how often it was used.
+    struct XorProvider {
+        sealed: AtomicUsize,
+        opened: AtomicUsize,
+    }
+
+    impl AeadProvider for XorProvider {
+        fn nonce_size(&self) -> usize {
+            4
+        }
+
+        fn seal(&self, _nonce: &[u8], _aad: &[u8], plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+            self.sealed.fetch_add(1, Ordering::SeqCst);
+            Ok(plaintext.iter().map(|b| b ^ 0xff).collect())
+        }
+
+        fn open(&self, _nonce: &[u8], _aad: &[u8], ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+            self.opened.fetch_add(1, Ordering::SeqCst);
+            Ok(ciphertext.iter().map(|b| b ^ 0xff).collect())
+        }
+    }
+
+    #[test]
+    fn reject_a_modified_nonce() {
+        let provider = EncryptedMmapDirectory::create_provider(&[1u8; KEY_SIZE]).unwrap();
+        let nonce = [2u8; IV_SIZE];
+        let sealed = provider.seal(&nonce, &[], b"content").unwrap();
+        assert_eq!(provider.open(&nonce, &[], &sealed).unwrap(), b"content");
+
+        // Flipping a byte of the nonce only changes the keystream, the MAC
+        // needs to cover the nonce for this to be detected.
+        let mut modified = nonce;
+        modified[0] ^= 1;
+        assert!(
+            provider.open(&modified, &[], &sealed).is_err(),
+            "Opened a file using a modified nonce"
+        );
+    }
+
+    /// A test provider that claims to use the cipher of the key file but seals
+    /// files using 12 byte nonces, like AES-GCM would.
+    #[derive(Debug)]
+    struct MismatchedProvider;
+
+    impl AeadProvider for MismatchedProvider {
+        fn nonce_size(&self) -> usize {
+            12
+        }
+
+        fn cipher(&self) -> Option<Cipher> {
+            Some(Cipher::Aes256Ctr)
+        }
+
+        fn seal(&self, _nonce: &[u8], _aad: &[u8], plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+            Ok(plaintext.to_vec())
+        }
+
+        fn open(&self, _nonce: &[u8], _aad: &[u8], ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+            Ok(ciphertext.to_vec())
+        }
+    }
+
+    #[test]
+    fn refuse_a_provider_that_doesnt_match_the_key_file() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+
+        let mut dir = dir.with_provider(Arc::new(MismatchedProvider));
+        let expected = CryptoError::CipherMismatch {
+            key_file: Cipher::Aes256Ctr,
+            provider: Cipher::Aes256Ctr,
+            nonce_size: 12,
+        };
+
+        match dir.atomic_write(path, b"content") {
+            Ok(_) => panic!("Sealed a file using a provider that doesn't match the key file"),
+            Err(e) => assert_eq!(e.to_string(), expected.to_string()),
+        }
+
+        match dir.atomic_read(path) {
+            Ok(_) => panic!("Opened a file using a provider that doesn't match the key file"),
+            Err(e) => assert!(e.to_string().contains(&expected.to_string())),
+        }
+    }
+
+    #[test]
+    fn custom_aead_provider() {
+        let tmpdir = tempdir().unwrap();
+        let provider = Arc::new(XorProvider::default());
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store")
+                .with_provider(provider.clone());
+
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        assert_eq!(provider.sealed.load(Ordering::SeqCst), 1);
+
+        let on_disk = std::fs::read(tmpdir.path().join(path)).unwrap();
+        let expected: Vec<u8> = b"content".iter().map(|b| b ^ 0xff).collect();
+        assert_eq!(&on_disk[WRITE_MODE_SIZE + 4..], &expected[..]);
+
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        assert_eq!(provider.opened.load(Ordering::SeqCst), 1);
+
+        let path = Path::new("segment");
+        let mut writer = dir.open_write(path).unwrap();
+        writer.write_all(b"segment data").unwrap();
+        writer.terminate().unwrap();
+        assert_eq!(provider.sealed.load(Ordering::SeqCst), 2);
+
+        let source = dir.open_read(path).unwrap();
+        assert_eq!(source.as_slice(), b"segment data");
+        assert_eq!(provider.opened.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn self_test() {
+        EncryptedMmapDirectory::self_test().expect("The self test failed");
+    }
+
+    #[test]
+    fn self_test_rejects_broken_provider() {
+        // The XOR provider neither hides the plaintext nor authenticates it.
+        assert!(
+            EncryptedMmapDirectory::self_test_provider(&XorProvider::default()).is_err(),
+            "A broken provider passed the self test"
+        );
+    }
+
+    #[test]
+    fn combine_key_wrapper_and_provider() {
+        let tmpdir = tempdir().unwrap();
+        let wrapper = Arc::new(MockHardwareWrapper::default());
+        wrapper.device_present.store(true, Ordering::SeqCst);
+        let provider = Arc::new(XorProvider::default());
+
+        let (dir, _) =
+            EncryptedMmapDirectory::open_or_create_with_key_wrapper(tmpdir.path(), wrapper.clone())
+                .expect("Can't create a new store using a key wrapper");
+        let mut dir = dir.with_provider(provider.clone());
+
+        let atomic = Path::new("meta.json");
+        dir.atomic_write(atomic, b"content").unwrap();
+
+        let streamed = Path::new("segment");
+        let mut writer = dir.open_write(streamed).unwrap();
+        writer.write_all(b"segment data").unwrap();
+        writer.terminate().unwrap();
+        drop(dir);
+
+        let key_file = std::fs::read(tmpdir.path().join(KEYFILE)).unwrap();
+        assert_eq!(&key_file[2..2 + key_file[1] as usize], b"mock-hardware");
+
+        let (dir, opened) =
+            EncryptedMmapDirectory::open_or_create_with_key_wrapper(tmpdir.path(), wrapper)
+                .expect("Can't reopen the store");
+        assert_eq!(opened, Opened::Existing);
+        let dir = dir.with_provider(provider.clone());
+
+        assert_eq!(dir.atomic_read(atomic).unwrap(), b"content");
+        assert_eq!(dir.open_read(streamed).unwrap().as_slice(), b"segment data");
+        assert_eq!(provider.sealed.load(Ordering::SeqCst), 2);
+        assert_eq!(provider.opened.load(Ordering::SeqCst), 2);
+    }
+
+    #[derive(Debug)]
+    struct CountingKms {
+        data_key: [u8; KEY_SIZE],
+        fetches: Arc<AtomicUsize>,
+    }
+
+    impl KeyProvider for CountingKms {
+        fn data_key(&self) -> std::io::Result<Zeroizing<Vec<u8>>> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            Ok(Zeroizing::new(self.data_key.to_vec()))
+        }
+    }
+
+    #[test]
+    fn resolve_the_data_key_through_a_key_provider() {
+        let tmpdir = tempdir().unwrap();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let kms = |fetches: &Arc<AtomicUsize>| {
+            Box::new(CountingKms {
+                data_key: [7u8; KEY_SIZE],
+                fetches: fetches.clone(),
+            })
+        };
+
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store")
+                .with_key_provider(kms(&fetches), Duration::from_secs(3600));
+        assert_eq!(fetches.load(Ordering::SeqCst), 0);
+
+        for i in 0..10 {
+            let path = PathBuf::from(format!("file-{}", i));
+            dir.atomic_write(&path, format!("content {}", i).as_bytes())
+                .unwrap();
+            assert_eq!(
+                dir.atomic_read(&path).unwrap(),
+                format!("content {}", i).as_bytes()
+            );
+        }
+
+        // The data key was fetched once and cached for the rest of the files.
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        drop(dir);
+
+        // Without caching every file needs its own fetch.
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the store")
+            .with_key_provider(kms(&fetches), Duration::from_secs(0));
+
+        for i in 0..10 {
+            assert_eq!(
+                dir.atomic_read(&PathBuf::from(format!("file-{}", i)))
+                    .unwrap(),
+                format!("content {}", i).as_bytes()
+            );
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 10);
+
+        // The files aren't encrypted using the store key.
+        drop(dir);
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert!(dir.atomic_read(Path::new("file-0")).is_err());
+    }
+}
@@ -0,0 +1,1089 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Store key rotation, including resuming a rotation that was interrupted.
+
+use super::*;
+
+#[derive(Serialize, Deserialize)]
+/// The progress of a store key rotation, it's persisted after every file so
+/// an interrupted rotation can be resumed.
+///
+/// If the store key gets wrapped using a new passphrase as well, the new key
+/// file is prepared before the rotation starts.
+pub(super) struct RotationJournal {
+    pub(super) store_key: Vec<u8>,
+    done: BTreeSet<String>,
+    #[serde(default)]
+    key_file: Option<Vec<u8>>,
+    #[serde(default)]
+    file_names: Option<Vec<u8>>,
+}
+
+impl Drop for RotationJournal {
+    fn drop(&mut self) {
+        self.store_key.zeroize();
+
+        if let Some(secret) = &mut self.file_names {
+            secret.zeroize();
+        }
+    }
+}
+
+impl EncryptedMmapDirectory {
+    /// Rotate the store key automatically once the given number of bytes has
+    /// been encrypted using the current store key.
+    ///
+    /// The number of encrypted bytes is persisted next to the key file. The
+    /// threshold is checked whenever a file is atomically written, Tantivy
+    /// does so for every commit, at which point the store key gets rotated,
+    /// see `rotate_store_key()`.
+    ///
+    /// `DEFAULT_REKEY_THRESHOLD` is a conservative choice for the default
+    /// AES-CTR based scheme. Has no effect if a custom provider is used.
+    ///
+    /// # Arguments
+    ///
+    /// * `threshold` - The number of bytes that may be encrypted using a
+    /// single store key.
+    #[allow(dead_code)]
+    pub fn with_rekey_threshold(mut self, threshold: u64) -> std::io::Result<Self> {
+        let counter_path = self.path.join(self.config.counter_file());
+
+        let encrypted_bytes = match File::open(&counter_path) {
+            Ok(mut f) => f.read_u64::<BigEndian>()?,
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(e);
+                }
+                0
+            }
+        };
+
+        self.state
+            .encrypted_bytes
+            .fetch_add(encrypted_bytes, Ordering::SeqCst);
+        self.rekey_threshold = Some(threshold);
+
+        Ok(self)
+    }
+
+    /// Replace both the passphrase and the store key of a store in one go,
+    /// e.g. after the passphrase or the device was compromised.
+    ///
+    /// This is a shorthand for `maintain()` with a plan that changes the
+    /// passphrase and rotates the store key. The files are re-encrypted
+    /// once, the key file is replaced once, and the progress is recorded in
+    /// a journal. Afterwards neither the old passphrase nor the old store key
+    /// can decrypt the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `old_passphrase` - The passphrase that was used to encrypt our
+    /// directory.
+    /// * `new_passphrase` - The passphrase that should be used from now on.
+    #[allow(dead_code)]
+    pub fn rekey_with_new_passphrase_and_key<P: AsRef<Path>>(
+        path: P,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), OpenDirectoryError> {
+        let plan = MaintenancePlan::new()
+            .change_passphrase(new_passphrase)
+            .rotate_store_key();
+
+        EncryptedMmapDirectory::maintain(path, old_passphrase, plan)
+    }
+
+    /// Replace the store key with a newly generated one.
+    ///
+    /// All the files of the store get re-encrypted using the new store key,
+    /// afterwards the new store key is written to the key file, encrypted
+    /// using the same passphrase.
+    ///
+    /// Files that are being written while the rotation happens will be
+    /// encrypted using the new store key once they are complete. The
+    /// rotation waits for writers that were opened before it started, those
+    /// need to be terminated or dropped, by a different thread, first.
+    ///
+    /// The new store key is wrapped using the key wrapper the store was
+    /// opened with.
+    ///
+    /// The progress of the rotation is recorded in an encrypted journal. If
+    /// the rotation gets interrupted, it's resumed the next time the store is
+    /// opened for writing, files that were already re-encrypted are skipped.
+    ///
+    /// Returns an error if a custom `AeadProvider` is used, the provider is
+    /// responsible for its own keys.
+    #[allow(dead_code)]
+    pub fn rotate_store_key(&self) -> std::io::Result<()> {
+        self.rotate_store_key_with_progress(|_, _| ())
+    }
+
+    /// Replace the store key with a newly generated one and report the
+    /// progress of the rotation.
+    ///
+    /// This behaves exactly like `rotate_store_key()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `progress` - Callback that is called after every file that was
+    /// processed with the number of processed files and the total number of
+    /// files.
+    pub fn rotate_store_key_with_progress<F: FnMut(usize, usize)>(
+        &self,
+        progress: F,
+    ) -> std::io::Result<()> {
+        self.rotate(None, None, progress)
+    }
+
+    /// Replace the store key with a newly generated one, stopping before the
+    /// next file once the given token gets cancelled.
+    ///
+    /// This behaves exactly like `rotate_store_key()`, a cancelled rotation
+    /// returns an error of the `Interrupted` kind and leaves its journal
+    /// behind. Files that were already re-encrypted can't be read using the
+    /// old store key, the rotation needs to be finished before the store is
+    /// used again, either by rotating the store key once more or by
+    /// reopening the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token that cancels the rotation.
+    #[allow(dead_code)]
+    pub fn rotate_store_key_cancellable(&self, token: &CancellationToken) -> std::io::Result<()> {
+        self.rotate(None, Some(token), |_, _| ())
+    }
+
+    /// Rotate the store key, if a key wrapper is given the new store key is
+    /// wrapped using it instead of the key wrapper of the directory.
+    ///
+    /// If a token is given, the rotation stops between files once it gets
+    /// cancelled.
+    ///
+    /// If an interrupted rotation that was started using a different key
+    /// wrapper is resumed, the key wrapper of the directory is outdated once
+    /// the rotation is done, the store gets locked in that case.
+    pub(super) fn rotate<F: FnMut(usize, usize)>(
+        &self,
+        key_wrapper: Option<Arc<dyn KeyWrapper>>,
+        token: Option<&CancellationToken>,
+        mut progress: F,
+    ) -> std::io::Result<()> {
+        if self.custom_provider {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                "the keys of a custom AEAD provider can't be rotated",
+            ));
+        }
+
+        if self.config.index_id.is_some() {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                "the store key is shared by other indexes, it can't be rotated",
+            ));
+        }
+
+        self.check_committed()?;
+
+        // Files that are being streamed are sealed using the old key, they
+        // get re-encrypted once they are complete. New files are buffered
+        // until the rotation is done.
+        let _rotation = self.state.start_rotation();
+
+        // Block all reads and writes until every file uses the new key.
+        let mut provider = self.state.provider.write().unwrap();
+        self.state.check_unlocked()?;
+
+        // The passphrase can't be changed while the store key changes.
+        let _key_lock = EncryptedMmapDirectory::lock_key_file(&self.path, &self.config)?;
+
+        info!("Rotating the store key of {}", self.path.display());
+
+        // Resume an interrupted rotation or start a new one.
+        let mut journal = match self.read_journal()? {
+            Some(j) => j,
+            None => RotationJournal {
+                store_key: EncryptedMmapDirectory::generate_key()?.to_vec(),
+                done: BTreeSet::new(),
+                key_file: None,
+                file_names: self
+                    .state
+                    .file_names
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .map(|n| n.secret.to_vec()),
+            },
+        };
+
+        if let (Some(w), None) = (&key_wrapper, &journal.key_file) {
+            journal.key_file = Some(self.wrap_key_file(&**w, &journal.store_key)?);
+        }
+
+        self.write_journal(&journal)?;
+
+        let new_provider = EncryptedMmapDirectory::create_provider(&journal.store_key)?;
+
+        let inner_dir = self.inner_dir.clone();
+
+        let files = self.disk_files()?;
+        let total = files.len();
+
+        for (done, path) in files.iter().enumerate() {
+            let name = path.to_string_lossy().into_owned();
+
+            if !journal.done.contains(&name) {
+                if let Err(e) = CancellationToken::check(token) {
+                    info!("Cancelled the store key rotation after {} files", done);
+                    return Err(e);
+                }
+
+                let data = inner_dir
+                    .atomic_read(&path)
+                    .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))?;
+
+                // Files that are still being written are empty, they will be
+                // sealed using the new key.
+                if !data.is_empty() {
+                    let data = Arc::new(data);
+
+                    match self.reencrypt(provider.clone(), &*new_provider, data.clone()) {
+                        Ok(encrypted) => {
+                            self.config
+                                .write_atomically(&self.path.join(&path), &encrypted)?;
+                        }
+                        // We might have been interrupted after the file was
+                        // re-encrypted but before the journal was updated.
+                        Err(e) => {
+                            if StoreState::open(&*new_provider, &data, None).is_err() {
+                                return Err(e);
+                            }
+                        }
+                    }
+                }
+
+                journal.done.insert(name);
+                self.write_journal(&journal)?;
+            }
+
+            progress(done + 1, total);
+        }
+
+        let schema_path = self.path.join(self.config.schema_file());
+
+        if schema_path.exists() {
+            self.reseal_file(&schema_path, &**provider, &*new_provider)?;
+        }
+
+        let metadata_path = self.path.join(self.config.metadata_file());
+
+        if metadata_path.exists() {
+            self.reseal_file(&metadata_path, &**provider, &*new_provider)?;
+        }
+
+        // The secret that hides the file names is sealed using the new key
+        // as well, the journal keeps a copy in case we get interrupted.
+        if let Some(secret) = &journal.file_names {
+            self.write_file_names(&*new_provider, secret)?;
+        }
+
+        self.write_key_created(&*new_provider, EncryptedMmapDirectory::key_creation_time())?;
+
+        // The additional wrappings hold the old store key.
+        let wrapped_keys_path = self.path.join(self.config.wrapped_keys_file());
+
+        if wrapped_keys_path.exists() {
+            std::fs::remove_file(&wrapped_keys_path)?;
+        }
+
+        match &journal.key_file {
+            Some(key_file) => {
+                self.config
+                    .write_atomically(&self.path.join(self.config.key_file()), key_file)?;
+
+                // The key slots hold the keys of the old key file.
+                EncryptedMmapDirectory::remove_key_slots(&self.path, &self.config)?;
+            }
+            None => self.write_key_file(&journal.store_key)?,
+        }
+
+        std::fs::remove_file(self.path.join(self.config.journal_file()))?;
+
+        *self.state.journal_provider.write().unwrap() =
+            EncryptedMmapDirectory::create_provider_for(&journal.store_key, b"journal")?;
+        *provider = new_provider;
+        self.state.encrypted_bytes.store(0, Ordering::SeqCst);
+
+        info!("Rotated the store key, re-encrypted {} files", total);
+
+        if journal.key_file.is_some() {
+            match key_wrapper {
+                Some(w) => *self.state.key_wrapper.write().unwrap() = w,
+                None => self.state.lock_keys(&mut provider),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get how long ago the store key was created, e.g. to rotate the store
+    /// key on a schedule.
+    ///
+    /// The creation time is stored encrypted next to the key file. Stores
+    /// that were created before the creation time was recorded count the
+    /// age of their store key from the first time a writer opened them.
+    /// The keys of a custom AEAD provider have no known age.
+    #[allow(dead_code)]
+    pub fn store_key_age(&self) -> std::io::Result<Duration> {
+        if self.custom_provider {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                "the age of the keys of a custom AEAD provider is unknown",
+            ));
+        }
+
+        let sealed = std::fs::read(self.path.join(self.config.key_created_file()))?;
+        let data = self.decrypt(&sealed, Some(WriteMode::Atomic))?;
+
+        if data.len() != 8 {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "invalid store key creation time",
+            ));
+        }
+
+        let mut seconds = [0u8; 8];
+        seconds.copy_from_slice(&data);
+        let created = UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(seconds));
+
+        // A clock that was turned back makes the store key look brand new.
+        Ok(SystemTime::now()
+            .duration_since(created)
+            .unwrap_or_default())
+    }
+
+    /// Rotate the store key if it's older than the given age.
+    ///
+    /// Returns whether the store key was rotated, see `store_key_age()` and
+    /// `rotate_store_key()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_age` - The age after which the store key should be rotated.
+    #[allow(dead_code)]
+    pub fn rotate_store_key_if_older_than(&self, max_age: Duration) -> std::io::Result<bool> {
+        if self.store_key_age()? < max_age {
+            return Ok(false);
+        }
+
+        self.rotate_store_key()?;
+
+        Ok(true)
+    }
+
+    /// Record when the store key was created, unless it's already known.
+    ///
+    /// Only writers record it, the store key of a freshly created store is
+    /// recorded when the store is opened for the first time.
+    pub(super) fn record_key_creation(&self) -> std::io::Result<()> {
+        if self.read_mode != ReadMode::Mmap
+            || self.path.join(self.config.key_created_file()).exists()
+        {
+            return Ok(());
+        }
+
+        let provider = self.state.provider()?;
+        self.write_key_created(&**provider, EncryptedMmapDirectory::key_creation_time())
+    }
+
+    /// The creation time that gets recorded for a new store key.
+    ///
+    /// The deterministic mode records the epoch so snapshots don't depend on
+    /// the clock.
+    fn key_creation_time() -> SystemTime {
+        if cfg!(feature = "deterministic-rng") {
+            UNIX_EPOCH
+        } else {
+            SystemTime::now()
+        }
+    }
+
+    /// Encrypt and persist the creation time of the store key that the given
+    /// provider belongs to.
+    fn write_key_created(
+        &self,
+        provider: &dyn AeadProvider,
+        created: SystemTime,
+    ) -> std::io::Result<()> {
+        let seconds = created
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| IoError::new(ErrorKind::Other, e))?
+            .as_secs();
+        let sealed = StoreState::seal_file(provider, &seconds.to_be_bytes(), WriteMode::Atomic)?;
+
+        self.config
+            .write_atomically(&self.path.join(self.config.key_created_file()), &sealed)
+    }
+
+    /// Re-encrypt the given encrypted file data using the new provider of a
+    /// store key rotation, the file keeps the mode it was written in.
+    ///
+    /// The file is decrypted on a background thread while the plaintext is
+    /// being sealed, the decryption of the next chunk overlaps with the
+    /// encryption of the current one. Like for every other read the whole
+    /// file is authenticated before the first chunk gets sealed.
+    fn reencrypt(
+        &self,
+        provider: Arc<dyn AeadProvider>,
+        new_provider: &dyn AeadProvider,
+        data: Arc<Vec<u8>>,
+    ) -> std::io::Result<Vec<u8>> {
+        let mode = StoreState::write_mode(&*provider, &data)?;
+
+        let mut plaintext = PipelinedReader::spawn(self.read_ahead, move |writer| {
+            StoreState::open_into(&*provider, &data, None, writer)
+        });
+
+        self.state.seal_from(new_provider, &mut plaintext, mode)
+    }
+
+    /// Re-encrypt one of our own files using the new provider of a store key
+    /// rotation.
+    ///
+    /// An interrupted rotation might have re-encrypted the file already.
+    fn reseal_file(
+        &self,
+        path: &Path,
+        provider: &dyn AeadProvider,
+        new_provider: &dyn AeadProvider,
+    ) -> std::io::Result<()> {
+        let data = std::fs::read(path)?;
+
+        match StoreState::open(provider, &data, Some(WriteMode::Atomic)) {
+            Ok(decrypted) => {
+                let decrypted = Zeroizing::new(decrypted);
+                let sealed = StoreState::seal_file(new_provider, &decrypted, WriteMode::Atomic)?;
+                self.config.write_atomically(path, &sealed)
+            }
+            Err(e) => {
+                if StoreState::open(new_provider, &data, Some(WriteMode::Atomic)).is_ok() {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Return an error if resuming a store key rotation replaced the key
+    /// file using a new passphrase, the store needs to be opened using the
+    /// new passphrase.
+    pub(super) fn check_rekeyed(&self) -> std::io::Result<()> {
+        self.state.check_unlocked().map_err(|_| {
+            IoError::new(
+                ErrorKind::PermissionDenied,
+                "the passphrase of the store was changed",
+            )
+        })
+    }
+
+    /// Read the journal of an interrupted store key rotation, if there is one.
+    pub(super) fn read_journal(&self) -> std::io::Result<Option<RotationJournal>> {
+        let sealed = match std::fs::read(self.path.join(self.config.journal_file())) {
+            Ok(s) => s,
+            Err(e) => {
+                if e.kind() == ErrorKind::NotFound {
+                    return Ok(None);
+                }
+                return Err(e);
+            }
+        };
+
+        let provider = self.state.journal_provider()?;
+
+        // The journal is protected by keys that are expanded from the store
+        // key in the key file. If it fails to authenticate, the rotation was
+        // completed and the key file replaced, but we didn't manage to
+        // remove the journal.
+        let journal = match StoreState::open(&*provider, &sealed, Some(WriteMode::Atomic)) {
+            Ok(j) => Zeroizing::new(j),
+            Err(_) => {
+                info!("Removing the journal of a finished store key rotation");
+                std::fs::remove_file(self.path.join(self.config.journal_file()))?;
+                return Ok(None);
+            }
+        };
+
+        // The journal contains the new store key, errors of the parser might
+        // quote parts of it.
+        let journal = serde_json::from_slice(&journal)
+            .map_err(|_| IoError::new(ErrorKind::InvalidData, "invalid rotation journal"))?;
+
+        Ok(Some(journal))
+    }
+
+    /// Encrypt and persist the journal of a store key rotation.
+    fn write_journal(&self, journal: &RotationJournal) -> std::io::Result<()> {
+        let provider = self.state.journal_provider()?;
+        let journal = Zeroizing::new(serde_json::to_vec(journal)?);
+
+        let sealed = StoreState::seal_file(&*provider, &journal, WriteMode::Atomic)?;
+
+        self.config
+            .write_atomically(&self.path.join(self.config.journal_file()), &sealed)
+    }
+
+    /// Create a provider for files that need to be protected by the keys
+    /// that protect the store key, the provider keys are expanded from the
+    /// wrapping keys using the given info string.
+    pub(super) fn wrapping_provider(
+        wrapping_key: &WrappingKey,
+        info: &[u8],
+    ) -> std::io::Result<Arc<dyn AeadProvider>> {
+        let mut key = Zeroizing::new(wrapping_key.key.to_vec());
+        key.extend_from_slice(&wrapping_key.mac_key);
+
+        EncryptedMmapDirectory::create_provider_for(&key, info)
+    }
+
+    /// Rotate the store key if the rekey threshold was reached, otherwise
+    /// persist the number of bytes that were encrypted so far.
+    pub(super) fn maybe_rotate_store_key(&self) -> std::io::Result<()> {
+        let threshold = match self.rekey_threshold {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+
+        if self.custom_provider {
+            return Ok(());
+        }
+
+        // The rotation would wait for files that are being written, those
+        // might be written by the thread that writes this file. The
+        // rotation is postponed to the next write instead.
+        if self.state.encrypted_bytes.load(Ordering::SeqCst) >= threshold {
+            if let Some(_rotation) = self.state.try_start_rotation() {
+                self.rotate_store_key()?;
+            }
+        }
+
+        let encrypted_bytes = self.state.encrypted_bytes.load(Ordering::SeqCst);
+        let mut counter = Vec::new();
+        counter.write_u64::<BigEndian>(encrypted_bytes)?;
+
+        self.config
+            .write_atomically(&self.path.join(self.config.counter_file()), &counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn rotate_store_key_after_threshold() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store")
+                .with_rekey_threshold(150)
+                .expect("Can't enable automatic rekeying");
+
+        let key_path = tmpdir.path().join(KEYFILE);
+        let first = Path::new("first");
+        let second = Path::new("second");
+
+        dir.atomic_write(first, &[1u8; 100]).unwrap();
+        let key_file = std::fs::read(&key_path).unwrap();
+        let encrypted_first = std::fs::read(tmpdir.path().join(first)).unwrap();
+
+        // This pushes us over the threshold and the store key gets rotated.
+        dir.atomic_write(second, &[2u8; 100]).unwrap();
+        assert_ne!(std::fs::read(&key_path).unwrap(), key_file);
+        assert_ne!(
+            std::fs::read(tmpdir.path().join(first)).unwrap(),
+            encrypted_first
+        );
+        assert_eq!(dir.state.encrypted_bytes.load(Ordering::SeqCst), 0);
+
+        assert_eq!(dir.atomic_read(first).unwrap(), vec![1u8; 100]);
+        assert_eq!(dir.atomic_read(second).unwrap(), vec![2u8; 100]);
+        drop(dir);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the store after the store key was rotated");
+        assert_eq!(dir.atomic_read(first).unwrap(), vec![1u8; 100]);
+        assert_eq!(dir.atomic_read(second).unwrap(), vec![2u8; 100]);
+    }
+
+    #[test]
+    fn rotate_store_key_while_a_file_is_streamed() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let mut existing = dir.open_write(Path::new("existing")).unwrap();
+        existing.write_all(b"existing").unwrap();
+        existing.terminate().unwrap();
+
+        let (started, rotate) = sync_channel(0);
+
+        let writer = {
+            let mut dir = dir.clone();
+
+            std::thread::spawn(move || {
+                let mut streamed = dir.open_write(Path::new("streamed")).unwrap();
+                streamed.write_all(b"streamed").unwrap();
+                started.send(()).unwrap();
+
+                // Give the rotation a chance to wait for the stream.
+                std::thread::sleep(Duration::from_millis(100));
+
+                // Neither new files nor reads wait for the rotation while the
+                // stream is running.
+                let mut buffered = dir.open_write(Path::new("buffered")).unwrap();
+                buffered.write_all(b"buffered").unwrap();
+                assert_eq!(
+                    dir.open_read(Path::new("existing")).unwrap().as_slice(),
+                    b"existing"
+                );
+
+                streamed.terminate().unwrap();
+                buffered.terminate().unwrap();
+            })
+        };
+
+        rotate.recv().unwrap();
+        dir.rotate_store_key().expect("Can't rotate the store key");
+        writer.join().expect("The writer thread failed");
+
+        drop(dir);
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the store after the store key was rotated");
+
+        for name in &["existing", "streamed", "buffered"] {
+            assert_eq!(
+                dir.open_read(Path::new(name)).unwrap().as_slice(),
+                name.as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn rotate_store_key_automatically_using_the_config() {
+        assert_eq!(StoreConfig::new().rekey_threshold, DEFAULT_REKEY_THRESHOLD);
+
+        let tmpdir = tempdir().unwrap();
+        let config = StoreConfig::new()
+            .rotate_store_key_automatically(true)
+            .set_rekey_threshold(150);
+        let (mut dir, _) = EncryptedMmapDirectory::open_or_create_with_config(
+            tmpdir.path(),
+            "wordpass",
+            PBKDF_COUNT,
+            &config,
+        )
+        .expect("Can't create a new store");
+        assert_eq!(dir.rekey_threshold, Some(150));
+
+        let key_path = tmpdir.path().join(KEYFILE);
+        let first = Path::new("first");
+
+        dir.atomic_write(first, &[1u8; 100]).unwrap();
+        let key_file = std::fs::read(&key_path).unwrap();
+        drop(dir);
+
+        // The number of encrypted bytes survives reopening the store.
+        let mut dir = EncryptedMmapDirectory::open_with_config(tmpdir.path(), "wordpass", &config)
+            .expect("Can't open the store");
+        dir.atomic_write(Path::new("second"), &[2u8; 100]).unwrap();
+        assert_ne!(std::fs::read(&key_path).unwrap(), key_file);
+        assert_eq!(dir.atomic_read(first).unwrap(), vec![1u8; 100]);
+    }
+
+    #[test]
+    fn report_store_key_rotation_progress() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        for file in ["first", "second", "third"].iter() {
+            dir.atomic_write(Path::new(file), file.as_bytes()).unwrap();
+        }
+
+        let mut calls = Vec::new();
+        dir.rotate_store_key_with_progress(|done, total| calls.push((done, total)))
+            .expect("Can't rotate the store key");
+
+        assert_eq!(calls, vec![(1, 3), (2, 3), (3, 3)]);
+        assert_eq!(dir.atomic_read(Path::new("second")).unwrap(), b"second");
+    }
+
+    #[test]
+    fn resume_interrupted_store_key_rotation() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let files = ["first", "second", "third", "fourth"];
+        for file in files.iter() {
+            dir.atomic_write(Path::new(file), file.as_bytes()).unwrap();
+        }
+
+        let read_files = || -> Vec<Vec<u8>> {
+            files
+                .iter()
+                .map(|f| std::fs::read(tmpdir.path().join(f)).unwrap())
+                .collect()
+        };
+
+        let before = read_files();
+        let key_file = std::fs::read(tmpdir.path().join(KEYFILE)).unwrap();
+
+        // Abort the rotation after two files were re-encrypted.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            dir.rotate_store_key_with_progress(|done, _| {
+                if done == 2 {
+                    panic!("Aborting the rotation");
+                }
+            })
+        }));
+        assert!(result.is_err());
+        drop(dir);
+
+        let interrupted = read_files();
+        assert!(tmpdir.path().join("seshat-index.journal").exists());
+        assert_eq!(
+            std::fs::read(tmpdir.path().join(KEYFILE)).unwrap(),
+            key_file
+        );
+
+        // A replica is able to read the files during the rotation.
+        let replica = EncryptedMmapDirectory::open_replica(tmpdir.path(), "wordpass")
+            .expect("Can't open a replica while a rotation is in progress");
+        for file in files.iter() {
+            assert_eq!(
+                replica.atomic_read(Path::new(file)).unwrap(),
+                file.as_bytes()
+            );
+        }
+        drop(replica);
+
+        // Opening the store for writing resumes the rotation.
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the store after an interrupted rotation");
+        assert!(!tmpdir.path().join("seshat-index.journal").exists());
+        assert_ne!(
+            std::fs::read(tmpdir.path().join(KEYFILE)).unwrap(),
+            key_file
+        );
+
+        let after = read_files();
+
+        for i in 0..files.len() {
+            // Every file was re-encrypted exactly once, either before the
+            // rotation was interrupted or after it was resumed.
+            let reencrypted_before_abort = before[i] != interrupted[i];
+            let reencrypted_after_resume = interrupted[i] != after[i];
+            assert!(reencrypted_before_abort ^ reencrypted_after_resume);
+
+            assert_eq!(
+                dir.atomic_read(Path::new(files[i])).unwrap(),
+                files[i].as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn rekey_with_new_passphrase_and_key() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+        drop(dir);
+
+        let key_file = std::fs::read(tmpdir.path().join(KEYFILE)).unwrap();
+        let (_, old_store_key) =
+            EncryptedMmapDirectory::load_store_key(&key_file[..], "wordpass").unwrap();
+
+        EncryptedMmapDirectory::rekey_with_new_passphrase_and_key(
+            tmpdir.path(),
+            "wordpass",
+            "password",
+        )
+        .expect("Can't rekey the store");
+
+        assert!(EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").is_err());
+
+        let old_provider = EncryptedMmapDirectory::create_provider(&old_store_key).unwrap();
+        let encrypted = std::fs::read(tmpdir.path().join(path)).unwrap();
+        assert!(StoreState::open(&*old_provider, &encrypted, None).is_err());
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "password")
+            .expect("Can't open the store using the new passphrase");
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+        assert!(!tmpdir.path().join("seshat-index.journal").exists());
+
+        // Rotating the store key keeps the new passphrase.
+        dir.rotate_store_key().unwrap();
+        drop(dir);
+        assert!(EncryptedMmapDirectory::open(tmpdir.path(), "password").is_ok());
+    }
+
+    #[test]
+    fn rotate_store_key_by_age() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let path = Path::new("meta.json");
+        dir.atomic_write(path, b"content").unwrap();
+
+        let max_age = Duration::from_secs(90 * 24 * 60 * 60);
+        assert!(dir.store_key_age().unwrap() < Duration::from_secs(60));
+        assert!(!dir.rotate_store_key_if_older_than(max_age).unwrap());
+
+        // The creation time is encrypted.
+        let sealed = std::fs::read(tmpdir.path().join("seshat-index.created")).unwrap();
+        assert_eq!(sealed.len(), WRITE_MODE_SIZE + IV_SIZE + 8 + MAC_LENGTH);
+
+        {
+            let provider = dir.state.provider().unwrap();
+            let created = SystemTime::now() - Duration::from_secs(100 * 24 * 60 * 60);
+            dir.write_key_created(&**provider, created).unwrap();
+        }
+
+        let age = dir.store_key_age().unwrap();
+        assert!(age >= Duration::from_secs(100 * 24 * 60 * 60));
+        assert!(age < Duration::from_secs(101 * 24 * 60 * 60));
+
+        let key_file = std::fs::read(tmpdir.path().join(KEYFILE)).unwrap();
+        assert!(dir.rotate_store_key_if_older_than(max_age).unwrap());
+        assert_ne!(
+            std::fs::read(tmpdir.path().join(KEYFILE)).unwrap(),
+            key_file
+        );
+        assert!(dir.store_key_age().unwrap() < Duration::from_secs(60));
+        assert!(!dir.rotate_store_key_if_older_than(max_age).unwrap());
+        drop(dir);
+
+        // The age survives reopening the store.
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert!(dir.store_key_age().unwrap() < Duration::from_secs(60));
+        assert_eq!(dir.atomic_read(path).unwrap(), b"content");
+    }
+
+    #[test]
+    fn cancel_a_store_key_rotation() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let files = ["first", "second", "third", "fourth"];
+        for file in files.iter() {
+            dir.atomic_write(Path::new(file), file.as_bytes()).unwrap();
+        }
+
+        let read_files = || -> Vec<Vec<u8>> {
+            files
+                .iter()
+                .map(|f| std::fs::read(tmpdir.path().join(f)).unwrap())
+                .collect()
+        };
+
+        let before = read_files();
+        let key_file = std::fs::read(tmpdir.path().join(KEYFILE)).unwrap();
+
+        // A token that is already cancelled stops the rotation before the first
+        // file.
+        let token = CancellationToken::new();
+        token.cancel();
+        let error = dir.rotate_store_key_cancellable(&token).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Interrupted);
+        assert_eq!(read_files(), before);
+
+        // Cancel the rotation after two files were re-encrypted.
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let error = dir
+            .rotate(None, Some(&token), |done, _| {
+                if done == 2 {
+                    canceller.cancel();
+                }
+            })
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Interrupted);
+        drop(dir);
+
+        let cancelled = read_files();
+        let reencrypted: Vec<bool> = (0..files.len())
+            .map(|i| before[i] != cancelled[i])
+            .collect();
+        assert_eq!(reencrypted.iter().filter(|r| **r).count(), 2);
+        assert!(tmpdir.path().join("seshat-index.journal").exists());
+        assert_eq!(
+            std::fs::read(tmpdir.path().join(KEYFILE)).unwrap(),
+            key_file
+        );
+
+        // Reopening the store resumes the rotation where it stopped.
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the store after a cancelled rotation");
+        assert!(dir.recovery().resumed_rotation);
+        assert!(!tmpdir.path().join("seshat-index.journal").exists());
+
+        let after = read_files();
+
+        for i in 0..files.len() {
+            assert_eq!(cancelled[i] != after[i], !reencrypted[i]);
+            assert_eq!(
+                dir.atomic_read(Path::new(files[i])).unwrap(),
+                files[i].as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn reencrypt_large_files_while_rotating() {
+        let content: Vec<u8> = (0..(PIPELINE_CHUNK_SIZE * 3 + 17) as u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let configurations: Vec<fn(EncryptedMmapDirectory) -> EncryptedMmapDirectory> = vec![
+            |dir| dir,
+            |dir| dir.with_read_ahead(1000).unwrap(),
+            |dir| dir.with_hidden_headers().unwrap(),
+            |dir| dir.with_tag_size(16).unwrap(),
+            |dir| dir.with_padding(Padding::Bucket(4096)).unwrap(),
+        ];
+
+        for configure in configurations {
+            let tmpdir = tempdir().unwrap();
+            let mut dir = configure(
+                EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                    .expect("Can't create a new store"),
+            );
+
+            let mut writer = dir.open_write(Path::new("segment")).unwrap();
+            writer.write_all(&content).unwrap();
+            writer.terminate().unwrap();
+            dir.atomic_write(Path::new("meta.json"), &content).unwrap();
+
+            let before = std::fs::read(tmpdir.path().join("segment")).unwrap();
+
+            dir.rotate_store_key().unwrap();
+
+            assert_ne!(
+                std::fs::read(tmpdir.path().join("segment")).unwrap(),
+                before
+            );
+            assert_eq!(
+                dir.open_read(Path::new("segment")).unwrap().as_slice(),
+                &content[..]
+            );
+            assert_eq!(dir.atomic_read(Path::new("meta.json")).unwrap(), content);
+            drop(dir);
+
+            let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+            assert_eq!(
+                dir.open_read(Path::new("segment")).unwrap().as_slice(),
+                &content[..]
+            );
+        }
+
+        // A corrupt file isn't re-encrypted, the rotation fails.
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        dir.atomic_write(Path::new("segment"), &content).unwrap();
+
+        let path = tmpdir.path().join("segment");
+        let mut encrypted = std::fs::read(&path).unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 0x01;
+        std::fs::write(&path, &encrypted).unwrap();
+
+        assert!(dir.rotate_store_key().is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), encrypted);
+    }
+
+    #[test]
+    #[ignore]
+    // Run using `cargo test --release -- --ignored --nocapture bench_reencrypt`.
+    fn bench_reencrypt_large_files() {
+        use std::time::Instant;
+
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let content = vec![0u8; 64 * 1024 * 1024];
+        let files = 4;
+
+        for i in 0..files {
+            dir.atomic_write(&PathBuf::from(format!("segment-{}", i)), &content)
+                .unwrap();
+        }
+
+        let provider = dir.state.provider().unwrap().clone();
+        let new_provider = EncryptedMmapDirectory::create_provider(
+            &EncryptedMmapDirectory::generate_key().unwrap().to_vec(),
+        )
+        .unwrap();
+        let megabytes = (files * content.len()) as f64 / (1024.0 * 1024.0);
+
+        // Decrypt every file as a whole before it gets encrypted again.
+        let start = Instant::now();
+        for i in 0..files {
+            let data = std::fs::read(tmpdir.path().join(format!("segment-{}", i))).unwrap();
+            let decrypted = StoreState::open(&*provider, &data, None).unwrap();
+            dir.state
+                .seal(&*new_provider, &decrypted, WriteMode::Atomic)
+                .unwrap();
+        }
+        let sequential_time = start.elapsed();
+
+        let start = Instant::now();
+        for i in 0..files {
+            let data = std::fs::read(tmpdir.path().join(format!("segment-{}", i))).unwrap();
+            dir.reencrypt(provider.clone(), &*new_provider, Arc::new(data))
+                .unwrap();
+        }
+        let pipelined_time = start.elapsed();
+
+        println!(
+            "sequential: {:.1} MB/s, pipelined: {:.1} MB/s",
+            megabytes / sequential_time.as_secs_f64(),
+            megabytes / pipelined_time.as_secs_f64()
+        );
+
+        let start = Instant::now();
+        dir.rotate_store_key().unwrap();
+
+        println!(
+            "rotation: {:.1} MB/s",
+            megabytes / start.elapsed().as_secs_f64()
+        );
+    }
+}
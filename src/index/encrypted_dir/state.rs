@@ -0,0 +1,1545 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The encryption state of a store, shared by all the clones of a directory,
+//! and the format of the files it seals.
+
+use super::*;
+
+/// A writer that seals the data of a file using an `AeadProvider`.
+///
+/// If the provider can seal a stream, the data is encrypted as it's written
+/// and only the authentication tag is written once the file gets
+/// terminated. Otherwise, e.g. for padded files or truncated tags, the file
+/// is held in memory until it's complete and sealed at once. Errors of the
+/// final seal are reported by `terminate()`, a writer that is dropped
+/// without being terminated is sealed on a best effort basis and failures
+/// are merely logged.
+pub(super) struct SealingWriter<W: TerminatingWrite> {
+    writer: W,
+    state: Arc<StoreState>,
+    buffer: Vec<u8>,
+    // The stream that seals the data as it's written, the data is buffered
+    // and sealed at once if the file can't be streamed.
+    stream: Option<Box<dyn SealStream>>,
+    started: bool,
+    header_size: usize,
+    streamed: u64,
+    sealed: bool,
+}
+
+impl<W: TerminatingWrite> SealingWriter<W> {
+    pub(super) fn new(writer: W, state: Arc<StoreState>) -> Self {
+        SealingWriter {
+            writer,
+            state,
+            buffer: Vec::new(),
+            stream: None,
+            started: false,
+            header_size: 0,
+            streamed: 0,
+            sealed: false,
+        }
+    }
+
+    /// Start streaming the file, the header gets written to the underlying
+    /// writer right away.
+    fn start(&mut self) -> std::io::Result<()> {
+        let stream = self.state.start_stream(WriteMode::Streaming)?;
+        self.started = true;
+
+        if let Some((header, stream)) = stream {
+            self.stream = Some(stream);
+            self.header_size = header.len();
+            self.writer.write_all(&header)?;
+        }
+
+        Ok(())
+    }
+
+    /// Seal the buffered data and write it, prefixed by the write mode tag
+    /// and the nonce, to the underlying writer. If the file was streamed,
+    /// only the authentication tag is left to be written.
+    fn seal(&mut self) -> std::io::Result<()> {
+        if self.sealed {
+            return Ok(());
+        }
+
+        // Mark the file as sealed before anything gets written, a failed
+        // write can't be retried since the file might be partially written.
+        self.sealed = true;
+
+        if let Some(stream) = self.stream.take() {
+            let tag = self.state.finish_stream(stream, self.streamed)?;
+
+            self.writer.write_all(&tag)?;
+            self.writer.flush()?;
+
+            self.state.io_counters.record_write(
+                self.streamed as usize,
+                self.header_size + self.streamed as usize + tag.len(),
+            );
+
+            return Ok(());
+        }
+
+        // Hold on to the provider until the file is written, a store key
+        // rotation needs to wait for us so it doesn't miss the file.
+        let provider = self.state.provider()?;
+        let sealed = self
+            .state
+            .seal(&**provider, &self.buffer, WriteMode::Streaming)?;
+
+        self.writer.write_all(&sealed)?;
+        self.writer.flush()?;
+
+        self.state
+            .io_counters
+            .record_write(self.buffer.len(), sealed.len());
+
+        Ok(())
+    }
+}
+
+impl<W: TerminatingWrite> Write for SealingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.sealed {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                "File has been already sealed",
+            ));
+        }
+
+        if !self.started {
+            self.start()?;
+        }
+
+        match &mut self.stream {
+            Some(stream) => {
+                let mut data = Zeroizing::new(buf.to_vec());
+                stream.update(&mut data)?;
+                self.writer.write_all(&data)?;
+                self.streamed += data.len() as u64;
+            }
+            None => self.buffer.extend_from_slice(buf),
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // A file that is readable at all needs to be sealed first, there's
+        // no need to flush the underlying writer before that.
+        Ok(())
+    }
+}
+
+impl<W: TerminatingWrite> TerminatingWrite for SealingWriter<W> {
+    fn terminate_ref(&mut self, token: AntiCallToken) -> std::io::Result<()> {
+        self.seal()?;
+        self.writer.terminate_ref(token)
+    }
+}
+
+impl<W: TerminatingWrite> Drop for SealingWriter<W> {
+    fn drop(&mut self) {
+        if self.sealed {
+            return;
+        }
+
+        // Panicking here would take the whole process down, e.g. if the
+        // disk is full.
+        if let Err(e) = self.seal() {
+            warn!(
+                "Failed to seal a file that was dropped without being terminated: {}",
+                e
+            );
+        }
+    }
+}
+
+// The files that are being sealed piece by piece, using the keys they were
+// started with, and the store key rotations that wait for them.
+#[derive(Debug, Default)]
+struct Streams {
+    running: usize,
+    rotations: usize,
+}
+
+/// Allows new streams to be started again once the store key rotation that
+/// holds it is done, see `StoreState::start_rotation()`.
+pub(super) struct RotationGuard<'a> {
+    state: &'a StoreState,
+}
+
+impl Drop for RotationGuard<'_> {
+    fn drop(&mut self) {
+        self.state.streams.lock().unwrap().rotations -= 1;
+    }
+}
+
+#[derive(Debug)]
+/// The encryption state of a store, it's shared between all the clones of a
+/// directory and the writers the directory hands out.
+///
+/// All the keys of the store live here, locking the store replaces them so
+/// they get zeroized once the last read or write that uses them is done.
+pub(super) struct StoreState {
+    pub(super) provider: RwLock<Arc<dyn AeadProvider>>,
+    pub(super) journal_provider: RwLock<Arc<dyn AeadProvider>>,
+    pub(super) key_wrapper: RwLock<Arc<dyn KeyWrapper>>,
+    pub(super) padding: RwLock<Option<Padding>>,
+    pub(super) hidden_headers: AtomicBool,
+    pub(super) meta_backup: AtomicBool,
+    // The tag size bits of the write mode tag, unset if tags aren't
+    // truncated.
+    pub(super) tag_size_bits: AtomicU8,
+    pub(super) file_names: RwLock<Option<Arc<FileNames>>>,
+    // The cipher the key file declares, unset for wrapped store keys.
+    pub(super) key_file_cipher: RwLock<Option<Cipher>>,
+    pub(super) metadata: RwLock<BTreeMap<String, String>>,
+    decrypted: Mutex<DecryptedFiles>,
+    pub(super) io_counters: IoCounters,
+    pub(super) encrypted_bytes: AtomicU64,
+    streams: Mutex<Streams>,
+    streams_done: Condvar,
+    locked: AtomicBool,
+    created: Instant,
+    // The time of the last read or write, in milliseconds since the state
+    // was created.
+    last_used: AtomicU64,
+}
+
+impl StoreState {
+    pub(super) fn new(
+        provider: Arc<dyn AeadProvider>,
+        journal_provider: Arc<dyn AeadProvider>,
+        key_wrapper: Arc<dyn KeyWrapper>,
+    ) -> Self {
+        StoreState {
+            provider: RwLock::new(provider),
+            journal_provider: RwLock::new(journal_provider),
+            key_wrapper: RwLock::new(key_wrapper),
+            padding: RwLock::new(None),
+            hidden_headers: AtomicBool::new(false),
+            meta_backup: AtomicBool::new(false),
+            tag_size_bits: AtomicU8::new(0),
+            file_names: RwLock::new(None),
+            key_file_cipher: RwLock::new(None),
+            metadata: RwLock::new(BTreeMap::new()),
+            decrypted: Mutex::new(DecryptedFiles::default()),
+            io_counters: IoCounters::default(),
+            encrypted_bytes: AtomicU64::new(0),
+            streams: Mutex::new(Streams::default()),
+            streams_done: Condvar::new(),
+            locked: AtomicBool::new(false),
+            created: Instant::now(),
+            last_used: AtomicU64::new(0),
+        }
+    }
+
+    /// Create the state of a store that is locked until its keys are
+    /// provided using `unlock()`.
+    pub(super) fn locked() -> Self {
+        let state = StoreState::new(
+            Arc::new(LockedKeys),
+            Arc::new(LockedKeys),
+            Arc::new(LockedKeys),
+        );
+        state.locked.store(true, Ordering::SeqCst);
+        state
+    }
+
+    /// Get the current AEAD provider.
+    ///
+    /// The provider gets replaced while the store key is rotated, the guard
+    /// needs to be held until the file that is read or written using the
+    /// provider hit the disk. Returns a `Locked` error while the store is
+    /// locked, every other call counts as a use of the store.
+    pub(super) fn provider(&self) -> std::io::Result<RwLockReadGuard<'_, Arc<dyn AeadProvider>>> {
+        let provider = self.provider.read().unwrap();
+        self.check_unlocked()?;
+        self.check_cipher(&**provider)?;
+        self.last_used
+            .store(self.created.elapsed().as_millis() as u64, Ordering::SeqCst);
+
+        Ok(provider)
+    }
+
+    /// Get the provider that protects the journal of a store key rotation.
+    pub(super) fn journal_provider(&self) -> std::io::Result<Arc<dyn AeadProvider>> {
+        let provider = self.journal_provider.read().unwrap();
+        self.check_unlocked()?;
+
+        Ok(provider.clone())
+    }
+
+    /// Get the key wrapper that protects the store key in the key file.
+    pub(super) fn key_wrapper(&self) -> std::io::Result<Arc<dyn KeyWrapper>> {
+        let key_wrapper = self.key_wrapper.read().unwrap();
+        self.check_unlocked()?;
+
+        Ok(key_wrapper.clone())
+    }
+
+    /// Check that the given provider uses the cipher the key file declares,
+    /// see `AeadProvider::cipher()`.
+    fn check_cipher(&self, provider: &dyn AeadProvider) -> std::io::Result<()> {
+        let key_file = match *self.key_file_cipher.read().unwrap() {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+
+        match provider.cipher() {
+            Some(c) if c != key_file || provider.nonce_size() != key_file.iv_size() => {
+                Err(CryptoError::CipherMismatch {
+                    key_file,
+                    provider: c,
+                    nonce_size: provider.nonce_size(),
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Return a `Locked` error if the store is locked.
+    pub(super) fn check_unlocked(&self) -> std::io::Result<()> {
+        if self.locked.load(Ordering::SeqCst) {
+            Err(Locked::error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Get the decrypted content of a file if a reader still holds it.
+    pub(super) fn cached_file(&self, path: &Path) -> Option<Arc<DecryptedData>> {
+        let cache = self.decrypted.lock().unwrap();
+        cache.0.get(path).and_then(Weak::upgrade)
+    }
+
+    /// Remember the decrypted content of a file, without keeping it alive.
+    pub(super) fn cache_file(&self, path: &Path, data: &Arc<DecryptedData>) {
+        let mut cache = self.decrypted.lock().unwrap();
+        cache.0.retain(|_, d| d.strong_count() > 0);
+        cache.0.insert(path.to_owned(), Arc::downgrade(data));
+    }
+
+    /// Forget the decrypted content of a file that gets replaced or deleted.
+    pub(super) fn forget_file(&self, path: &Path) {
+        self.decrypted.lock().unwrap().0.remove(path);
+    }
+
+    /// How long the store wasn't used.
+    pub(super) fn idle_time(&self) -> Duration {
+        let last_used = Duration::from_millis(self.last_used.load(Ordering::SeqCst));
+        self.created
+            .elapsed()
+            .checked_sub(last_used)
+            .unwrap_or_default()
+    }
+
+    /// Drop all the keys of the store, reads and writes fail until the store
+    /// gets unlocked again.
+    ///
+    /// Waits for a running store key rotation to finish.
+    pub(super) fn lock(&self) {
+        let mut provider = self.provider.write().unwrap();
+        self.lock_keys(&mut provider);
+    }
+
+    /// Drop all the keys of the store while the provider is locked for
+    /// writing.
+    pub(super) fn lock_keys(&self, provider: &mut Arc<dyn AeadProvider>) {
+        *self.journal_provider.write().unwrap() = Arc::new(LockedKeys);
+        *self.key_wrapper.write().unwrap() = Arc::new(LockedKeys);
+        *provider = Arc::new(LockedKeys);
+        self.locked.store(true, Ordering::SeqCst);
+    }
+
+    /// Replace the keys of a locked store.
+    pub(super) fn unlock(
+        &self,
+        provider: Arc<dyn AeadProvider>,
+        journal_provider: Arc<dyn AeadProvider>,
+        key_wrapper: Arc<dyn KeyWrapper>,
+    ) {
+        let mut guard = self.provider.write().unwrap();
+        *self.journal_provider.write().unwrap() = journal_provider;
+        *self.key_wrapper.write().unwrap() = key_wrapper;
+        *guard = provider;
+        self.locked.store(false, Ordering::SeqCst);
+        self.last_used
+            .store(self.created.elapsed().as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Encrypt the given data, the returned buffer contains the write mode
+    /// tag and the nonce followed by the sealed data.
+    ///
+    /// If padding is enabled, the data is prefixed by its length and padded
+    /// so the encrypted file ends on a boundary of the padding scheme. If
+    /// hidden headers are enabled, the write mode tag is hidden as well. If
+    /// truncated tags are enabled, the tag size is recorded in the write
+    /// mode tag.
+    pub(super) fn seal(
+        &self,
+        provider: &dyn AeadProvider,
+        data: &[u8],
+        mode: WriteMode,
+    ) -> std::io::Result<Vec<u8>> {
+        let padding = *self.padding.read().unwrap();
+        let hidden = self.hidden_headers.load(Ordering::SeqCst);
+        let tag_size_bits = self.tag_size_bits.load(Ordering::SeqCst);
+
+        let padded;
+        let (data, tag) = match padding {
+            Some(padding) => {
+                padded = StoreState::pad(data, padding, self.overhead(provider)?);
+                (&padded[..], mode as u8 | tag_size_bits | PADDED_FLAG)
+            }
+            None => (data, mode as u8 | tag_size_bits),
+        };
+
+        let encrypted = if hidden {
+            StoreState::seal_hidden(provider, data, tag)?
+        } else {
+            StoreState::seal_tagged(provider, data, tag)?
+        };
+
+        self.encrypted_bytes
+            .fetch_add(data.len() as u64, Ordering::SeqCst);
+
+        Ok(encrypted)
+    }
+
+    /// Encrypt the plaintext the given reader produces like `seal()` does
+    /// it, the plaintext is sealed while it's being read.
+    ///
+    /// Padding and truncated tags need the whole plaintext up front, it's
+    /// read into memory first if either of them is enabled.
+    pub(super) fn seal_from(
+        &self,
+        provider: &dyn AeadProvider,
+        plaintext: &mut dyn Read,
+        mode: WriteMode,
+    ) -> std::io::Result<Vec<u8>> {
+        if self.padding.read().unwrap().is_some() || self.tag_size_bits.load(Ordering::SeqCst) != 0
+        {
+            let mut data = Zeroizing::new(Vec::new());
+            plaintext.read_to_end(&mut data)?;
+            return self.seal(provider, &data, mode);
+        }
+
+        let nonce = EncryptedMmapDirectory::generate_nonce(provider.nonce_size())?;
+        let (mut encrypted, aad) = self.header(provider, &nonce, mode)?;
+        let sealed = provider.seal_into(&nonce, &aad, plaintext, &mut encrypted)?;
+
+        self.encrypted_bytes.fetch_add(sealed, Ordering::SeqCst);
+
+        Ok(encrypted)
+    }
+
+    /// Start sealing a file whose plaintext is handed over piece by piece.
+    ///
+    /// Returns the header of the file, that is the write mode tag and the
+    /// nonce, and the stream that seals the plaintext. Returns `None` if
+    /// padding or truncated tags are enabled or if the provider can't seal
+    /// a stream, the plaintext needs to be sealed at once using `seal()`
+    /// then. Every started stream needs to be passed to `finish_stream()`.
+    fn start_stream(&self, mode: WriteMode) -> std::io::Result<Option<StartedStream>> {
+        if self.padding.read().unwrap().is_some() || self.tag_size_bits.load(Ordering::SeqCst) != 0
+        {
+            return Ok(None);
+        }
+
+        // A store key rotation can't start while the stream is registered,
+        // see `start_rotation()`. If a rotation is waiting for the running
+        // streams, the file is sealed at once, using the new key.
+        {
+            let mut streams = self.streams.lock().unwrap();
+
+            if streams.rotations > 0 {
+                return Ok(None);
+            }
+
+            streams.running += 1;
+        }
+
+        let stream = self.provider().and_then(|provider| {
+            let nonce = EncryptedMmapDirectory::generate_nonce(provider.nonce_size())?;
+            let (header, aad) = self.header(&**provider, &nonce, mode)?;
+
+            Ok(provider
+                .seal_stream(&nonce, &aad)?
+                .map(|stream| (header, stream)))
+        });
+
+        if let Ok(None) | Err(_) = stream {
+            self.stream_done();
+        }
+
+        stream
+    }
+
+    /// Create the header of a file that is sealed using the given nonce.
+    ///
+    /// Returns the header, the write mode tag followed by the nonce, and the
+    /// additional authenticated data the file needs to be sealed with.
+    fn header(
+        &self,
+        provider: &dyn AeadProvider,
+        nonce: &[u8],
+        mode: WriteMode,
+    ) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+        let tag = mode as u8;
+
+        let (mut header, aad) = if self.hidden_headers.load(Ordering::SeqCst) {
+            let masked = tag ^ provider.header_mask(nonce)?;
+            (
+                vec![HIDDEN_HEADER_TAG, masked],
+                vec![HIDDEN_HEADER_TAG, tag],
+            )
+        } else {
+            (vec![tag], vec![tag])
+        };
+
+        header.extend_from_slice(nonce);
+
+        Ok((header, aad))
+    }
+
+    /// Finish a stream that was started using `start_stream()`, the given
+    /// number of plaintext bytes were sealed.
+    fn finish_stream(&self, stream: Box<dyn SealStream>, sealed: u64) -> std::io::Result<Vec<u8>> {
+        let tag = stream.finish();
+
+        self.encrypted_bytes.fetch_add(sealed, Ordering::SeqCst);
+        self.stream_done();
+
+        tag
+    }
+
+    /// Unregister a stream that was registered by `start_stream()`.
+    fn stream_done(&self) {
+        let mut streams = self.streams.lock().unwrap();
+        streams.running -= 1;
+        self.streams_done.notify_all();
+    }
+
+    /// Prepare a store key rotation, no new stream can be started until the
+    /// returned guard is dropped.
+    ///
+    /// Waits until every file that is being sealed piece by piece is
+    /// finished. Needs to be called before the provider is locked for
+    /// writing, the threads that write those files might need to read
+    /// other files before they can finish them.
+    pub(super) fn start_rotation(&self) -> RotationGuard<'_> {
+        let mut streams = self.streams.lock().unwrap();
+        streams.rotations += 1;
+
+        while streams.running > 0 {
+            streams = self.streams_done.wait(streams).unwrap();
+        }
+
+        RotationGuard { state: self }
+    }
+
+    /// Prepare a store key rotation like `start_rotation()`, but return
+    /// `None` instead of waiting if files are being sealed piece by piece
+    /// right now.
+    pub(super) fn try_start_rotation(&self) -> Option<RotationGuard<'_>> {
+        let mut streams = self.streams.lock().unwrap();
+
+        if streams.running > 0 {
+            return None;
+        }
+
+        streams.rotations += 1;
+
+        Some(RotationGuard { state: self })
+    }
+
+    /// The number of bytes sealing adds to the data of a file, the header,
+    /// the nonce, and the authentication tag.
+    pub(super) fn overhead(&self, provider: &dyn AeadProvider) -> std::io::Result<usize> {
+        let header_size = if self.hidden_headers.load(Ordering::SeqCst) {
+            2 * WRITE_MODE_SIZE
+        } else {
+            WRITE_MODE_SIZE
+        };
+        let tag_size = StoreState::tag_size(provider, self.tag_size_bits.load(Ordering::SeqCst))?;
+
+        Ok(header_size + provider.nonce_size() + tag_size)
+    }
+
+    /// Prefix the given data with its length and pad it with zeros, so that
+    /// the sealed file size falls on a boundary of the padding scheme.
+    ///
+    /// The overhead is the number of bytes sealing adds to the data.
+    fn pad(data: &[u8], padding: Padding, overhead: usize) -> Vec<u8> {
+        let size = (overhead + PADDING_LENGTH_SIZE + data.len()) as u64;
+        let padded_size = padding.padded_size(size) as usize - overhead;
+
+        let mut padded = Vec::with_capacity(padded_size);
+        padded.extend_from_slice(&(data.len() as u64).to_be_bytes());
+        padded.extend_from_slice(data);
+        padded.resize(padded_size, 0);
+
+        padded
+    }
+
+    /// The size of the authentication tag of a file with the given tag size
+    /// bits.
+    pub(super) fn tag_size(
+        provider: &dyn AeadProvider,
+        tag_size_bits: u8,
+    ) -> std::io::Result<usize> {
+        match StoreState::truncated_tag_size(tag_size_bits) {
+            Some(tag_size) => Ok(tag_size),
+            None => provider.tag_size(),
+        }
+    }
+
+    /// The size a write mode tag says the authentication tag was truncated
+    /// to, `None` if it wasn't truncated.
+    fn truncated_tag_size(tag: u8) -> Option<usize> {
+        TRUNCATED_TAG_SIZES
+            .iter()
+            .find(|(bits, _)| tag & TAG_SIZE_BITS == *bits)
+            .map(|(_, tag_size)| *tag_size)
+    }
+
+    /// Seal the given data, truncating the authentication tag if the write
+    /// mode tag asks for it.
+    fn seal_with_tag(
+        provider: &dyn AeadProvider,
+        nonce: &[u8],
+        aad: &[u8],
+        data: &[u8],
+        tag: u8,
+    ) -> std::io::Result<Vec<u8>> {
+        match StoreState::truncated_tag_size(tag) {
+            Some(tag_size) => provider.seal_truncated(nonce, aad, data, tag_size),
+            None => provider.seal(nonce, aad, data),
+        }
+    }
+
+    /// Authenticate and decrypt the ciphertext of a file into the given
+    /// writer, taking a truncated authentication tag into account.
+    fn open_ciphertext_into(
+        provider: &dyn AeadProvider,
+        header: &FileHeader,
+        ciphertext: &[u8],
+        sink: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        match StoreState::truncated_tag_size(header.tag) {
+            Some(tag_size) => {
+                provider.open_truncated_into(header.nonce, &header.aad, ciphertext, tag_size, sink)
+            }
+            None => provider.open_into(header.nonce, &header.aad, ciphertext, sink),
+        }
+    }
+
+    /// Encrypt the given data without accounting for it, used for our own
+    /// bookkeeping files.
+    pub(super) fn seal_file(
+        provider: &dyn AeadProvider,
+        data: &[u8],
+        mode: WriteMode,
+    ) -> std::io::Result<Vec<u8>> {
+        StoreState::seal_tagged(provider, data, mode as u8)
+    }
+
+    /// Encrypt the given data, the tag is stored in front of the nonce and
+    /// authenticated with the data.
+    fn seal_tagged(provider: &dyn AeadProvider, data: &[u8], tag: u8) -> std::io::Result<Vec<u8>> {
+        let nonce = EncryptedMmapDirectory::generate_nonce(provider.nonce_size())?;
+        let sealed = StoreState::seal_with_tag(provider, &nonce, &[tag], data, tag)?;
+        let tag = [tag];
+
+        let mut encrypted = Vec::with_capacity(WRITE_MODE_SIZE + nonce.len() + sealed.len());
+        encrypted.extend_from_slice(&tag);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&sealed);
+
+        Ok(encrypted)
+    }
+
+    /// Encrypt the given data, the tag is masked using a key that is
+    /// derived from the store key and stored behind the hidden header tag.
+    ///
+    /// Both tags are authenticated with the data:
+    ///
+    /// ```text
+    ///     file_data = (hidden || tag ^ mask(nonce) || nonce ||
+    ///                  seal(nonce, hidden || tag, plaintext))
+    /// ```
+    fn seal_hidden(provider: &dyn AeadProvider, data: &[u8], tag: u8) -> std::io::Result<Vec<u8>> {
+        let nonce = EncryptedMmapDirectory::generate_nonce(provider.nonce_size())?;
+        let masked = tag ^ provider.header_mask(&nonce)?;
+        let sealed =
+            StoreState::seal_with_tag(provider, &nonce, &[HIDDEN_HEADER_TAG, tag], data, tag)?;
+
+        let mut encrypted = Vec::with_capacity(2 * WRITE_MODE_SIZE + nonce.len() + sealed.len());
+        encrypted.extend_from_slice(&[HIDDEN_HEADER_TAG, masked]);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&sealed);
+
+        Ok(encrypted)
+    }
+
+    /// The size of the header in front of the nonce, the first byte of the
+    /// encrypted file data tells if the header is hidden.
+    pub(super) fn header_size(data: &[u8]) -> usize {
+        if data.first() == Some(&HIDDEN_HEADER_TAG) {
+            2 * WRITE_MODE_SIZE
+        } else {
+            WRITE_MODE_SIZE
+        }
+    }
+
+    /// Split encrypted file data into its header and the ciphertext.
+    ///
+    /// If an expected write mode is given, files that were written in a
+    /// different mode are rejected the same way as files that fail to
+    /// authenticate.
+    pub(super) fn split<'a>(
+        provider: &dyn AeadProvider,
+        data: &'a [u8],
+        expected: Option<WriteMode>,
+    ) -> std::io::Result<(FileHeader<'a>, &'a [u8])> {
+        let nonce_size = provider.nonce_size();
+        let header_size = StoreState::header_size(data);
+
+        if data.len() < header_size + nonce_size {
+            return Err(authentication_error());
+        }
+
+        let (header, rest) = data.split_at(header_size);
+        let (nonce, ciphertext) = rest.split_at(nonce_size);
+
+        let aad = if header[0] == HIDDEN_HEADER_TAG {
+            vec![HIDDEN_HEADER_TAG, header[1] ^ provider.header_mask(nonce)?]
+        } else {
+            header.to_vec()
+        };
+
+        let tag = aad[aad.len() - 1];
+        let mode = WriteMode::from_tag(tag).ok_or_else(authentication_error)?;
+
+        if expected.map_or(false, |e| e != mode) {
+            return Err(authentication_error());
+        }
+
+        Ok((FileHeader { tag, aad, nonce }, ciphertext))
+    }
+
+    /// Authenticate and decrypt the given encrypted file data.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - The provider that sealed the data.
+    /// * `data` - The encrypted file data.
+    /// * `expected` - The mode the file needs to be written in, `None` if
+    /// either mode is fine.
+    pub(super) fn open(
+        provider: &dyn AeadProvider,
+        data: &[u8],
+        expected: Option<WriteMode>,
+    ) -> std::io::Result<Vec<u8>> {
+        let (header, ciphertext) = StoreState::split(provider, data, expected)?;
+        let mut plaintext = if StoreState::truncated_tag_size(header.tag).is_some() {
+            let mut plaintext = Vec::new();
+            StoreState::open_ciphertext_into(provider, &header, ciphertext, &mut plaintext)?;
+            plaintext
+        } else {
+            provider.open(header.nonce, &header.aad, ciphertext)?
+        };
+
+        if header.tag & PADDED_FLAG != 0 {
+            let length = StoreState::padded_length(&plaintext)?;
+            plaintext.truncate(PADDING_LENGTH_SIZE + length as usize);
+            plaintext.drain(..PADDING_LENGTH_SIZE);
+        }
+
+        Ok(plaintext)
+    }
+
+    /// Authenticate and decrypt the given encrypted file data, the plaintext
+    /// is streamed into the given writer.
+    ///
+    /// Returns the number of plaintext bytes that were written.
+    pub(super) fn open_into(
+        provider: &dyn AeadProvider,
+        data: &[u8],
+        expected: Option<WriteMode>,
+        sink: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        let (header, ciphertext) = StoreState::split(provider, data, expected)?;
+
+        if header.tag & PADDED_FLAG == 0 {
+            return StoreState::open_ciphertext_into(provider, &header, ciphertext, sink);
+        }
+
+        let mut writer = UnpaddingWriter::new(sink);
+        StoreState::open_ciphertext_into(provider, &header, ciphertext, &mut writer)?;
+        writer.finish()
+    }
+
+    /// Read the length of the original data from padded plaintext.
+    fn padded_length(plaintext: &[u8]) -> std::io::Result<u64> {
+        if plaintext.len() < PADDING_LENGTH_SIZE {
+            return Err(authentication_error());
+        }
+
+        let mut length = [0u8; PADDING_LENGTH_SIZE];
+        length.copy_from_slice(&plaintext[..PADDING_LENGTH_SIZE]);
+        let length = u64::from_be_bytes(length);
+
+        if length > (plaintext.len() - PADDING_LENGTH_SIZE) as u64 {
+            return Err(authentication_error());
+        }
+
+        Ok(length)
+    }
+
+    /// Get the mode the given encrypted file data was written in.
+    pub(super) fn write_mode(
+        provider: &dyn AeadProvider,
+        data: &[u8],
+    ) -> std::io::Result<WriteMode> {
+        let (header, _) = StoreState::split(provider, data, None)?;
+        WriteMode::from_tag(header.tag).ok_or_else(authentication_error)
+    }
+
+    /// Authenticate and decrypt a file that was written before files got a
+    /// write mode tag, see `EncryptedMmapDirectory::with_legacy_files()`.
+    ///
+    /// Those files consist of the nonce followed by the sealed data, nothing
+    /// else is authenticated with the data.
+    pub(super) fn open_legacy(
+        provider: &dyn AeadProvider,
+        data: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        let nonce_size = provider.nonce_size();
+
+        if data.len() < nonce_size {
+            return Err(authentication_error());
+        }
+
+        let (nonce, ciphertext) = data.split_at(nonce_size);
+        provider.open(nonce, &[], ciphertext)
+    }
+}
+
+/// The header in front of the sealed data of an encrypted file.
+pub(super) struct FileHeader<'a> {
+    /// The write mode tag, unmasked if the header is hidden.
+    pub(super) tag: u8,
+    /// The additional authenticated data the file was sealed with.
+    aad: Vec<u8>,
+    pub(super) nonce: &'a [u8],
+}
+
+/// A writer that strips the length prefix and the padding of padded
+/// plaintext while it's being decrypted.
+struct UnpaddingWriter<'a> {
+    sink: &'a mut dyn Write,
+    header: Vec<u8>,
+    remaining: u64,
+    written: u64,
+}
+
+impl<'a> UnpaddingWriter<'a> {
+    fn new(sink: &'a mut dyn Write) -> Self {
+        UnpaddingWriter {
+            sink,
+            header: Vec::with_capacity(PADDING_LENGTH_SIZE),
+            remaining: 0,
+            written: 0,
+        }
+    }
+
+    /// Check that the whole original data was written, returns its length.
+    fn finish(self) -> std::io::Result<u64> {
+        if self.header.len() < PADDING_LENGTH_SIZE || self.remaining != 0 {
+            return Err(authentication_error());
+        }
+
+        Ok(self.written)
+    }
+}
+
+impl Write for UnpaddingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut data = buf;
+
+        if self.header.len() < PADDING_LENGTH_SIZE {
+            let missing = PADDING_LENGTH_SIZE - self.header.len();
+            let (header, rest) = data.split_at(missing.min(data.len()));
+            self.header.extend_from_slice(header);
+            data = rest;
+
+            if self.header.len() == PADDING_LENGTH_SIZE {
+                let mut length = [0u8; PADDING_LENGTH_SIZE];
+                length.copy_from_slice(&self.header);
+                self.remaining = u64::from_be_bytes(length);
+            }
+        }
+
+        // Everything past the original data is padding.
+        let take = self.remaining.min(data.len() as u64) as usize;
+        self.sink.write_all(&data[..take])?;
+        self.remaining -= take as u64;
+        self.written += take as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How a file was written, the mode is stored in front of every encrypted
+/// file and authenticated with it.
+///
+/// Tantivy reads files that were written using `atomic_write()` only using
+/// `atomic_read()` and files that were written using `open_write()` only
+/// using `open_read()`. Binding the mode to the file makes sure that a file
+/// can't be swapped with one that was written in the other mode.
+pub(super) enum WriteMode {
+    Atomic = 1,
+    Streaming = 2,
+}
+
+impl WriteMode {
+    /// Get the write mode of a tag, ignoring the padding flag and the tag
+    /// size bits.
+    pub(super) fn from_tag(tag: u8) -> Option<WriteMode> {
+        if tag & TAG_SIZE_BITS == TAG_SIZE_BITS {
+            return None;
+        }
+
+        match tag & !(PADDED_FLAG | TAG_SIZE_BITS) {
+            1 => Some(WriteMode::Atomic),
+            2 => Some(WriteMode::Streaming),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A scheme to pad encrypted files to, hiding their exact size.
+///
+/// File sizes reveal how much data a store contains, padding trades disk
+/// space for less metadata leakage, see `with_padding()`.
+#[allow(dead_code)]
+pub enum Padding {
+    /// Pad every file to the next power of two.
+    PowerOfTwo,
+    /// Pad every file to the next multiple of the given number of bytes.
+    Bucket(u64),
+}
+
+impl Padding {
+    /// Get the size a file of the given size gets padded to.
+    pub(super) fn padded_size(self, size: u64) -> u64 {
+        match self {
+            Padding::PowerOfTwo => size.next_power_of_two(),
+            Padding::Bucket(bucket) => ((size + bucket - 1) / bucket) * bucket,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// The counters behind `IoStats`, they're shared by all the clones of a
+/// directory.
+pub(super) struct IoCounters {
+    plaintext_read: AtomicU64,
+    ciphertext_read: AtomicU64,
+    plaintext_written: AtomicU64,
+    ciphertext_written: AtomicU64,
+}
+
+impl IoCounters {
+    pub(super) fn record_read(&self, plaintext: usize, ciphertext: usize) {
+        self.plaintext_read
+            .fetch_add(plaintext as u64, Ordering::SeqCst);
+        self.ciphertext_read
+            .fetch_add(ciphertext as u64, Ordering::SeqCst);
+    }
+
+    pub(super) fn record_write(&self, plaintext: usize, ciphertext: usize) {
+        self.plaintext_written
+            .fetch_add(plaintext as u64, Ordering::SeqCst);
+        self.ciphertext_written
+            .fetch_add(ciphertext as u64, Ordering::SeqCst);
+    }
+
+    pub(super) fn stats(&self) -> IoStats {
+        IoStats {
+            plaintext_read: self.plaintext_read.load(Ordering::SeqCst),
+            ciphertext_read: self.ciphertext_read.load(Ordering::SeqCst),
+            plaintext_written: self.plaintext_written.load(Ordering::SeqCst),
+            ciphertext_written: self.ciphertext_written.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// The decrypted content of a file, in memory or in a spill file.
+pub(super) type DecryptedData = Box<dyn Deref<Target = [u8]> + Send + Sync>;
+
+#[derive(Default)]
+/// The decrypted files that are still held by a reader, keyed by the path
+/// Tantivy knows them by.
+struct DecryptedFiles(HashMap<PathBuf, Weak<DecryptedData>>);
+
+impl std::fmt::Debug for DecryptedFiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::encrypted_dir::tests::FailingWriter;
+    use tempfile::tempdir;
+
+    /// A test writer that accepts all writes but fails to flush them.
+    struct FailingFlushWriter;
+
+    impl Write for FailingFlushWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(IoError::new(ErrorKind::Other, "disk full"))
+        }
+    }
+
+    impl TerminatingWrite for FailingFlushWriter {
+        fn terminate_ref(&mut self, _: AntiCallToken) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stream_files_instead_of_buffering_them() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let path = Path::new("segment");
+        let content: Vec<u8> = (0..1_000_000).map(|i| i as u8).collect();
+
+        let mut writer = dir.open_write(path).unwrap();
+        for chunk in content.chunks(10_000) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+
+        // The data reaches the disk before the file is complete.
+        let size = std::fs::metadata(tmpdir.path().join(path)).unwrap().len();
+        assert!(
+            size > 500_000,
+            "The file was buffered, only {} bytes were written",
+            size
+        );
+
+        // A store key rotation waits for the file to be complete.
+        let rotating = dir.clone();
+        let rotated = Arc::new(AtomicBool::new(false));
+        let done = rotated.clone();
+        let rotation = std::thread::spawn(move || {
+            let result = rotating.rotate_store_key();
+            done.store(true, Ordering::SeqCst);
+            result
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!rotated.load(Ordering::SeqCst));
+
+        writer.terminate().unwrap();
+        rotation
+            .join()
+            .unwrap()
+            .expect("Can't rotate the store key");
+
+        assert_eq!(dir.open_read(path).unwrap().as_slice(), &content[..]);
+        drop(dir);
+
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open the store after the store key was rotated");
+        assert_eq!(dir.open_read(path).unwrap().as_slice(), &content[..]);
+    }
+
+    #[test]
+    fn terminate_propagates_write_errors() {
+        let key = [0u8; KEY_SIZE];
+        let provider = EncryptedMmapDirectory::create_provider(&key).unwrap();
+
+        let state = StoreState::new(
+            provider.clone(),
+            provider,
+            Arc::new(WrappingKey::derive("wordpass", &[0u8; SALT_SIZE], 1)),
+        );
+        let mut writer = SealingWriter::new(FailingFlushWriter, Arc::new(state));
+        writer.write_all(b"content").unwrap();
+        let error = writer
+            .terminate()
+            .expect_err("Terminating a sealing writer didn't report the failed write");
+        assert_eq!(error.to_string(), "disk full");
+
+        let mut writer =
+            AesWriter::<Aes256Ctr, Hmac<Sha256>, _>::new(FailingFlushWriter, &key, &key, IV_SIZE)
+                .unwrap();
+        writer.write_all(b"content").unwrap();
+        let error = writer
+            .terminate()
+            .expect_err("Terminating a AES writer didn't report the failed write");
+        assert_eq!(error.to_string(), "disk full");
+    }
+
+    #[test]
+    fn empty_and_single_byte_files() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        for content in [&b""[..], &b"a"[..]].iter() {
+            let path = PathBuf::from(format!("atomic-{}", content.len()));
+            dir.atomic_write(&path, content).unwrap();
+            assert_eq!(
+                std::fs::metadata(tmpdir.path().join(&path)).unwrap().len(),
+                (WRITE_MODE_SIZE + IV_SIZE + content.len() + MAC_LENGTH) as u64
+            );
+            assert_eq!(&dir.atomic_read(&path).unwrap()[..], *content);
+
+            let path = PathBuf::from(format!("segment-{}", content.len()));
+            let mut writer = dir.open_write(&path).unwrap();
+            writer.write_all(content).unwrap();
+            writer.terminate().unwrap();
+            assert_eq!(dir.open_read(&path).unwrap().as_slice(), *content);
+
+            let mut sink = Vec::new();
+            assert_eq!(
+                dir.read_decrypted_into(&path, &mut sink).unwrap(),
+                content.len() as u64
+            );
+            assert_eq!(&sink[..], *content);
+        }
+
+        // A file without a MAC wasn't written by us, even if it's empty.
+        std::fs::write(tmpdir.path().join("truncated"), b"").unwrap();
+        assert!(dir.open_read(Path::new("truncated")).is_err());
+    }
+
+    #[test]
+    fn reject_files_read_in_the_wrong_mode() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        let atomic = Path::new("meta.json");
+        dir.atomic_write(atomic, b"content").unwrap();
+
+        let streamed = Path::new("segment");
+        let mut writer = dir.open_write(streamed).unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.terminate().unwrap();
+
+        assert_eq!(dir.atomic_read(atomic).unwrap(), b"content");
+        assert_eq!(dir.open_read(streamed).unwrap().as_slice(), b"content");
+
+        assert!(
+            dir.open_read(atomic).is_err(),
+            "Read an atomically written file through the streaming path"
+        );
+        assert!(
+            dir.atomic_read(streamed).is_err(),
+            "Read a streamed file through the atomic path"
+        );
+
+        // Rewriting the tag doesn't help, the tag is authenticated.
+        let mut encrypted = std::fs::read(tmpdir.path().join(atomic)).unwrap();
+        encrypted[0] = WriteMode::Streaming as u8;
+        std::fs::write(tmpdir.path().join(atomic), encrypted).unwrap();
+        assert!(dir.open_read(atomic).is_err());
+        assert!(dir.atomic_read(atomic).is_err());
+    }
+
+    #[test]
+    fn pad_files() {
+        for padding in [Padding::PowerOfTwo, Padding::Bucket(4096)].iter() {
+            let tmpdir = tempdir().unwrap();
+            let mut dir =
+                EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                    .expect("Can't create a new store")
+                    .with_padding(*padding)
+                    .expect("Can't enable padding");
+
+            let on_boundary = |path: &Path| {
+                let size = std::fs::metadata(tmpdir.path().join(path)).unwrap().len();
+                match padding {
+                    Padding::PowerOfTwo => size.is_power_of_two(),
+                    Padding::Bucket(bucket) => size % bucket == 0,
+                }
+            };
+
+            for length in [0, 1, 100, 4000, 5000, 70_000].iter() {
+                let content: Vec<u8> = (0..*length).map(|i| i as u8).collect();
+
+                let path = PathBuf::from(format!("atomic-{}", length));
+                dir.atomic_write(&path, &content).unwrap();
+                assert!(on_boundary(&path), "The file size isn't on a boundary");
+                assert_eq!(dir.atomic_read(&path).unwrap(), content);
+
+                let path = PathBuf::from(format!("segment-{}", length));
+                let mut writer = dir.open_write(&path).unwrap();
+                writer.write_all(&content).unwrap();
+                writer.terminate().unwrap();
+                assert!(on_boundary(&path), "The file size isn't on a boundary");
+                assert_eq!(dir.open_read(&path).unwrap().as_slice(), &content[..]);
+
+                let mut sink = Vec::new();
+                assert_eq!(
+                    dir.read_decrypted_into(&path, &mut sink).unwrap(),
+                    content.len() as u64
+                );
+                assert_eq!(sink, content);
+            }
+        }
+    }
+
+    #[test]
+    fn reject_empty_padding_bucket() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+
+        assert!(dir.with_padding(Padding::Bucket(0)).is_err());
+    }
+
+    #[test]
+    fn count_plaintext_and_ciphertext_bytes() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+        let overhead = (WRITE_MODE_SIZE + IV_SIZE + MAC_LENGTH) as u64;
+
+        let atomic = Path::new("meta.json");
+        dir.atomic_write(atomic, &[1u8; 100]).unwrap();
+        assert_eq!(
+            dir.io_stats(),
+            IoStats {
+                plaintext_written: 100,
+                ciphertext_written: 100 + overhead,
+                ..IoStats::default()
+            }
+        );
+
+        let streamed = Path::new("segment.idx");
+        let mut writer = dir.open_write(streamed).unwrap();
+        writer.write_all(&[2u8; 10]).unwrap();
+        writer.terminate().unwrap();
+
+        assert_eq!(dir.atomic_read(atomic).unwrap().len(), 100);
+        assert_eq!(dir.open_read(streamed).unwrap().len(), 10);
+
+        assert_eq!(
+            dir.clone().io_stats(),
+            IoStats {
+                plaintext_read: 110,
+                ciphertext_read: 110 + 2 * overhead,
+                plaintext_written: 110,
+                ciphertext_written: 110 + 2 * overhead,
+            }
+        );
+    }
+
+    #[test]
+    fn round_trip_around_block_boundaries() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store");
+
+        for padding in [None, Some(Padding::Bucket(16))].iter() {
+            if let Some(padding) = padding {
+                dir = dir.with_padding(*padding).unwrap();
+            }
+
+            for length in [15, 16, 17, 32].iter() {
+                let content: Vec<u8> = (1..=*length).map(|i| i as u8).collect();
+
+                let path = PathBuf::from(format!("atomic-{}-{}", length, padding.is_some()));
+                dir.atomic_write(&path, &content).unwrap();
+                assert_eq!(dir.atomic_read(&path).unwrap(), content);
+
+                let path = PathBuf::from(format!("segment-{}-{}", length, padding.is_some()));
+                let mut writer = dir.open_write(&path).unwrap();
+                writer.write_all(&content).unwrap();
+                writer.terminate().unwrap();
+                assert_eq!(dir.open_read(&path).unwrap().as_slice(), &content[..]);
+
+                let mut sink = Vec::new();
+                dir.read_decrypted_into(&path, &mut sink).unwrap();
+                assert_eq!(sink, content);
+
+                let mut sink = Vec::new();
+                dir.pipelined_reader(&path)
+                    .unwrap()
+                    .read_to_end(&mut sink)
+                    .unwrap();
+                assert_eq!(sink, content);
+            }
+        }
+    }
+
+    #[test]
+    fn hide_the_headers_of_files() {
+        let tmpdir = tempdir().unwrap();
+        let mut dir =
+            EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                .expect("Can't create a new store")
+                .with_hidden_headers()
+                .unwrap();
+
+        let content: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let mut masked = BTreeSet::new();
+
+        for i in 0..16 {
+            let segment = PathBuf::from(format!("segment-{}", i));
+            let mut writer = dir.open_write(&segment).unwrap();
+            writer.write_all(&content).unwrap();
+            writer.terminate().unwrap();
+
+            let on_disk = std::fs::read(tmpdir.path().join(&segment)).unwrap();
+            assert_eq!(on_disk[0], HIDDEN_HEADER_TAG);
+            masked.insert(on_disk[WRITE_MODE_SIZE]);
+
+            assert_eq!(dir.open_read(&segment).unwrap().as_slice(), &content[..]);
+
+            let meta = dir.file_crypto_meta(&segment).unwrap();
+            assert_eq!(meta.write_mode, "streaming");
+            assert_eq!(meta.plaintext_length, content.len() as u64);
+        }
+
+        // The masked tags differ from file to file, they don't give away the
+        // write mode.
+        assert!(masked.len() > 1);
+
+        let meta = Path::new("meta.json");
+        dir.atomic_write(meta, b"{}").unwrap();
+        assert_eq!(dir.atomic_read(meta).unwrap(), b"{}");
+        assert!(dir.open_read(meta).is_err());
+
+        // Hidden headers are authenticated, a flipped tag fails to decrypt.
+        let path = tmpdir.path().join("segment-0");
+        let mut on_disk = std::fs::read(&path).unwrap();
+        on_disk[WRITE_MODE_SIZE] ^= 0x03;
+        std::fs::write(&path, &on_disk).unwrap();
+        assert!(dir.open_read(Path::new("segment-0")).is_err());
+
+        // Padding takes the larger header into account.
+        let mut dir = dir.with_padding(Padding::Bucket(512)).unwrap();
+        let mut writer = dir.open_write(Path::new("padded")).unwrap();
+        writer.write_all(b"padded").unwrap();
+        writer.terminate().unwrap();
+        assert_eq!(
+            std::fs::metadata(tmpdir.path().join("padded"))
+                .unwrap()
+                .len(),
+            512
+        );
+        assert_eq!(
+            dir.open_read(Path::new("padded")).unwrap().as_slice(),
+            b"padded"
+        );
+
+        // Reading hidden headers doesn't need to be enabled.
+        drop(dir);
+        let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+        assert_eq!(
+            dir.open_read(Path::new("segment-1")).unwrap().as_slice(),
+            &content[..]
+        );
+    }
+
+    #[test]
+    fn report_seal_failures_without_panicking_on_drop() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+
+        // Streamed files fail as soon as the data is written.
+        let mut writer = SealingWriter::new(FailingWriter, dir.state.clone());
+        assert!(writer.write_all(b"content").is_err());
+        match writer.terminate() {
+            Ok(_) => panic!("Terminated a file that couldn't be written"),
+            Err(e) => assert!(e.to_string().contains("disk full")),
+        }
+
+        let mut writer = SealingWriter::new(FailingWriter, dir.state.clone());
+        assert!(writer.write_all(b"content").is_err());
+        drop(writer);
+        assert_eq!(dir.state.streams.lock().unwrap().running, 0);
+
+        // Buffered files fail once they are sealed.
+        *dir.state.padding.write().unwrap() = Some(Padding::Bucket(4096));
+
+        let mut writer = SealingWriter::new(FailingWriter, dir.state.clone());
+        writer.write_all(b"content").unwrap();
+        match writer.terminate() {
+            Ok(_) => panic!("Terminated a file that couldn't be written"),
+            Err(e) => assert!(e.to_string().contains("disk full")),
+        }
+
+        let mut writer = SealingWriter::new(FailingWriter, dir.state.clone());
+        writer.write_all(b"content").unwrap();
+        drop(writer);
+
+        let mut writer = AesWriter::<Aes256Ctr, Hmac<Sha256>, _>::with_iv(
+            FailingWriter,
+            &[1u8; KEY_SIZE],
+            &[2u8; KEY_SIZE],
+            &[3u8; IV_SIZE],
+            &[],
+        )
+        .unwrap();
+        assert!(writer.finalize().is_err());
+
+        let writer = AesWriter::<Aes256Ctr, Hmac<Sha256>, _>::with_iv(
+            FailingWriter,
+            &[1u8; KEY_SIZE],
+            &[2u8; KEY_SIZE],
+            &[3u8; IV_SIZE],
+            &[],
+        )
+        .unwrap();
+        drop(writer);
+    }
+
+    #[test]
+    fn truncate_authentication_tags() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+
+        assert!(dir.clone().with_tag_size(20).is_err());
+        assert!(dir.clone().with_tag_size(64).is_err());
+
+        let mut dir = dir.with_tag_size(16).unwrap();
+        let content: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+        let segment = Path::new("segment");
+
+        let mut writer = dir.open_write(segment).unwrap();
+        writer.write_all(&content).unwrap();
+        writer.terminate().unwrap();
+        dir.atomic_write(Path::new("meta.json"), b"{}").unwrap();
+
+        let on_disk = std::fs::read(tmpdir.path().join(segment)).unwrap();
+        assert_eq!(
+            on_disk.len(),
+            WRITE_MODE_SIZE + IV_SIZE + content.len() + 16
+        );
+
+        assert_eq!(dir.open_read(segment).unwrap().as_slice(), &content[..]);
+        assert_eq!(dir.atomic_read(Path::new("meta.json")).unwrap(), b"{}");
+
+        let meta = dir.file_crypto_meta(segment).unwrap();
+        assert_eq!(meta.tag_size, 16);
+        assert_eq!(meta.plaintext_length, content.len() as u64);
+
+        // Files with full tags stay readable once truncation is turned off.
+        let mut dir = dir.with_tag_size(MAC_LENGTH).unwrap();
+        dir.atomic_write(Path::new("full"), b"full").unwrap();
+        assert_eq!(
+            dir.file_crypto_meta(Path::new("full")).unwrap().tag_size,
+            MAC_LENGTH
+        );
+        assert_eq!(dir.open_read(segment).unwrap().as_slice(), &content[..]);
+
+        // The tag size is authenticated, a header that claims a different size
+        // fails to decrypt.
+        let mut tampered = on_disk.clone();
+        tampered[0] ^= 0x40 ^ 0x20;
+        std::fs::write(tmpdir.path().join(segment), &tampered).unwrap();
+        assert!(dir.open_read(segment).is_err());
+
+        let mut tampered = on_disk.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        std::fs::write(tmpdir.path().join(segment), &tampered).unwrap();
+        assert!(dir.open_read(segment).is_err());
+    }
+
+    #[test]
+    fn predict_the_ciphertext_overhead() {
+        let tmpdir = tempdir().unwrap();
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+            .expect("Can't create a new store");
+
+        assert_eq!(
+            dir.ciphertext_overhead(0).unwrap(),
+            (WRITE_MODE_SIZE + IV_SIZE + MAC_LENGTH) as u64
+        );
+        drop(dir);
+
+        // The settings are shared by the clones of a directory, every
+        // configuration gets its own store.
+        let configurations: Vec<fn(EncryptedMmapDirectory) -> EncryptedMmapDirectory> = vec![
+            |dir| dir,
+            |dir| dir.with_hidden_headers().unwrap(),
+            |dir| dir.with_tag_size(16).unwrap(),
+            |dir| dir.with_padding(Padding::Bucket(512)).unwrap(),
+            |dir| {
+                dir.with_padding(Padding::PowerOfTwo)
+                    .unwrap()
+                    .with_hidden_headers()
+                    .unwrap()
+            },
+        ];
+
+        for configure in configurations {
+            let tmpdir = tempdir().unwrap();
+            let mut dir = configure(
+                EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+                    .expect("Can't create a new store"),
+            );
+
+            for length in [0usize, 1, 100, 511, 4096, 10_000].iter() {
+                let path = PathBuf::from(format!("file-{}", length));
+                let content = vec![7u8; *length];
+
+                let mut writer = dir.open_write(&path).unwrap();
+                writer.write_all(&content).unwrap();
+                writer.terminate().unwrap();
+
+                let on_disk = std::fs::metadata(tmpdir.path().join(&path)).unwrap().len();
+                assert_eq!(
+                    dir.ciphertext_overhead(*length as u64).unwrap(),
+                    on_disk - *length as u64
+                );
+            }
+        }
+    }
+}
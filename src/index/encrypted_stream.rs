@@ -25,14 +25,72 @@ use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
 use std::ops::Neg;
 
 use crypto_mac::{Mac, MacResult};
+use log::warn;
 
 use aes_ctr::stream_cipher::generic_array::GenericArray;
 use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher, SyncStreamCipherSeek};
 
+#[cfg(feature = "deterministic-rng")]
+use rand::{rngs::StdRng, Rng, SeedableRng};
+#[cfg(not(feature = "deterministic-rng"))]
 use rand::{thread_rng, Rng};
+#[cfg(feature = "deterministic-rng")]
+use std::cell::RefCell;
 
 const BUFFER_SIZE: usize = 8192;
 
+/// The seed of the random number generator in the deterministic mode.
+#[cfg(feature = "deterministic-rng")]
+const DETERMINISTIC_SEED: u64 = 0x0005_e5a7;
+
+// The error message for every authentication failure of an encrypted file.
+//
+// CTR mode doesn't use any padding and the MAC is checked before a single byte
+// gets decrypted, so there's no padding oracle to begin with. Still, a file
+// that is too short to contain an IV and MAC and a file that fails the MAC
+// check return the exact same error so the reason for the failure can't be
+// distinguished by the caller.
+const AUTHENTICATION_ERROR: &str = "Invalid MAC";
+
+pub(crate) fn authentication_error() -> Error {
+    Error::new(ErrorKind::Other, AUTHENTICATION_ERROR)
+}
+
+#[cfg(feature = "deterministic-rng")]
+thread_local! {
+    static DETERMINISTIC_RNG: RefCell<StdRng> = RefCell::new(StdRng::seed_from_u64(DETERMINISTIC_SEED));
+}
+
+/// Fill the given buffer with random bytes.
+///
+/// Every key, salt, IV and nonce of the store is generated here. With the
+/// `deterministic-rng` feature enabled the bytes come from a generator with a
+/// fixed seed instead, see `reset_deterministic_rng()`.
+pub(crate) fn fill_random(buf: &mut [u8]) -> std::result::Result<(), rand::Error> {
+    #[cfg(feature = "deterministic-rng")]
+    {
+        DETERMINISTIC_RNG.with(|rng| rng.borrow_mut().try_fill(buf))
+    }
+
+    #[cfg(not(feature = "deterministic-rng"))]
+    {
+        thread_rng().try_fill(buf)
+    }
+}
+
+/// Reset the random number generator of the current thread to its fixed seed.
+///
+/// Every thread has its own generator, the same sequence of operations on a
+/// thread produces byte for byte the same files after a reset. This is meant
+/// for snapshot tests only, a store created this way is not secure.
+///
+/// The sequence is only stable as long as the `rand` crate keeps the
+/// algorithm of `StdRng` unchanged.
+#[cfg(feature = "deterministic-rng")]
+pub fn reset_deterministic_rng() {
+    DETERMINISTIC_RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(DETERMINISTIC_SEED));
+}
+
 /// Wraps a [`Write`](https://doc.rust-lang.org/std/io/trait.Write.html)
 /// implementation with a [`SyncStreamCipher`][cy], additionally authenticates the
 /// writer with the given [`Mac`][mac]
@@ -62,6 +120,9 @@ impl<E: NewStreamCipher + SyncStreamCipher, M: Mac, W: Write> AesWriter<E, M, W>
     /// * `mac_key`: The authentication key for the MAC.
     /// * `iv_size`: The size of the initialization vector or nonce for the
     /// streaam cipher.
+    // The directory seals whole files using an explicit nonce, this is only
+    // used by our tests for now.
+    #[allow(dead_code)]
     pub fn new(
         mut writer: W,
         key: &[u8],
@@ -69,20 +130,47 @@ impl<E: NewStreamCipher + SyncStreamCipher, M: Mac, W: Write> AesWriter<E, M, W>
         iv_size: usize,
     ) -> Result<AesWriter<E, M, W>> {
         let mut iv = vec![0u8; iv_size];
-        let mut rng = thread_rng();
-        rng.try_fill(&mut iv[..])
+        fill_random(&mut iv[..])
             .map_err(|e| Error::new(ErrorKind::Other, format!("error generating iv: {:?}", e)))?;
 
-        let mac = M::new_varkey(mac_key)
+        writer.write_all(&iv)?;
+        AesWriter::with_iv(writer, key, mac_key, &iv, &[])
+    }
+
+    /// Creates a new AesWriter using the given IV.
+    ///
+    /// Unlike `new()` the IV won't be written to the underlying writer, the
+    /// caller is responsible to store it.
+    ///
+    /// The MAC covers the additional data, the IV, and the ciphertext, it
+    /// will be written at the end of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer`: Writer to write encrypted data into
+    /// * `key`: The encryption key for the stream cipher.
+    /// * `mac_key`: The authentication key for the MAC.
+    /// * `iv`: The initialization vector or nonce for the stream cipher.
+    /// * `aad`: Additional data that will be authenticated but not encrypted.
+    pub fn with_iv(
+        writer: W,
+        key: &[u8],
+        mac_key: &[u8],
+        iv: &[u8],
+        aad: &[u8],
+    ) -> Result<AesWriter<E, M, W>> {
+        let mut mac = M::new_varkey(mac_key)
             .map_err(|e| Error::new(ErrorKind::Other, format!("error creating mac: {:?}", e)))?;
+        mac.input(aad);
+        mac.input(iv);
 
-        let enc = E::new_var(key, &iv).map_err(|e| {
+        let enc = E::new_var(key, iv).map_err(|e| {
             Error::new(
                 ErrorKind::Other,
                 format!("Invalid key or iv length: {}", e.to_string()),
             )
         })?;
-        writer.write_all(&iv)?;
+
         Ok(AesWriter {
             writer,
             enc,
@@ -117,20 +205,23 @@ impl<E: NewStreamCipher + SyncStreamCipher, M: Mac, W: Write> AesWriter<E, M, W>
     }
 
     /// Finalize the file and mark it so no more writes can happen.
+    ///
+    /// Dropping the writer finalizes it as well but can't report errors, a
+    /// file whose MAC couldn't be written fails to authenticate.
     pub fn finalize(&mut self) -> Result<()> {
         // If our encryptor is using padding this write will insert it now.
         // Otherwise it will do nothing.
         self.encrypt_write(&mut [])?;
 
-        // Write our mac after our encrypted data.
-        let mac_result = self.mac.result_reset();
-        self.writer.write_all(mac_result.code().as_slice())?;
-
-        // Mark the file as finalized and flush our underlying writer.
+        // Mark the file as finalized before the MAC gets written, a failed
+        // write can't be retried since the MAC might be partially written.
         self.finalized = true;
-        // self.flush()?;
 
-        Ok(())
+        // Write our mac after our encrypted data and flush our underlying
+        // writer.
+        let mac_result = self.mac.result_reset();
+        self.writer.write_all(mac_result.code().as_slice())?;
+        self.writer.flush()
     }
 }
 
@@ -152,15 +243,15 @@ impl<E: NewStreamCipher + SyncStreamCipher, M: Mac, W: Write> Write for AesWrite
 impl<E: NewStreamCipher + SyncStreamCipher, M: Mac, W: Write> Drop for AesWriter<E, M, W> {
     /// Drop our AesWriter adding the MAC at the end of the file and flushing
     /// our buffers.
+    ///
+    /// This is best effort, errors are only reported by `finalize()`.
     fn drop(&mut self) {
         if self.finalized {
             return;
         }
 
-        if std::thread::panicking() {
-            let _ = self.finalize();
-        } else {
-            self.finalize().unwrap();
+        if let Err(e) = self.finalize() {
+            warn!("Failed to finalize a dropped encrypted file: {}", e);
         }
     }
 }
@@ -196,6 +287,11 @@ impl<D: NewStreamCipher + SyncStreamCipher + SyncStreamCipherSeek, R: Read + See
     /// * `mac_key`: The authentication key for the MAC.
     /// * `iv_size`: The size of the initialization vector or nonce for the
     /// streaam cipher.
+    /// * `mac_size`: The size of the MAC that is stored at the end of the
+    /// file.
+    // The directory opens whole files using an explicit nonce, this is only
+    // used by our tests for now.
+    #[allow(dead_code)]
     pub fn new<M: Mac>(
         mut reader: R,
         key: &[u8],
@@ -203,31 +299,72 @@ impl<D: NewStreamCipher + SyncStreamCipher + SyncStreamCipherSeek, R: Read + See
         iv_size: usize,
         mac_size: usize,
     ) -> Result<AesReader<D, R>> {
-        let iv_length = iv_size;
+        let u_iv_length = u64::try_from(iv_size)
+            .map_err(|_| Error::new(ErrorKind::Other, "IV length is too big"))?;
+
+        let end = reader.seek(SeekFrom::End(0))?;
+
+        if end < u_iv_length {
+            return Err(authentication_error());
+        }
+
+        let mut iv = vec![0u8; iv_size];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut iv)?;
+
+        AesReader::create::<M>(reader, key, mac_key, &iv, &[], mac_size, u_iv_length)
+    }
+
+    /// Creates a new AesReader using the given IV.
+    ///
+    /// Unlike `new()` the reader is expected to contain only the ciphertext
+    /// followed by the MAC.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader`: Reader to read encrypted data from
+    /// * `key`: The decryption key for the stream cipher.
+    /// * `mac_key`: The authentication key for the MAC.
+    /// * `iv`: The initialization vector or nonce for the stream cipher.
+    /// * `aad`: Additional data that was authenticated but not encrypted.
+    /// * `mac_size`: The size of the MAC that is stored at the end of the
+    /// file.
+    pub fn with_iv<M: Mac>(
+        reader: R,
+        key: &[u8],
+        mac_key: &[u8],
+        iv: &[u8],
+        aad: &[u8],
+        mac_size: usize,
+    ) -> Result<AesReader<D, R>> {
+        AesReader::create::<M>(reader, key, mac_key, iv, aad, mac_size, 0)
+    }
 
+    /// Authenticate the IV and the ciphertext that starts at the given offset
+    /// of the reader and create a decryptor for it.
+    fn create<M: Mac>(
+        mut reader: R,
+        key: &[u8],
+        mac_key: &[u8],
+        iv: &[u8],
+        aad: &[u8],
+        mac_size: usize,
+        start: u64,
+    ) -> Result<AesReader<D, R>> {
         let mut mac = M::new_varkey(mac_key)
             .map_err(|e| Error::new(ErrorKind::Other, format!("error creating mac: {:?}", e)))?;
 
-        let mac_length = mac_size;
-
-        let u_iv_length = u64::try_from(iv_length)
-            .map_err(|_| Error::new(ErrorKind::Other, "IV length is too big"))?;
-        let u_mac_length = u64::try_from(mac_length)
+        let u_mac_length = u64::try_from(mac_size)
             .map_err(|_| Error::new(ErrorKind::Other, "MAC length is too big"))?;
-        let i_mac_length = i64::try_from(mac_length)
+        let i_mac_length = i64::try_from(mac_size)
             .map_err(|_| Error::new(ErrorKind::Other, "MAC length is too big"))?;
 
-        let mut iv = vec![0u8; iv_length];
-        let mut expected_mac = vec![0u8; mac_length];
+        let mut expected_mac = vec![0u8; mac_size];
 
-        reader.read_exact(&mut iv)?;
         let end = reader.seek(SeekFrom::End(0))?;
 
-        if end < (u_iv_length + u_mac_length) {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "File doesn't contain a valid IV or MAC",
-            ));
+        if end < (start + u_mac_length) {
+            return Err(authentication_error());
         }
 
         let seek_back = i_mac_length.neg();
@@ -236,7 +373,9 @@ impl<D: NewStreamCipher + SyncStreamCipher + SyncStreamCipherSeek, R: Read + See
 
         let expected_mac = MacResult::new(GenericArray::clone_from_slice(&expected_mac));
 
-        reader.seek(SeekFrom::Start(u_iv_length))?;
+        mac.input(aad);
+        mac.input(iv);
+        reader.seek(SeekFrom::Start(start))?;
 
         let mut buffer = [0u8; BUFFER_SIZE];
 
@@ -252,10 +391,10 @@ impl<D: NewStreamCipher + SyncStreamCipher + SyncStreamCipherSeek, R: Read + See
         }
 
         if mac.result() != expected_mac {
-            return Err(Error::new(ErrorKind::Other, "Invalid MAC"));
+            return Err(authentication_error());
         }
 
-        reader.seek(SeekFrom::Start(u_iv_length))?;
+        reader.seek(SeekFrom::Start(start))?;
 
         let dec = D::new_var(&key, &iv).map_err(|e| {
             Error::new(
@@ -310,12 +449,14 @@ impl<D: NewStreamCipher + SyncStreamCipher + SyncStreamCipherSeek, R: Read + See
     fn read_decrypt(&mut self, buf: &mut [u8]) -> Result<usize> {
         let read =
             AesReader::<D, R>::read_until_mac(buf, &mut self.reader, self.length, self.mac_length)?;
-        self.dec.try_apply_keystream(buf).map_err(|_| {
-            Error::new(
-                ErrorKind::Other,
-                "Decryption error, reached end of the keystream.",
-            )
-        })?;
+        self.dec
+            .try_apply_keystream(&mut buf[..read])
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::Other,
+                    "Decryption error, reached end of the keystream.",
+                )
+            })?;
         Ok(read)
     }
 }
@@ -420,3 +561,48 @@ fn dec_read_unaligned() {
     }
     assert_eq!(dec, &orig);
 }
+
+#[test]
+fn authentication_errors_are_indistinguishable() {
+    let orig = [0u8; 16];
+    let enc = encrypt(&orig);
+    let key = [0u8; 16];
+
+    let mut bad_mac = enc.clone();
+    let last = bad_mac.len() - 1;
+    bad_mac[last] ^= 1;
+
+    let mut bad_ciphertext = enc.clone();
+    bad_ciphertext[16] ^= 1;
+
+    let mut bad_iv = enc.clone();
+    bad_iv[0] ^= 1;
+
+    let truncated = enc[..20].to_vec();
+    let no_iv = enc[..8].to_vec();
+
+    let errors: Vec<Error> = vec![bad_mac, bad_ciphertext, bad_iv, truncated, no_iv]
+        .into_iter()
+        .map(|data| {
+            AesReader::<Aes128Ctr, _>::new::<Hmac<Sha256>>(Cursor::new(data), &key, &key, 16, 32)
+                .err()
+                .expect("Decrypted a tampered file")
+        })
+        .collect();
+
+    for error in &errors {
+        assert_eq!(error.kind(), errors[0].kind());
+        assert_eq!(error.to_string(), errors[0].to_string());
+    }
+}
+
+#[test]
+fn round_trip_around_block_boundaries() {
+    for length in [15, 16, 17, 32].iter() {
+        let orig: Vec<u8> = (1..=*length).map(|i| i as u8).collect();
+
+        let enc = encrypt(&orig);
+        assert_eq!(enc.len(), 16 + orig.len() + 32);
+        assert_eq!(decrypt(Cursor::new(&enc)), orig);
+    }
+}
@@ -12,10 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "encryption")]
+mod crypto;
 #[cfg(feature = "encryption")]
 mod encrypted_dir;
 #[cfg(feature = "encryption")]
 mod encrypted_stream;
+#[cfg(feature = "fuzz")]
+pub use encrypted_dir::fuzz;
+#[cfg(feature = "deterministic-rng")]
+pub use encrypted_stream::reset_deterministic_rng;
 mod japanese_tokenizer;
 
 use std::path::Path;
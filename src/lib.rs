@@ -50,13 +50,15 @@
 
 #![deny(missing_docs)]
 
-#[cfg(test)]
 #[macro_use]
 extern crate lazy_static;
 
 #[macro_use]
 extern crate serde;
 
+#[cfg(feature = "encryption")]
+extern crate alloc;
+
 mod config;
 mod database;
 mod error;
@@ -75,5 +77,10 @@ pub use events::{CheckpointDirection, CrawlerCheckpoint, Event, EventType, Profi
 
 pub use std::sync::mpsc::Receiver;
 
+#[cfg(feature = "fuzz")]
+pub use index::fuzz;
+#[cfg(feature = "deterministic-rng")]
+pub use index::reset_deterministic_rng;
+
 #[cfg(test)]
 pub use events::{EVENT, EVENT_SOURCE, TOPIC_EVENT, TOPIC_EVENT_SOURCE};